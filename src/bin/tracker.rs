@@ -0,0 +1,509 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Runs the tracker without Godot. Opens a camera or a video file, pushes it through the same
+// pipeline the editor uses and writes the packets out as JSON lines or VMC.
+
+use open2dholo::{
+    configuration::{
+        overrides::Overrides,
+        processing_config::{LandmarkBackend, ProcessingConfig},
+        profile::Profile,
+        user_config::UserConfig,
+    },
+    error::processing_thread_error::ProcessingThreadError,
+    output::{OutputTarget, PacketSink},
+    processing::{input_processor::InputProcesser, smoothing::Smoother, supervisor::ThreadExit},
+    util::{
+        camera::{
+            camera_device::VideoFileDevice,
+            device_utils::{
+                enumerate_cache_device, CachedDeviceList, DeviceFormat, PossibleDevice, Resolution,
+            },
+            webcam::Webcam,
+        },
+        log::{self, Level},
+        misc::{Backend, BackendConfig, DecodeOptions},
+    },
+};
+use std::{
+    env,
+    path::PathBuf,
+    process::exit,
+    time::{Duration, Instant},
+};
+
+const USAGE: &str = "\
+Usage: open2dholo-tracker [OPTIONS]
+
+Input, one of:
+    --camera NAME|INDEX   Track a camera, by name or by its number in --list
+    --video PATH          Track a video file, as fast as it can be tracked
+
+Options:
+    --list                List the cameras and what they support, then quit
+    --res WIDTHxHEIGHT    Camera resolution [default: the biggest up to 720p]
+    --fps FPS             Camera frame rate [default: the highest at that resolution]
+    --format FORMAT       Camera format, MJPG, YUYV or NV12 [default: MJPG if there is one]
+    --scale FACTOR        Fraction of the resolution to track at, 1.0 for all of it
+    --threads COUNT       Most threads to use, capture included (at least 3)
+    --output TARGET       Where packets go, can be given more than once [default: stdout]
+                            - or stdout        JSON lines on standard output
+                            vmc://HOST[:PORT]  VMC over UDP, the port defaults to 39539
+                            anything else      JSON lines appended to that file
+                          Videos and recordings to a file get every frame, nothing is dropped
+                          to keep up like it is for a live camera
+    --frames COUNT        Stop after this many frames
+    --seconds SECONDS     Stop after this long
+    --calibrate           Take the first face seen as the neutral pose
+    --verbose             Log debug messages too
+    --models PATH         Where the landmark models are [default: models]
+    --backend NAME        Landmark model, dlib or onnx [default: dlib, onnx with --onnx-model]
+    --onnx-model PATH     The .onnx landmark model, relative paths are looked up in --models
+    --help                Print this and quit
+
+Settings:
+    The smoothing, thread count, landmark model and outputs from the editor's settings are used
+    too, the options above win. These work like they do for the editor:
+    --config PATH         Read the settings from there instead
+    --profile NAME        Use this profile's outputs and calibration
+    --headless-output TARGET
+                          Also send packets there, like --output
+    --set KEY=VALUE       Override a setting, e.g. --set smoothing.enabled=true
+    and the OPEN2DHOLO_* environment variables.
+";
+
+// how long to wait for packets before checking whether it is time to stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// don't pick something enormous just because the camera can do it
+const DEFAULT_MAX_HEIGHT: u32 = 720;
+
+#[derive(Default)]
+struct Args {
+    list: bool,
+    camera: Option<String>,
+    video: Option<String>,
+    res: Option<Resolution>,
+    fps: Option<u32>,
+    format: Option<DeviceFormat>,
+    scale: Option<f64>,
+    threads: Option<usize>,
+    outputs: Vec<OutputTarget>,
+    frames: Option<u64>,
+    seconds: Option<f64>,
+    calibrate: bool,
+    verbose: bool,
+    model_dir: Option<PathBuf>,
+    backend: Option<LandmarkBackend>,
+    onnx_model: Option<String>,
+}
+
+fn main() {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return;
+        }
+        Err(why) => {
+            eprintln!("open2dholo-tracker: {}\n\n{}", why, USAGE);
+            exit(2);
+        }
+    };
+    if let Err(why) = run(args) {
+        eprintln!("open2dholo-tracker: {}", why);
+        exit(1);
+    }
+}
+
+// `None` means --help
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Args>, String> {
+    let mut parsed = Args::default();
+    while let Some(arg) = args.next() {
+        // both `--flag value` and `--flag=value`
+        let (flag, inline_value) = match arg.find('=') {
+            Some(idx) if arg.starts_with("--") => {
+                (arg[..idx].to_string(), Some(arg[idx + 1..].to_string()))
+            }
+            _ => (arg.clone(), None),
+        };
+        let mut value = || match inline_value.clone().or_else(|| args.next()) {
+            Some(value) => Ok(value),
+            None => Err(format!("{} needs a value", flag)),
+        };
+        match &flag[..] {
+            "--help" | "-h" => return Ok(None),
+            "--list" => parsed.list = true,
+            "--calibrate" => parsed.calibrate = true,
+            "--verbose" | "-v" => parsed.verbose = true,
+            "--camera" => parsed.camera = Some(value()?),
+            "--video" => parsed.video = Some(value()?),
+            "--res" => parsed.res = Some(parse_res(&value()?)?),
+            "--fps" => parsed.fps = Some(parse_number(&flag, &value()?)?),
+            "--format" => {
+                let value = value()?;
+                parsed.format = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("{} is not a format", value))?,
+                );
+            }
+            "--scale" => {
+                let scale: f64 = parse_number(&flag, &value()?)?;
+                if scale <= 0_f64 || scale > 1_f64 {
+                    return Err("--scale has to be above 0.0 and at most 1.0".to_string());
+                }
+                parsed.scale = Some(scale);
+            }
+            "--threads" => parsed.threads = Some(parse_number(&flag, &value()?)?),
+            "--output" => {
+                let value = value()?;
+                parsed
+                    .outputs
+                    .push(value.parse().map_err(|why| format!("{}", why))?);
+            }
+            "--frames" => parsed.frames = Some(parse_number(&flag, &value()?)?),
+            "--seconds" => parsed.seconds = Some(parse_number(&flag, &value()?)?),
+            "--models" => parsed.model_dir = Some(PathBuf::from(value()?)),
+            "--backend" => {
+                let value = value()?;
+                parsed.backend = Some(LandmarkBackend::from_name(&value).ok_or_else(|| {
+                    format!(
+                        "{} is not a backend, try one of {}",
+                        value,
+                        LandmarkBackend::NAMES.join(", ")
+                    )
+                })?);
+            }
+            "--onnx-model" => parsed.onnx_model = Some(value()?),
+            // `Overrides` reads these itself
+            "--config" | "--profile" | "--headless-output" | "--set" => {
+                value()?;
+            }
+            _ => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+
+    if parsed.camera.is_some() && parsed.video.is_some() {
+        return Err("--camera and --video can't be used together".to_string());
+    }
+    if parsed.video.is_some()
+        && (parsed.res.is_some() || parsed.fps.is_some() || parsed.format.is_some())
+    {
+        return Err("--res, --fps and --format are for cameras, a video is what it is".to_string());
+    }
+    Ok(Some(parsed))
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} needs a number, not {}", flag, value))
+}
+
+fn parse_res(value: &str) -> Result<Resolution, String> {
+    let mut parts = value.splitn(2, |c| c == 'x' || c == 'X');
+    match (
+        parts.next().and_then(|x| x.parse().ok()),
+        parts.next().and_then(|y| y.parse().ok()),
+    ) {
+        (Some(x), Some(y)) if x > 0 && y > 0 => Ok(Resolution::new(x, y)),
+        _ => Err(format!(
+            "{} is not a resolution, try something like 1280x720",
+            value
+        )),
+    }
+}
+
+fn run(mut args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    if args.verbose {
+        log::set_max_level(Level::Debug);
+    }
+    if args.list {
+        list_devices();
+        return Ok(());
+    }
+
+    let overrides = Overrides::from_env()?;
+    overrides.install();
+    let mut user_cfg = UserConfig::from_cfg()?;
+    if args.camera.is_none() && args.video.is_none() {
+        args.camera = Overrides::take_camera();
+    }
+    if args.camera.is_none() && args.video.is_none() {
+        return Err("Nothing to track, give it --camera or --video".into());
+    }
+    // ours first, then the settings', then the launch options'
+    for target in user_cfg
+        .output()
+        .targets
+        .iter()
+        .chain(Overrides::current().outputs())
+    {
+        if !args.outputs.contains(target) {
+            args.outputs.push(target.clone());
+        }
+    }
+    if args.outputs.is_empty() {
+        args.outputs.push(OutputTarget::Stdout);
+    }
+
+    let (device, name) = match &args.video {
+        Some(path) => {
+            // open it once to find out what is in there, the pipeline opens its own
+            let video = VideoFileDevice::new(path.clone())?;
+            let device = PossibleDevice::VideoFile {
+                path: path.clone(),
+                res: video.get_resolution()?,
+                fps: video.get_framerate()?,
+            };
+            (device, path.clone())
+        }
+        None => pick_camera(&args)?,
+    };
+
+    let mut config = BackendConfig::new(device.res(), backend(&args, user_cfg.processing_mut())?)
+        .with_max_threads(
+            args.threads
+                .unwrap_or_else(|| user_cfg.processing().max_threads()),
+        )
+        .with_cnn_detector(user_cfg.processing().use_cnn())
+        .with_decode(DecodeOptions {
+            grayscale: user_cfg.processing().grayscale_decode(),
+            keep_full_frame: false,
+        });
+    if let Some(scale) = args.scale {
+        config = config.with_scale_factor(scale);
+    }
+    // --calibrate takes a new one anyway
+    if !args.calibrate {
+        if let Some(profile) = Profile::active() {
+            config = config.with_calibration(profile.calibration);
+        }
+    }
+    if let Some(model_dir) = &args.model_dir {
+        config = config.with_model_dir(model_dir.clone());
+    }
+    // nobody is watching live, better late than missing
+    let recording = args
+        .outputs
+        .iter()
+        .any(|target| matches!(target, OutputTarget::File(_)));
+    config = config.with_lossless(args.video.is_some() || recording);
+    eprintln!(
+        "open2dholo-tracker: tracking {} at {} {} fps",
+        name,
+        device.res(),
+        device.fps()
+    );
+
+    let started = Instant::now();
+    let mut sinks = args
+        .outputs
+        .iter()
+        .map(|target| target.open(started))
+        .collect::<Result<Vec<Box<dyn PacketSink>>, _>>()?;
+
+    let mut smoother = Smoother::new(user_cfg.smoothing());
+    let processer = InputProcesser::new(device, config)?;
+    if args.calibrate {
+        processer.calibrate()?;
+    }
+
+    let deadline = args
+        .seconds
+        .map(|secs| started + Duration::from_secs_f64(secs));
+    let mut stopping = false;
+    let exit = loop {
+        let mut packets = processer.wait_results(POLL_INTERVAL);
+        smoother.apply(&mut packets);
+        for packet in packets {
+            // a few frames may already be in flight once the count is reached
+            if args
+                .frames
+                .map_or(false, |frames| packet.frame_number > frames)
+            {
+                continue;
+            }
+            for sink in &mut sinks {
+                sink.send(&packet)?;
+            }
+        }
+        for sink in &mut sinks {
+            sink.flush()?;
+        }
+
+        if let Some(exit) = processer.poll_exit() {
+            break exit;
+        }
+        let out_of_frames = args
+            .frames
+            .map_or(false, |frames| processer.stats().frames_captured >= frames);
+        let out_of_time = deadline.map_or(false, |deadline| Instant::now() >= deadline);
+        if !stopping && (out_of_frames || out_of_time) {
+            // if the thread is already gone, `poll_exit` says so next time around
+            let _ = processer.shutdown();
+            stopping = true;
+        }
+    };
+
+    // whatever was still on its way when the thread stopped
+    while !processer.results_finished() {
+        let mut packets = processer.wait_results(POLL_INTERVAL);
+        smoother.apply(&mut packets);
+        // the thread is gone, so an empty wait means there is nothing left
+        if packets.is_empty() {
+            break;
+        }
+        for packet in packets {
+            if args
+                .frames
+                .map_or(false, |frames| packet.frame_number > frames)
+            {
+                continue;
+            }
+            for sink in &mut sinks {
+                sink.send(&packet)?;
+            }
+        }
+    }
+    for sink in &mut sinks {
+        sink.flush()?;
+    }
+
+    eprintln!("open2dholo-tracker: {}", processer.stats());
+    match exit {
+        ThreadExit::Shutdown | ThreadExit::Failed(ProcessingThreadError::EndOfStream) => Ok(()),
+        ThreadExit::Failed(why) => Err(Box::new(why)),
+        ThreadExit::Panicked(why) => Err(format!("The processing thread panicked: {}", why).into()),
+    }
+}
+
+// the one from the settings unless the command line says otherwise
+fn backend(args: &Args, processing: &mut ProcessingConfig) -> Result<Backend, String> {
+    if let Some(path) = &args.onnx_model {
+        processing.set_onnx_model_path(path.clone());
+        processing.set_landmark_backend(LandmarkBackend::Onnx);
+    }
+    if let Some(backend) = args.backend {
+        processing.set_landmark_backend(backend);
+    }
+    match processing.backend() {
+        Backend::Onnx { model_path, .. } if model_path.is_empty() => {
+            Err("The ONNX landmark model needs --onnx-model".to_string())
+        }
+        backend => Ok(backend),
+    }
+}
+
+fn sorted_devices() -> Vec<CachedDeviceList> {
+    let mut devices: Vec<CachedDeviceList> = enumerate_cache_device()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(_, device)| device)
+        .collect();
+    devices.sort_by_key(CachedDeviceList::get_name);
+    devices
+}
+
+fn list_devices() {
+    let devices = sorted_devices();
+    if devices.is_empty() {
+        println!("No cameras found.");
+    }
+    for (idx, device) in devices.iter().enumerate() {
+        println!("{}: {}", idx, device.get_name());
+        for fmt in device.get_formats() {
+            let mut supported: Vec<(Resolution, Vec<u32>)> =
+                device.get_supported(fmt).into_iter().collect();
+            supported.sort_by(|(a, _), (b, _)| b.cmp(a));
+            for (res, mut fps) in supported {
+                fps.sort_unstable_by(|a, b| b.cmp(a));
+                let fps: Vec<String> = fps.iter().map(ToString::to_string).collect();
+                println!("    {} {} @ {} fps", fmt, res, fps.join(", "));
+            }
+        }
+    }
+}
+
+fn pick_camera(args: &Args) -> Result<(PossibleDevice, String), Box<dyn std::error::Error>> {
+    let wanted = args.camera.clone().unwrap_or_default();
+    let devices = sorted_devices();
+    let device = match wanted.parse::<usize>() {
+        Ok(idx) => devices.get(idx),
+        Err(_) => devices.iter().find(|device| device.get_name() == wanted),
+    };
+    let device = match device {
+        Some(device) => device.clone(),
+        None => return Err(format!("No camera {}, see --list", wanted).into()),
+    };
+
+    let formats = device.get_formats();
+    let fmt = match args.format {
+        Some(fmt) if formats.contains(&fmt) => fmt,
+        Some(fmt) => return Err(format!("{} can't do {}", device.get_name(), fmt).into()),
+        None if formats.contains(&DeviceFormat::MJpeg) => DeviceFormat::MJpeg,
+        None => match formats.first() {
+            Some(fmt) => *fmt,
+            None => return Err(format!("{} has no formats we can read", device.get_name()).into()),
+        },
+    };
+
+    let supported = device.get_supported(fmt);
+    let res = match args.res {
+        Some(res) if supported.contains_key(&res) => res,
+        Some(res) => {
+            return Err(format!("{} can't do {} in {}", device.get_name(), res, fmt).into())
+        }
+        None => {
+            let mut resolutions: Vec<Resolution> = supported.keys().copied().collect();
+            resolutions.sort();
+            match resolutions
+                .iter()
+                .rev()
+                .find(|res| res.y <= DEFAULT_MAX_HEIGHT)
+                .or_else(|| resolutions.first())
+            {
+                Some(res) => *res,
+                None => {
+                    return Err(
+                        format!("{} has no resolutions in {}", device.get_name(), fmt).into(),
+                    )
+                }
+            }
+        }
+    };
+
+    let rates = supported.get(&res).cloned().unwrap_or_default();
+    let fps = match args.fps {
+        Some(fps) if rates.contains(&fps) => fps,
+        Some(fps) => {
+            return Err(format!("{} can't do {} fps at {}", device.get_name(), fps, res).into())
+        }
+        None => match rates.iter().max() {
+            Some(fps) => *fps,
+            None => {
+                return Err(format!("{} has no frame rates at {}", device.get_name(), res).into())
+            }
+        },
+    };
+
+    let name = device.get_name();
+    Ok((
+        PossibleDevice::from_cached_device(device, res, fps, fmt),
+        name,
+    ))
+}
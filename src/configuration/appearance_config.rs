@@ -0,0 +1,50 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::configuration::fields::{Accessor, ConfigField, FieldAccess, SettingsSection};
+use serde::{Deserialize, Serialize};
+
+// what the scene ships with
+const DEFAULT_BACKGROUND: [f32; 3] = [0.121_569, 0.125_49, 0.211_765];
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AppearanceConfig {
+    /// Behind the whole window, RGB 0.0 ~ 1.0.
+    pub background_color: [f32; 3],
+}
+
+impl Default for AppearanceConfig {
+    fn default() -> Self {
+        AppearanceConfig {
+            background_color: DEFAULT_BACKGROUND,
+        }
+    }
+}
+
+impl SettingsSection for AppearanceConfig {
+    const NAME: &'static str = "appearance";
+    const TITLE: &'static str = "Appearance";
+    const FIELDS: &'static [ConfigField<Self>] = &[ConfigField {
+        key: "appearance.background_color",
+        label: "Background",
+        hint: "Behind the whole window.",
+        access: FieldAccess::Color(Accessor {
+            get: |cfg| cfg.background_color,
+            set: |cfg, rgb| cfg.background_color = rgb,
+        }),
+    }];
+}
@@ -0,0 +1,110 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{
+    configuration::{overrides, user_config::UserConfig},
+    log_info, log_warn,
+};
+use flume::{Receiver, Sender};
+use std::{
+    fs::{metadata, read_to_string},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{Builder, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+// a settings file changes when someone saves it, no point in looking more often than this
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Watches the settings file on a background thread and hands out the new settings whenever
+/// someone else changes it. Saves through `UserConfig::write_current` don't count.
+pub struct ConfigWatcher {
+    changes: Receiver<UserConfig>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ConfigWatcher {
+    pub fn new(file_path: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+        let (sender, changes) = flume::unbounded();
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let thread = Builder::new()
+            .name("config_watcher".to_string())
+            .spawn(move || watch_config(file_path, sender, thread_running))?;
+        Ok(ConfigWatcher {
+            changes,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    /// The newest settings since the last call, older ones are already out of date.
+    pub fn query_change(&self) -> Option<UserConfig> {
+        self.changes.try_iter().last()
+    }
+}
+
+impl Drop for ConfigWatcher {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn modified(file_path: &Path) -> Option<SystemTime> {
+    metadata(file_path).and_then(|meta| meta.modified()).ok()
+}
+
+fn watch_config(file_path: PathBuf, sender: Sender<UserConfig>, running: Arc<AtomicBool>) {
+    let origin = file_path.to_string_lossy().to_string();
+    let mut last_modified = modified(&file_path);
+    while running.load(Ordering::Relaxed) {
+        std::thread::sleep(POLL_INTERVAL);
+
+        let now_modified = modified(&file_path);
+        if now_modified == last_modified {
+            continue;
+        }
+        last_modified = now_modified;
+
+        // deleted or mid-save, either way the running settings stay as they are
+        let contents = match read_to_string(&file_path) {
+            Ok(contents) => contents,
+            Err(_why) => continue,
+        };
+        if UserConfig::is_own_write(&contents) {
+            continue;
+        }
+        match UserConfig::from_ron(&contents, &origin) {
+            Ok(mut cfg) => {
+                overrides::layer(&mut cfg);
+                log_info!(Ui, "Reloading settings from {}", origin);
+                if sender.send(cfg).is_err() {
+                    // nobody is listening anymore
+                    return;
+                }
+            }
+            Err(why) => log_warn!(Ui, "Keeping the current settings: {}", why),
+        }
+    }
+}
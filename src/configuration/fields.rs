@@ -0,0 +1,347 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The settings the settings dialog shows. Every config struct describes its own fields through
+// `SettingsSection`, so the dialog can build itself from them and everything else that changes
+// settings by name goes through the same checks.
+
+use crate::{
+    configuration::{processing_config::StreamSettings, user_config::UserConfig},
+    util::camera::device_utils::DeviceDesc,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FieldKind {
+    Toggle,
+    Integer {
+        min: i64,
+        max: i64,
+    },
+    Number {
+        min: f64,
+        max: f64,
+        step: f64,
+    },
+    /// One of a fixed set of names.
+    Choice(&'static [&'static str]),
+    /// Free text, e.g. a path.
+    Text,
+    /// One entry per line, empty lines don't count.
+    Lines,
+    /// RGB, 0.0 ~ 1.0.
+    Color,
+    /// A camera and how to run it. Whoever shows the field knows which ones are plugged in.
+    Camera,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValue {
+    Toggle(bool),
+    Integer(i64),
+    Number(f64),
+    /// For both `Choice` and `Text`.
+    Text(String),
+    Lines(Vec<String>),
+    Color([f32; 3]),
+    /// `None` for no camera at all.
+    Camera(Option<(DeviceDesc, StreamSettings)>),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct SettingField {
+    /// `section.name`, the section being the `SettingsSection::NAME` of the struct it is in.
+    pub key: &'static str,
+    pub label: &'static str,
+    pub hint: &'static str,
+    pub kind: FieldKind,
+}
+
+impl SettingField {
+    pub fn section(&self) -> &'static str {
+        self.key.split('.').next().unwrap_or(self.key)
+    }
+
+    /// Whether `value` fits this field, the error can be shown to the user as is.
+    pub fn check(&self, value: &FieldValue) -> Result<(), String> {
+        match (self.kind, value) {
+            (FieldKind::Toggle, FieldValue::Toggle(_))
+            | (FieldKind::Text, FieldValue::Text(_))
+            | (FieldKind::Lines, FieldValue::Lines(_))
+            | (FieldKind::Camera, FieldValue::Camera(_)) => Ok(()),
+            (FieldKind::Integer { min, max }, FieldValue::Integer(value)) => {
+                if *value < min || *value > max {
+                    Err(format!("has to be between {} and {}", min, max))
+                } else {
+                    Ok(())
+                }
+            }
+            (FieldKind::Number { min, max, .. }, FieldValue::Number(value)) => {
+                if !value.is_finite() || *value < min || *value > max {
+                    Err(format!("has to be between {} and {}", min, max))
+                } else {
+                    Ok(())
+                }
+            }
+            (FieldKind::Choice(names), FieldValue::Text(name)) => {
+                if names.contains(&name.as_str()) {
+                    Ok(())
+                } else {
+                    Err(format!("has to be one of {}", names.join(", ")))
+                }
+            }
+            (FieldKind::Color, FieldValue::Color(rgb)) => {
+                if rgb.iter().all(|c| (0_f32..=1_f32).contains(c)) {
+                    Ok(())
+                } else {
+                    Err("is not a color".to_string())
+                }
+            }
+            _ => Err("got the wrong kind of value".to_string()),
+        }
+    }
+    /// A value for this field written out as text, like on the command line. Toggles take
+    /// true/false, yes/no, on/off or 1/0, lines are split on commas too and colors are `#RRGGBB`
+    /// or `R,G,B` from 0.0 to 1.0. The result still has to pass `check`.
+    pub fn parse(&self, text: &str) -> Result<FieldValue, String> {
+        let text = text.trim();
+        match self.kind {
+            FieldKind::Toggle => match text.to_ascii_lowercase().as_str() {
+                "true" | "yes" | "on" | "1" => Ok(FieldValue::Toggle(true)),
+                "false" | "no" | "off" | "0" => Ok(FieldValue::Toggle(false)),
+                _ => Err(format!("has to be true or false, not {}", text)),
+            },
+            FieldKind::Integer { .. } => text
+                .parse()
+                .map(FieldValue::Integer)
+                .map_err(|_| format!("has to be a whole number, not {}", text)),
+            FieldKind::Number { .. } => text
+                .parse()
+                .map(FieldValue::Number)
+                .map_err(|_| format!("has to be a number, not {}", text)),
+            FieldKind::Choice(_) => Ok(FieldValue::Text(text.to_ascii_lowercase())),
+            FieldKind::Text => Ok(FieldValue::Text(text.to_string())),
+            FieldKind::Lines => Ok(FieldValue::Lines(
+                text.split(|c| c == ',' || c == '\n')
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            )),
+            FieldKind::Color => parse_color(text)
+                .map(FieldValue::Color)
+                .ok_or_else(|| format!("has to be #RRGGBB or R,G,B, not {}", text)),
+            FieldKind::Camera => Err("can't be written out as text".to_string()),
+        }
+    }
+}
+
+fn parse_color(text: &str) -> Option<[f32; 3]> {
+    if let Some(hex) = text.strip_prefix('#') {
+        if hex.len() != 6 || !hex.is_ascii() {
+            return None;
+        }
+        let channel = |idx: usize| {
+            u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16)
+                .ok()
+                .map(|c| f32::from(c) / 255_f32)
+        };
+        return Some([channel(0)?, channel(1)?, channel(2)?]);
+    }
+    let channels: Vec<f32> = text
+        .split(',')
+        .map(|c| c.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    match channels[..] {
+        [r, g, b] => Some([r, g, b]),
+        _ => None,
+    }
+}
+
+/// A config struct that is one section of the settings dialog, and describes its own fields.
+pub trait SettingsSection: Sized + 'static {
+    /// The part of every key before the dot.
+    const NAME: &'static str;
+    const TITLE: &'static str;
+    const FIELDS: &'static [ConfigField<Self>];
+}
+
+/// Reads and writes one value of a `C`.
+pub struct Accessor<C, T> {
+    pub get: fn(&C) -> T,
+    pub set: fn(&mut C, T),
+}
+
+/// What a field holds, and how to get at it. The bounds are the ones `FieldKind` ends up with.
+pub enum FieldAccess<C: 'static> {
+    Toggle(Accessor<C, bool>),
+    Integer {
+        min: i64,
+        max: i64,
+        value: Accessor<C, i64>,
+    },
+    Number {
+        min: f64,
+        max: f64,
+        step: f64,
+        value: Accessor<C, f64>,
+    },
+    Choice {
+        names: &'static [&'static str],
+        value: Accessor<C, &'static str>,
+    },
+    Text(Accessor<C, String>),
+    /// Lines usually have to be parsed into something, which can fail.
+    Lines {
+        get: fn(&C) -> Vec<String>,
+        set: fn(&mut C, Vec<String>) -> Result<(), String>,
+    },
+    Color(Accessor<C, [f32; 3]>),
+    Camera(Accessor<C, Option<(DeviceDesc, StreamSettings)>>),
+}
+
+/// One field of a `SettingsSection`.
+pub struct ConfigField<C: 'static> {
+    pub key: &'static str,
+    pub label: &'static str,
+    pub hint: &'static str,
+    pub access: FieldAccess<C>,
+}
+
+impl<C> ConfigField<C> {
+    pub fn describe(&self) -> SettingField {
+        let kind = match self.access {
+            FieldAccess::Toggle(_) => FieldKind::Toggle,
+            FieldAccess::Integer { min, max, .. } => FieldKind::Integer { min, max },
+            FieldAccess::Number { min, max, step, .. } => FieldKind::Number { min, max, step },
+            FieldAccess::Choice { names, .. } => FieldKind::Choice(names),
+            FieldAccess::Text(_) => FieldKind::Text,
+            FieldAccess::Lines { .. } => FieldKind::Lines,
+            FieldAccess::Color(_) => FieldKind::Color,
+            FieldAccess::Camera(_) => FieldKind::Camera,
+        };
+        SettingField {
+            key: self.key,
+            label: self.label,
+            hint: self.hint,
+            kind,
+        }
+    }
+
+    pub fn get(&self, cfg: &C) -> FieldValue {
+        match &self.access {
+            FieldAccess::Toggle(value) => FieldValue::Toggle((value.get)(cfg)),
+            FieldAccess::Integer { value, .. } => FieldValue::Integer((value.get)(cfg)),
+            FieldAccess::Number { value, .. } => FieldValue::Number((value.get)(cfg)),
+            FieldAccess::Choice { value, .. } => FieldValue::Text((value.get)(cfg).to_string()),
+            FieldAccess::Text(value) => FieldValue::Text((value.get)(cfg)),
+            FieldAccess::Lines { get, .. } => FieldValue::Lines(get(cfg)),
+            FieldAccess::Color(value) => FieldValue::Color((value.get)(cfg)),
+            FieldAccess::Camera(value) => FieldValue::Camera((value.get)(cfg)),
+        }
+    }
+
+    /// Nothing changes if `value` doesn't fit, the error can be shown to the user as is.
+    pub fn set(&self, cfg: &mut C, value: FieldValue) -> Result<(), String> {
+        self.describe().check(&value)?;
+        match (&self.access, value) {
+            (FieldAccess::Toggle(access), FieldValue::Toggle(value)) => (access.set)(cfg, value),
+            (FieldAccess::Integer { value: access, .. }, FieldValue::Integer(value)) => {
+                (access.set)(cfg, value)
+            }
+            (FieldAccess::Number { value: access, .. }, FieldValue::Number(value)) => {
+                (access.set)(cfg, value)
+            }
+            (
+                FieldAccess::Choice {
+                    names,
+                    value: access,
+                },
+                FieldValue::Text(value),
+            ) => {
+                // `check` already made sure it is one of them
+                if let Some(name) = names.iter().find(|name| **name == value) {
+                    (access.set)(cfg, name)
+                }
+            }
+            (FieldAccess::Text(access), FieldValue::Text(value)) => (access.set)(cfg, value),
+            (FieldAccess::Lines { set, .. }, FieldValue::Lines(lines)) => return set(cfg, lines),
+            (FieldAccess::Color(access), FieldValue::Color(value)) => (access.set)(cfg, value),
+            (FieldAccess::Camera(access), FieldValue::Camera(value)) => (access.set)(cfg, value),
+            _ => return Err("got the wrong kind of value".to_string()),
+        }
+        Ok(())
+    }
+}
+
+/// A `SettingsSection` with the type taken out, so `UserConfig` can keep its sections in a list.
+pub trait AnySection {
+    fn name(&self) -> &'static str;
+    fn title(&self) -> &'static str;
+    fn fields(&self) -> Vec<SettingField>;
+    /// `None` if `key` isn't one of the fields of this section.
+    fn field(&self, key: &str) -> Option<FieldValue>;
+    fn set_field(&mut self, key: &str, value: FieldValue) -> Option<Result<(), String>>;
+}
+
+impl<S: SettingsSection> AnySection for S {
+    fn name(&self) -> &'static str {
+        S::NAME
+    }
+
+    fn title(&self) -> &'static str {
+        S::TITLE
+    }
+
+    fn fields(&self) -> Vec<SettingField> {
+        S::FIELDS.iter().map(ConfigField::describe).collect()
+    }
+
+    fn field(&self, key: &str) -> Option<FieldValue> {
+        S::FIELDS
+            .iter()
+            .find(|field| field.key == key)
+            .map(|field| field.get(self))
+    }
+
+    fn set_field(&mut self, key: &str, value: FieldValue) -> Option<Result<(), String>> {
+        S::FIELDS
+            .iter()
+            .find(|field| field.key == key)
+            .map(|field| field.set(self, value))
+    }
+}
+
+/// The sections in the order they are shown, as `(name, title)`.
+pub fn sections() -> Vec<(&'static str, &'static str)> {
+    UserConfig::default()
+        .sections()
+        .iter()
+        .map(|section| (section.name(), section.title()))
+        .collect()
+}
+
+/// Every setting there is, section by section.
+pub fn setting_fields() -> Vec<SettingField> {
+    UserConfig::default()
+        .sections()
+        .iter()
+        .flat_map(|section| section.fields())
+        .collect()
+}
+
+pub fn setting_field(key: &str) -> Option<SettingField> {
+    setting_fields().into_iter().find(|field| field.key == key)
+}
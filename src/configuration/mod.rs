@@ -14,5 +14,10 @@
 //     You should have received a copy of the GNU General Public License
 //     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod appearance_config;
+pub mod config_watcher;
+pub mod fields;
+pub mod overrides;
 pub mod processing_config;
+pub mod profile;
 pub mod user_config;
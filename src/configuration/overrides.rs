@@ -0,0 +1,480 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Launch options that sit on top of the settings file for one run, for scripted stream setups and
+// for running the library under test with a known configuration. From the command line:
+//
+//     --config PATH              Read and write the settings (and profiles next to them) there
+//     --profile NAME             Use this profile instead of the active one
+//     --camera NAME|INDEX        Start this camera, by name or by its place in the sorted list
+//     --model PATH               Load this model instead of the one from the profile
+//     --headless-output TARGET   Also send tracking there, e.g. vmc://127.0.0.1:39539. Can be
+//                                given more than once.
+//     --set KEY=VALUE            Any of `setting_fields`, e.g. --set smoothing.enabled=true
+//
+// or from the environment, as OPEN2DHOLO_CONFIG, OPEN2DHOLO_PROFILE, OPEN2DHOLO_CAMERA,
+// OPEN2DHOLO_MODEL, OPEN2DHOLO_HEADLESS_OUTPUT (comma separated) and OPEN2DHOLO_<KEY> with the
+// dot as an underscore, e.g. OPEN2DHOLO_SMOOTHING_ENABLED=true. The command line wins.
+//
+// `UserConfig::from_cfg` layers the overrides over what the file says and `write_current` puts
+// the file's values back for them, so nothing given here gets saved. Once an overridden setting
+// is changed in the app the override is dropped and the change is saved like any other.
+
+use crate::{
+    configuration::{
+        fields::{setting_field, setting_fields},
+        profile::Profile,
+        user_config::UserConfig,
+    },
+    error::config_error::ConfigError,
+    log_warn,
+    output::OutputTarget,
+};
+use std::{
+    env,
+    fmt::{Display, Formatter},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+pub const ENV_PREFIX: &str = "OPEN2DHOLO_";
+
+// tests that install overrides or go through the settings path, which the overrides decide
+#[cfg(test)]
+lazy_static! {
+    pub(crate) static ref TEST_LOCK: Mutex<()> = Mutex::new(());
+}
+
+lazy_static! {
+    static ref CURRENT: Mutex<Overrides> = Mutex::new(Overrides::default());
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Overrides {
+    config: Option<PathBuf>,
+    profile: Option<String>,
+    camera: Option<String>,
+    model: Option<String>,
+    outputs: Vec<OutputTarget>,
+    // setting key and its value as text, already checked against the field
+    settings: Vec<(&'static str, String)>,
+}
+
+impl Overrides {
+    /// Whatever this process was started with.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::parse(env::vars(), env::args().skip(1))
+    }
+
+    /// `vars` first, then `args` on top. Arguments that aren't ours are skipped, whoever started
+    /// us (Godot, mostly) has its own, and so are variables with our prefix that aren't anything.
+    pub fn parse(
+        vars: impl IntoIterator<Item = (String, String)>,
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<Self, ConfigError> {
+        let mut overrides = Overrides::default();
+        for (name, value) in vars {
+            let option = match name.strip_prefix(ENV_PREFIX) {
+                Some(option) => option.to_ascii_lowercase(),
+                None => continue,
+            };
+            match option.as_str() {
+                "config" | "profile" | "camera" | "model" => {
+                    overrides.set_option(&option, value)?
+                }
+                "headless_output" => {
+                    for target in value.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+                        overrides.set_option("headless-output", target.to_string())?;
+                    }
+                }
+                _ => {
+                    // could be meant for another version, or something else entirely
+                    match setting_fields()
+                        .into_iter()
+                        .find(|field| field.key.replace('.', "_") == option)
+                    {
+                        Some(field) => overrides.set_setting(field.key, &value)?,
+                        None => log_warn!(Ui, "Ignoring {}, there is no such setting", name),
+                    }
+                }
+            }
+        }
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            // both `--flag value` and `--flag=value`
+            let (flag, inline_value) = match arg.find('=') {
+                Some(idx) if arg.starts_with("--") => {
+                    (arg[..idx].to_string(), Some(arg[idx + 1..].to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+            let option = match flag.as_str() {
+                "--config" | "--profile" | "--camera" | "--model" | "--headless-output"
+                | "--set" => &flag[2..],
+                _ => continue,
+            };
+            let value = match inline_value.or_else(|| args.next()) {
+                Some(value) => value,
+                None => {
+                    return Err(ConfigError::InvalidValue(
+                        flag.clone(),
+                        "needs a value".to_string(),
+                    ))
+                }
+            };
+            if option == "set" {
+                let mut parts = value.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    // same as the variables, a typo there shouldn't cost every other option
+                    (Some(key), Some(_)) if setting_field(key.trim()).is_none() => {
+                        log_warn!(Ui, "Ignoring {} {}, there is no such setting", flag, value)
+                    }
+                    (Some(key), Some(setting)) => overrides.set_setting(key.trim(), setting)?,
+                    _ => {
+                        return Err(ConfigError::InvalidValue(
+                            flag,
+                            format!("needs KEY=VALUE, not {}", value),
+                        ))
+                    }
+                }
+            } else {
+                overrides.set_option(option, value)?;
+            }
+        }
+        Ok(overrides)
+    }
+
+    fn set_option(&mut self, option: &str, value: String) -> Result<(), ConfigError> {
+        let flag = format!("--{}", option.replace('_', "-"));
+        if value.trim().is_empty() {
+            return Err(ConfigError::InvalidValue(flag, "is empty".to_string()));
+        }
+        match option {
+            "config" => self.config = Some(PathBuf::from(value)),
+            "profile" => self.profile = Some(value),
+            "camera" => self.camera = Some(value),
+            "model" => self.model = Some(value),
+            _ => {
+                let target: OutputTarget = value.parse().map_err(|why| {
+                    ConfigError::InvalidValue(flag, format!("has a bad target: {}", why))
+                })?;
+                if !self.outputs.contains(&target) {
+                    self.outputs.push(target);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn set_setting(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let field =
+            setting_field(key).ok_or_else(|| ConfigError::UnknownSetting(key.to_string()))?;
+        let invalid = |why: String| ConfigError::InvalidValue(field.key.to_string(), why);
+        field
+            .parse(value)
+            .and_then(|parsed| field.check(&parsed))
+            .map_err(invalid)?;
+        self.settings.retain(|(k, _)| *k != field.key);
+        self.settings.push((field.key, value.trim().to_string()));
+        Ok(())
+    }
+
+    /// Use these for the rest of the run.
+    pub fn install(self) {
+        *CURRENT.lock().unwrap() = self;
+    }
+
+    /// The ones in use right now.
+    pub fn current() -> Self {
+        CURRENT.lock().unwrap().clone()
+    }
+
+    /// The camera to start with. Only the first start gets it, after that it's the user's pick.
+    pub fn take_camera() -> Option<String> {
+        CURRENT.lock().unwrap().camera.take()
+    }
+
+    /// The model to start with, once like `take_camera`.
+    pub fn take_model() -> Option<String> {
+        CURRENT.lock().unwrap().model.take()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Overrides::default()
+    }
+
+    pub fn config(&self) -> Option<&Path> {
+        self.config.as_deref()
+    }
+
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    pub fn camera(&self) -> Option<&str> {
+        self.camera.as_deref()
+    }
+
+    pub fn model(&self) -> Option<&str> {
+        self.model.as_deref()
+    }
+
+    /// Where tracking goes on top of the outputs from the settings.
+    pub fn outputs(&self) -> &[OutputTarget] {
+        &self.outputs
+    }
+
+    // the settings keys the layering touches
+    fn touched_keys(&self) -> Vec<&'static str> {
+        let mut keys: Vec<&'static str> = self.settings.iter().map(|(key, _)| *key).collect();
+        if self.profile.is_some() {
+            keys.extend(&["processing.default_device", "output.targets"]);
+        }
+        keys
+    }
+
+    fn layer_onto(&self, cfg: &mut UserConfig) {
+        if let Some(name) = &self.profile {
+            match Profile::load(name) {
+                Ok(profile) => cfg.apply_profile(&profile),
+                Err(why) => log_warn!(Ui, "Not using profile {}: {}", name, why),
+            }
+        }
+        for (key, value) in &self.settings {
+            let field = match setting_field(key) {
+                Some(field) => field,
+                None => continue,
+            };
+            let applied = field
+                .parse(value)
+                .map_err(|why| ConfigError::InvalidValue(key.to_string(), why))
+                .and_then(|parsed| cfg.set_field(key, parsed));
+            if let Err(why) = applied {
+                log_warn!(Ui, "Not overriding {}: {}", key, why);
+            }
+        }
+    }
+}
+
+impl Display for Overrides {
+    // the way it would be given on the command line
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut args = Vec::new();
+        if let Some(config) = &self.config {
+            args.push(format!("--config {}", config.display()));
+        }
+        let options = &[
+            ("--profile", &self.profile),
+            ("--camera", &self.camera),
+            ("--model", &self.model),
+        ];
+        for (flag, value) in options {
+            if let Some(value) = value {
+                args.push(format!("{} {}", flag, value));
+            }
+        }
+        for target in &self.outputs {
+            args.push(format!("--headless-output {}", target));
+        }
+        for (key, value) in &self.settings {
+            args.push(format!("--set {}={}", key, value));
+        }
+        write!(f, "{}", args.join(" "))
+    }
+}
+
+/// The settings file to use instead of the usual one, if any.
+pub(crate) fn config_path() -> Option<PathBuf> {
+    CURRENT.lock().unwrap().config.clone()
+}
+
+/// `cfg` fresh from a file, with the current overrides on top.
+pub(crate) fn layer(cfg: &mut UserConfig) {
+    // loading the profile wants the settings path, which locks again
+    let overrides = Overrides::current();
+    overrides.layer_onto(cfg);
+}
+
+/// What of `cfg` goes into the file, with `file` being what it says now. Overridden settings
+/// that are still as the override left them get the file's value back; the ones that were
+/// changed since keep the change and lose their override.
+pub(crate) fn unlayer(cfg: &UserConfig, file: &UserConfig) -> UserConfig {
+    let overrides = Overrides::current();
+    let mut out = cfg.clone();
+    let keys = overrides.touched_keys();
+    if keys.is_empty() {
+        return out;
+    }
+    let mut layered = file.clone();
+    overrides.layer_onto(&mut layered);
+
+    let mut changed = Vec::new();
+    for key in keys {
+        if cfg.field(key) != layered.field(key) {
+            changed.push(key);
+            continue;
+        }
+        if let Some(value) = file.field(key) {
+            if let Err(why) = out.set_field(key, value) {
+                log_warn!(Ui, "Could not keep {} as it was: {}", key, why);
+            }
+        }
+    }
+    let profile_switched = cfg.active_profile() != layered.active_profile();
+    if !profile_switched {
+        out.set_active_profile(file.active_profile());
+    }
+
+    let mut current = CURRENT.lock().unwrap();
+    current.settings.retain(|(key, _)| !changed.contains(key));
+    if profile_switched
+        || changed
+            .iter()
+            .any(|key| *key == "processing.default_device" || *key == "output.targets")
+    {
+        current.profile = None;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::fields::FieldValue;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn vars(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_reads_both_flag_forms() {
+        let overrides = Overrides::parse(
+            Vec::new(),
+            args(&[
+                "--config=/tmp/settings.ron",
+                "--profile",
+                "Desk",
+                "--set",
+                "smoothing.enabled=yes",
+            ]),
+        )
+        .unwrap();
+        assert_eq!(overrides.config(), Some(Path::new("/tmp/settings.ron")));
+        assert_eq!(overrides.profile(), Some("Desk"));
+        assert_eq!(
+            overrides.touched_keys(),
+            vec![
+                "smoothing.enabled",
+                "processing.default_device",
+                "output.targets"
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_skips_arguments_that_arent_ours() {
+        let overrides =
+            Overrides::parse(Vec::new(), args(&["--path", "res://", "--camera", "2"])).unwrap();
+        assert_eq!(overrides.camera(), Some("2"));
+        assert_eq!(overrides.config(), None);
+    }
+
+    #[test]
+    fn parse_lets_arguments_win_over_variables() {
+        let overrides = Overrides::parse(
+            vars(&[("OPEN2DHOLO_CAMERA", "0"), ("OPEN2DHOLO_MODEL", "a.vrm")]),
+            args(&["--camera", "1"]),
+        )
+        .unwrap();
+        assert_eq!(overrides.camera(), Some("1"));
+        assert_eq!(overrides.model(), Some("a.vrm"));
+    }
+
+    #[test]
+    fn parse_skips_unknown_settings() {
+        let overrides = Overrides::parse(
+            vars(&[("OPEN2DHOLO_NOT_A_THING", "1")]),
+            args(&[
+                "--set",
+                "smoothing.nope=1",
+                "--profile",
+                "Desk",
+                "--headless-output",
+                "vmc://127.0.0.1:39539",
+            ]),
+        )
+        .unwrap();
+        assert_eq!(overrides.profile(), Some("Desk"));
+        assert_eq!(overrides.outputs().len(), 1);
+        assert!(overrides.settings.is_empty());
+    }
+
+    #[test]
+    fn parse_rejects_bad_values() {
+        assert!(Overrides::parse(Vec::new(), args(&["--set", "smoothing.enabled=maybe"])).is_err());
+        assert!(Overrides::parse(Vec::new(), args(&["--set", "smoothing.enabled"])).is_err());
+        assert!(Overrides::parse(Vec::new(), args(&["--profile"])).is_err());
+        assert!(
+            Overrides::parse(vars(&[("OPEN2DHOLO_SMOOTHING_STRENGTH", "9")]), Vec::new()).is_err()
+        );
+    }
+
+    #[test]
+    fn unlayer_keeps_overrides_out_of_the_file() {
+        let _lock = TEST_LOCK
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        Overrides::parse(
+            Vec::new(),
+            args(&[
+                "--set",
+                "smoothing.enabled=true",
+                "--set",
+                "smoothing.strength=0.8",
+            ]),
+        )
+        .unwrap()
+        .install();
+
+        let file = UserConfig::default();
+        let mut cfg = file.clone();
+        layer(&mut cfg);
+        assert!(cfg.smoothing().enabled);
+        // changed in the app since, that one is the user's now
+        cfg.set_field("smoothing.strength", FieldValue::Number(0.3))
+            .unwrap();
+
+        let written = unlayer(&cfg, &file);
+        assert_eq!(written.smoothing().enabled, file.smoothing().enabled);
+        assert_eq!(
+            written.field("smoothing.strength"),
+            Some(FieldValue::Number(0.3))
+        );
+        assert_eq!(
+            Overrides::current().touched_keys(),
+            vec!["smoothing.enabled"]
+        );
+
+        Overrides::default().install();
+    }
+}
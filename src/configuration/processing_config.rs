@@ -14,13 +14,287 @@
 //     You should have received a copy of the GNU General Public License
 //     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::util::camera::device_utils::DeviceDesc;
+use crate::{
+    configuration::fields::{Accessor, ConfigField, FieldAccess, SettingsSection},
+    processing::{
+        landmark::{LandmarkLayout, LandmarkScale},
+        preprocess::PreprocessConfig,
+    },
+    util::{
+        camera::device_utils::{DeviceDesc, DeviceFormat, Resolution},
+        misc::{Backend, DEFAULT_MAX_THREADS, MIN_THREADS},
+    },
+};
 use serde::{Deserialize, Serialize};
-use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
+// fields missing from the file get filled in from `Default`
 #[derive(Deserialize, Serialize)]
+#[serde(default)]
 pub struct ProcessingConfig {
     pub(crate) use_cnn: AtomicBool,
     pub(crate) max_threads: AtomicUsize,
     pub(crate) default_device: DeviceDesc,
+    pub(crate) default_stream: Option<StreamSettings>,
+    pub(crate) grayscale_decode: bool,
+    pub(crate) preprocess: Vec<DevicePreprocess>,
+    pub(crate) landmark_backend: LandmarkBackend,
+    pub(crate) onnx_model: OnnxModelConfig,
+}
+
+impl Default for ProcessingConfig {
+    fn default() -> Self {
+        ProcessingConfig {
+            use_cnn: AtomicBool::new(false),
+            max_threads: AtomicUsize::new(DEFAULT_MAX_THREADS),
+            default_device: DeviceDesc::from_default(),
+            default_stream: None,
+            grayscale_decode: false,
+            preprocess: Vec::new(),
+            landmark_backend: LandmarkBackend::Dlib,
+            onnx_model: OnnxModelConfig::default(),
+        }
+    }
+}
+
+// the atomics don't clone on their own
+impl Clone for ProcessingConfig {
+    fn clone(&self) -> Self {
+        ProcessingConfig {
+            use_cnn: AtomicBool::new(self.use_cnn()),
+            max_threads: AtomicUsize::new(self.max_threads()),
+            default_device: self.default_device.clone(),
+            default_stream: self.default_stream,
+            grayscale_decode: self.grayscale_decode,
+            preprocess: self.preprocess.clone(),
+            landmark_backend: self.landmark_backend,
+            onnx_model: self.onnx_model.clone(),
+        }
+    }
+}
+
+impl SettingsSection for ProcessingConfig {
+    const NAME: &'static str = "processing";
+    const TITLE: &'static str = "Processing";
+    const FIELDS: &'static [ConfigField<Self>] = &[
+        ConfigField {
+            key: "processing.default_device",
+            label: "Camera on start",
+            hint: "Started with the app if it is plugged in.",
+            access: FieldAccess::Camera(Accessor {
+                get: |cfg| {
+                    cfg.default_device()
+                        .map(|(device, stream)| (device.clone(), stream))
+                },
+                set: |cfg, device| match device {
+                    Some((device, stream)) => cfg.set_default_device(device, stream),
+                    None => cfg.clear_default_device(),
+                },
+            }),
+        },
+        ConfigField {
+            key: "processing.max_threads",
+            label: "Tracking threads",
+            hint: "Threads for tracking in all. Two read the camera and put results in order, the rest work on frames. Applies the next time tracking starts.",
+            access: FieldAccess::Integer {
+                min: MIN_THREADS as i64,
+                max: 64,
+                value: Accessor {
+                    get: |cfg| cfg.max_threads() as i64,
+                    set: |cfg, threads| *cfg.max_threads.get_mut() = threads as usize,
+                },
+            },
+        },
+        ConfigField {
+            key: "processing.use_cnn",
+            label: "CNN face detector",
+            hint: "Finds faces the default detector misses, but is much slower. Applies the next time tracking starts.",
+            access: FieldAccess::Toggle(Accessor {
+                get: |cfg| cfg.use_cnn(),
+                set: |cfg, on| *cfg.use_cnn.get_mut() = on,
+            }),
+        },
+        ConfigField {
+            key: "processing.grayscale_decode",
+            label: "Decode in grayscale",
+            hint: "Cheaper, the face detector doesn't use colour anyway.",
+            access: FieldAccess::Toggle(Accessor {
+                get: |cfg| cfg.grayscale_decode,
+                set: |cfg, on| cfg.grayscale_decode = on,
+            }),
+        },
+        ConfigField {
+            key: "processing.landmark_backend",
+            label: "Landmark model",
+            hint: "dlib's predictor, or the ONNX model below. Applies the next time tracking starts.",
+            access: FieldAccess::Choice {
+                names: LandmarkBackend::NAMES,
+                value: Accessor {
+                    get: |cfg| cfg.landmark_backend.name(),
+                    set: |cfg, name| {
+                        if let Some(backend) = LandmarkBackend::from_name(name) {
+                            cfg.landmark_backend = backend;
+                        }
+                    },
+                },
+            },
+        },
+        ConfigField {
+            key: "processing.onnx_model",
+            label: "ONNX model",
+            hint: "The .onnx file to use with the ONNX landmark model, relative to the models folder.",
+            access: FieldAccess::Text(Accessor {
+                get: |cfg| cfg.onnx_model.path.clone(),
+                set: |cfg, path| cfg.onnx_model.path = path.trim().to_string(),
+            }),
+        },
+    ];
+}
+
+impl ProcessingConfig {
+    // values that parse but can't be used get their default back
+    pub(crate) fn sanitize(&mut self) {
+        let max_threads = self.max_threads.get_mut();
+        if *max_threads == 0 {
+            *max_threads = DEFAULT_MAX_THREADS;
+        } else if *max_threads < MIN_THREADS {
+            *max_threads = MIN_THREADS;
+        }
+    }
+
+    pub fn use_cnn(&self) -> bool {
+        self.use_cnn.load(Ordering::Relaxed)
+    }
+
+    pub fn max_threads(&self) -> usize {
+        self.max_threads.load(Ordering::Relaxed)
+    }
+
+    /// Only decode the brightness of camera frames, see `DecodeOptions::grayscale`.
+    pub fn grayscale_decode(&self) -> bool {
+        self.grayscale_decode
+    }
+
+    /// Which landmark predictor to track with, see `Backend`.
+    pub fn backend(&self) -> Backend {
+        match self.landmark_backend {
+            LandmarkBackend::Dlib => Backend::Dlib,
+            LandmarkBackend::Onnx => Backend::Onnx {
+                model_path: self.onnx_model.path.clone(),
+                input_size: self.onnx_model.input_size,
+                layout: self.onnx_model.layout,
+                scale: self.onnx_model.scale,
+            },
+        }
+    }
+
+    pub fn set_landmark_backend(&mut self, backend: LandmarkBackend) {
+        self.landmark_backend = backend;
+    }
+
+    pub fn set_onnx_model_path(&mut self, path: String) {
+        self.onnx_model.path = path;
+    }
+
+    /// The camera and settings tracking last ran with, if there was one.
+    pub fn default_device(&self) -> Option<(&DeviceDesc, StreamSettings)> {
+        if self.default_device.is_empty() {
+            return None;
+        }
+        self.default_stream
+            .map(|stream| (&self.default_device, stream))
+    }
+
+    pub fn set_default_device(&mut self, device: DeviceDesc, stream: StreamSettings) {
+        self.default_device = device;
+        self.default_stream = Some(stream);
+    }
+
+    /// Don't start any camera with the app.
+    pub fn clear_default_device(&mut self) {
+        self.default_device = DeviceDesc::from_default();
+        self.default_stream = None;
+    }
+
+    /// How frames from this camera get flipped, cropped, etc. before detection.
+    pub fn preprocess_for(&self, device: &DeviceDesc) -> PreprocessConfig {
+        self.preprocess
+            .iter()
+            .find(|entry| entry.device.matches(device))
+            .map(|entry| entry.settings)
+            .unwrap_or_default()
+    }
+
+    pub fn set_preprocess(&mut self, device: DeviceDesc, settings: PreprocessConfig) {
+        match self
+            .preprocess
+            .iter_mut()
+            .find(|entry| entry.device.matches(&device))
+        {
+            Some(entry) => entry.settings = settings,
+            None => self.preprocess.push(DevicePreprocess { device, settings }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DevicePreprocess {
+    pub device: DeviceDesc,
+    pub settings: PreprocessConfig,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct StreamSettings {
+    pub res: Resolution,
+    pub fps: u32,
+    pub fmt: DeviceFormat,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum LandmarkBackend {
+    Dlib,
+    Onnx,
+}
+
+impl LandmarkBackend {
+    /// As written in settings and on the command line.
+    pub const NAMES: &'static [&'static str] = &["dlib", "onnx"];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LandmarkBackend::Dlib => "dlib",
+            LandmarkBackend::Onnx => "onnx",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match &name.to_ascii_lowercase()[..] {
+            "dlib" => Some(LandmarkBackend::Dlib),
+            "onnx" => Some(LandmarkBackend::Onnx),
+            _ => None,
+        }
+    }
+}
+
+/// The ONNX model `LandmarkBackend::Onnx` runs. The defaults fit a PFLD export.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct OnnxModelConfig {
+    /// Relative paths are looked up in the model directory.
+    pub path: String,
+    /// Only used if the model doesn't have a fixed input size.
+    pub input_size: u32,
+    pub layout: LandmarkLayout,
+    pub scale: LandmarkScale,
+}
+
+impl Default for OnnxModelConfig {
+    fn default() -> Self {
+        OnnxModelConfig {
+            path: String::new(),
+            input_size: 112,
+            layout: LandmarkLayout::Wflw98,
+            scale: LandmarkScale::Normalized,
+        }
+    }
 }
@@ -0,0 +1,406 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Named setups to switch between, e.g. the desk camera with one avatar sending VMC and the laptop
+// camera with another. One RON file per profile, next to the settings.
+
+use crate::{
+    configuration::{
+        processing_config::StreamSettings,
+        user_config::{replace_file, UserConfig},
+    },
+    error::profile_error::ProfileError,
+    log_info, log_warn,
+    output::OutputConfig,
+    util::{camera::device_utils::DeviceDesc, misc::NeutralPose},
+};
+use ron::{
+    de::from_str,
+    ser::{to_string_pretty, PrettyConfig},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{read_dir, read_to_string},
+    path::{Path, PathBuf},
+};
+
+/// Same idea as `CONFIG_VERSION`, for profile files.
+pub const PROFILE_VERSION: u32 = 1;
+const PROFILE_DIR: &str = "profiles";
+const PROFILE_EXTENSION: &str = "ron";
+
+/// Where the model sits in the scene, as set in the model editor.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ModelTransform {
+    pub offset: [f64; 3],
+    /// In degrees.
+    pub rotation: [f64; 3],
+}
+
+/// Which blend shapes of the model the tracked face drives.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BlendshapeMapping {
+    pub left_eye: String,
+    pub right_eye: String,
+    pub mouth_open: String,
+}
+
+impl Default for BlendshapeMapping {
+    // what VRoid models call them
+    fn default() -> Self {
+        BlendshapeMapping {
+            left_eye: "blend_shapes/morph_13".to_string(),
+            right_eye: "blend_shapes/morph_14".to_string(),
+            mouth_open: "blend_shapes/morph_29".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Profile {
+    #[serde(default)]
+    version: u32,
+    pub name: String,
+    pub device: DeviceDesc,
+    pub stream: Option<StreamSettings>,
+    /// Whatever the model was loaded from, `res://` paths included.
+    pub model_path: Option<String>,
+    pub model_transform: ModelTransform,
+    pub calibration: Option<NeutralPose>,
+    pub blendshapes: BlendshapeMapping,
+    pub output: OutputConfig,
+}
+
+impl Profile {
+    pub fn new(name: &str) -> Self {
+        Profile {
+            version: PROFILE_VERSION,
+            name: name.to_string(),
+            ..Profile::default()
+        }
+    }
+
+    /// The camera and outputs out of the settings, everything else is up to the caller.
+    pub fn from_user_config(name: &str, user_cfg: &UserConfig) -> Self {
+        let mut profile = Profile::new(name);
+        if let Some((device, stream)) = user_cfg.processing().default_device() {
+            profile.device = device.clone();
+            profile.stream = Some(stream);
+        }
+        profile.output = user_cfg.output().clone();
+        profile
+    }
+
+    /// A copy under another name.
+    pub fn renamed(&self, name: &str) -> Self {
+        Profile {
+            name: name.to_string(),
+            ..self.clone()
+        }
+    }
+
+    pub fn dir() -> PathBuf {
+        let settings_path = UserConfig::settings_path();
+        settings_path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(PROFILE_DIR)
+    }
+
+    /// Names of every profile there is, sorted.
+    pub fn list() -> Vec<String> {
+        let mut names: Vec<String> = Self::read_all()
+            .into_iter()
+            .map(|(_path, profile)| profile.name)
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    pub fn exists(name: &str) -> bool {
+        Self::find(name).is_some()
+    }
+
+    pub fn load(name: &str) -> Result<Self, ProfileError> {
+        match Self::find(name) {
+            Some((_path, profile)) => Ok(profile),
+            None => Err(ProfileError::NotFound(name.to_string())),
+        }
+    }
+
+    /// The profile the settings say was switched to last, if it is still there.
+    pub fn active() -> Option<Self> {
+        let user_cfg = UserConfig::from_cfg().ok()?;
+        let name = user_cfg.active_profile()?;
+        match Self::load(name) {
+            Ok(profile) => Some(profile),
+            Err(why) => {
+                log_warn!(Ui, "Could not load the active profile: {}", why);
+                None
+            }
+        }
+    }
+
+    /// `base`, or `base 2`, `base 3`... whatever isn't taken yet.
+    pub fn unique_name(base: &str) -> String {
+        let base = base.trim();
+        if !Self::exists(base) {
+            return base.to_string();
+        }
+        (2..)
+            .map(|n| format!("{} {}", base, n))
+            .find(|name| !Self::exists(name))
+            .unwrap()
+    }
+
+    pub fn save(&self) -> Result<(), ProfileError> {
+        let path = Self::path_for(&self.name)?;
+        self.write_to(&path)
+    }
+
+    /// Save under a name that must not be taken yet.
+    pub fn save_new(&self) -> Result<(), ProfileError> {
+        if Self::exists(&self.name) {
+            return Err(ProfileError::AlreadyExists(self.name.clone()));
+        }
+        self.save()
+    }
+
+    /// Write a copy of the profile somewhere else, to move it to another machine.
+    pub fn export(&self, path: &Path) -> Result<(), ProfileError> {
+        self.write_to(path)
+    }
+
+    /// Read a profile written by `export`. It isn't saved, and it may well have the name of one
+    /// that is already there.
+    pub fn import(path: &Path) -> Result<Self, ProfileError> {
+        Self::read(path)
+    }
+
+    // every profile file that can be read, in file name order
+    fn read_all() -> Vec<(PathBuf, Self)> {
+        let entries = match read_dir(Self::dir()) {
+            Ok(entries) => entries,
+            Err(_why) => return Vec::new(),
+        };
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension()
+                    .map_or(false, |ext| ext == PROFILE_EXTENSION)
+            })
+            .collect();
+        paths.sort();
+        paths
+            .into_iter()
+            .filter_map(|path| match Self::read(&path) {
+                Ok(profile) => Some((path, profile)),
+                Err(why) => {
+                    log_warn!(Ui, "Skipping profile: {}", why);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    // profiles go by the name inside the file, the file name is only a hint for people browsing
+    // the folder. A file that was renamed by hand still loads under its own name.
+    fn find(name: &str) -> Option<(PathBuf, Self)> {
+        let name = name.trim();
+        Self::read_all()
+            .into_iter()
+            .find(|(_path, profile)| profile.name.trim() == name)
+    }
+
+    fn read(path: &Path) -> Result<Self, ProfileError> {
+        let origin = path.to_string_lossy().to_string();
+        let contents = read_to_string(path)
+            .map_err(|why| ProfileError::Invalid(origin.clone(), why.to_string()))?;
+        let profile: Profile = from_str(&contents)
+            .map_err(|why| ProfileError::Invalid(origin.clone(), why.to_string()))?;
+        if profile.name.trim().is_empty() {
+            return Err(ProfileError::Invalid(origin, "it has no name".to_string()));
+        }
+        Ok(profile.migrate(&origin))
+    }
+
+    fn write_to(&self, path: &Path) -> Result<(), ProfileError> {
+        let origin = path.to_string_lossy().to_string();
+        if self.version > PROFILE_VERSION {
+            return Err(ProfileError::UnsupportedVersion(origin, self.version));
+        }
+        let serialized = to_string_pretty(self, PrettyConfig::default())
+            .map_err(|why| ProfileError::CannotWrite(origin.clone(), why.to_string()))?;
+        replace_file(path, &serialized)
+            .map_err(|why| ProfileError::CannotWrite(origin, why.to_string()))
+    }
+
+    fn migrate(mut self, origin: &str) -> Self {
+        if self.version > PROFILE_VERSION {
+            log_warn!(
+                Ui,
+                "{} is from a newer version (profile version {}, we know {}), some of it may be ignored",
+                origin,
+                self.version,
+                PROFILE_VERSION
+            );
+            return self;
+        }
+        if self.version == 0 {
+            // nothing to migrate from yet, profiles came with a version from the start
+            log_info!(
+                Ui,
+                "{} has no version, treating it as {}",
+                origin,
+                PROFILE_VERSION
+            );
+            self.version = PROFILE_VERSION;
+        }
+        self
+    }
+
+    // the file the profile is in already, or a new one named after it. Names that only differ in
+    // what gets replaced below ("My Cam" and "my_cam") get a number added instead of sharing a
+    // file.
+    fn path_for(name: &str) -> Result<PathBuf, ProfileError> {
+        if let Some((path, _profile)) = Self::find(name) {
+            return Ok(path);
+        }
+        let stem = Self::stem_for(name)?;
+        let dir = Self::dir();
+        let free = |stem: &str| {
+            let path = dir.join(format!("{}.{}", stem, PROFILE_EXTENSION));
+            if path.exists() {
+                None
+            } else {
+                Some(path)
+            }
+        };
+        Ok(free(&stem)
+            .or_else(|| (2..).find_map(|n| free(&format!("{}_{}", stem, n))))
+            .unwrap())
+    }
+
+    // the profile name with everything a file system might choke on replaced
+    fn stem_for(name: &str) -> Result<String, ProfileError> {
+        let stem: String = name
+            .trim()
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '-' || c == '_' {
+                    c.to_ascii_lowercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        if stem.trim_matches('_').is_empty() {
+            return Err(ProfileError::InvalidName(name.to_string()));
+        }
+        Ok(stem)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::overrides::{Overrides, TEST_LOCK};
+    use std::fs::{create_dir_all, remove_dir_all, rename};
+
+    // points the settings, and with them the profiles, at an empty dir for the length of `test`
+    fn in_empty_dir(label: &str, test: impl FnOnce()) {
+        let _lock = TEST_LOCK
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let dir =
+            std::env::temp_dir().join(format!("open2dholo-test-{}-{}", label, std::process::id()));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        let config = dir.join("settings.ron").to_string_lossy().to_string();
+        Overrides::parse(Vec::new(), vec!["--config".to_string(), config])
+            .unwrap()
+            .install();
+        test();
+        Overrides::default().install();
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn similar_names_get_their_own_files() {
+        in_empty_dir("profile-clash", || {
+            Profile::new("My Cam").save_new().unwrap();
+            Profile::new("my_cam").save_new().unwrap();
+            Profile::new("my cam").save_new().unwrap();
+            let dir = Profile::dir();
+            assert!(dir.join("my_cam.ron").exists());
+            assert!(dir.join("my_cam_2.ron").exists());
+            assert!(dir.join("my_cam_3.ron").exists());
+            assert_eq!(Profile::list(), vec!["My Cam", "my cam", "my_cam"]);
+            assert_eq!(Profile::load("my_cam").unwrap().name, "my_cam");
+        });
+    }
+
+    #[test]
+    fn saving_again_keeps_the_file() {
+        in_empty_dir("profile-resave", || {
+            Profile::new("Desk").save_new().unwrap();
+            let mut profile = Profile::load("Desk").unwrap();
+            profile.model_path = Some("res://model.vrm".to_string());
+            profile.save().unwrap();
+            assert!(!Profile::dir().join("desk_2.ron").exists());
+            assert_eq!(
+                Profile::load("Desk").unwrap().model_path.as_deref(),
+                Some("res://model.vrm")
+            );
+            assert!(matches!(
+                Profile::new("Desk").save_new(),
+                Err(ProfileError::AlreadyExists(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn renamed_files_load_by_their_name() {
+        in_empty_dir("profile-renamed", || {
+            Profile::new("Desk").save_new().unwrap();
+            let dir = Profile::dir();
+            rename(dir.join("desk.ron"), dir.join("whatever.ron")).unwrap();
+            assert_eq!(Profile::load("Desk").unwrap().name, "Desk");
+            assert!(matches!(
+                Profile::load("whatever"),
+                Err(ProfileError::NotFound(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn names_without_anything_usable_are_rejected() {
+        in_empty_dir("profile-invalid", || {
+            assert!(matches!(
+                Profile::new("???").save(),
+                Err(ProfileError::InvalidName(_))
+            ));
+            assert_eq!(Profile::unique_name("Desk"), "Desk");
+            Profile::new("Desk").save_new().unwrap();
+            assert_eq!(Profile::unique_name("Desk"), "Desk 2");
+        });
+    }
+}
@@ -14,60 +14,380 @@
 //     You should have received a copy of the GNU General Public License
 //     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::configuration::processing_config::ProcessingConfig;
-use crate::error::config_error::ConfigError;
-use crate::util::camera::device_utils::DeviceDesc;
-use ron::de::from_reader;
+use crate::{
+    configuration::{
+        appearance_config::AppearanceConfig,
+        fields::{setting_field, AnySection, FieldValue},
+        overrides,
+        processing_config::ProcessingConfig,
+        profile::Profile,
+    },
+    error::config_error::ConfigError,
+    log_info, log_warn,
+    output::OutputConfig,
+    processing::smoothing::SmoothingConfig,
+};
+use ron::{
+    de::from_str,
+    ser::{to_string_pretty, PrettyConfig},
+};
 use serde::{Deserialize, Serialize};
-use std::sync::atomic::{AtomicBool, AtomicUsize};
-use std::{fs::File, path::Path};
+use std::{
+    fs::{copy, create_dir_all, read_to_string, rename, write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
-#[derive(Serialize, Deserialize)]
+/// Goes up whenever the settings change in a way new fields with defaults can't cover, with a
+/// step in `migrate` that gets older files there.
+pub const CONFIG_VERSION: u32 = 1;
+const CONFIG_DIR: &str = "open2dholo";
+const SETTINGS_FILE: &str = "settings.ron";
+// where the settings lived before they moved to the config dir, relative to the working directory
+const LEGACY_SETTINGS_PATH: &str = "config/settings.ron";
+
+lazy_static! {
+    // what got written last, so the watcher can tell our own saves from someone editing the file
+    static ref LAST_WRITTEN: Mutex<Option<String>> = Mutex::new(None);
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct UserConfig {
+    // files from before there was a version don't have one, they are version 0
+    #[serde(default)]
+    version: u32,
     processing: ProcessingConfig,
+    smoothing: SmoothingConfig,
+    output: OutputConfig,
+    appearance: AppearanceConfig,
+    /// The name of the profile that was switched to last, see `Profile`.
+    active_profile: Option<String>,
 }
 
-impl UserConfig {
-    pub fn from_default() -> Self {
+impl Default for UserConfig {
+    fn default() -> Self {
         UserConfig {
-            processing: ProcessingConfig {
-                use_cnn: AtomicBool::new(false),
-                max_threads: AtomicUsize::new(8),
-                default_device: DeviceDesc::from_default(),
-            },
+            version: CONFIG_VERSION,
+            processing: ProcessingConfig::default(),
+            smoothing: SmoothingConfig::default(),
+            output: OutputConfig::default(),
+            appearance: AppearanceConfig::default(),
+            active_profile: None,
         }
     }
+}
+
+impl UserConfig {
+    pub fn from_default() -> Self {
+        UserConfig::default()
+    }
 
+    /// Reads the settings from `settings_path`, with the launch overrides on top (see
+    /// `overrides`). Settings still sitting in the old place next to the executable get moved
+    /// over first, and no file at all means the defaults.
     pub fn from_cfg() -> Result<Self, Box<dyn std::error::Error>> {
-        let file_path: &Path = Path::new("config/settings.ron");
-        let cfg_file = File::open(file_path);
-        match cfg_file {
-            Ok(file) => match from_reader(file) {
-                Ok(cfg) => Ok(cfg),
-                Err(_why) => Err(Box::new(ConfigError::InvalidConfiguration(String::from(
-                    "config/settings.ron",
-                )))),
-            },
-            Err(_why) => Err(Box::new(ConfigError::FileNotFound(String::from(
-                "config/settings.ron",
-            )))),
+        let file_path = Self::settings_path();
+        if !file_path.exists() && overrides::config_path().is_none() {
+            move_legacy_settings(&file_path);
         }
+        let mut cfg = if file_path.exists() {
+            Self::load(&file_path)?
+        } else {
+            Self::default()
+        };
+        overrides::layer(&mut cfg);
+        Ok(cfg)
     }
 
     pub fn from_custom_cfg(file_path: Box<Path>) -> Result<Self, Box<dyn std::error::Error>> {
-        let file_path_str = match file_path.to_path_buf().into_os_string().into_string() {
-            Ok(p) => p,
-            Err(_str) => return Err(Box::new(ConfigError::InvalidPath)),
+        if file_path.to_str().is_none() {
+            return Err(Box::new(ConfigError::InvalidPath));
+        }
+        Ok(Self::load(&file_path)?)
+    }
+
+    /// Where the settings are read from and written to, the platform config dir if there is one
+    /// and `--config` didn't say otherwise.
+    pub fn settings_path() -> PathBuf {
+        if let Some(path) = overrides::config_path() {
+            return path;
+        }
+        match dirs::config_dir() {
+            Some(dir) => dir.join(CONFIG_DIR).join(SETTINGS_FILE),
+            None => PathBuf::from(LEGACY_SETTINGS_PATH),
+        }
+    }
+
+    /// Parse settings from RON and bring them up to `CONFIG_VERSION`. Anything missing gets its
+    /// default. `origin` is only used in errors.
+    pub fn from_ron(contents: &str, origin: &str) -> Result<Self, ConfigError> {
+        let cfg: UserConfig = from_str(contents).map_err(|why| {
+            log_warn!(Ui, "Could not parse {}: {}", origin, why);
+            ConfigError::InvalidConfiguration(origin.to_string(), why.to_string())
+        })?;
+        Ok(cfg.migrate(origin))
+    }
+
+    fn load(file_path: &Path) -> Result<Self, ConfigError> {
+        let origin = file_path.to_string_lossy().to_string();
+        let contents =
+            read_to_string(file_path).map_err(|_why| ConfigError::FileNotFound(origin.clone()))?;
+        Self::from_ron(&contents, &origin)
+    }
+
+    fn migrate(mut self, origin: &str) -> Self {
+        if self.version > CONFIG_VERSION {
+            // best effort, unknown fields are skipped. `write_current` won't touch it though.
+            log_warn!(
+                Ui,
+                "{} is from a newer version (settings version {}, we know {}), some settings may be ignored",
+                origin,
+                self.version,
+                CONFIG_VERSION
+            );
+            return self;
+        }
+        let from = self.version;
+        if self.version == 0 {
+            // 0 -> 1: nothing inside changed, the file just moved into the config dir
+            self.version = 1;
+        }
+        if from != self.version {
+            log_info!(
+                Ui,
+                "Migrated {} from settings version {} to {}",
+                origin,
+                from,
+                self.version
+            );
+        }
+        self.processing.sanitize();
+        self
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn processing(&self) -> &ProcessingConfig {
+        &self.processing
+    }
+
+    pub fn processing_mut(&mut self) -> &mut ProcessingConfig {
+        &mut self.processing
+    }
+
+    pub fn smoothing(&self) -> SmoothingConfig {
+        self.smoothing
+    }
+
+    pub fn output(&self) -> &OutputConfig {
+        &self.output
+    }
+
+    pub fn appearance(&self) -> AppearanceConfig {
+        self.appearance
+    }
+
+    pub fn active_profile(&self) -> Option<&str> {
+        self.active_profile.as_deref()
+    }
+
+    pub(crate) fn set_active_profile(&mut self, name: Option<&str>) {
+        self.active_profile = name.map(str::to_string);
+    }
+
+    /// Take over the camera and outputs of `profile` and remember it as the active one. The
+    /// rest of a profile is up to whoever shows the model.
+    pub fn apply_profile(&mut self, profile: &Profile) {
+        match profile.stream {
+            Some(stream) if !profile.device.is_empty() => self
+                .processing
+                .set_default_device(profile.device.clone(), stream),
+            _ => self.processing.clear_default_device(),
+        }
+        self.output = profile.output.clone();
+        self.active_profile = Some(profile.name.clone());
+    }
+
+    /// Every part of the settings the settings dialog shows, in the order it shows them.
+    pub(crate) fn sections(&self) -> Vec<&dyn AnySection> {
+        vec![
+            &self.processing as &dyn AnySection,
+            &self.smoothing,
+            &self.output,
+            &self.appearance,
+        ]
+    }
+
+    fn sections_mut(&mut self) -> Vec<&mut dyn AnySection> {
+        vec![
+            &mut self.processing as &mut dyn AnySection,
+            &mut self.smoothing,
+            &mut self.output,
+            &mut self.appearance,
+        ]
+    }
+
+    /// The current value of one of `setting_fields`.
+    pub fn field(&self, key: &str) -> Option<FieldValue> {
+        self.sections()
+            .into_iter()
+            .find_map(|section| section.field(key))
+    }
+
+    /// Change one of `setting_fields`, nothing changes if `value` doesn't fit.
+    pub fn set_field(&mut self, key: &str, value: FieldValue) -> Result<(), ConfigError> {
+        let field =
+            setting_field(key).ok_or_else(|| ConfigError::UnknownSetting(key.to_string()))?;
+        let section = self
+            .sections_mut()
+            .into_iter()
+            .find(|section| section.name() == field.section())
+            .ok_or_else(|| ConfigError::UnknownSetting(key.to_string()))?;
+        match section.set_field(key, value) {
+            Some(result) => {
+                result.map_err(|why| ConfigError::InvalidValue(field.label.to_string(), why))
+            }
+            None => Err(ConfigError::UnknownSetting(key.to_string())),
+        }
+    }
+
+    pub fn write_current(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let file_path = Self::settings_path();
+        let origin = file_path.to_string_lossy().to_string();
+        if self.version > CONFIG_VERSION {
+            return Err(Box::new(ConfigError::UnsupportedVersion(
+                origin,
+                self.version,
+            )));
+        }
+        let cannot_write = |why: String| ConfigError::CannotWrite(origin.clone(), why);
+        // whatever only the launch options say stays out of the file, and a file we can't read
+        // is left alone rather than replaced with defaults plus our changes
+        let on_disk = if file_path.exists() {
+            Self::load(&file_path)
+                .map_err(|why| ConfigError::WontOverwrite(origin.clone(), why.to_string()))?
+        } else {
+            Self::default()
         };
-        let cfg_file = File::open(file_path);
-        match cfg_file {
-            Ok(file) => match from_reader(file) {
-                Ok(cfg) => Ok(cfg),
-                Err(_why) => Err(Box::new(ConfigError::InvalidConfiguration(file_path_str))),
-            },
-            Err(_why) => Err(Box::new(ConfigError::FileNotFound(file_path_str))),
+        let to_write = overrides::unlayer(self, &on_disk);
+        let serialized = to_string_pretty(&to_write, PrettyConfig::default())
+            .map_err(|why| cannot_write(why.to_string()))?;
+        *LAST_WRITTEN.lock().unwrap() = Some(serialized.clone());
+        replace_file(&file_path, &serialized).map_err(|why| cannot_write(why.to_string()))?;
+        Ok(())
+    }
+
+    /// Whether `contents` is exactly what `write_current` wrote last.
+    pub(crate) fn is_own_write(contents: &str) -> bool {
+        LAST_WRITTEN.lock().unwrap().as_deref() == Some(contents)
+    }
+}
+
+/// Writes next to `file_path` and swaps the result in, so nobody ever reads half a file.
+pub(crate) fn replace_file(file_path: &Path, contents: &str) -> std::io::Result<()> {
+    if let Some(dir) = file_path.parent() {
+        create_dir_all(dir)?;
+    }
+    let mut tmp_name = file_path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    write(&tmp_path, contents)?;
+    rename(&tmp_path, file_path)
+}
+
+// the old file stays where it is, in case an older build still wants it
+fn move_legacy_settings(file_path: &Path) {
+    let legacy = Path::new(LEGACY_SETTINGS_PATH);
+    if file_path == legacy || !legacy.exists() {
+        return;
+    }
+    if let Some(dir) = file_path.parent() {
+        if let Err(why) = create_dir_all(dir) {
+            log_warn!(Ui, "Could not create {}: {}", dir.display(), why);
+            return;
         }
     }
+    match copy(legacy, file_path) {
+        Ok(_) => log_info!(
+            Ui,
+            "Moved settings from {} to {}",
+            legacy.display(),
+            file_path.display()
+        ),
+        Err(why) => log_warn!(
+            Ui,
+            "Could not move settings from {} to {}: {}",
+            legacy.display(),
+            file_path.display(),
+            why
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        configuration::overrides::Overrides,
+        util::misc::{DEFAULT_MAX_THREADS, MIN_THREADS},
+    };
+
+    #[test]
+    fn missing_version_is_migrated() {
+        let cfg = UserConfig::from_ron("(smoothing: (enabled: true))", "test").unwrap();
+        assert_eq!(cfg.version(), CONFIG_VERSION);
+        assert!(cfg.smoothing().enabled);
+        // everything the file doesn't have is the default
+        assert_eq!(cfg.appearance(), AppearanceConfig::default());
+    }
 
-    //pub fn write_current(&self) -> Result<(), Box<dyn std::error::Error>> {}
+    #[test]
+    fn newer_version_is_left_alone() {
+        let cfg = UserConfig::from_ron("(version: 99)", "test").unwrap();
+        assert_eq!(cfg.version(), 99);
+        assert!(cfg.write_current().is_err());
+    }
+
+    #[test]
+    fn unusable_values_get_their_default() {
+        let cfg = UserConfig::from_ron("(processing: (max_threads: 0))", "test").unwrap();
+        assert_eq!(cfg.processing().max_threads(), DEFAULT_MAX_THREADS);
+        let cfg = UserConfig::from_ron("(processing: (max_threads: 1))", "test").unwrap();
+        assert_eq!(cfg.processing().max_threads(), MIN_THREADS);
+    }
+
+    #[test]
+    fn broken_file_is_an_error() {
+        assert!(matches!(
+            UserConfig::from_ron("(smoothing: (enabled: 7", "test"),
+            Err(ConfigError::InvalidConfiguration(_, _))
+        ));
+    }
+
+    #[test]
+    fn broken_file_is_not_overwritten() {
+        let _lock = overrides::TEST_LOCK
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let dir = std::env::temp_dir().join(format!("open2dholo-test-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let path = dir.join("broken_settings.ron");
+        write(&path, "(smoothing: (enabled: 7").unwrap();
+        Overrides::parse(
+            Vec::new(),
+            vec!["--config".to_string(), path.to_string_lossy().to_string()],
+        )
+        .unwrap()
+        .install();
+
+        let written = UserConfig::default().write_current();
+
+        Overrides::default().install();
+        assert!(written.is_err());
+        assert_eq!(read_to_string(&path).unwrap(), "(smoothing: (enabled: 7");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
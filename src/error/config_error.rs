@@ -19,8 +19,18 @@ use thiserror::Error;
 pub enum ConfigError {
     #[error("Config file not found at location: {0}!")]
     FileNotFound(String),
-    #[error("Config file at {0} is invalid!")]
-    InvalidConfiguration(String),
+    #[error("Config file at {0} is invalid: {1}")]
+    InvalidConfiguration(String, String),
+    #[error("Not saving to {0}, the file there can't be read ({1}). Fix or remove it first.")]
+    WontOverwrite(String, String),
+    #[error("Could not write config file at {0}: {1}")]
+    CannotWrite(String, String),
+    #[error("Config file at {0} is from a newer version ({1}), not overwriting it")]
+    UnsupportedVersion(String, u32),
+    #[error("There is no setting called {0}")]
+    UnknownSetting(String),
+    #[error("{0} {1}")]
+    InvalidValue(String, String),
     #[error("Path is invalid! (Could not be converted)")]
     InvalidPath,
     #[error("General Error, Could not load config.")]
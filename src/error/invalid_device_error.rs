@@ -38,4 +38,6 @@ pub enum InvalidDeviceError {
     InvalidPlatform(String),
     #[error("Cannot get frame from camera: {0}")]
     CannotGetFrame(String),
+    #[error("The video has no frames left")]
+    EndOfStream,
 }
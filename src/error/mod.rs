@@ -18,5 +18,8 @@ pub mod config_error;
 pub mod conversion_error;
 pub mod invalid_device_error;
 pub mod model_error;
+pub mod output_error;
 pub mod processing_error;
+pub mod processing_thread_error;
+pub mod profile_error;
 pub mod thread_send_message_error;
@@ -0,0 +1,28 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use thiserror::Error;
+
+/// Something went wrong getting packets out of the tracker.
+#[derive(Error, Debug)]
+pub enum OutputError {
+    #[error("Not an output: {0}")]
+    InvalidTarget(String),
+    #[error("Could not open output {0}: {1}")]
+    CannotOpen(String, String),
+    #[error("Could not write to output {0}: {1}")]
+    CannotWrite(String, String),
+}
@@ -26,4 +26,8 @@ pub enum ProcessingError {
     LandmarkPredictorNotFound(String),
     #[error("Expected 68 landmark points, only found {0}!")]
     AllPointsNotDetected(usize),
+    #[error("Could not get the ONNX Landmark Model at filepath: {0}!")]
+    OnnxModelNotFound(String),
+    #[error("Landmark model output has the wrong size: expected {expected}, got {got}!")]
+    InvalidModelOutput { expected: usize, got: usize },
 }
@@ -0,0 +1,41 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use thiserror::Error;
+
+/// Why the processing thread died, or why it refused a request.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ProcessingThreadError {
+    #[error("Could not open the camera: {0}")]
+    CannotOpenCamera(String),
+    #[error("Could not get a frame from the camera: {0}")]
+    CannotCapture(String),
+    /// Only happens with video files, cameras don't run out.
+    #[error("The video has no frames left.")]
+    EndOfStream,
+    #[error("Could not change the camera settings: {0}")]
+    CannotConfigureCamera(String),
+    #[error("Could not load the landmark model: {0}")]
+    CannotLoadModel(String),
+    #[error("Could not start the {0} thread: {1}")]
+    CannotSpawn(String, String),
+    #[error("A worker thread died.")]
+    WorkerDied,
+    #[error("Nobody is listening to the processing thread anymore.")]
+    Disconnected,
+    #[error("The processing thread is not paused.")]
+    NotPaused,
+}
@@ -0,0 +1,33 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ProfileError {
+    #[error("\"{0}\" can't be used as a profile name")]
+    InvalidName(String),
+    #[error("There is no profile called \"{0}\"")]
+    NotFound(String),
+    #[error("There already is a profile called \"{0}\"")]
+    AlreadyExists(String),
+    #[error("Profile file at {0} is invalid: {1}")]
+    Invalid(String, String),
+    #[error("Profile file at {0} is from a newer version ({1}), not overwriting it")]
+    UnsupportedVersion(String, u32),
+    #[error("Could not write profile file at {0}: {1}")]
+    CannotWrite(String, String),
+}
@@ -20,6 +20,8 @@ use thiserror::Error;
 pub enum ThreadSendMessageError {
     #[error("Error sending message to thread.")]
     CannotSend,
+    #[error("The processing thread is not keeping up with messages.")]
+    QueueFull,
     #[error("Create a new thread")]
     CreateNewThread,
 }
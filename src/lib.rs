@@ -33,14 +33,21 @@
 #![allow(clippy::never_loop)]
 #![allow(clippy::too_many_arguments)]
 #![allow(clippy::upper_case_acronyms)]
+#[cfg(feature = "godot")]
 use crate::util::camera::device_utils::DeviceContact;
+#[cfg(feature = "godot")]
 use gdnative::prelude::*;
-use std::{cell::RefCell, rc::Rc, sync::Arc};
+use std::sync::Arc;
+#[cfg(feature = "godot")]
+use std::{cell::RefCell, rc::Rc};
 use uvc::Context;
 
 pub mod configuration;
 pub mod error;
+// everything else works without Godot, this is the part that hooks it all up to the editor
+#[cfg(feature = "godot")]
 pub mod nodes;
+pub mod output;
 pub mod processing;
 pub mod util;
 
@@ -53,10 +60,15 @@ lazy_static! {
     static ref UVC: Arc<Context<'static>> = Arc::new(Context::new().unwrap());
 }
 
+#[cfg(feature = "godot")]
 thread_local! {
     pub(crate) static CURRENT_DEVICE: Rc<RefCell<Option<DeviceContact>>> = Rc::new(RefCell::new(None));
 }
+
+#[cfg(feature = "godot")]
 fn init(handle: InitHandle) {
+    init_logging();
+    init_overrides();
     handle.add_class::<nodes::open2dholoctrl::Open2DHoloCtrl>();
     handle.add_class::<nodes::model_tree_edit::ModelTreeEditor>();
     handle.add_class::<nodes::webcam_input_edit::WebcamInputEditor>();
@@ -71,4 +83,40 @@ fn init(handle: InitHandle) {
     handle.add_class::<nodes::preview_viewport::PreviewViewport>();
 }
 
+// the Godot console, plus files in the user data dir that survive a crash
+#[cfg(feature = "godot")]
+fn init_logging() {
+    let mut loggers: Vec<Box<dyn util::log::Logger>> = vec![Box::new(nodes::util::GodotLogger)];
+    let file_logger = util::log::log_dir()
+        .map(util::log::RotatingFileLogger::new)
+        .transpose();
+    let file_error = match file_logger {
+        Ok(Some(logger)) => {
+            loggers.push(Box::new(logger));
+            None
+        }
+        Ok(None) => Some("there is no data directory on this platform".to_string()),
+        Err(why) => Some(why.to_string()),
+    };
+    util::log::set_loggers(loggers);
+    if let Some(why) = file_error {
+        log_warn!(Ui, "Not logging to a file: {}", why);
+    }
+}
+
+// before any node reads the settings
+#[cfg(feature = "godot")]
+fn init_overrides() {
+    match configuration::overrides::Overrides::from_env() {
+        Ok(overrides) => {
+            if !overrides.is_empty() {
+                log_info!(Ui, "Launch options: {}", overrides);
+            }
+            overrides.install();
+        }
+        Err(why) => log_error!(Ui, "Ignoring the launch options: {}", why),
+    }
+}
+
+#[cfg(feature = "godot")]
 godot_init!(init);
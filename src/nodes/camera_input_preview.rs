@@ -1,24 +1,144 @@
-use crate::wtf;
-use gdnative::{api::Control, prelude::*, NativeClass};
+use crate::{
+    processing::aspect_ratio::{calc_ear, calc_mar},
+    wtf,
+};
+use cv::nalgebra::{Rotation3, Vector3 as NVector3};
+use gdnative::{
+    api::{Control, Image, ImageTexture, Texture},
+    prelude::*,
+    NativeClass,
+};
 use std::cell::{Cell, RefCell};
 
-// TODO: gen gdns file and add to inithandle
+const FACE_BOX_COLOR: Color = Color {
+    r: 0.2,
+    g: 1.0,
+    b: 0.2,
+    a: 1.0,
+};
+const LANDMARK_COLOR: Color = Color {
+    r: 1.0,
+    g: 1.0,
+    b: 1.0,
+    a: 1.0,
+};
+const TEXT_COLOR: Color = Color {
+    r: 1.0,
+    g: 1.0,
+    b: 0.4,
+    a: 1.0,
+};
+// x, y, z like everywhere else: red, green, blue
+const AXIS_COLORS: [Color; 3] = [
+    Color {
+        r: 1.0,
+        g: 0.2,
+        b: 0.2,
+        a: 1.0,
+    },
+    Color {
+        r: 0.2,
+        g: 1.0,
+        b: 0.2,
+        a: 1.0,
+    },
+    Color {
+        r: 0.3,
+        g: 0.5,
+        b: 1.0,
+        a: 1.0,
+    },
+];
+// of the face box width
+const AXIS_LENGTH: f32 = 0.6;
+// where the axes start from, the tip of the nose
+const NOSE_TIP: usize = 30;
+
+/// What gets drawn in the camera preview. Travels between nodes as a Dictionary, see
+/// `to_dictionary`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PreviewLayers {
+    /// Also decides whether the pipeline bothers copying frames for us.
+    pub camera_image: bool,
+    pub face_box: bool,
+    pub landmarks: bool,
+    pub landmark_indices: bool,
+    pub head_pose: bool,
+    pub metrics: bool,
+}
+
+impl PreviewLayers {
+    pub fn from_dictionary(dict: &Dictionary) -> Self {
+        let default = PreviewLayers::default();
+        // missing keys keep their default
+        let get = |key: &str, default: bool| {
+            let value = dict.get(key);
+            if value.is_nil() {
+                default
+            } else {
+                value.to_bool()
+            }
+        };
+        PreviewLayers {
+            camera_image: get("camera_image", default.camera_image),
+            face_box: get("face_box", default.face_box),
+            landmarks: get("landmarks", default.landmarks),
+            landmark_indices: get("landmark_indices", default.landmark_indices),
+            head_pose: get("head_pose", default.head_pose),
+            metrics: get("metrics", default.metrics),
+        }
+    }
+
+    pub fn to_dictionary(&self) -> Dictionary {
+        let dict = Dictionary::new();
+        dict.insert("camera_image", self.camera_image);
+        dict.insert("face_box", self.face_box);
+        dict.insert("landmarks", self.landmarks);
+        dict.insert("landmark_indices", self.landmark_indices);
+        dict.insert("head_pose", self.head_pose);
+        dict.insert("metrics", self.metrics);
+        dict.into_shared()
+    }
+}
+
+impl Default for PreviewLayers {
+    fn default() -> Self {
+        // 68 numbers on top of each other are only useful when looking for something specific
+        PreviewLayers {
+            camera_image: true,
+            face_box: true,
+            landmarks: true,
+            landmark_indices: false,
+            head_pose: true,
+            metrics: true,
+        }
+    }
+}
 
 #[derive(NativeClass)]
 #[inherit(Control)]
 pub struct CameraInputPreview {
-    processed_68pt_normalized: RefCell<Vec<Vector2>>,
-    x_max: Cell<f32>,
-    y_max: Cell<f32>,
+    texture: RefCell<Option<Ref<ImageTexture>>>,
+    // the camera resolution, which is what everything below is in
+    source_res: Cell<Vector2>,
+    landmarks: RefCell<Vec<Vector2>>,
+    face_box: Cell<Rect2>,
+    facing: Cell<Vector3>,
+    confidence: Cell<f64>,
+    layers: Cell<PreviewLayers>,
 }
 
 #[methods]
 impl CameraInputPreview {
     fn new(_owner: &Control) -> Self {
         CameraInputPreview {
-            processed_68pt_normalized: RefCell::new(Vec::new()),
-            x_max: Cell::new(0_f32),
-            y_max: Cell::new(0_f32),
+            texture: RefCell::new(None),
+            source_res: Cell::new(Vector2::new(0_f32, 0_f32)),
+            landmarks: RefCell::new(Vec::new()),
+            face_box: Cell::new(Rect2::default()),
+            facing: Cell::new(Vector3::default()),
+            confidence: Cell::new(0_f64),
+            layers: Cell::new(PreviewLayers::default()),
         }
     }
 
@@ -27,54 +147,240 @@ impl CameraInputPreview {
         let input_process = unsafe {
             owner.get_node("/root/Open2DHolo/Open2DHoloMainUINode/Panel/VBoxContainer/HSplitContainer/VSplitContainer/HSplitContainer2").unwrap().assume_safe()
         };
+        let input_editor = unsafe {
+            owner.get_node("/root/Open2DHolo/Open2DHoloMainUINode/Panel/VBoxContainer/HSplitContainer/TabContainer/Input/GridContainer/VBoxContainer/Tree").unwrap().assume_safe()
+        };
 
         wtf!(input_process.connect(
-            "new_processed_frame_68pt",
+            "frame_processed",
             owner,
-            "on_new_processed_frame_68pt",
+            "on_frame_processed",
+            VariantArray::new_shared(),
+            0
+        ));
+
+        wtf!(input_process.connect(
+            "camera_preview",
+            owner,
+            "on_camera_preview",
+            VariantArray::new_shared(),
+            0
+        ));
+
+        wtf!(input_editor.connect(
+            "preview_layers_changed",
+            owner,
+            "on_preview_layers_changed",
             VariantArray::new_shared(),
             0
         ));
     }
 
-    // Draw points here
     #[export]
     fn _draw(&self, owner: TRef<Control>) {
-        let current_size = owner.size();
-        let ratio = (self.x_max.get() / current_size.x).min(self.y_max.get() / current_size.y);
-        for v2 in self.processed_68pt_normalized.borrow().iter() {
-            owner.draw_circle(
-                Vector2::new(
-                    (v2.x / ratio) - current_size.x / 2_f32,
-                    (v2.y / ratio) - current_size.y / 2_f32,
-                ),
-                1.0_f64,
-                Color::rgb(255_f32, 255_f32, 255_f32),
+        let res = self.source_res.get();
+        if res.x <= 0_f32 || res.y <= 0_f32 {
+            return;
+        }
+        let area = fit(owner.size(), res);
+        let layers = self.layers.get();
+
+        if layers.camera_image {
+            if let Some(texture) = &*self.texture.borrow() {
+                let texture = unsafe { texture.assume_safe() };
+                owner.draw_texture_rect(
+                    texture.upcast::<Texture>(),
+                    area,
+                    false,
+                    Color::rgb(1_f32, 1_f32, 1_f32),
+                    false,
+                    Null::null(),
+                );
+            }
+        }
+
+        let landmarks = self.landmarks.borrow();
+        if landmarks.is_empty() {
+            return;
+        }
+        let scale_x = area.size.width / res.x;
+        let scale_y = area.size.height / res.y;
+        let to_area = |pt: Vector2| {
+            Vector2::new(
+                area.origin.x + pt.x * scale_x,
+                area.origin.y + pt.y * scale_y,
             )
+        };
+        let font = owner.get_font("font", "");
+
+        // the box comes in bottom up, sort the corners out first
+        let face_box = self.face_box.get();
+        let corner_a = to_area(Vector2::new(face_box.origin.x, face_box.origin.y));
+        let corner_b = to_area(Vector2::new(
+            face_box.origin.x + face_box.size.width,
+            face_box.origin.y + face_box.size.height,
+        ));
+        let box_area = Rect2::new(
+            Point2::new(corner_a.x.min(corner_b.x), corner_a.y.min(corner_b.y)),
+            Size2::new(
+                (corner_a.x - corner_b.x).abs(),
+                (corner_a.y - corner_b.y).abs(),
+            ),
+        );
+        if layers.face_box {
+            owner.draw_rect(box_area, FACE_BOX_COLOR, false, 2_f64, false);
+        }
+
+        if layers.landmarks || layers.landmark_indices {
+            for (idx, pt) in landmarks.iter().enumerate() {
+                let pt = to_area(*pt);
+                if layers.landmarks {
+                    owner.draw_circle(pt, 1.5_f64, LANDMARK_COLOR);
+                }
+                if let (true, Some(font)) = (layers.landmark_indices, &font) {
+                    owner.draw_string(
+                        font,
+                        pt + Vector2::new(2_f32, -2_f32),
+                        idx.to_string(),
+                        LANDMARK_COLOR,
+                        -1,
+                    );
+                }
+            }
+        }
+
+        let facing = self.facing.get();
+        if layers.head_pose {
+            let origin = match landmarks.get(NOSE_TIP) {
+                Some(nose) => to_area(*nose),
+                None => Vector2::new(
+                    box_area.origin.x + box_area.size.width / 2_f32,
+                    box_area.origin.y + box_area.size.height / 2_f32,
+                ),
+            };
+            let length = box_area.size.width * AXIS_LENGTH;
+            let rotation = Rotation3::from_euler_angles(
+                f64::from(facing.x),
+                f64::from(facing.y),
+                f64::from(facing.z),
+            );
+            let axes = [NVector3::x(), NVector3::y(), NVector3::z()];
+            // straight down the line of sight, so depth just gets dropped. y points up.
+            for (axis, color) in axes.iter().zip(AXIS_COLORS.iter()) {
+                let tip = rotation * axis;
+                let end = origin + Vector2::new(tip.x as f32, -tip.y as f32) * length;
+                owner.draw_line(origin, end, *color, 2_f64, true);
+            }
+        }
+
+        if let (true, Some(font)) = (layers.metrics, &font) {
+            let mut lines = vec![format!(
+                "rotation x {:.0}  y {:.0}  z {:.0}  confidence {:.2}",
+                f64::from(facing.x).to_degrees(),
+                f64::from(facing.y).to_degrees(),
+                f64::from(facing.z).to_degrees(),
+                self.confidence.get(),
+            )];
+            // the ratios need the full 68 points
+            if landmarks.len() >= 68 {
+                let (left_eye, right_eye) = calc_ear(&landmarks);
+                lines.push(format!(
+                    "eyes {:.2} / {:.2}  mouth {:.2}",
+                    left_eye,
+                    right_eye,
+                    calc_mar(&landmarks)
+                ));
+            }
+            let line_height = unsafe { font.assume_safe() }.get_height() as f32;
+            for (idx, line) in lines.into_iter().enumerate() {
+                owner.draw_string(
+                    font,
+                    area.origin.to_vector() + Vector2::new(4_f32, line_height * (idx + 1) as f32),
+                    line,
+                    TEXT_COLOR,
+                    -1,
+                );
+            }
         }
     }
 
     #[export]
-    pub fn on_new_processed_frame_68pt(&self, owner: TRef<Control>, pointarray: Variant) {
+    pub fn on_frame_processed(
+        &self,
+        owner: TRef<Control>,
+        pointarray: Variant,
+        face_box: Variant,
+        facing: Variant,
+        confidence: Variant,
+    ) {
         let vec2_arr = pointarray.to_vector2_array();
-        if !vec2_arr.is_empty() {
-            let mut x_mx = 0_f32;
-            let mut y_mx = 0_f32;
-            let mut vec = vec![];
-            for i in 0..vec2_arr.len() {
-                let vec2 = vec2_arr.get(i);
-                if vec2.x > x_mx {
-                    x_mx = vec2.x;
-                }
-                if vec2.y > y_mx {
-                    y_mx = vec2.y;
+        let mut landmarks = Vec::with_capacity(vec2_arr.len() as usize);
+        for i in 0..vec2_arr.len() {
+            landmarks.push(vec2_arr.get(i));
+        }
+        *self.landmarks.borrow_mut() = landmarks;
+        self.face_box.set(face_box.to_rect2());
+        self.facing.set(facing.to_vector3());
+        self.confidence.set(confidence.to_f64());
+        CanvasItem::update(&owner)
+    }
+
+    // `image` is nil when a camera was just started, the resolution is still worth knowing then
+    #[export]
+    pub fn on_camera_preview(&self, owner: TRef<Control>, image: Variant, source_res: Variant) {
+        let res = source_res.to_vector2();
+        if res != self.source_res.get() {
+            self.source_res.set(res);
+            // whatever was there belongs to the old resolution
+            self.landmarks.borrow_mut().clear();
+        }
+
+        match image.try_to_object::<Image>() {
+            Some(image) => {
+                let image = unsafe { image.assume_safe() };
+                let same_size = self.texture.borrow().as_ref().map_or(false, |texture| {
+                    let texture = unsafe { texture.assume_safe() };
+                    texture.get_width() == image.get_width()
+                        && texture.get_height() == image.get_height()
+                });
+                if same_size {
+                    if let Some(texture) = &*self.texture.borrow() {
+                        unsafe { texture.assume_safe() }.set_data(image);
+                    }
+                } else {
+                    let texture = ImageTexture::new();
+                    texture.create_from_image(image, Texture::FLAG_FILTER);
+                    *self.texture.borrow_mut() = Some(texture.into_shared());
                 }
-                vec.push(vec2);
             }
-            *self.processed_68pt_normalized.borrow_mut() = vec;
-            self.x_max.set(x_mx);
-            self.y_max.set(y_mx);
+            None => {
+                *self.texture.borrow_mut() = None;
+                self.landmarks.borrow_mut().clear();
+            }
+        }
+        CanvasItem::update(&owner)
+    }
+
+    #[export]
+    pub fn on_preview_layers_changed(&self, owner: TRef<Control>, layers: Variant) {
+        let layers = PreviewLayers::from_dictionary(&layers.to_dictionary());
+        if !layers.camera_image {
+            *self.texture.borrow_mut() = None;
         }
+        self.layers.set(layers);
         CanvasItem::update(&owner)
     }
 }
+
+// the biggest rect with the aspect ratio of `res` that fits into `size`, centered
+fn fit(size: Vector2, res: Vector2) -> Rect2 {
+    let scale = (size.x / res.x).min(size.y / res.y);
+    let fitted = Size2::new(res.x * scale, res.y * scale);
+    Rect2::new(
+        Point2::new(
+            (size.x - fitted.width) / 2_f32,
+            (size.y - fitted.height) / 2_f32,
+        ),
+        fitted,
+    )
+}
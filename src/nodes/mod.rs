@@ -23,6 +23,6 @@ pub mod open2dholoctrl;
 pub mod preview_viewport;
 pub mod settings_dialog;
 pub mod upper_tab_popups;
-mod util;
+pub(crate) mod util;
 pub mod viewport_holder;
 pub mod webcam_input_edit;
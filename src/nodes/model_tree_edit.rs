@@ -15,6 +15,7 @@
 //     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::{
+    configuration::profile::Profile,
     nodes::util::{create_editable_item, create_editable_range, get_immidiate_treeitems},
     wtf,
 };
@@ -181,16 +182,110 @@ impl ModelTreeEditor {
             VariantArray::new_shared(),
             0,
         ));
+
+        let viewport_holder = unsafe {
+            owner.get_node("/root/Open2DHolo/Open2DHoloMainUINode/Panel/VBoxContainer/HSplitContainer/VSplitContainer/HSplitContainer2").unwrap().assume_safe()
+        };
+        wtf!(viewport_holder.connect(
+            "profile_changed",
+            owner,
+            "on_profile_changed",
+            VariantArray::new_shared(),
+            0,
+        ));
     }
 
     #[export]
     fn on_item_edited(&self, owner: TRef<Tree>) {
-        // validate x,y,z offsets
+        let edited = match owner.get_edited() {
+            Some(item) => unsafe { item.assume_safe() },
+            None => return,
+        };
+        let label = edited.get_text(0).to_string();
+        let cell = match self.cell_for(&label) {
+            Some(cell) => cell,
+            None => return,
+        };
+        // validate x,y,z offsets, the rotations are ranges and can't be anything wrong
+        let value = if label.ends_with("Offset") {
+            match edited.get_text(1).to_string().trim().parse::<f64>() {
+                Ok(v) if v.is_finite() => v,
+                _ => {
+                    edited.set_text(1, cell.get().to_string());
+                    return;
+                }
+            }
+        } else {
+            edited.get_range(1)
+        };
+        cell.set(value);
+        self.emit_transform(owner);
+    }
+
+    // the active profile brings its own model placement
+    #[export]
+    fn on_profile_changed(&self, owner: TRef<Tree>, _switched: bool) {
+        let transform = match Profile::active() {
+            Some(profile) => profile.model_transform,
+            None => return,
+        };
+        self.x_offset.set(transform.offset[0]);
+        self.y_offset.set(transform.offset[1]);
+        self.z_offset.set(transform.offset[2]);
+        self.x_rotate.set(transform.rotation[0]);
+        self.y_rotate.set(transform.rotation[1]);
+        self.z_rotate.set(transform.rotation[2]);
 
         // sift through every item in the tree
         // we know that every item is only 1 deep
         let root = unsafe { owner.get_root().unwrap().assume_safe() };
-        let mut _treeitems: Vec<Ref<TreeItem, Shared>> = get_immidiate_treeitems(owner, root);
+        for section in get_immidiate_treeitems(owner, root) {
+            let section = unsafe { section.assume_safe() };
+            for item in get_immidiate_treeitems(owner, section) {
+                let item = unsafe { item.assume_safe() };
+                let label = item.get_text(0).to_string();
+                if let Some(cell) = self.cell_for(&label) {
+                    if label.ends_with("Offset") {
+                        item.set_text(1, cell.get().to_string());
+                    } else {
+                        item.set_range(1, cell.get());
+                    }
+                }
+            }
+        }
+        self.emit_transform(owner);
+    }
+
+    fn cell_for(&self, label: &str) -> Option<&Cell<f64>> {
+        match label {
+            "X Offset" => Some(&self.x_offset),
+            "Y Offset" => Some(&self.y_offset),
+            "Z Offset" => Some(&self.z_offset),
+            "X Rotation" => Some(&self.x_rotate),
+            "Y Rotation" => Some(&self.y_rotate),
+            "Z Rotation" => Some(&self.z_rotate),
+            _ => None,
+        }
+    }
+
+    fn emit_transform(&self, owner: TRef<Tree>) {
+        let offset = Vector3::new(
+            self.x_offset.get() as f32,
+            self.y_offset.get() as f32,
+            self.z_offset.get() as f32,
+        );
+        let angle = Vector3::new(
+            self.x_rotate.get() as f32,
+            self.y_rotate.get() as f32,
+            self.z_rotate.get() as f32,
+        );
+        owner.emit_signal(
+            "model_transform_change",
+            &[
+                Variant::from_vector3(&offset),
+                Variant::from_vector3(&angle),
+            ],
+        );
     }
 
     /// Get a reference to the model tree editor's x offset.
@@ -21,7 +21,7 @@ use gdnative::{
     NativeClass,
 };
 
-use crate::wtf;
+use crate::{configuration::user_config::UserConfig, wtf};
 
 #[derive(NativeClass)]
 #[inherit(Control)]
@@ -51,8 +51,35 @@ impl Open2DHoloCtrl {
             0,
         ));
 
+        let input_process = unsafe {
+            owner.get_node("/root/Open2DHolo/Open2DHoloMainUINode/Panel/VBoxContainer/HSplitContainer/VSplitContainer/HSplitContainer2").unwrap().assume_safe()
+        };
+        wtf!(input_process.connect(
+            "config_reloaded",
+            owner,
+            "on_config_reloaded",
+            VariantArray::new_shared(),
+            0,
+        ));
+
         // set the size at ready to avoid weird UI scaling on first boot
         self.on_size_change(owner);
+        self.on_config_reloaded(owner);
+    }
+
+    #[export]
+    pub fn on_config_reloaded(&self, owner: TRef<Control>) {
+        let user_cfg = UserConfig::from_cfg().unwrap_or_else(|_| UserConfig::from_default());
+        let colorrect = unsafe {
+            &*owner
+                .get_node("ColorRect")
+                .unwrap()
+                .assume_safe()
+                .cast::<ColorRect>()
+                .unwrap()
+        };
+        let [r, g, b] = user_cfg.appearance().background_color;
+        colorrect.set_frame_color(Color::rgb(r, g, b));
     }
     #[export]
     pub fn on_size_change(&self, owner: TRef<Control>) {
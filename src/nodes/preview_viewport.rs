@@ -1,7 +1,11 @@
-use crate::wtf;
-use euclid::{UnknownUnit, Vector2D};
+use crate::{
+    configuration::profile::{BlendshapeMapping, Profile},
+    log_debug, log_error, log_info,
+    processing::aspect_ratio::{calc_ear, calc_mar},
+    wtf,
+};
 use gdnative::{
-    api::{MeshInstance, Resource, Skeleton, Viewport},
+    api::{MeshInstance, Resource, Skeleton, Spatial, Viewport},
     prelude::*,
     NativeClass,
 };
@@ -14,6 +18,12 @@ pub struct PreviewViewport {
     loaded_model: RefCell<Option<Ref<Resource>>>,
     name: RefCell<String>,
     neck_bone_id: Cell<i32>,
+    // last values we drove the model with, blended towards the new ones by confidence
+    last_angle: Cell<Vector3>,
+    last_shapes: Cell<(f32, f32, f32)>,
+    // from the model editor, offset and rotation in degrees
+    model_transform: Cell<(Vector3, Vector3)>,
+    blendshapes: RefCell<BlendshapeMapping>,
 }
 
 #[methods]
@@ -23,6 +33,10 @@ impl PreviewViewport {
             loaded_model: RefCell::new(None),
             neck_bone_id: Cell::new(-1),
             name: RefCell::new(String::new()),
+            last_angle: Cell::new(Vector3::default()),
+            last_shapes: Cell::new((0_f32, 0_f32, 0_f32)),
+            model_transform: Cell::new((Vector3::default(), Vector3::default())),
+            blendshapes: RefCell::new(BlendshapeMapping::default()),
         }
     }
 
@@ -47,22 +61,77 @@ impl PreviewViewport {
             VariantArray::new_shared(),
             0,
         ));
+
+        wtf!(model_load_origin.connect(
+            "profile_changed",
+            owner,
+            "on_profile_changed",
+            VariantArray::new_shared(),
+            0,
+        ));
+
+        let model_editor = unsafe {
+            &mut owner.get_node("/root/Open2DHolo/Open2DHoloMainUINode/Panel/VBoxContainer/HSplitContainer/TabContainer/Scene/Grid/VBoxContainer/Tree").unwrap().assume_safe()
+        };
+
+        wtf!(model_editor.connect(
+            "model_transform_change",
+            owner,
+            "on_model_transform_change",
+            VariantArray::new_shared(),
+            0,
+        ));
+
+        if let Some(profile) = Profile::active() {
+            *self.blendshapes.borrow_mut() = profile.blendshapes;
+        }
+    }
+
+    #[export]
+    fn on_profile_changed(&self, _owner: TRef<Viewport>, _switched: bool) {
+        *self.blendshapes.borrow_mut() = Profile::active()
+            .map(|profile| profile.blendshapes)
+            .unwrap_or_default();
+    }
+
+    #[export]
+    fn on_model_transform_change(&self, owner: TRef<Viewport>, offset: Variant, angle: Variant) {
+        self.model_transform
+            .set((offset.to_vector3(), angle.to_vector3()));
+        self.apply_model_transform(owner);
+    }
+
+    fn apply_model_transform(&self, owner: TRef<Viewport>) {
+        let node_name = self.name.borrow().clone();
+        if node_name.is_empty() {
+            return;
+        }
+        let model_root = match owner.get_node(node_name) {
+            Some(node) => node,
+            None => return,
+        };
+        let model_root = match unsafe { model_root.assume_safe() }.cast::<Spatial>() {
+            Some(spatial) => spatial,
+            None => return,
+        };
+        let (offset, angle) = self.model_transform.get();
+        model_root.set_translation(offset);
+        model_root.set_rotation_degrees(angle);
     }
 
     #[export]
     fn on_model_load_start(&self, owner: TRef<Viewport>, path: Variant) {
-        godot_print!("?");
         let path_string = path.to_string();
+        log_info!(Model, "Loading {}", path_string);
         let loader = ResourceLoader::godot_singleton();
         match loader.load(path_string, "", false) {
             // What does `type_hint` do?
             Some(mdl) => {
-                godot_print!("?");
                 *self.loaded_model.borrow_mut() = Some(mdl);
                 self.start_track_model(owner)
             }
             None => {
-                godot_print!("failed to load model!");
+                log_error!(Model, "Failed to load {}", path.to_string());
             }
         }
     }
@@ -79,14 +148,23 @@ impl PreviewViewport {
                         .unwrap()
                         .assume_safe()
                 };
-                let name = node.name().to_string();
+                // switching profiles loads another model, the old one goes
+                let previous = self.name.borrow().clone();
+                if !previous.is_empty() {
+                    if let Some(previous) = owner.get_node(previous) {
+                        unsafe { previous.assume_safe() }.queue_free();
+                    }
+                }
                 owner.add_child(node, true);
+                // the old one may still be around until the end of the frame, so the name may change
+                let name = node.name().to_string();
                 for child_id in 0..owner.get_child_count() {
                     let node_name =
                         unsafe { owner.get_child(child_id).unwrap().assume_safe() }.name();
-                    godot_print!("{}", node_name);
+                    log_debug!(Model, "viewport child {}", node_name);
                 }
                 *self.name.borrow_mut() = name.clone();
+                self.apply_model_transform(owner);
                 // FIXME: replace with acutal node!
                 let model_skeleton = unsafe {
                     owner
@@ -118,14 +196,9 @@ impl PreviewViewport {
         landmarks: Variant,
         facebox: Variant,
         angle: Variant,
+        confidence: Variant,
     ) {
-        godot_print!("process");
-        godot_print!(
-            "landmark: {:?} \nfacebox: {:?} \nangle:{:?}",
-            landmarks,
-            facebox,
-            angle
-        );
+        log_debug!(Model, "facebox: {:?} angle: {:?}", facebox, angle);
         if self.loaded_model.borrow().is_some() {
             let node_name = self.name.borrow().clone();
             // FIXME: replace with acutal node!
@@ -148,7 +221,15 @@ impl PreviewViewport {
                     .unwrap()
             };
 
-            let angle_vec3 = angle.to_vector3();
+            // low confidence frames only nudge the model instead of snapping it somewhere wrong
+            let weight = confidence
+                .try_to_f64()
+                .unwrap_or(1_f64)
+                .max(0_f64)
+                .min(1_f64) as f32;
+            let last_angle = self.last_angle.get();
+            let angle_vec3 = last_angle + (angle.to_vector3() - last_angle) * weight;
+            self.last_angle.set(angle_vec3);
             let landmarks_vec = {
                 let ld = landmarks.to_vector2_array();
                 let mut p2d_vec = vec![];
@@ -172,63 +253,30 @@ impl PreviewViewport {
             // this currently makes the model require an exorcism. Change to OpenCV and see if it keeps segfaulting, and if so throw computer out of window.
             model_skeleton.set_bone_custom_pose(self.neck_bone_id.get().into(), new_neck_tranform);
             let (left_eye, right_eye) = calc_ear(&landmarks_vec);
-            let mouth_open = single_ear(
-                *landmarks_vec.get(48).unwrap(),
-                *landmarks_vec.get(50).unwrap(),
-                *landmarks_vec.get(52).unwrap(),
-                *landmarks_vec.get(54).unwrap(),
-                *landmarks_vec.get(56).unwrap(),
-                *landmarks_vec.get(58).unwrap(),
-            );
+            let mouth_open = calc_mar(&landmarks_vec);
+
+            let (last_left, last_right, last_mouth) = self.last_shapes.get();
+            let left_eye = last_left + (left_eye - last_left) * weight;
+            let right_eye = last_right + (right_eye - last_right) * weight;
+            let mouth_open = last_mouth + (mouth_open - last_mouth) * weight;
+            self.last_shapes.set((left_eye, right_eye, mouth_open));
 
-            // Face transformations for vroid style 3D model
+            // Face transformations, by default for vroid style 3D model
             // 13 => blink right, 14 => blink left
             // 29 => mouth
+            // the profile can point them at other blend shapes
             // all lies from a scale of 0.0~1.0. Never negative
-            godot_print!("{} {} {}", left_eye, right_eye, mouth_open);
-            model_mesh_inst.set("blend_shapes/morph_13", f64::from(left_eye));
-            model_mesh_inst.set("blend_shapes/morph_14", f64::from(right_eye));
-            model_mesh_inst.set("blend_shapes/morph_29", f64::from(mouth_open));
+            log_debug!(
+                Model,
+                "eyes {} {} mouth {}",
+                left_eye,
+                right_eye,
+                mouth_open
+            );
+            let blendshapes = self.blendshapes.borrow();
+            model_mesh_inst.set(blendshapes.left_eye.as_str(), f64::from(left_eye));
+            model_mesh_inst.set(blendshapes.right_eye.as_str(), f64::from(right_eye));
+            model_mesh_inst.set(blendshapes.mouth_open.as_str(), f64::from(mouth_open));
         }
     }
 }
-
-// FIXME: this is ***BAD***
-#[inline]
-fn calc_ear(landmarks: &[Vector2D<f32, UnknownUnit>]) -> (f32, f32) {
-    // eye left
-    let left = single_ear(
-        *landmarks.get(36).unwrap(),
-        *landmarks.get(37).unwrap(),
-        *landmarks.get(38).unwrap(),
-        *landmarks.get(39).unwrap(),
-        *landmarks.get(40).unwrap(),
-        *landmarks.get(41).unwrap(),
-    );
-    let right = single_ear(
-        *landmarks.get(42).unwrap(),
-        *landmarks.get(43).unwrap(),
-        *landmarks.get(44).unwrap(),
-        *landmarks.get(45).unwrap(),
-        *landmarks.get(46).unwrap(),
-        *landmarks.get(47).unwrap(),
-    );
-    (left, right)
-}
-
-#[inline]
-fn single_ear(
-    p1: Vector2D<f32, UnknownUnit>,
-    p2: Vector2D<f32, UnknownUnit>,
-    p3: Vector2D<f32, UnknownUnit>,
-    p4: Vector2D<f32, UnknownUnit>,
-    p5: Vector2D<f32, UnknownUnit>,
-    p6: Vector2D<f32, UnknownUnit>,
-) -> f32 {
-    (euclid_distance(p2, p6) + euclid_distance(p3, p5)) / (2_f32 * euclid_distance(p1, p4))
-}
-
-#[inline]
-fn euclid_distance(p1: Vector2D<f32, UnknownUnit>, p2: Vector2D<f32, UnknownUnit>) -> f32 {
-    ((p1.x - p2.x).powf(2_f32) + (p1.y - p2.y).powf(2_f32)).sqrt()
-}
@@ -14,25 +14,393 @@
 //     You should have received a copy of the GNU General Public License
 //     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use gdnative::{api::WindowDialog, methods, prelude::*, NativeClass};
+use crate::{
+    configuration::{
+        fields::{sections, setting_fields, FieldKind, FieldValue, SettingField},
+        processing_config::StreamSettings,
+        user_config::UserConfig,
+    },
+    log_info,
+    nodes::util::preferred_stream,
+    util::camera::device_utils::{enumerate_cache_device, CachedDeviceList, DeviceDesc},
+    wtf,
+};
+use gdnative::{
+    api::{
+        BoxContainer, Button, CheckBox, ColorPickerButton, Control, GridContainer, HBoxContainer,
+        Label, LineEdit, OptionButton, SpinBox, TabContainer, TextEdit, VBoxContainer,
+        WindowDialog,
+    },
+    methods,
+    prelude::*,
+    NativeClass,
+};
+use std::{cell::RefCell, collections::HashMap};
+
+const ERROR_COLOR: Color = Color {
+    r: 1.0,
+    g: 0.4,
+    b: 0.4,
+    a: 1.0,
+};
 
 // TODO: Use window node for 4.0
 #[derive(NativeClass)]
 #[inherit(WindowDialog)]
-pub struct SettingsDialog;
+#[register_with(Self::register_signals)]
+pub struct SettingsDialog {
+    // one control per entry of `setting_fields`
+    controls: RefCell<HashMap<&'static str, Ref<Control>>>,
+    // what each item of the camera list stands for
+    camera_choices: RefCell<Vec<Option<(DeviceDesc, StreamSettings)>>>,
+    error_label: RefCell<Option<Ref<Label>>>,
+}
 
 #[methods]
 impl SettingsDialog {
+    fn register_signals(builder: &ClassBuilder<Self>) {
+        // the settings were saved, everyone should read them again
+        builder.add_signal(Signal {
+            name: "settings_applied",
+            args: &[],
+        });
+    }
+
     fn new(_owner: &WindowDialog) -> Self {
-        SettingsDialog
+        SettingsDialog {
+            controls: RefCell::new(HashMap::new()),
+            camera_choices: RefCell::new(Vec::new()),
+            error_label: RefCell::new(None),
+        }
     }
 
     #[export]
     fn _ready(&self, owner: TRef<WindowDialog>) {
         owner.set_title("Open2DHolo Settings");
+        owner.set_resizable(true);
+
+        let layout = VBoxContainer::new();
+        layout.set_anchors_and_margins_preset(
+            Control::PRESET_WIDE,
+            Control::PRESET_MODE_MINSIZE,
+            8,
+        );
+
+        let tabs = TabContainer::new();
+        tabs.set_v_size_flags(Control::SIZE_EXPAND_FILL);
+        let fields = setting_fields();
+        for (section, title) in sections() {
+            let grid = GridContainer::new();
+            grid.set_name(title);
+            grid.set_columns(2);
+            for field in fields.iter().filter(|f| f.section() == section) {
+                let label = Label::new();
+                label.set_text(field.label);
+                label.set_tooltip(field.hint);
+                grid.add_child(label, false);
+
+                let control = make_control(field);
+                control.set_tooltip(field.hint);
+                control.set_h_size_flags(Control::SIZE_EXPAND_FILL);
+                let control = control.into_shared();
+                grid.add_child(control, false);
+                self.controls.borrow_mut().insert(field.key, control);
+            }
+            tabs.add_child(grid, false);
+        }
+        layout.add_child(tabs, false);
+
+        let error_label = Label::new();
+        error_label.set_autowrap(true);
+        error_label.set_modulate(ERROR_COLOR);
+        let error_label = error_label.into_shared();
+        layout.add_child(error_label, false);
+        *self.error_label.borrow_mut() = Some(error_label);
+
+        let buttons = HBoxContainer::new();
+        buttons.set_alignment(BoxContainer::ALIGN_END);
+        for (text, method) in &[
+            ("Reset to Defaults", "on_reset_pressed"),
+            ("Cancel", "on_cancel_pressed"),
+            ("Apply", "on_apply_pressed"),
+        ] {
+            let button = Button::new();
+            button.set_text(*text);
+            wtf!(button.connect("pressed", owner, *method, VariantArray::new_shared(), 0));
+            buttons.add_child(button, false);
+        }
+        layout.add_child(buttons, false);
+        owner.add_child(layout, false);
+
+        wtf!(owner.connect(
+            "about_to_show",
+            owner,
+            "on_about_to_show",
+            VariantArray::new_shared(),
+            0
+        ));
     }
 
-    // fn on_show(&self, owner: TRef<WindowDialog>) {
+    // always starts from what is saved, whatever was left unapplied last time is gone
+    #[export]
+    pub fn on_about_to_show(&self, _owner: TRef<WindowDialog>) {
+        match UserConfig::from_cfg() {
+            Ok(user_cfg) => {
+                self.set_error("");
+                self.show_config(&user_cfg);
+            }
+            Err(why) => {
+                // Apply would refuse to save anyway, better to say so up front
+                self.set_error(&format!("Could not read the settings: {}", why));
+                self.show_config(&UserConfig::default());
+            }
+        }
+    }
+
+    // nothing is saved until Apply
+    #[export]
+    pub fn on_reset_pressed(&self, _owner: TRef<WindowDialog>) {
+        self.show_config(&UserConfig::default());
+    }
 
-    // }
+    #[export]
+    pub fn on_cancel_pressed(&self, owner: TRef<WindowDialog>) {
+        owner.hide();
+    }
+
+    #[export]
+    pub fn on_apply_pressed(&self, owner: TRef<WindowDialog>) {
+        // the parts of the file the dialog doesn't show have to survive
+        let mut user_cfg = match UserConfig::from_cfg() {
+            Ok(user_cfg) => user_cfg,
+            Err(why) => {
+                self.set_error(&format!("Could not read the settings: {}", why));
+                return;
+            }
+        };
+        let mut problems = Vec::new();
+        for field in &setting_fields() {
+            let value = match self.read_control(field) {
+                Some(value) => value,
+                None => continue,
+            };
+            if let Err(why) = user_cfg.set_field(field.key, value) {
+                problems.push(why.to_string());
+            }
+        }
+        if !problems.is_empty() {
+            self.set_error(&problems.join("\n"));
+            return;
+        }
+        if let Err(why) = user_cfg.write_current() {
+            self.set_error(&format!("Could not save the settings: {}", why));
+            return;
+        }
+        self.set_error("");
+        log_info!(
+            Ui,
+            "Saved settings to {}",
+            UserConfig::settings_path().display()
+        );
+        owner.emit_signal("settings_applied", &[]);
+        owner.hide();
+    }
+
+    fn show_config(&self, user_cfg: &UserConfig) {
+        self.set_error("");
+        for field in &setting_fields() {
+            if let Some(value) = user_cfg.field(field.key) {
+                self.write_control(field, &value);
+            }
+        }
+    }
+
+    fn set_error(&self, text: &str) {
+        if let Some(label) = &*self.error_label.borrow() {
+            unsafe { label.assume_safe() }.set_text(text);
+        }
+    }
+
+    fn control(&self, field: &SettingField) -> Option<TRef<Control>> {
+        self.controls
+            .borrow()
+            .get(field.key)
+            .map(|control| unsafe { control.assume_safe() })
+    }
+
+    fn write_control(&self, field: &SettingField, value: &FieldValue) {
+        let control = match self.control(field) {
+            Some(control) => control,
+            None => return,
+        };
+        match value {
+            FieldValue::Toggle(on) => {
+                if let Some(check) = control.cast::<CheckBox>() {
+                    check.set_pressed(*on);
+                }
+            }
+            FieldValue::Integer(value) => {
+                if let Some(spin) = control.cast::<SpinBox>() {
+                    spin.set_value(*value as f64);
+                }
+            }
+            FieldValue::Number(value) => {
+                if let Some(spin) = control.cast::<SpinBox>() {
+                    spin.set_value(*value);
+                }
+            }
+            FieldValue::Text(text) => {
+                if let Some(options) = control.cast::<OptionButton>() {
+                    if let FieldKind::Choice(names) = field.kind {
+                        if let Some(idx) = names.iter().position(|name| name == text) {
+                            options.select(idx as i64);
+                        }
+                    }
+                } else if let Some(edit) = control.cast::<LineEdit>() {
+                    edit.set_text(text);
+                }
+            }
+            FieldValue::Lines(lines) => {
+                if let Some(edit) = control.cast::<TextEdit>() {
+                    edit.set_text(lines.join("\n"));
+                }
+            }
+            FieldValue::Color(rgb) => {
+                if let Some(picker) = control.cast::<ColorPickerButton>() {
+                    picker.set_pick_color(Color::rgb(rgb[0], rgb[1], rgb[2]));
+                }
+            }
+            FieldValue::Camera(selected) => {
+                if let Some(options) = control.cast::<OptionButton>() {
+                    self.fill_cameras(options, selected);
+                }
+            }
+        }
+    }
+
+    // `None` if the control isn't there, which leaves the setting alone
+    fn read_control(&self, field: &SettingField) -> Option<FieldValue> {
+        let control = self.control(field)?;
+        let value = match field.kind {
+            FieldKind::Toggle => FieldValue::Toggle(control.cast::<CheckBox>()?.is_pressed()),
+            FieldKind::Integer { .. } => {
+                FieldValue::Integer(control.cast::<SpinBox>()?.value().round() as i64)
+            }
+            FieldKind::Number { .. } => FieldValue::Number(control.cast::<SpinBox>()?.value()),
+            FieldKind::Choice(names) => {
+                let selected = control.cast::<OptionButton>()?.selected();
+                FieldValue::Text(names.get(selected as usize)?.to_string())
+            }
+            FieldKind::Text => FieldValue::Text(control.cast::<LineEdit>()?.text().to_string()),
+            FieldKind::Lines => FieldValue::Lines(
+                control
+                    .cast::<TextEdit>()?
+                    .text()
+                    .to_string()
+                    .lines()
+                    .map(String::from)
+                    .collect(),
+            ),
+            FieldKind::Color => {
+                let color = control.cast::<ColorPickerButton>()?.pick_color();
+                FieldValue::Color([color.r, color.g, color.b])
+            }
+            FieldKind::Camera => {
+                let selected = control.cast::<OptionButton>()?.selected();
+                let choices = self.camera_choices.borrow();
+                FieldValue::Camera(choices.get(selected as usize).cloned().flatten())
+            }
+        };
+        Some(value)
+    }
+
+    // no camera, the saved one if it isn't plugged in right now, then whatever is plugged in
+    fn fill_cameras(
+        &self,
+        options: TRef<OptionButton>,
+        selected: &Option<(DeviceDesc, StreamSettings)>,
+    ) {
+        let mut choices = vec![None];
+        options.clear();
+        options.add_item("None", 0);
+        let mut select = 0;
+
+        let mut devices: Vec<(String, CachedDeviceList)> = enumerate_cache_device()
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        devices.sort_by(|a, b| a.0.cmp(&b.0));
+
+        if let Some((saved, stream)) = selected {
+            let plugged_in = devices
+                .iter()
+                .any(|(_, device)| saved.matches(&device.get_identity()));
+            if !plugged_in {
+                let name = saved
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| "Saved camera".to_string());
+                options.add_item(format!("{} (not plugged in)", name), choices.len() as i64);
+                select = choices.len();
+                choices.push(Some((saved.clone(), *stream)));
+            }
+        }
+        for (name, device) in devices {
+            let identity = device.get_identity();
+            let choice = match selected {
+                Some((saved, stream)) if saved.matches(&identity) => {
+                    select = choices.len();
+                    Some((saved.clone(), *stream))
+                }
+                _ => match preferred_stream(&device) {
+                    Some(stream) => Some((identity, stream)),
+                    // nothing it could be started with
+                    None => continue,
+                },
+            };
+            options.add_item(name, choices.len() as i64);
+            choices.push(choice);
+        }
+        options.select(select as i64);
+        *self.camera_choices.borrow_mut() = choices;
+    }
+}
+
+fn make_control(field: &SettingField) -> Ref<Control, Unique> {
+    match field.kind {
+        FieldKind::Toggle => CheckBox::new().upcast(),
+        FieldKind::Integer { min, max } => {
+            let spin = SpinBox::new();
+            spin.set_min(min as f64);
+            spin.set_max(max as f64);
+            spin.set_step(1_f64);
+            spin.upcast()
+        }
+        FieldKind::Number { min, max, step } => {
+            let spin = SpinBox::new();
+            spin.set_min(min);
+            spin.set_max(max);
+            spin.set_step(step);
+            spin.upcast()
+        }
+        FieldKind::Choice(names) => {
+            let options = OptionButton::new();
+            for (idx, name) in names.iter().enumerate() {
+                options.add_item(*name, idx as i64);
+            }
+            options.upcast()
+        }
+        FieldKind::Text => LineEdit::new().upcast(),
+        FieldKind::Lines => {
+            let edit = TextEdit::new();
+            edit.set_custom_minimum_size(Vector2::new(0_f32, 80_f32));
+            edit.upcast()
+        }
+        FieldKind::Color => {
+            let picker = ColorPickerButton::new();
+            picker.set_edit_alpha(false);
+            picker.upcast()
+        }
+        FieldKind::Camera => OptionButton::new().upcast(),
+    }
 }
@@ -15,23 +15,32 @@
 //     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::{
-    globalize_path,
+    configuration::{profile::Profile, user_config::UserConfig},
+    globalize_path, log_info,
     nodes::util::check_endswith_glb,
     show_error,
-    util::misc::{MdlRefBuilder, ModelReference},
+    util::{
+        diagnostics::collect_diagnostics,
+        log::log_dir,
+        misc::{MdlRefBuilder, ModelReference},
+    },
     wtf,
 };
 use dirs::home_dir;
 use gdnative::{
-    api::{MenuButton, PopupMenu, OS},
+    api::{ConfirmationDialog, LineEdit, MenuButton, PopupMenu, WindowDialog, OS},
     methods,
     prelude::*,
     NativeClass,
 };
 use native_dialog::FileDialog as NativeFileDialog;
-use std::{cell::RefCell, collections::HashMap, convert::TryInto};
+use std::{cell::RefCell, collections::HashMap, convert::TryInto, path::PathBuf};
 use walkdir::WalkDir;
 
+// children of the edit menu, made in `_ready`
+const PROFILES_POPUP: &str = "Profiles";
+const NAME_DIALOG: &str = "ProfileNameDialog";
+
 #[derive(NativeClass)]
 #[inherit(MenuButton)]
 #[register_with(Self::register_signals)]
@@ -126,7 +135,7 @@ impl FileMenuButton {
         ));
 
         popupmenu.add_separator("");
-        popupmenu.add_item("Open Settigs", 2, -1);
+        popupmenu.add_item("Open Settings", 2, -1);
 
         *self.default_model_paths.borrow_mut() = file_hashmap;
     }
@@ -172,7 +181,15 @@ impl FileMenuButton {
             }
 
             2 => {
-                godot_print!("AAAA");
+                let settings_dialog = unsafe {
+                    owner
+                        .get_node("SettingsDialog")
+                        .unwrap()
+                        .assume_safe()
+                        .cast::<WindowDialog>()
+                        .unwrap()
+                };
+                settings_dialog.popup_centered(Vector2::new(520_f32, 420_f32));
             }
             _ => {}
         }
@@ -180,7 +197,6 @@ impl FileMenuButton {
 
     #[export]
     pub fn on_default_model_popupmenu_button_clicked(&self, owner: TRef<MenuButton>, id: i32) {
-        godot_print!("?");
         let default_popupmenu = unsafe {
             &*owner.get_node("/root/Open2DHolo/Open2DHoloMainUINode/Panel/VBoxContainer/HBoxContainer/HBoxContainer/File/Default").unwrap().assume_safe().cast::<PopupMenu>().unwrap()
         };
@@ -189,7 +205,7 @@ impl FileMenuButton {
         if let Some(mdl_ref) = self.default_model_paths.borrow().get(&selected_text) {
             owner.emit_signal(
                 "new_tscn_model_load",
-                &[Variant::from_str(mdl_ref.globalized_tscn_path())],
+                &[Variant::from_str(globalize_path!(mdl_ref.tscn_path()))],
             );
         }
     }
@@ -198,7 +214,11 @@ impl FileMenuButton {
 #[derive(NativeClass)]
 #[inherit(MenuButton)]
 #[register_with(Self::register_signals)]
-pub struct EditMenuButton;
+pub struct EditMenuButton {
+    // what the name dialog is asking a name for, "create" or "duplicate"
+    pending_action: RefCell<String>,
+    previous_profile_path: RefCell<String>,
+}
 
 #[methods]
 impl EditMenuButton {
@@ -206,15 +226,55 @@ impl EditMenuButton {
         builder.add_signal(Signal {
             name: "settings_open",
             args: &[],
-        })
+        });
+
+        // `action` is one of create, duplicate, save, switch, import, export or calibrate. `arg`
+        // is the profile name or file path it needs, empty otherwise.
+        builder.add_signal(Signal {
+            name: "profile_action",
+            args: &[
+                SignalArgument {
+                    name: "action",
+                    default: Variant::from_str(""),
+                    export_info: ExportInfo::new(VariantType::GodotString),
+                    usage: PropertyUsage::DEFAULT,
+                },
+                SignalArgument {
+                    name: "arg",
+                    default: Variant::from_str(""),
+                    export_info: ExportInfo::new(VariantType::GodotString),
+                    usage: PropertyUsage::DEFAULT,
+                },
+            ],
+        });
     }
     fn new(_owner: &MenuButton) -> Self {
-        EditMenuButton
+        let home_dir = home_dir().map_or_else(String::new, |h| h.to_string_lossy().to_string());
+        EditMenuButton {
+            pending_action: RefCell::new(String::new()),
+            previous_profile_path: RefCell::new(home_dir),
+        }
     }
     #[export]
     fn _ready(&self, owner: TRef<MenuButton>) {
         let popupmenu = unsafe { &*owner.get_popup().unwrap().assume_safe() };
         popupmenu.add_item("Open Editor", 0, -1);
+        popupmenu.add_separator("");
+
+        // filled in every time it shows, profiles come and go
+        let profiles_popup = PopupMenu::new();
+        profiles_popup.set_name(PROFILES_POPUP);
+        let profiles_popup = unsafe { profiles_popup.into_shared().assume_safe() };
+        popupmenu.add_child(profiles_popup, false);
+        popupmenu.add_submenu_item("Switch Profile", PROFILES_POPUP, 10);
+        popupmenu.add_item("New Profile...", 1, -1);
+        popupmenu.add_item("Duplicate Profile...", 2, -1);
+        popupmenu.add_item("Save To Current Profile", 3, -1);
+        popupmenu.add_separator("");
+        popupmenu.add_item("Import Profile...", 4, -1);
+        popupmenu.add_item("Export Current Profile...", 5, -1);
+        popupmenu.add_separator("");
+        popupmenu.add_item("Calibrate Neutral Pose", 6, -1);
 
         wtf!(popupmenu.connect(
             "id_pressed",
@@ -222,16 +282,191 @@ impl EditMenuButton {
             "on_popupmenu_button_clicked",
             VariantArray::new_shared(),
             0,
-        ))
+        ));
+        wtf!(popupmenu.connect(
+            "about_to_show",
+            owner,
+            "on_popupmenu_about_to_show",
+            VariantArray::new_shared(),
+            0,
+        ));
+        wtf!(profiles_popup.connect(
+            "id_pressed",
+            owner,
+            "on_profile_selected",
+            VariantArray::new_shared(),
+            0,
+        ));
+
+        let name_dialog = ConfirmationDialog::new();
+        name_dialog.set_name(NAME_DIALOG);
+        name_dialog.set_title("Profile Name");
+        let name_edit = LineEdit::new();
+        name_edit.set_name("NameEdit");
+        name_dialog.add_child(name_edit, false);
+        let name_dialog = unsafe { name_dialog.into_shared().assume_safe() };
+        owner.add_child(name_dialog, false);
+        wtf!(name_dialog.connect(
+            "confirmed",
+            owner,
+            "on_name_confirmed",
+            VariantArray::new_shared(),
+            0,
+        ));
     }
 
     #[export]
-    pub fn on_popupmenu_button_clicked(&self, _owner: TRef<MenuButton>, id: i32) {
+    pub fn on_popupmenu_about_to_show(&self, owner: TRef<MenuButton>) {
+        let popupmenu = unsafe { &*owner.get_popup().unwrap().assume_safe() };
+        let active = active_profile_name();
+        let has_active = active.is_some();
+        for id in &[3, 5] {
+            let idx = popupmenu.get_item_index(*id);
+            popupmenu.set_item_disabled(idx, !has_active);
+        }
+
+        let profiles_popup = unsafe {
+            popupmenu
+                .get_node(PROFILES_POPUP)
+                .unwrap()
+                .assume_safe()
+                .cast::<PopupMenu>()
+                .unwrap()
+        };
+        profiles_popup.clear();
+        let names = Profile::list();
+        if names.is_empty() {
+            profiles_popup.add_item("No Profiles Yet", 0, -1);
+            profiles_popup.set_item_disabled(0, true);
+            return;
+        }
+        for (idx, name) in names.iter().enumerate() {
+            profiles_popup.add_radio_check_item(name, idx as i64, -1);
+            profiles_popup.set_item_checked(idx as i64, active.as_deref() == Some(name.as_str()));
+        }
+    }
+
+    #[export]
+    pub fn on_popupmenu_button_clicked(&self, owner: TRef<MenuButton>, id: i32) {
         match id {
-            // 0 => {}
+            1 => self.ask_for_name(owner, "create", Profile::unique_name("New Profile")),
+            2 => {
+                let base = match active_profile_name() {
+                    Some(name) => format!("{} Copy", name),
+                    None => "New Profile".to_string(),
+                };
+                self.ask_for_name(owner, "duplicate", Profile::unique_name(&base));
+            }
+            3 => emit_profile_action(owner, "save", ""),
+            4 => {
+                let picked = NativeFileDialog::new()
+                    .set_location(&*self.previous_profile_path.borrow())
+                    .add_filter("Open2DHolo Profile", &["*.ron"])
+                    .show_open_single_file();
+                if let Some(path) = self.picked_path(picked) {
+                    emit_profile_action(owner, "import", &path);
+                }
+            }
+            5 => {
+                let picked = NativeFileDialog::new()
+                    .set_location(&*self.previous_profile_path.borrow())
+                    .add_filter("Open2DHolo Profile", &["*.ron"])
+                    .show_save_single_file();
+                if let Some(path) = self.picked_path(picked) {
+                    emit_profile_action(owner, "export", &path);
+                }
+            }
+            6 => emit_profile_action(owner, "calibrate", ""),
             _ => {}
         }
     }
+
+    #[export]
+    pub fn on_profile_selected(&self, owner: TRef<MenuButton>, id: i32) {
+        let profiles_popup = unsafe {
+            owner
+                .get_popup()
+                .unwrap()
+                .assume_safe()
+                .get_node(PROFILES_POPUP)
+                .unwrap()
+                .assume_safe()
+                .cast::<PopupMenu>()
+                .unwrap()
+        };
+        let idx = profiles_popup.get_item_index(i64::from(id));
+        let name = profiles_popup.get_item_text(idx).to_string();
+        emit_profile_action(owner, "switch", &name);
+    }
+
+    #[export]
+    pub fn on_name_confirmed(&self, owner: TRef<MenuButton>) {
+        let name_edit = unsafe {
+            owner
+                .get_node(format!("{}/NameEdit", NAME_DIALOG))
+                .unwrap()
+                .assume_safe()
+                .cast::<LineEdit>()
+                .unwrap()
+        };
+        let name = name_edit.text().to_string();
+        let action = self.pending_action.borrow().clone();
+        emit_profile_action(owner, &action, name.trim());
+    }
+
+    fn ask_for_name(&self, owner: TRef<MenuButton>, action: &str, suggested: String) {
+        *self.pending_action.borrow_mut() = action.to_string();
+        let name_dialog = unsafe {
+            owner
+                .get_node(NAME_DIALOG)
+                .unwrap()
+                .assume_safe()
+                .cast::<ConfirmationDialog>()
+                .unwrap()
+        };
+        let name_edit = unsafe {
+            name_dialog
+                .get_node("NameEdit")
+                .unwrap()
+                .assume_safe()
+                .cast::<LineEdit>()
+                .unwrap()
+        };
+        name_edit.set_text(suggested);
+        name_edit.select_all();
+        name_dialog.popup_centered(Vector2::new(320_f32, 80_f32));
+        name_edit.grab_focus();
+    }
+
+    fn picked_path(&self, picked: Result<Option<PathBuf>, native_dialog::Error>) -> Option<String> {
+        match picked {
+            Ok(Some(path)) => {
+                if let Some(dir) = path.parent() {
+                    *self.previous_profile_path.borrow_mut() = dir.to_string_lossy().to_string();
+                }
+                Some(path.to_string_lossy().to_string())
+            }
+            // cancelled
+            Ok(None) => None,
+            Err(why) => {
+                show_error!("Failed to open file", why);
+                None
+            }
+        }
+    }
+}
+
+fn active_profile_name() -> Option<String> {
+    UserConfig::from_cfg()
+        .ok()
+        .and_then(|cfg| cfg.active_profile().map(str::to_string))
+}
+
+fn emit_profile_action(owner: TRef<MenuButton>, action: &str, arg: &str) {
+    owner.emit_signal(
+        "profile_action",
+        &[Variant::from_str(action), Variant::from_str(arg)],
+    );
 }
 
 #[derive(NativeClass)]
@@ -256,6 +491,9 @@ impl HelpMenuButton {
         };
         popupmenu.add_item("Open Docs", 0, -1); // TODO: Fix Nonexistant Docs
         popupmenu.add_separator("");
+        popupmenu.add_item("Copy Diagnostics", 2, -1);
+        popupmenu.add_item("Open Log Folder", 3, -1);
+        popupmenu.add_separator("");
         popupmenu.add_item("About", 1, -1);
         wtf!(popupmenu.connect(
             "id_pressed",
@@ -267,7 +505,29 @@ impl HelpMenuButton {
     }
 
     #[export]
-    pub fn on_popupmenu_button_clicked(&self, _owner: TRef<MenuButton>, _id: i32) {}
+    pub fn on_popupmenu_button_clicked(&self, _owner: TRef<MenuButton>, id: i32) {
+        match id {
+            // for bug reports, paste it wherever the report goes
+            2 => {
+                OS::godot_singleton().set_clipboard(collect_diagnostics());
+                log_info!(Ui, "Copied diagnostics to the clipboard");
+            }
+            3 => match log_dir() {
+                Some(dir) => {
+                    if let Err(why) =
+                        OS::godot_singleton().shell_open(dir.to_string_lossy().to_string())
+                    {
+                        show_error!("Could not open the log folder", format!("{:?}", why));
+                    }
+                }
+                None => show_error!(
+                    "Could not open the log folder",
+                    "There is no log folder on this platform."
+                ),
+            },
+            _ => {}
+        }
+    }
 }
 
 // Crawls the provided directory for GLB files, a type of graphic interchange format.
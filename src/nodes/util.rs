@@ -14,11 +14,87 @@
 //     You should have received a copy of the GNU General Public License
 //     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::{
+    configuration::processing_config::StreamSettings,
+    error::conversion_error::ConversionError::ConversionFromError,
+    util::{
+        camera::device_utils::{CachedDeviceList, DeviceFormat, Resolution},
+        log::{Level, Logger, Record},
+        misc::FullyCalculatedPacket,
+    },
+};
 use gdnative::{
     api::{Tree, TreeItem},
-    GodotObject, Ref, TRef,
+    core_types::{ToVariant, Variant, Vector2, Vector2Array, Vector3},
+    godot_error, godot_print, godot_warn, GodotObject, Ref, TRef,
 };
 
+// a camera without a saved stream starts at this at most, the input editor can change it later
+const PREFERRED_PIXELS: u32 = 1280 * 720;
+
+/// Puts log records into the Godot console, errors and warnings show up in the debugger too.
+pub struct GodotLogger;
+
+impl Logger for GodotLogger {
+    fn log(&self, record: &Record) {
+        match record.level {
+            Level::Error => godot_error!("{} {}", record.target, record.message),
+            Level::Warn => godot_warn!("{} {}", record.target, record.message),
+            Level::Info | Level::Debug => {
+                godot_print!("{} {} {}", record.level, record.target, record.message)
+            }
+        }
+    }
+}
+
+/// Landmarks, face box, rotation and confidence, the way `frame_processed` sends them.
+pub fn packet_to_variants(packet: &FullyCalculatedPacket) -> (Variant, Variant, Variant, Variant) {
+    let mut landmarks = Vector2Array::new();
+    for point in &packet.landmarks {
+        landmarks.push(Vector2::new(point.x as f32, point.y as f32));
+    }
+    let facebox = packet.face_location.to_rect().to_f32().to_variant();
+    let angles = Vector3::new(
+        packet.euler.x as f32,
+        packet.euler.y as f32,
+        packet.euler.z as f32,
+    )
+    .to_variant();
+    (
+        landmarks.to_variant(),
+        facebox,
+        angles,
+        packet.confidence.overall().to_variant(),
+    )
+}
+
+pub fn resolution_from_variant(var: &Variant) -> Result<Resolution, Box<dyn std::error::Error>> {
+    if let Some(v) = var.try_to_vector2() {
+        return if v.x > 0.0 && v.y > 0.0 {
+            Ok(Resolution::new(v.x as u32, v.y as u32))
+        } else {
+            Err(Box::new(ConversionFromError {
+                from: "Variant".to_string(),
+                to: "u32".to_string(),
+            }))
+        };
+    }
+    Err(Box::new(ConversionFromError {
+        from: "Variant".to_string(),
+        to: "Vector2".to_string(),
+    }))
+}
+
+pub fn format_from_variant(var: &Variant) -> Result<DeviceFormat, Box<dyn std::error::Error>> {
+    if let Some(st) = var.try_to_string() {
+        return Ok(st.parse()?);
+    }
+    Err(Box::new(ConversionFromError {
+        from: "Variant".to_string(),
+        to: "String".to_string(),
+    }))
+}
+
 pub fn create_editable_item(item: &TreeItem, field: &str) {
     item.set_text(0, field);
     item.set_text_align(0, TreeItem::ALIGN_LEFT);
@@ -45,10 +121,13 @@ pub fn create_custom_editable_item(owner: TRef<Tree>, parent: &TreeItem, field:
     webcam_format_resoultion.set_editable(1, true);
 }
 
-pub fn get_immidiate_treeitems(_owner: TRef<Tree>, _root: TRef<TreeItem>) -> Vec<Ref<TreeItem>> {
-    // let mut item = root.get_children();
-    let /*mut*/ children: Vec<Ref<TreeItem>> = vec![];
-    // while let Some(i) = item { }
+pub fn get_immidiate_treeitems(_owner: TRef<Tree>, root: TRef<TreeItem>) -> Vec<Ref<TreeItem>> {
+    let mut item = root.get_children();
+    let mut children: Vec<Ref<TreeItem>> = vec![];
+    while let Some(i) = item {
+        item = unsafe { i.assume_safe() }.get_next();
+        children.push(i);
+    }
     children
 }
 
@@ -62,3 +141,27 @@ pub fn check_endswith(entry: &walkdir::DirEntry, end: &'_ str) -> bool {
 pub fn check_endswith_glb(entry: &walkdir::DirEntry) -> bool {
     check_endswith(entry, ".glb")
 }
+
+// the fastest the camera goes, at the biggest resolution up to `PREFERRED_PIXELS` it does that at
+pub fn preferred_stream(device: &CachedDeviceList) -> Option<StreamSettings> {
+    let fmt = *device.get_formats().first()?;
+    let supported = device.get_supported(fmt);
+    let max_fps = supported.values().flatten().copied().max()?;
+    let mut candidates: Vec<_> = supported
+        .iter()
+        .filter(|(_, framerates)| framerates.contains(&max_fps))
+        .map(|(res, _)| *res)
+        .collect();
+    candidates.sort_by_key(|res| res.x * res.y);
+    let res = candidates
+        .iter()
+        .rev()
+        .find(|res| res.x * res.y <= PREFERRED_PIXELS)
+        .or_else(|| candidates.first())
+        .copied()?;
+    Some(StreamSettings {
+        res,
+        fps: max_fps,
+        fmt,
+    })
+}
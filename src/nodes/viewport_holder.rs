@@ -15,23 +15,82 @@
 //     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::{
-    localize_path,
-    processing::input_processor::InputProcesser,
+    configuration::{
+        config_watcher::ConfigWatcher,
+        overrides::Overrides,
+        profile::{ModelTransform, Profile},
+        user_config::UserConfig,
+    },
+    globalize_path, localize_path, log_debug, log_error, log_info, log_warn,
+    nodes::{
+        camera_input_preview::PreviewLayers,
+        util::{format_from_variant, packet_to_variants, resolution_from_variant},
+    },
+    output::{OutputTarget, PacketSink},
+    processing::{
+        face_identity::PrimaryFacePolicy,
+        input_processor::InputProcesser,
+        pipeline_stats::{PipelineStatsSnapshot, LATENCY_BUCKETS_MS},
+        preprocess::{CropRect, PreprocessConfig, Rotation},
+        smoothing::{Smoother, SmoothingConfig},
+        supervisor::{Supervisor, SupervisorAction, ThreadExit},
+    },
     show_error,
     util::{
-        camera::device_utils::{DeviceConfig, DeviceFormat, PossibleDevice, Resolution},
-        misc::{Backend, BackendConfig},
+        camera::{
+            camera_controls::{CameraControl, ControlDescription, ControlKind},
+            device_utils::{DeviceConfig, DeviceFormat, PossibleDevice},
+            frame::{Frame, PixelFormat},
+        },
+        misc::{BackendConfig, ControlReply, DecodeOptions, FullyCalculatedPacket, NeutralPose},
     },
     wtf,
 };
-use gdnative::{api::VSplitContainer, prelude::*, NativeClass};
-use std::cell::RefCell;
+use gdnative::{
+    api::{Image, VSplitContainer},
+    prelude::*,
+    NativeClass,
+};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+// seconds between stats updates
+const STATS_INTERVAL: f32 = 1.0;
+// only every n-th stats update ends up in the log
+const STATS_LOG_EVERY: u32 = 10;
+// where the landmark models ship in the project
+const MODEL_DIR_RES: &str = "res://models";
 
 #[derive(NativeClass)]
 #[inherit(VSplitContainer)]
 #[register_with(Self::register_signals)]
 pub struct ViewportHolder {
     input_processer: RefCell<Option<InputProcesser>>,
+    supervisor: RefCell<Supervisor>,
+    // what to start again once the supervisor says so
+    restart_with: RefCell<Option<(PossibleDevice, BackendConfig)>>,
+    primary_face: Cell<PrimaryFacePolicy>,
+    stats_timer: Cell<f32>,
+    stats_count: Cell<u32>,
+    // what the user set the camera controls to, put back when the processer gets restarted
+    camera_controls: RefCell<HashMap<CameraControl, i64>>,
+    // for the camera that is selected in the editor
+    preprocess: Cell<PreprocessConfig>,
+    // whether anyone wants to see camera frames
+    preview: Cell<bool>,
+    config_watcher: RefCell<Option<ConfigWatcher>>,
+    smoother: RefCell<Smoother>,
+    // what the outputs were opened from, to tell when they need opening again
+    outputs: RefCell<Vec<(OutputTarget, Box<dyn PacketSink>)>>,
+    started: Instant,
+    // what goes into a profile besides the settings
+    current_model: RefCell<Option<String>>,
+    model_transform: Cell<ModelTransform>,
+    calibration: Cell<Option<NeutralPose>>,
 }
 
 #[methods]
@@ -86,13 +145,93 @@ impl ViewportHolder {
                     export_info: ExportInfo::new(VariantType::Vector3),
                     usage: PropertyUsage::DEFAULT,
                 },
+                SignalArgument {
+                    name: "confidence",
+                    default: Variant::from_f64(1_f64),
+                    export_info: ExportInfo::new(VariantType::F64),
+                    usage: PropertyUsage::DEFAULT,
+                },
             ],
-        })
+        });
+
+        builder.add_signal(Signal {
+            name: "pipeline_stats",
+            args: &[SignalArgument {
+                name: "stats",
+                default: Variant::from_dictionary(&Dictionary::new_shared()),
+                export_info: ExportInfo::new(VariantType::Dictionary),
+                usage: PropertyUsage::DEFAULT,
+            }],
+        });
+
+        // a working resolution copy of the camera frame, packets are relative to `source_res`.
+        // `image` is nil right after a camera got started.
+        builder.add_signal(Signal {
+            name: "camera_preview",
+            args: &[
+                SignalArgument {
+                    name: "image",
+                    default: Variant::new(),
+                    export_info: ExportInfo::new(VariantType::Object),
+                    usage: PropertyUsage::DEFAULT,
+                },
+                SignalArgument {
+                    name: "source_res",
+                    default: Variant::from_vector2(&Vector2::new(0_f32, 0_f32)),
+                    export_info: ExportInfo::new(VariantType::Vector2),
+                    usage: PropertyUsage::DEFAULT,
+                },
+            ],
+        });
+
+        // one Dictionary per control the running camera supports
+        builder.add_signal(Signal {
+            name: "camera_controls",
+            args: &[SignalArgument {
+                name: "controls",
+                default: Variant::from_array(&VariantArray::new_shared()),
+                export_info: ExportInfo::new(VariantType::VariantArray),
+                usage: PropertyUsage::DEFAULT,
+            }],
+        });
+
+        // the settings file changed on disk and the running parts picked it up
+        builder.add_signal(Signal {
+            name: "config_reloaded",
+            args: &[],
+        });
+
+        // the active profile is another one now, or was just made. `switched` is false when the
+        // camera stays the same.
+        builder.add_signal(Signal {
+            name: "profile_changed",
+            args: &[SignalArgument {
+                name: "switched",
+                default: Variant::from_bool(false),
+                export_info: ExportInfo::new(VariantType::Bool),
+                usage: PropertyUsage::DEFAULT,
+            }],
+        });
     }
 
     fn new(_owner: &VSplitContainer) -> Self {
         ViewportHolder {
             input_processer: RefCell::new(None),
+            supervisor: RefCell::new(Supervisor::default()),
+            restart_with: RefCell::new(None),
+            primary_face: Cell::new(PrimaryFacePolicy::default()),
+            stats_timer: Cell::new(0_f32),
+            stats_count: Cell::new(0),
+            camera_controls: RefCell::new(HashMap::new()),
+            preprocess: Cell::new(PreprocessConfig::default()),
+            preview: Cell::new(PreviewLayers::default().camera_image),
+            config_watcher: RefCell::new(None),
+            smoother: RefCell::new(Smoother::new(SmoothingConfig::default())),
+            outputs: RefCell::new(Vec::new()),
+            started: Instant::now(),
+            current_model: RefCell::new(None),
+            model_transform: Cell::new(ModelTransform::default()),
+            calibration: Cell::new(None),
         }
     }
     #[export]
@@ -121,6 +260,73 @@ impl ViewportHolder {
             0,
         ));
 
+        wtf!(emitter_tree.connect(
+            "input_device_removed",
+            owner,
+            "on_input_device_removed",
+            VariantArray::new_shared(),
+            0,
+        ));
+
+        wtf!(emitter_tree.connect(
+            "camera_control_changed",
+            owner,
+            "on_camera_control_changed",
+            VariantArray::new_shared(),
+            0,
+        ));
+
+        wtf!(emitter_tree.connect(
+            "preprocess_changed",
+            owner,
+            "on_preprocess_changed",
+            VariantArray::new_shared(),
+            0,
+        ));
+
+        wtf!(emitter_tree.connect(
+            "preview_layers_changed",
+            owner,
+            "on_preview_layers_changed",
+            VariantArray::new_shared(),
+            0,
+        ));
+
+        wtf!(owner.connect(
+            "camera_controls",
+            *emitter_tree,
+            "on_camera_controls",
+            VariantArray::new_shared(),
+            0,
+        ));
+
+        let settings_dialog = unsafe {
+            owner.get_node("/root/Open2DHolo/Open2DHoloMainUINode/Panel/VBoxContainer/HBoxContainer/HBoxContainer/File/SettingsDialog").unwrap().assume_safe()
+        };
+        wtf!(settings_dialog.connect(
+            "settings_applied",
+            owner,
+            "on_settings_applied",
+            VariantArray::new_shared(),
+            0,
+        ));
+
+        wtf!(owner.connect(
+            "config_reloaded",
+            *emitter_tree,
+            "on_config_reloaded",
+            VariantArray::new_shared(),
+            0,
+        ));
+
+        wtf!(emitter_tree.connect(
+            "primary_face_changed",
+            owner,
+            "on_primary_face_changed",
+            VariantArray::new_shared(),
+            0,
+        ));
+
         wtf!(emitter_loader.connect(
             "new_model_load",
             owner,
@@ -136,6 +342,48 @@ impl ViewportHolder {
             VariantArray::new_shared(),
             0,
         ));
+
+        let edit_menu = unsafe {
+            owner.get_node("/root/Open2DHolo/Open2DHoloMainUINode/Panel/VBoxContainer/HBoxContainer/HBoxContainer/Edit").unwrap().assume_safe()
+        };
+        wtf!(edit_menu.connect(
+            "profile_action",
+            owner,
+            "on_profile_action",
+            VariantArray::new_shared(),
+            0,
+        ));
+
+        let model_editor = unsafe {
+            owner.get_node("/root/Open2DHolo/Open2DHoloMainUINode/Panel/VBoxContainer/HSplitContainer/TabContainer/Scene/Grid/VBoxContainer/Tree").unwrap().assume_safe()
+        };
+        wtf!(model_editor.connect(
+            "model_transform_change",
+            owner,
+            "on_model_transform_change",
+            VariantArray::new_shared(),
+            0,
+        ));
+
+        wtf!(owner.connect(
+            "profile_changed",
+            *emitter_tree,
+            "on_profile_changed",
+            VariantArray::new_shared(),
+            0,
+        ));
+
+        match ConfigWatcher::new(UserConfig::settings_path()) {
+            Ok(watcher) => *self.config_watcher.borrow_mut() = Some(watcher),
+            Err(why) => log_warn!(Ui, "Settings won't reload when changed: {}", why),
+        }
+        let user_cfg = UserConfig::from_cfg().unwrap_or_else(|_| UserConfig::from_default());
+        self.apply_config(&user_cfg);
+
+        // the model viewport has to be listening first
+        unsafe {
+            owner.call_deferred("apply_active_profile", &[]);
+        }
     }
 
     #[export]
@@ -143,36 +391,121 @@ impl ViewportHolder {
 
     // poll the channel to get the data from the input process thread
     #[export]
-    pub fn _process(&self, owner: TRef<VSplitContainer>, _delta: f32) {
+    pub fn _process(&self, owner: TRef<VSplitContainer>, delta: f32) {
+        let reloaded = self
+            .config_watcher
+            .borrow()
+            .as_ref()
+            .and_then(ConfigWatcher::query_change);
+        if let Some(user_cfg) = reloaded {
+            self.apply_config(&user_cfg);
+            owner.emit_signal("config_reloaded", &[]);
+        }
+
+        let exit = self
+            .input_processer
+            .borrow()
+            .as_ref()
+            .and_then(|input| input.poll_exit());
+        if let Some(exit) = exit {
+            self.on_processer_exit(exit);
+        }
+        if self.supervisor.borrow_mut().ready_to_restart() {
+            self.restart_processer();
+        }
+
         if let Some(input) = &*self.input_processer.borrow() {
-            let results = input.query_gotten_results();
-            for pkt in results {
-                let mut variant_arr: Vector2Array = Vector2Array::new();
-                for pt in pkt.landmarks {
-                    variant_arr.push(Vector2::new(pt.x() as f32, pt.y() as f32))
+            self.stats_timer.set(self.stats_timer.get() + delta);
+            if self.stats_timer.get() >= STATS_INTERVAL {
+                self.stats_timer.set(0_f32);
+                if let Err(why) = input.request_stats() {
+                    log_warn!(Processing, "Could not ask for stats: {}", why);
                 }
-                let vector3_angles = Variant::from_vector3(&Vector3::new(
-                    pkt.euler.x() as f32,
-                    pkt.euler.y() as f32,
-                    pkt.euler.z() as f32,
-                ));
-                let facebox_rect = Variant::from_rect2(&pkt.face_location.to_rect().to_f32());
-                owner.emit_signal(
-                    "new_processed_frame_68pt",
-                    &[Variant::from_vector2_array(&variant_arr)],
-                );
+            }
+            for response in input.query_responses() {
+                match response.result {
+                    Ok(ControlReply::Stats(stats)) => {
+                        self.stats_count.set(self.stats_count.get().wrapping_add(1));
+                        if self.stats_count.get() % STATS_LOG_EVERY == 0 {
+                            log_info!(Processing, "pipeline: {}", stats);
+                        }
+                        owner.emit_signal(
+                            "pipeline_stats",
+                            &[Variant::from_dictionary(&stats_to_dictionary(&stats))],
+                        );
+                    }
+                    Ok(ControlReply::Device(state)) => {
+                        log_debug!(Camera, "device: {:?}", state);
+                    }
+                    Ok(ControlReply::Controls(controls)) => {
+                        owner.emit_signal(
+                            "camera_controls",
+                            &[Variant::from_array(&controls_to_array(&controls))],
+                        );
+                    }
+                    Ok(ControlReply::Calibrated(neutral)) => {
+                        self.on_calibrated(neutral);
+                    }
+                    Ok(ControlReply::Done) => {}
+                    Err(why) => {
+                        show_error!("Processing request failed", why);
+                    }
+                }
+            }
+
+            if let Some(preview) = input.take_preview() {
+                let source_res = preview.source_res;
+                if let Some(image) = frame_to_image(preview.frame) {
+                    owner.emit_signal(
+                        "camera_preview",
+                        &[
+                            image,
+                            Variant::from_vector2(&Vector2::new(
+                                source_res.x as f32,
+                                source_res.y as f32,
+                            )),
+                        ],
+                    );
+                }
+            }
+
+            // the UI only cares about the newest frame, older ones would just replay the past
+            let mut results = input.query_latest_results();
+            if !results.is_empty() {
+                self.supervisor.borrow_mut().healthy();
+            }
+            self.smoother.borrow_mut().apply(&mut results);
+            let min_confidence = input.backend_cfg().borrow().min_confidence();
+            // only the primary face gets to move the avatar, otherwise it jumps between people
+            for pkt in results.into_iter().filter(|pkt| pkt.is_primary) {
+                let confidence = pkt.confidence.overall();
+                // a bad frame is worse than no frame, the avatar just holds still instead
+                if confidence < min_confidence {
+                    continue;
+                }
+                let (landmarks, facebox_rect, angles, confidence) = packet_to_variants(&pkt);
+                owner.emit_signal("new_processed_frame_68pt", &[landmarks.clone()]);
                 owner.emit_signal(
                     "frame_processed",
-                    &[
-                        Variant::from_vector2_array(&variant_arr),
-                        facebox_rect,
-                        vector3_angles,
-                    ],
+                    &[landmarks, facebox_rect, angles, confidence],
                 );
+                self.send_to_outputs(&pkt);
             }
         }
     }
 
+    // the settings dialog saved, which the watcher skips on purpose
+    #[export]
+    pub fn on_settings_applied(&self, owner: TRef<VSplitContainer>) {
+        match UserConfig::from_cfg() {
+            Ok(user_cfg) => {
+                self.apply_config(&user_cfg);
+                owner.emit_signal("config_reloaded", &[]);
+            }
+            Err(why) => log_warn!(Ui, "Could not read the settings back: {}", why),
+        }
+    }
+
     #[export]
     pub fn on_kill_signal(&self, _owner: TRef<VSplitContainer>) {
         //if let Some(mut input) = self.input_processer.replace(None) {
@@ -183,16 +516,18 @@ impl ViewportHolder {
     #[export]
     pub fn on_new_input_processer(
         &self,
-        _owner: TRef<VSplitContainer>,
+        owner: TRef<VSplitContainer>,
         _name: Variant,
         res: Variant,
         fps: Variant,
+        scale: Variant,
+        format: Variant,
     ) {
         {
             // fill with input processor spawn logic
             // TODO: Allow regeneration of face processer
 
-            let device_res = match Resolution::from_variant(&res) {
+            let device_res = match resolution_from_variant(&res) {
                 Ok(r) => r,
                 Err(_) => panic!("Improper resolution format set!"),
             };
@@ -202,39 +537,295 @@ impl ViewportHolder {
                 None => panic!("Improper framerate format set!"),
             };
 
-            // TODO: Get backend config from backend settings panel
-            let backend = BackendConfig::new(device_res, Backend::Dlib);
+            let device_fmt = format_from_variant(&format).unwrap_or(DeviceFormat::MJpeg);
+
+            let user_cfg = UserConfig::from_cfg().unwrap_or_else(|_| UserConfig::from_default());
+            let mut backend = BackendConfig::new(device_res, user_cfg.processing().backend())
+                .with_model_dir(PathBuf::from(globalize_path!(MODEL_DIR_RES)))
+                .with_primary_face(self.primary_face.get())
+                .with_max_threads(user_cfg.processing().max_threads())
+                .with_cnn_detector(user_cfg.processing().use_cnn())
+                .with_decode(DecodeOptions {
+                    grayscale: user_cfg.processing().grayscale_decode(),
+                    keep_full_frame: false,
+                })
+                .with_preprocess(self.preprocess.get())
+                .with_preview(self.preview.get())
+                .with_calibration(self.calibration.get());
+            let processing_scale = scale.try_to_f64().filter(|s| *s > 0_f64);
+            if let Some(s) = processing_scale {
+                backend = backend.with_scale_factor(s);
+            }
 
             let device_contact = crate::CURRENT_DEVICE.with(|dev| dev.borrow().clone().unwrap());
 
+            // frames from the last camera don't fit anymore, the preview waits for new ones
+            owner.emit_signal(
+                "camera_preview",
+                &[
+                    Variant::new(),
+                    Variant::from_vector2(&Vector2::new(device_res.x as f32, device_res.y as f32)),
+                ],
+            );
+
             let device_exists = { self.input_processer.borrow().is_some() };
 
             if device_exists {
-                let dev_cfg: DeviceConfig = PossibleDevice::from_device_contact(
+                let new_device = PossibleDevice::from_device_contact(
                     device_contact,
                     device_res,
                     device_fps as u32,
-                    DeviceFormat::MJpeg,
-                )
-                .into();
-                wtf!(self
-                    .input_processer
-                    .borrow()
-                    .as_ref()
-                    .unwrap()
-                    .set_device_cfg(dev_cfg));
+                    device_fmt,
+                );
+                let input_processer = self.input_processer.borrow();
+                let input_processer = input_processer.as_ref().unwrap();
+                // a busy or restarting pipeline can turn these down, that's no reason to quit
+                let requested = if input_processer.device().fmt() == device_fmt {
+                    let dev_cfg: DeviceConfig = new_device.into();
+                    input_processer.set_device_cfg(dev_cfg)
+                } else {
+                    // another pixel format means opening the stream again
+                    input_processer.change_device(new_device)
+                }
+                .and_then(|_| input_processer.set_scale_factor(backend.scale_factor()));
+                if let Err(why) = requested {
+                    show_error!("Could not change the camera settings", why);
+                }
             } else {
+                // the user picked a camera, whatever died before doesn't matter anymore
+                self.supervisor.borrow_mut().cancel();
+                *self.restart_with.borrow_mut() = None;
+                self.camera_controls.borrow_mut().clear();
                 // create new InputProcesser to run pipeline
                 let input_processer = match InputProcesser::from_device_contact(
                     device_contact,
                     device_res,
                     device_fps as u32,
+                    device_fmt,
                     backend,
                 ) {
                     Ok(return_to_monke) => Some(return_to_monke),
                     Err(why) => panic!("Could not generate InputProcesser: {}", why.to_string()),
                 };
                 *self.input_processer.borrow_mut() = input_processer;
+                self.restore_camera_controls();
+            }
+        }
+    }
+
+    #[export]
+    pub fn on_input_device_removed(&self, _owner: TRef<VSplitContainer>, name: Variant) {
+        // there is no point in the supervisor retrying a camera that isn't there. if it comes back
+        // the editor starts a new processer on it.
+        self.supervisor.borrow_mut().cancel();
+        *self.restart_with.borrow_mut() = None;
+        if let Some(input) = self.input_processer.borrow_mut().take() {
+            if let Err(why) = input.shutdown() {
+                log_warn!(Processing, "Could not stop processing: {}", why);
+            }
+        }
+        log_warn!(Camera, "{} was unplugged", name.to_string());
+        show_error!(
+            "Camera disconnected",
+            format!(
+                "{} was unplugged. Tracking picks up again once it is plugged back in.",
+                name.to_string()
+            )
+        );
+    }
+
+    // what can change while running gets changed, the rest waits for the next start
+    fn apply_config(&self, user_cfg: &UserConfig) {
+        if self.smoother.borrow().config() != user_cfg.smoothing() {
+            self.smoother.borrow_mut().set_config(user_cfg.smoothing());
+        }
+        // the ones from the launch options go on top, they aren't in the file
+        let mut targets = user_cfg.output().targets.clone();
+        for target in Overrides::current().outputs() {
+            if !targets.contains(target) {
+                targets.push(target.clone());
+            }
+        }
+        self.open_outputs(&targets);
+
+        let input = self.input_processer.borrow();
+        let input = match &*input {
+            Some(input) => input,
+            None => return,
+        };
+        let backend = input.backend_cfg().borrow().clone();
+        let grayscale = user_cfg.processing().grayscale_decode();
+        if backend.decode().grayscale != grayscale {
+            let decode = DecodeOptions {
+                grayscale,
+                ..backend.decode()
+            };
+            if let Err(why) = input.set_decode(decode) {
+                show_error!("Could not apply the reloaded settings", why);
+            }
+        }
+        if backend.max_threads() != user_cfg.processing().max_threads() {
+            log_info!(
+                Processing,
+                "The thread count changed to {}, it takes effect once tracking restarts",
+                user_cfg.processing().max_threads()
+            );
+        }
+    }
+
+    // the ones that are already open stay as they are, only new (or failed) ones get opened
+    fn open_outputs(&self, targets: &[OutputTarget]) {
+        let mut outputs = self.outputs.borrow_mut();
+        outputs.retain(|(target, _)| {
+            let keep = targets.contains(target);
+            if !keep {
+                log_info!(Output, "Stopped sending tracking to {}", target);
+            }
+            keep
+        });
+        for target in targets {
+            if outputs.iter().any(|(open, _)| open == target) {
+                continue;
+            }
+            match target.open(self.started) {
+                Ok(sink) => {
+                    log_info!(Output, "Sending tracking to {}", target);
+                    outputs.push((target.clone(), sink));
+                }
+                Err(why) => show_error!("Could not open an output", why),
+            }
+        }
+    }
+
+    // an output that fails once is dropped, it gets opened again the next time settings apply
+    fn send_to_outputs(&self, pkt: &FullyCalculatedPacket) {
+        let mut outputs = self.outputs.borrow_mut();
+        let mut idx = 0;
+        while idx < outputs.len() {
+            let (target, sink) = &mut outputs[idx];
+            let sent = sink.send(pkt).and_then(|_| sink.flush());
+            match sent {
+                Ok(_) => idx += 1,
+                Err(why) => {
+                    log_warn!(Output, "Stopped sending tracking to {}: {}", target, why);
+                    outputs.remove(idx);
+                }
+            }
+        }
+    }
+
+    fn on_processer_exit(&self, exit: ThreadExit) {
+        let input = match self.input_processer.borrow_mut().take() {
+            Some(input) => input,
+            None => return,
+        };
+        if exit == ThreadExit::Shutdown {
+            return;
+        }
+        log_error!(Processing, "Processing stopped: {}", exit);
+
+        match self.supervisor.borrow_mut().exited(&exit) {
+            SupervisorAction::Restart { after } => {
+                *self.restart_with.borrow_mut() =
+                    Some((input.device(), input.backend_cfg().borrow().clone()));
+                show_error!(
+                    "Input processing stopped",
+                    format!("{} Trying again in {:.1}s.", exit, after.as_secs_f32())
+                );
+            }
+            SupervisorAction::GiveUp => {
+                show_error!("Input processing stopped", exit);
+            }
+        }
+    }
+
+    fn restart_processer(&self) {
+        let (device, cfg) = match self.restart_with.borrow_mut().take() {
+            Some(restart) => restart,
+            None => return,
+        };
+        match InputProcesser::new(device, cfg) {
+            Ok(input) => {
+                *self.input_processer.borrow_mut() = Some(input);
+                self.restore_camera_controls();
+            }
+            Err(why) => {
+                self.supervisor.borrow_mut().cancel();
+                show_error!("Could not restart input processing", why);
+            }
+        }
+    }
+
+    // a fresh processer opens the camera fresh, which may or may not keep what was set before
+    fn restore_camera_controls(&self) {
+        if let Some(input) = &*self.input_processer.borrow() {
+            for (control, value) in self.camera_controls.borrow().iter() {
+                if let Err(why) = input.set_control(*control, *value) {
+                    log_warn!(Camera, "Could not restore {}: {}", control.name(), why);
+                }
+            }
+            if let Err(why) = input.query_controls() {
+                log_warn!(Camera, "Could not ask for the camera controls: {}", why);
+            }
+        }
+    }
+
+    #[export]
+    pub fn on_camera_control_changed(
+        &self,
+        _owner: TRef<VSplitContainer>,
+        name: Variant,
+        value: Variant,
+    ) {
+        let control = match CameraControl::from_name(&name.to_string()) {
+            Some(c) => c,
+            None => return,
+        };
+        let value = value.to_i64();
+        self.camera_controls.borrow_mut().insert(control, value);
+        if let Some(input) = &*self.input_processer.borrow() {
+            if let Err(why) = input.set_control(control, value) {
+                show_error!("Could not change the camera settings", why);
+            }
+        }
+    }
+
+    #[export]
+    pub fn on_preprocess_changed(&self, _owner: TRef<VSplitContainer>, settings: Variant) {
+        let preprocess = preprocess_from_dictionary(&settings.to_dictionary());
+        self.preprocess.set(preprocess);
+        if let Some(input) = &*self.input_processer.borrow() {
+            if let Err(why) = input.set_preprocess(preprocess) {
+                show_error!("Could not change the image adjustments", why);
+            }
+        }
+    }
+
+    #[export]
+    pub fn on_preview_layers_changed(&self, _owner: TRef<VSplitContainer>, layers: Variant) {
+        let layers = PreviewLayers::from_dictionary(&layers.to_dictionary());
+        if layers.camera_image == self.preview.get() {
+            return;
+        }
+        self.preview.set(layers.camera_image);
+        if let Some(input) = &*self.input_processer.borrow() {
+            if let Err(why) = input.set_preview(layers.camera_image) {
+                show_error!("Could not turn the camera preview on or off", why);
+            }
+        }
+    }
+
+    #[export]
+    pub fn on_primary_face_changed(&self, _owner: TRef<VSplitContainer>, policy: Variant) {
+        let policy = match policy.to_string().as_str() {
+            "Closest To Center" => PrimaryFacePolicy::ClosestToCenter,
+            "Lock Current Face" => PrimaryFacePolicy::LockCurrent,
+            _ => PrimaryFacePolicy::Largest,
+        };
+        self.primary_face.set(policy);
+        if let Some(input) = &*self.input_processer.borrow() {
+            if let Err(why) = input.set_primary_face(policy) {
+                show_error!("Could not change the primary face", why);
             }
         }
     }
@@ -265,6 +856,296 @@ impl ViewportHolder {
 
     #[export]
     fn emit_loaded(&self, owner: TRef<VSplitContainer>, mdl_path: String) {
+        *self.current_model.borrow_mut() = Some(mdl_path.clone());
         owner.emit_signal("model_load_start", &[Variant::from_str(mdl_path)]);
     }
+
+    #[export]
+    pub fn on_model_transform_change(
+        &self,
+        _owner: TRef<VSplitContainer>,
+        offset: Variant,
+        angle: Variant,
+    ) {
+        let (offset, angle) = (offset.to_vector3(), angle.to_vector3());
+        self.model_transform.set(ModelTransform {
+            offset: [offset.x.into(), offset.y.into(), offset.z.into()],
+            rotation: [angle.x.into(), angle.y.into(), angle.z.into()],
+        });
+    }
+
+    #[export]
+    pub fn on_profile_action(&self, owner: TRef<VSplitContainer>, action: String, arg: String) {
+        let result = match action.as_str() {
+            "create" => self.create_profile(owner, &arg, false),
+            "duplicate" => self.create_profile(owner, &arg, true),
+            "save" => self.save_active_profile(),
+            "switch" => self.switch_profile(owner, &arg),
+            "import" => self.import_profile(&arg),
+            "export" => self.export_active_profile(&arg),
+            "calibrate" => self.calibrate(),
+            _ => return,
+        };
+        if let Err(why) = result {
+            show_error!("Profile", why);
+        }
+    }
+
+    // the profile from last time, once everything is there to take it. A model from the launch
+    // options wins over the profile's.
+    #[export]
+    pub fn apply_active_profile(&self, owner: TRef<VSplitContainer>) {
+        let model_override = Overrides::take_model().map(|model| model_resource_path(&model));
+        match Profile::active() {
+            Some(mut profile) => {
+                log_info!(Ui, "Using profile {}", profile.name);
+                if model_override.is_some() {
+                    profile.model_path = model_override;
+                }
+                self.apply_profile(owner, &profile);
+                owner.emit_signal("profile_changed", &[Variant::from_bool(false)]);
+            }
+            None => {
+                if let Some(model_path) = model_override {
+                    self.emit_loaded(owner, model_path);
+                }
+            }
+        }
+    }
+
+    // a duplicate starts from the active profile as saved, a new one from how things are now
+    fn create_profile(
+        &self,
+        owner: TRef<VSplitContainer>,
+        name: &str,
+        duplicate: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let source = if duplicate { Profile::active() } else { None };
+        let profile = match source {
+            Some(active) => active.renamed(name),
+            None => self.capture_profile(name),
+        };
+        profile.save_new()?;
+        log_info!(Ui, "Created profile {}", profile.name);
+        self.make_active(&profile)?;
+        if duplicate {
+            self.apply_profile(owner, &profile);
+        }
+        owner.emit_signal("profile_changed", &[Variant::from_bool(duplicate)]);
+        Ok(())
+    }
+
+    fn save_active_profile(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let active = Profile::active().ok_or("There is no active profile to save to.")?;
+        let profile = Profile {
+            blendshapes: active.blendshapes,
+            ..self.capture_profile(&active.name)
+        };
+        profile.save()?;
+        log_info!(Ui, "Saved profile {}", profile.name);
+        Ok(())
+    }
+
+    fn switch_profile(
+        &self,
+        owner: TRef<VSplitContainer>,
+        name: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let profile = Profile::load(name)?;
+        self.make_active(&profile)?;
+        self.apply_profile(owner, &profile);
+        log_info!(Ui, "Switched to profile {}", profile.name);
+        owner.emit_signal("profile_changed", &[Variant::from_bool(true)]);
+        Ok(())
+    }
+
+    // an imported profile doesn't replace one with the same name, it gets a new one
+    fn import_profile(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let imported = Profile::import(Path::new(path))?;
+        let name = Profile::unique_name(&imported.name);
+        let profile = imported.renamed(&name);
+        profile.save_new()?;
+        log_info!(Ui, "Imported {} as profile {}", path, profile.name);
+        Ok(())
+    }
+
+    fn export_active_profile(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let active = Profile::active().ok_or("There is no active profile to export.")?;
+        active.export(Path::new(path))?;
+        log_info!(Ui, "Exported profile {} to {}", active.name, path);
+        Ok(())
+    }
+
+    fn calibrate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        match &*self.input_processer.borrow() {
+            Some(input) => {
+                input.calibrate()?;
+                Ok(())
+            }
+            None => Err("Start a camera first, calibrating needs a face to look at.".into()),
+        }
+    }
+
+    // keeps it for the next processer and in the active profile
+    fn on_calibrated(&self, neutral: NeutralPose) {
+        log_info!(Processing, "Calibrated the neutral pose to {:?}", neutral);
+        self.calibration.set(Some(neutral));
+        if let Some(mut profile) = Profile::active() {
+            profile.calibration = Some(neutral);
+            if let Err(why) = profile.save() {
+                show_error!("Could not save the calibration", why);
+            }
+        }
+    }
+
+    fn capture_profile(&self, name: &str) -> Profile {
+        let user_cfg = UserConfig::from_cfg().unwrap_or_else(|_| UserConfig::from_default());
+        Profile {
+            model_path: self.current_model.borrow().clone(),
+            model_transform: self.model_transform.get(),
+            calibration: self.calibration.get(),
+            ..Profile::from_user_config(name, &user_cfg)
+        }
+    }
+
+    fn make_active(&self, profile: &Profile) -> Result<(), Box<dyn std::error::Error>> {
+        let mut user_cfg = UserConfig::from_cfg()?;
+        user_cfg.apply_profile(profile);
+        user_cfg.write_current()?;
+        self.apply_config(&user_cfg);
+        Ok(())
+    }
+
+    // the camera is up to the input editor, it picks the saved one up on `profile_changed`
+    fn apply_profile(&self, owner: TRef<VSplitContainer>, profile: &Profile) {
+        self.calibration.set(profile.calibration);
+        if let Some(input) = &*self.input_processer.borrow() {
+            if let Err(why) = input.set_calibration(profile.calibration) {
+                log_warn!(Processing, "Could not apply the calibration: {}", why);
+            }
+        }
+        if let Some(model_path) = &profile.model_path {
+            if self.current_model.borrow().as_ref() != Some(model_path) {
+                self.emit_loaded(owner, model_path.clone());
+            }
+        }
+    }
+}
+
+// a path from the command line, relative to where we were started from
+fn model_resource_path(model: &str) -> String {
+    if model.starts_with("res://") {
+        return model.to_string();
+    }
+    let path = PathBuf::from(model);
+    let path = if path.is_relative() {
+        std::env::current_dir().map_or(path.clone(), |dir| dir.join(&path))
+    } else {
+        path
+    };
+    localize_path!(path.to_string_lossy().to_string())
+}
+
+fn controls_to_array(controls: &[ControlDescription]) -> VariantArray {
+    let array = VariantArray::new();
+    for control in controls {
+        let dict = Dictionary::new();
+        dict.insert("name", control.control.name());
+        let kind = match &control.kind {
+            ControlKind::Toggle => "toggle",
+            ControlKind::Range => "range",
+            ControlKind::Menu(labels) => {
+                let mut label_array = StringArray::new();
+                for label in labels {
+                    label_array.push(GodotString::from_str(label));
+                }
+                dict.insert("labels", label_array);
+                "menu"
+            }
+        };
+        dict.insert("kind", kind);
+        dict.insert("min", control.min);
+        dict.insert("max", control.max);
+        dict.insert("step", control.step);
+        dict.insert("default", control.default);
+        dict.insert("value", control.value);
+        if let Some(manual) = control.control.manual_counterpart() {
+            dict.insert("controls", manual.name());
+        }
+        array.push(dict.into_shared());
+    }
+    array.into_shared()
+}
+
+// see `preprocess_to_dictionary` in the webcam input editor
+// packed RGB into a Godot Image, anything else isn't meant for showing
+fn frame_to_image(frame: Frame) -> Option<Variant> {
+    if frame.format() != PixelFormat::Rgb24 {
+        return None;
+    }
+    let res = frame.res();
+    let mut data = frame.into_data();
+    // cameras like to hand out short frames every now and then
+    data.resize(res.x as usize * res.y as usize * 3, 0_u8);
+    let image = Image::new();
+    image.create_from_data(
+        i64::from(res.x),
+        i64::from(res.y),
+        false,
+        Image::FORMAT_RGB8,
+        ByteArray::from_vec(data),
+    );
+    Some(image.into_shared().to_variant())
+}
+
+fn preprocess_from_dictionary(dict: &Dictionary) -> PreprocessConfig {
+    PreprocessConfig {
+        flip_horizontal: dict.get("flip_horizontal").to_bool(),
+        flip_vertical: dict.get("flip_vertical").to_bool(),
+        rotation: Rotation::from_degrees(dict.get("rotation").to_i64()),
+        crop: CropRect {
+            x: dict.get("crop_x").to_f64(),
+            y: dict.get("crop_y").to_f64(),
+            width: dict.get("crop_width").to_f64(),
+            height: dict.get("crop_height").to_f64(),
+        },
+        gamma: dict.get("gamma").to_f64(),
+        clahe: dict.get("clahe").to_bool(),
+        grayscale: dict.get("grayscale").to_bool(),
+    }
+}
+
+fn stats_to_dictionary(stats: &PipelineStatsSnapshot) -> Dictionary {
+    let dict = Dictionary::new();
+    dict.insert("fps", stats.fps);
+    dict.insert("frames_captured", stats.frames_captured);
+    dict.insert("packets_sent", stats.packets_sent);
+    dict.insert("dropped_capture", stats.dropped_capture);
+    dict.insert("dropped_queue_full", stats.dropped_queue_full);
+    dict.insert("dropped_stale", stats.dropped_stale);
+    dict.insert("dropped_superseded", stats.dropped_superseded);
+    dict.insert("latency_mean_ms", stats.latency.mean_ms);
+    dict.insert("latency_max_ms", stats.latency.max_ms);
+
+    let stages = Dictionary::new();
+    for stage in &stats.stages {
+        let timing = Dictionary::new();
+        timing.insert("last_ms", stage.last_ms);
+        timing.insert("mean_ms", stage.mean_ms);
+        timing.insert("max_ms", stage.max_ms);
+        stages.insert(stage.stage.name(), timing.into_shared());
+    }
+    dict.insert("stages", stages.into_shared());
+
+    // bucket upper bound in ms => count, "inf" is everything slower than the last bound
+    let histogram = Dictionary::new();
+    for (idx, count) in stats.latency_histogram.iter().enumerate() {
+        let bound = match LATENCY_BUCKETS_MS.get(idx) {
+            Some(bound) => bound.to_string(),
+            None => "inf".to_string(),
+        };
+        histogram.insert(bound, *count);
+    }
+    dict.insert("latency_histogram", histogram.into_shared());
+    dict.into_shared()
 }
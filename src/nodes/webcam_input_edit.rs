@@ -14,7 +14,10 @@
 //     You should have received a copy of the GNU General Public License
 //     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::{cell::RefCell, collections::HashMap};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+};
 
 use gdnative::{
     api::{
@@ -27,10 +30,25 @@ use gdnative::{
 };
 
 use crate::{
-    nodes::util::create_custom_editable_item,
-    util::camera::device_utils::{
-        enumerate_cache_device, CachedDeviceList, DeviceContact, DeviceFormat, PossibleDevice,
-        Resolution,
+    configuration::{
+        overrides::Overrides, processing_config::StreamSettings, user_config::UserConfig,
+    },
+    log_debug, log_error, log_info, log_warn,
+    nodes::{
+        camera_input_preview::PreviewLayers,
+        util::{create_custom_editable_item, create_editable_range, preferred_stream},
+    },
+    processing::preprocess::{PreprocessConfig, Rotation},
+    util::{
+        camera::{
+            camera_controls::CameraControl,
+            device_monitor::{DeviceEvent, DeviceMonitor},
+            device_utils::{
+                enumerate_cache_device, CachedDeviceList, DeviceContact, DeviceFormat,
+                PossibleDevice, Resolution,
+            },
+        },
+        misc::{scale_resolution, DEFAULT_WORKING_HEIGHT},
     },
 };
 
@@ -40,10 +58,46 @@ use crate::{
 pub struct WebcamInputEditor {
     device_list: RefCell<HashMap<String, CachedDeviceList>>,
     device_selected: RefCell<Option<String>>,
+    format_selected: RefCell<Option<DeviceFormat>>,
     resolution_selected: RefCell<Option<Resolution>>,
     fps_selected: RefCell<Option<i32>>,
+    scale_selected: RefCell<Option<f64>>,
+    device_monitor: RefCell<Option<DeviceMonitor>>,
+    // the camera the input processer was last started with
+    running_device: RefCell<Option<String>>,
+    // the selected camera while it is unplugged, and whether it was running
+    lost_device: RefCell<Option<(String, bool)>>,
+    // false while starting the camera from the launch options, that one isn't saved
+    remember_device: Cell<bool>,
 }
 
+// fractions of the camera resolution offered for processing, smaller is faster but less accurate
+const PROCESSING_SCALES: [f64; 5] = [1.0, 0.75, 0.5, 0.375, 0.25];
+
+const CAMERA_CONTROLS: &str = "Camera Controls";
+
+const IMAGE_ADJUSTMENTS: &str = "Image Adjustments";
+const MIRROR: &str = "Mirror";
+const FLIP_VERTICAL: &str = "Flip Upside Down";
+const ROTATION: &str = "Rotation";
+const CROP_LEFT: &str = "Crop Left %";
+const CROP_TOP: &str = "Crop Top %";
+const CROP_WIDTH: &str = "Crop Width %";
+const CROP_HEIGHT: &str = "Crop Height %";
+const GAMMA: &str = "Gamma";
+const LOW_LIGHT: &str = "Low Light Contrast";
+const GRAYSCALE: &str = "Grayscale";
+
+const PREVIEW_OVERLAYS: &str = "Preview Overlays";
+const SHOW_CAMERA_IMAGE: &str = "Camera Image";
+const SHOW_FACE_BOX: &str = "Face Box";
+const SHOW_LANDMARKS: &str = "Landmarks";
+const SHOW_LANDMARK_INDICES: &str = "Landmark Numbers";
+const SHOW_HEAD_POSE: &str = "Head Pose Axes";
+const SHOW_METRICS: &str = "Eye/Mouth Metrics";
+
+const PRIMARY_FACE_POLICIES: [&str; 3] = ["Largest", "Closest To Center", "Lock Current Face"];
+
 #[methods]
 impl WebcamInputEditor {
     // register the signals to viewport we will need
@@ -74,14 +128,102 @@ impl WebcamInputEditor {
                     export_info: ExportInfo::new(VariantType::I64),
                     usage: PropertyUsage::DEFAULT,
                 },
+                // processing scale, anything <= 0 means automatic
+                SignalArgument {
+                    name: "processing_scale",
+                    default: Variant::from_f64(-1.0),
+                    export_info: ExportInfo::new(VariantType::F64),
+                    usage: PropertyUsage::DEFAULT,
+                },
+                // capture format
+                SignalArgument {
+                    name: "format",
+                    default: Variant::from_str("MJPG"),
+                    export_info: ExportInfo::new(VariantType::GodotString),
+                    usage: PropertyUsage::DEFAULT,
+                },
             ],
         });
 
+        builder.add_signal(Signal {
+            name: "primary_face_changed",
+            args: &[SignalArgument {
+                name: "policy",
+                default: Variant::from_str("Largest"),
+                export_info: ExportInfo::new(VariantType::GodotString),
+                usage: PropertyUsage::DEFAULT,
+            }],
+        });
+
         // kill input processer sigbal
         builder.add_signal(Signal {
             name: "kill_input_process",
             args: &[],
         });
+
+        // a camera got plugged in or out
+        for signal in &["device_added", "device_removed"] {
+            builder.add_signal(Signal {
+                name: *signal,
+                args: &[SignalArgument {
+                    name: "device_name",
+                    default: Variant::from_str(""),
+                    export_info: ExportInfo::new(VariantType::GodotString),
+                    usage: PropertyUsage::DEFAULT,
+                }],
+            });
+        }
+
+        builder.add_signal(Signal {
+            name: "camera_control_changed",
+            args: &[
+                SignalArgument {
+                    name: "control",
+                    default: Variant::from_str(""),
+                    export_info: ExportInfo::new(VariantType::GodotString),
+                    usage: PropertyUsage::DEFAULT,
+                },
+                SignalArgument {
+                    name: "value",
+                    default: Variant::from_i64(0),
+                    export_info: ExportInfo::new(VariantType::I64),
+                    usage: PropertyUsage::DEFAULT,
+                },
+            ],
+        });
+
+        // the image adjustments for the selected camera, see `preprocess_to_dictionary`
+        builder.add_signal(Signal {
+            name: "preprocess_changed",
+            args: &[SignalArgument {
+                name: "settings",
+                default: Variant::from_dictionary(&Dictionary::new_shared()),
+                export_info: ExportInfo::new(VariantType::Dictionary),
+                usage: PropertyUsage::DEFAULT,
+            }],
+        });
+
+        // which layers the camera preview should draw, see `PreviewLayers::to_dictionary`
+        builder.add_signal(Signal {
+            name: "preview_layers_changed",
+            args: &[SignalArgument {
+                name: "layers",
+                default: Variant::from_dictionary(&PreviewLayers::default().to_dictionary()),
+                export_info: ExportInfo::new(VariantType::Dictionary),
+                usage: PropertyUsage::DEFAULT,
+            }],
+        });
+
+        // the camera the input processer is running on got unplugged
+        builder.add_signal(Signal {
+            name: "input_device_removed",
+            args: &[SignalArgument {
+                name: "device_name",
+                default: Variant::from_str(""),
+                export_info: ExportInfo::new(VariantType::GodotString),
+                usage: PropertyUsage::DEFAULT,
+            }],
+        });
     }
 
     fn new(_owner: &Tree) -> Self {
@@ -89,8 +231,14 @@ impl WebcamInputEditor {
         WebcamInputEditor {
             device_list: dev_list,
             device_selected: RefCell::new(None),
+            format_selected: RefCell::new(None),
             resolution_selected: RefCell::new(None),
             fps_selected: RefCell::new(None),
+            scale_selected: RefCell::new(None),
+            device_monitor: RefCell::new(None),
+            running_device: RefCell::new(None),
+            lost_device: RefCell::new(None),
+            remember_device: Cell::new(true),
         }
     }
 
@@ -135,6 +283,25 @@ impl WebcamInputEditor {
             panic!("Failed to initialise UI!");
         }
 
+        let format_popup = unsafe {
+            owner
+                .get_node("../FormatPopup")
+                .unwrap()
+                .assume_safe()
+                .cast::<PopupMenu>()
+                .unwrap()
+        };
+        format_popup.set_visible(false);
+        if let Err(_why) = format_popup.connect(
+            "id_pressed",
+            owner,
+            "on_format_popup_menu_clicked",
+            VariantArray::new_shared(),
+            0,
+        ) {
+            panic!("Failed to initialise UI!");
+        }
+
         let resolution_popup = unsafe {
             owner
                 .get_node("../ResolutionPopup")
@@ -154,6 +321,44 @@ impl WebcamInputEditor {
             panic!("Failed to initialise UI!");
         }
 
+        let scale_popup = unsafe {
+            owner
+                .get_node("../ScalePopup")
+                .unwrap()
+                .assume_safe()
+                .cast::<PopupMenu>()
+                .unwrap()
+        };
+        scale_popup.set_visible(false);
+        if let Err(_why) = scale_popup.connect(
+            "id_pressed",
+            owner,
+            "on_scale_popup_menu_clicked",
+            VariantArray::new_shared(),
+            0,
+        ) {
+            panic!("Failed to initialise UI!");
+        }
+
+        let primary_face_popup = unsafe {
+            owner
+                .get_node("../PrimaryFacePopup")
+                .unwrap()
+                .assume_safe()
+                .cast::<PopupMenu>()
+                .unwrap()
+        };
+        primary_face_popup.set_visible(false);
+        if let Err(_why) = primary_face_popup.connect(
+            "id_pressed",
+            owner,
+            "on_primary_face_popup_menu_clicked",
+            VariantArray::new_shared(),
+            0,
+        ) {
+            panic!("Failed to initialise UI!");
+        }
+
         let root_item: &TreeItem = unsafe {
             &*owner
                 .create_item(owner.assume_shared(), 0)
@@ -202,9 +407,90 @@ impl WebcamInputEditor {
         webcam_video_input.set_disable_folding(false);
 
         create_custom_editable_item(owner, camera_settings_item, "Input Webcam:", 2);
+        create_custom_editable_item(owner, camera_settings_item, "Webcam Format:", 3);
         create_custom_editable_item(owner, camera_settings_item, "Webcam Resolution:", 4);
         create_custom_editable_item(owner, camera_settings_item, "Webcam Frame Rate:", 5);
 
+        // filled in by `on_camera_controls` once a camera is running
+        let camera_controls_item: &TreeItem = unsafe {
+            &*owner
+                .create_item(camera_settings_item.assume_shared(), 12)
+                .unwrap()
+                .assume_safe()
+        };
+        camera_controls_item.set_text(0, CAMERA_CONTROLS);
+        camera_controls_item.set_text(1, "Camera not running");
+        camera_controls_item.set_selectable(1, false);
+        camera_controls_item.set_collapsed(true);
+
+        let image_adjustments_item: &TreeItem = unsafe {
+            &*owner
+                .create_item(camera_settings_item.assume_shared(), 13)
+                .unwrap()
+                .assume_safe()
+        };
+        image_adjustments_item.set_text(0, IMAGE_ADJUSTMENTS);
+        image_adjustments_item.set_selectable(1, false);
+        image_adjustments_item.set_collapsed(true);
+        for (field, min, max, step) in &[
+            (ROTATION, 0.0, 270.0, 90.0),
+            (CROP_LEFT, 0.0, 99.0, 1.0),
+            (CROP_TOP, 0.0, 99.0, 1.0),
+            (CROP_WIDTH, 1.0, 100.0, 1.0),
+            (CROP_HEIGHT, 1.0, 100.0, 1.0),
+            (GAMMA, 0.2, 3.0, 0.1),
+        ] {
+            let item = unsafe {
+                &*owner
+                    .create_item(image_adjustments_item.assume_shared(), -1)
+                    .unwrap()
+                    .assume_safe()
+            };
+            create_editable_range(item, field, *min, *max, *step);
+        }
+        for field in &[MIRROR, FLIP_VERTICAL, LOW_LIGHT, GRAYSCALE] {
+            let item = unsafe {
+                &*owner
+                    .create_item(image_adjustments_item.assume_shared(), -1)
+                    .unwrap()
+                    .assume_safe()
+            };
+            item.set_text(0, *field);
+            item.set_cell_mode(1, TreeItem::CELL_MODE_CHECK);
+            item.set_editable(1, true);
+        }
+        show_preprocess(owner, &PreprocessConfig::default());
+
+        let preview_overlays_item: &TreeItem = unsafe {
+            &*owner
+                .create_item(camera_settings_item.assume_shared(), 14)
+                .unwrap()
+                .assume_safe()
+        };
+        preview_overlays_item.set_text(0, PREVIEW_OVERLAYS);
+        preview_overlays_item.set_selectable(1, false);
+        preview_overlays_item.set_collapsed(true);
+        let layers = PreviewLayers::default();
+        for (field, checked) in &[
+            (SHOW_CAMERA_IMAGE, layers.camera_image),
+            (SHOW_FACE_BOX, layers.face_box),
+            (SHOW_LANDMARKS, layers.landmarks),
+            (SHOW_LANDMARK_INDICES, layers.landmark_indices),
+            (SHOW_HEAD_POSE, layers.head_pose),
+            (SHOW_METRICS, layers.metrics),
+        ] {
+            let item = unsafe {
+                &*owner
+                    .create_item(preview_overlays_item.assume_shared(), -1)
+                    .unwrap()
+                    .assume_safe()
+            };
+            item.set_text(0, *field);
+            item.set_cell_mode(1, TreeItem::CELL_MODE_CHECK);
+            item.set_editable(1, true);
+            item.set_checked(1, *checked);
+        }
+
         // 2: Where did 3 go?
         // 5: 4 8 3.
         // 4: you're next 2
@@ -213,6 +499,8 @@ impl WebcamInputEditor {
 
         create_custom_editable_item(owner, face_detection_settings, "Detector Hardware:", 8); // CPU, GPGPU(CUDA/ROCm)
         create_custom_editable_item(owner, face_detection_settings, "Detector Type:", 9); // DLIB_FHOG, DLIB_CNN, <insert other face reconizer here>
+        create_custom_editable_item(owner, face_detection_settings, "Processing Resolution:", 10);
+        create_custom_editable_item(owner, face_detection_settings, "Primary Face:", 11);
 
         if let Err(_why) = owner.connect(
             "custom_popup_edited",
@@ -224,6 +512,16 @@ impl WebcamInputEditor {
             panic!("Could not initialise UI!");
         }
 
+        if let Err(_why) = owner.connect(
+            "item_edited",
+            owner,
+            "on_item_edited",
+            VariantArray::new_shared(),
+            0,
+        ) {
+            panic!("Could not initialise UI!");
+        }
+
         let button = unsafe {
             owner
                 .get_node("../StartButton")
@@ -242,6 +540,167 @@ impl WebcamInputEditor {
             panic!("Failed to initialise UI");
         }
         button.set_disabled(true);
+
+        match DeviceMonitor::new(self.device_list.borrow().clone()) {
+            Ok(monitor) => *self.device_monitor.borrow_mut() = Some(monitor),
+            Err(why) => {
+                log_warn!(Camera, "Not watching for cameras being plugged in: {}", why);
+            }
+        }
+
+        // wait until everyone listening to our signals is ready
+        unsafe {
+            owner.call_deferred("start_saved_device", &[]);
+        }
+    }
+
+    // picks the camera from last time back up, if it is plugged in. A camera from the launch
+    // options goes first.
+    #[export]
+    pub fn start_saved_device(&self, owner: TRef<Tree>) {
+        let user_cfg = UserConfig::from_cfg().unwrap_or_else(|_| UserConfig::from_default());
+        let saved = user_cfg
+            .processing()
+            .default_device()
+            .map(|(device, stream)| (device.clone(), stream));
+
+        let camera_override = Overrides::take_camera();
+        let remember = camera_override.is_none();
+        let (name, device, stream) = match camera_override {
+            Some(wanted) => {
+                let (name, device) = match self.find_camera(&wanted) {
+                    Some(found) => found,
+                    None => {
+                        show_error!(
+                            "Camera not found",
+                            format!("There is no camera called {}.", wanted)
+                        );
+                        return;
+                    }
+                };
+                // the saved stream if it is for this camera, otherwise whatever it does best
+                let stream = saved
+                    .filter(|(saved, _)| saved.matches(&device.get_identity()))
+                    .map(|(_, stream)| stream)
+                    .or_else(|| preferred_stream(&device));
+                match stream {
+                    Some(stream) => (name, device, stream),
+                    None => return,
+                }
+            }
+            None => {
+                let (saved, stream) = match saved {
+                    Some(saved) => saved,
+                    None => return,
+                };
+                let found = {
+                    let device_list = self.device_list.borrow();
+                    device_list
+                        .iter()
+                        .find(|(_, device)| saved.matches(&device.get_identity()))
+                        // the bus info changes with the port, the name doesn't
+                        .or_else(|| {
+                            device_list.iter().find(|(_, device)| {
+                                saved.name.is_some() && device.get_identity().name == saved.name
+                            })
+                        })
+                        .map(|(name, device)| (name.clone(), device.clone()))
+                };
+                match found {
+                    Some((name, device)) => (name, device, stream),
+                    None => {
+                        log_info!(Camera, "The camera from last time is not plugged in.");
+                        return;
+                    }
+                }
+            }
+        };
+        let supported = device
+            .get_supported(stream.fmt)
+            .get(&stream.res)
+            .map_or(false, |framerates| framerates.contains(&stream.fps));
+        if !supported {
+            return;
+        }
+
+        *self.device_selected.borrow_mut() = Some(name.clone());
+        *self.format_selected.borrow_mut() = Some(stream.fmt);
+        *self.resolution_selected.borrow_mut() = Some(stream.res);
+        *self.fps_selected.borrow_mut() = Some(stream.fps as i32);
+        set_field_text(owner, "Input Webcam:", &name);
+        set_field_text(owner, "Webcam Format:", &stream.fmt.to_string());
+        set_field_text(owner, "Webcam Resolution:", &stream.res.to_string());
+        set_field_text(owner, "Webcam Frame Rate:", &stream.fps.to_string());
+        self.check_button_eligibility(owner);
+        self.remember_device.set(remember);
+        self.on_start_button_pressed(owner);
+        self.remember_device.set(true);
+    }
+
+    // by name, or by its number in `open2dholo-tracker --list`
+    fn find_camera(&self, wanted: &str) -> Option<(String, CachedDeviceList)> {
+        let device_list = self.device_list.borrow();
+        let mut devices: Vec<(&String, &CachedDeviceList)> = device_list.iter().collect();
+        devices.sort_by_key(|(_, device)| device.get_name());
+        let found = match wanted.parse::<usize>() {
+            Ok(idx) => devices.get(idx),
+            Err(_) => devices
+                .iter()
+                .find(|(name, device)| name.as_str() == wanted || device.get_name() == wanted),
+        };
+        found.map(|(name, device)| ((*name).clone(), (*device).clone()))
+    }
+
+    // keep the device list in sync with what is actually plugged in
+    #[export]
+    pub fn _process(&self, owner: TRef<Tree>, _delta: f32) {
+        let events = match &*self.device_monitor.borrow() {
+            Some(monitor) => monitor.query_events(),
+            None => return,
+        };
+        for event in events {
+            match event {
+                DeviceEvent::Added { name, device } => {
+                    self.device_list.borrow_mut().insert(name.clone(), device);
+                    owner.emit_signal("device_added", &[Variant::from_str(&name)]);
+                    self.on_device_returned(owner, name);
+                }
+                DeviceEvent::Removed { name, .. } => {
+                    self.device_list.borrow_mut().remove(&name);
+                    owner.emit_signal("device_removed", &[Variant::from_str(&name)]);
+                    self.on_device_lost(owner, name);
+                }
+            }
+        }
+    }
+
+    fn on_device_lost(&self, owner: TRef<Tree>, name: String) {
+        if self.device_selected.borrow().as_deref() != Some(name.as_str()) {
+            return;
+        }
+        let was_running = self.running_device.borrow().as_deref() == Some(name.as_str());
+        set_field_text(owner, "Input Webcam:", &format!("{} (disconnected)", name));
+        self.check_button_eligibility(owner);
+        if was_running {
+            *self.running_device.borrow_mut() = None;
+            owner.emit_signal("input_device_removed", &[Variant::from_str(&name)]);
+        }
+        // keep the rest of the settings, the same camera is likely to come back
+        *self.lost_device.borrow_mut() = Some((name, was_running));
+    }
+
+    fn on_device_returned(&self, owner: TRef<Tree>, name: String) {
+        let was_running = match &*self.lost_device.borrow() {
+            Some((lost, was_running)) if *lost == name => *was_running,
+            _ => return,
+        };
+        *self.lost_device.borrow_mut() = None;
+        set_field_text(owner, "Input Webcam:", &name);
+        self.check_button_eligibility(owner);
+        if was_running {
+            // it may have come back under a different /dev/video*, this picks up the new one
+            self.on_start_button_pressed(owner);
+        }
     }
 
     #[export]
@@ -287,6 +746,39 @@ impl WebcamInputEditor {
                         camera_popup.set_visible(true);
                     }
                 }
+                "Webcam Format:" => match &*self.device_selected.borrow() {
+                    Some(camera) => {
+                        let format_popup = unsafe {
+                            owner
+                                .get_node("../FormatPopup")
+                                .unwrap()
+                                .assume_safe()
+                                .cast::<PopupMenu>()
+                                .unwrap()
+                        };
+                        format_popup.clear();
+                        if format_popup.is_visible() {
+                            format_popup.set_visible(false);
+                        } else {
+                            let rect = owner.get_custom_popup_rect();
+                            let size = rect.size.to_vector();
+                            let position = rect.origin.to_vector();
+
+                            if let Some(device) = self.device_list.borrow().get(camera) {
+                                for (id_cnt, fmt) in device.get_formats().into_iter().enumerate() {
+                                    format_popup.add_item(format!("{}", fmt), id_cnt as i64, -1);
+                                }
+                            }
+
+                            format_popup.set_size(size, true);
+                            format_popup.set_position(position, true);
+                            format_popup.set_visible(true);
+                        }
+                    }
+                    None => {
+                        log_debug!(Ui, "No camera selected");
+                    }
+                },
                 "Webcam Resolution:" => match &*self.device_selected.borrow() {
                     Some(camera) => {
                         let resolution_popup = unsafe {
@@ -307,10 +799,19 @@ impl WebcamInputEditor {
 
                             let selected_cache_dev = match self.device_list.borrow().get(camera) {
                                 Some(dev) => dev.clone(),
-                                None => panic!("The device no longer exists!"),
+                                None => {
+                                    show_error!(
+                                        "Camera unavailable",
+                                        format!("{} is not connected anymore.", camera)
+                                    );
+                                    return;
+                                }
                             };
                             let mut res_vec_sorted: Vec<Resolution> = Vec::new();
-                            for res in selected_cache_dev.get_supported_mjpg().keys() {
+                            for res in selected_cache_dev
+                                .get_supported(self.selected_format())
+                                .keys()
+                            {
                                 res_vec_sorted.push(*res)
                             }
                             res_vec_sorted.sort();
@@ -326,7 +827,7 @@ impl WebcamInputEditor {
                         }
                     }
                     None => {
-                        godot_print!("No Camera!");
+                        log_debug!(Ui, "No camera selected");
                     }
                 },
                 "Webcam Frame Rate:" => match self.device_selected.borrow().as_deref() {
@@ -351,7 +852,7 @@ impl WebcamInputEditor {
                                 if let Some(device) = self.device_list.borrow().get(device_name) {
                                     if let Some(res) = *self.resolution_selected.borrow() {
                                         if let Some(framerate_list) =
-                                            device.get_supported_mjpg().get(&res)
+                                            device.get_supported(self.selected_format()).get(&res)
                                         {
                                             for (id_cnt, fps) in framerate_list.iter().enumerate() {
                                                 fps_popup.add_item(
@@ -371,14 +872,313 @@ impl WebcamInputEditor {
                         }
                     }
                     None => {
-                        godot_print!("No Camera!");
+                        log_debug!(Ui, "No camera selected");
                     }
                 },
+                "Processing Resolution:" => {
+                    let scale_popup = unsafe {
+                        owner
+                            .get_node("../ScalePopup")
+                            .unwrap()
+                            .assume_safe()
+                            .cast::<PopupMenu>()
+                            .unwrap()
+                    };
+                    scale_popup.clear();
+                    if scale_popup.is_visible() {
+                        scale_popup.set_visible(false);
+                    } else {
+                        let rect = owner.get_custom_popup_rect();
+                        let size = rect.size.to_vector();
+                        let position = rect.origin.to_vector();
+
+                        scale_popup.add_item(
+                            format!("Automatic (<= {}p)", DEFAULT_WORKING_HEIGHT),
+                            0,
+                            -1,
+                        );
+                        for (id_cnt, scale) in PROCESSING_SCALES.iter().enumerate() {
+                            scale_popup.add_item(
+                                scale_label(*self.resolution_selected.borrow(), *scale),
+                                id_cnt as i64 + 1,
+                                -1,
+                            );
+                        }
+
+                        scale_popup.set_size(size, true);
+                        scale_popup.set_position(position, true);
+                        scale_popup.set_visible(true);
+                    }
+                }
+                "Primary Face:" => {
+                    let primary_face_popup = unsafe {
+                        owner
+                            .get_node("../PrimaryFacePopup")
+                            .unwrap()
+                            .assume_safe()
+                            .cast::<PopupMenu>()
+                            .unwrap()
+                    };
+                    primary_face_popup.clear();
+                    if primary_face_popup.is_visible() {
+                        primary_face_popup.set_visible(false);
+                    } else {
+                        let rect = owner.get_custom_popup_rect();
+                        let size = rect.size.to_vector();
+                        let position = rect.origin.to_vector();
+
+                        for (id_cnt, policy) in PRIMARY_FACE_POLICIES.iter().enumerate() {
+                            primary_face_popup.add_item(*policy, id_cnt as i64, -1);
+                        }
+
+                        primary_face_popup.set_size(size, true);
+                        primary_face_popup.set_position(position, true);
+                        primary_face_popup.set_visible(true);
+                    }
+                }
                 _ => (),
             }
         }
     }
 
+    // the settings file was changed outside, show what it says for the selected camera now
+    #[export]
+    pub fn on_config_reloaded(&self, owner: TRef<Tree>) {
+        let identity = self.device_selected.borrow().as_ref().and_then(|name| {
+            self.device_list
+                .borrow()
+                .get(name)
+                .map(|dev| dev.get_identity())
+        });
+        if let Some(identity) = identity {
+            let user_cfg = UserConfig::from_cfg().unwrap_or_else(|_| UserConfig::from_default());
+            let preprocess = user_cfg.processing().preprocess_for(&identity);
+            show_preprocess(owner, &preprocess);
+            owner.emit_signal(
+                "preprocess_changed",
+                &[Variant::from_dictionary(&preprocess_to_dictionary(
+                    &preprocess,
+                ))],
+            );
+        }
+    }
+
+    // switching profiles changed the saved camera, the rest of the editor follows the settings
+    #[export]
+    pub fn on_profile_changed(&self, owner: TRef<Tree>, switched: bool) {
+        if switched {
+            self.start_saved_device(owner);
+        }
+        self.on_config_reloaded(owner);
+    }
+
+    // rebuilds the camera controls section from what the running camera supports
+    #[export]
+    pub fn on_camera_controls(&self, owner: TRef<Tree>, controls: VariantArray) {
+        with_field(owner, CAMERA_CONTROLS, |parent| {
+            while let Some(child) = parent.get_children() {
+                unsafe {
+                    parent.remove_child(child.clone());
+                    child.assume_unique().free();
+                }
+            }
+            if controls.is_empty() {
+                parent.set_text(1, "Not supported by this camera");
+                return;
+            }
+            parent.set_text(1, "");
+
+            for control in controls.iter() {
+                let control = control.to_dictionary();
+                let item = unsafe {
+                    &*owner
+                        .create_item(parent.assume_shared(), -1)
+                        .unwrap()
+                        .assume_safe()
+                };
+                let name = control.get("name").to_string();
+                let value = control.get("value").to_i64();
+                match control.get("kind").to_string().as_str() {
+                    "toggle" => {
+                        item.set_text(0, name);
+                        item.set_cell_mode(1, TreeItem::CELL_MODE_CHECK);
+                        item.set_editable(1, true);
+                        item.set_checked(1, value != 0);
+                    }
+                    "menu" => {
+                        // a range cell with comma separated text shows up as a dropdown
+                        let labels = control.get("labels").to_string_array();
+                        let mut joined = Vec::with_capacity(labels.len() as usize);
+                        for idx in 0..labels.len() {
+                            joined.push(labels.get(idx).to_string());
+                        }
+                        create_editable_range(
+                            item,
+                            &name,
+                            0.0,
+                            (labels.len() - 1).max(0) as f64,
+                            1.0,
+                        );
+                        item.set_text(1, joined.join(","));
+                        item.set_range(1, value as f64);
+                    }
+                    _ => {
+                        create_editable_range(
+                            item,
+                            &name,
+                            control.get("min").to_i64() as f64,
+                            control.get("max").to_i64() as f64,
+                            control.get("step").to_i64() as f64,
+                        );
+                        item.set_range(1, value as f64);
+                    }
+                }
+            }
+        });
+
+        // the manual controls do nothing while their auto counterpart is on
+        for control in controls.iter() {
+            let control = control.to_dictionary();
+            if control.contains("controls") {
+                set_field_editable(
+                    owner,
+                    &control.get("controls").to_string(),
+                    control.get("value").to_i64() == 0,
+                );
+            }
+        }
+    }
+
+    #[export]
+    pub fn on_item_edited(&self, owner: TRef<Tree>) {
+        let edited = match owner.get_edited() {
+            Some(item) => unsafe { item.assume_safe() },
+            None => return,
+        };
+        let section = edited
+            .get_parent()
+            .map(|parent| unsafe { parent.assume_safe() }.get_text(0).to_string());
+        match section.as_deref() {
+            Some(IMAGE_ADJUSTMENTS) => {
+                self.on_preprocess_edited(owner);
+                return;
+            }
+            Some(PREVIEW_OVERLAYS) => {
+                owner.emit_signal(
+                    "preview_layers_changed",
+                    &[Variant::from_dictionary(
+                        &read_preview_layers(owner).to_dictionary(),
+                    )],
+                );
+                return;
+            }
+            _ => {}
+        }
+        let control = match CameraControl::from_name(&edited.get_text(0).to_string()) {
+            Some(c) => c,
+            None => return,
+        };
+        let value = if edited.get_cell_mode(1) == TreeCellMode::CHECK {
+            i64::from(edited.is_checked(1))
+        } else {
+            edited.get_range(1).round() as i64
+        };
+        if let Some(manual) = control.manual_counterpart() {
+            set_field_editable(owner, manual.name(), value == 0);
+        }
+        owner.emit_signal(
+            "camera_control_changed",
+            &[Variant::from_str(control.name()), Variant::from_i64(value)],
+        );
+    }
+
+    fn on_preprocess_edited(&self, owner: TRef<Tree>) {
+        let preprocess = read_preprocess(owner);
+        // remember it for this camera, the next one may well be mounted the right way up
+        let identity = self.device_selected.borrow().as_ref().and_then(|name| {
+            self.device_list
+                .borrow()
+                .get(name)
+                .map(|dev| dev.get_identity())
+        });
+        if let Some(identity) = identity {
+            match UserConfig::from_cfg() {
+                Ok(mut user_cfg) => {
+                    user_cfg
+                        .processing_mut()
+                        .set_preprocess(identity, preprocess);
+                    if let Err(why) = user_cfg.write_current() {
+                        log_error!(Ui, "Could not save the image adjustments: {}", why);
+                    }
+                }
+                Err(why) => {
+                    log_error!(Ui, "Not saving the image adjustments: {}", why);
+                }
+            }
+        }
+        owner.emit_signal(
+            "preprocess_changed",
+            &[Variant::from_dictionary(&preprocess_to_dictionary(
+                &preprocess,
+            ))],
+        );
+    }
+
+    #[export]
+    pub fn on_primary_face_popup_menu_clicked(&self, owner: TRef<Tree>, id: i32) {
+        let primary_face_popup = unsafe {
+            owner
+                .get_node("../PrimaryFacePopup")
+                .unwrap()
+                .assume_safe()
+                .cast::<PopupMenu>()
+                .unwrap()
+        };
+        let clicked_item = unsafe {
+            owner
+                .assume_shared()
+                .assume_safe()
+                .get_edited()
+                .unwrap()
+                .assume_safe()
+        };
+        let clicked_popup = primary_face_popup
+            .get_item_text(primary_face_popup.get_item_index(i64::from(id)))
+            .to_string();
+        clicked_item.set_text(1, clicked_popup.clone());
+        owner.emit_signal("primary_face_changed", &[Variant::from_str(clicked_popup)]);
+    }
+
+    #[export]
+    pub fn on_scale_popup_menu_clicked(&self, owner: TRef<Tree>, id: i32) {
+        let scale_popup = unsafe {
+            owner
+                .get_node("../ScalePopup")
+                .unwrap()
+                .assume_safe()
+                .cast::<PopupMenu>()
+                .unwrap()
+        };
+        let clicked_item = unsafe {
+            owner
+                .assume_shared()
+                .assume_safe()
+                .get_edited()
+                .unwrap()
+                .assume_safe()
+        };
+        let clicked_popup = scale_popup
+            .get_item_text(scale_popup.get_item_index(i64::from(id)))
+            .to_string();
+        // id 0 is automatic
+        *self.scale_selected.borrow_mut() = if id > 0 {
+            PROCESSING_SCALES.get(id as usize - 1).copied()
+        } else {
+            None
+        };
+        clicked_item.set_text(1, clicked_popup);
+    }
+
     #[export]
     pub fn on_camera_popup_menu_clicked(&self, owner: TRef<Tree>, id: i32) {
         self.clear_other_fields(owner, "camera");
@@ -403,10 +1203,53 @@ impl WebcamInputEditor {
             .to_string();
         // set selected device
         clicked_item.set_text(1, clicked_popup.clone());
+        // show what this camera was adjusted to last time, it gets applied on start
+        if let Some(dev) = self.device_list.borrow().get(&clicked_popup) {
+            let user_cfg = UserConfig::from_cfg().unwrap_or_else(|_| UserConfig::from_default());
+            show_preprocess(
+                owner,
+                &user_cfg.processing().preprocess_for(&dev.get_identity()),
+            );
+        }
         *self.device_selected.borrow_mut() = Some(clicked_popup);
         self.check_button_eligibility(owner);
     }
 
+    #[export]
+    pub fn on_format_popup_menu_clicked(&self, owner: TRef<Tree>, id: i32) {
+        self.clear_other_fields(owner, "fmt");
+
+        let format_popup = unsafe {
+            owner
+                .get_node("../FormatPopup")
+                .unwrap()
+                .assume_safe()
+                .cast::<PopupMenu>()
+                .unwrap()
+        };
+        let clicked_item = unsafe {
+            owner
+                .assume_shared()
+                .assume_safe()
+                .get_edited()
+                .unwrap()
+                .assume_safe()
+        };
+        let clicked_popup = format_popup
+            .get_item_text(format_popup.get_item_index(i64::from(id)))
+            .to_string();
+        let fmt = match clicked_popup.parse::<DeviceFormat>() {
+            Ok(f) => f,
+            Err(why) => {
+                log_warn!(Ui, "Not a camera format: {}", why);
+                return;
+            }
+        };
+        *self.format_selected.borrow_mut() = Some(fmt);
+        clicked_item.set_text(1, clicked_popup);
+        self.check_button_eligibility(owner);
+    }
+
     #[export]
     pub fn on_resolution_popup_menu_clicked(&self, owner: TRef<Tree>, id: i32) {
         self.clear_other_fields(owner, "res");
@@ -499,8 +1342,43 @@ impl WebcamInputEditor {
             Some(n) => n.clone(),
             None => return,
         };
+        let fmt = self.selected_format();
+        *self.running_device.borrow_mut() = Some(name.clone());
+        *self.lost_device.borrow_mut() = None;
+
+        // remember it for next launch
+        let (mut user_cfg, readable) = match UserConfig::from_cfg() {
+            Ok(user_cfg) => (user_cfg, true),
+            Err(why) => {
+                log_error!(Ui, "Not remembering the camera: {}", why);
+                (UserConfig::from_default(), false)
+            }
+        };
+        if readable && self.remember_device.get() {
+            user_cfg.processing_mut().set_default_device(
+                dev.get_identity(),
+                StreamSettings {
+                    res,
+                    fps: framerate,
+                    fmt,
+                },
+            );
+            if let Err(why) = user_cfg.write_current() {
+                log_error!(Ui, "Could not save the camera settings: {}", why);
+            }
+        }
 
-        let possible = PossibleDevice::from_cached_device(dev, res, framerate, DeviceFormat::MJpeg);
+        // this has to reach the viewport before the processer it is going to be handed to
+        let preprocess = user_cfg.processing().preprocess_for(&dev.get_identity());
+        show_preprocess(owner, &preprocess);
+        owner.emit_signal(
+            "preprocess_changed",
+            &[Variant::from_dictionary(&preprocess_to_dictionary(
+                &preprocess,
+            ))],
+        );
+
+        let possible = PossibleDevice::from_cached_device(dev, res, framerate, fmt);
 
         let resolution = Vector2::new(res.x as f32, res.y as f32);
 
@@ -515,26 +1393,36 @@ impl WebcamInputEditor {
                 Variant::from_str(name),
                 Variant::from_vector2(&resolution),
                 Variant::from_i64(i64::from(framerate)),
+                Variant::from_f64(self.scale_selected.borrow().unwrap_or(-1.0)),
+                Variant::from_str(fmt.to_string()),
             ],
         );
     }
 
     #[export]
     pub fn check_button_eligibility(&self, owner: TRef<Tree>) {
-        if self.device_selected.borrow_mut().is_some()
-            && self.resolution_selected.borrow_mut().is_some()
-            && self.fps_selected.borrow_mut().is_some()
-        {
-            let button = unsafe {
-                owner
-                    .get_node("../StartButton")
-                    .unwrap()
-                    .assume_safe()
-                    .cast::<Button>()
-                    .unwrap()
-            };
-            button.set_disabled(false);
-        }
+        let device_present = match &*self.device_selected.borrow() {
+            Some(device) => self.device_list.borrow().contains_key(device),
+            None => false,
+        };
+        let button = unsafe {
+            owner
+                .get_node("../StartButton")
+                .unwrap()
+                .assume_safe()
+                .cast::<Button>()
+                .unwrap()
+        };
+        button.set_disabled(
+            !(device_present
+                && self.resolution_selected.borrow().is_some()
+                && self.fps_selected.borrow().is_some()),
+        );
+    }
+
+    // MJPEG until the user picks something else, every camera we support can do it
+    fn selected_format(&self) -> DeviceFormat {
+        self.format_selected.borrow().unwrap_or(DeviceFormat::MJpeg)
     }
 
     // updates the device list to look for new devices, etc
@@ -556,6 +1444,7 @@ impl WebcamInputEditor {
             "camera" => {
                 self.update_device_list();
                 *self.device_selected.borrow_mut() = None;
+                *self.format_selected.borrow_mut() = None;
                 *self.resolution_selected.borrow_mut() = None;
                 *self.fps_selected.borrow_mut() = None;
                 let mut child = unsafe {
@@ -585,6 +1474,37 @@ impl WebcamInputEditor {
                     }
                 }
             }
+            "fmt" => {
+                self.update_device_list();
+                *self.resolution_selected.borrow_mut() = None;
+                *self.fps_selected.borrow_mut() = None;
+                let mut child = unsafe {
+                    owner
+                        .get_root()
+                        .unwrap()
+                        .assume_safe()
+                        .get_children()
+                        .unwrap()
+                        .assume_safe()
+                };
+                let mut clearable = false;
+                loop {
+                    // see if the child is a custom tree item
+                    if child.get_text(0).to_string() != *"Webcam Format:"
+                        && child.get_cell_mode(1) == TreeCellMode::CUSTOM
+                        && clearable
+                    {
+                        child.set_text(1, "");
+                    } else if child.get_text(0).to_string() == *"Webcam Format:" {
+                        clearable = true;
+                    }
+                    if let Some(a) = child.get_next() {
+                        child = unsafe { a.assume_safe() };
+                    } else {
+                        break;
+                    }
+                }
+            }
             "res" => {
                 self.update_device_list();
                 *self.fps_selected.borrow_mut() = None;
@@ -619,3 +1539,131 @@ impl WebcamInputEditor {
         }
     }
 }
+
+// "960x540 (50%)", or just the percentage if no camera resolution was picked yet
+fn scale_label(res: Option<Resolution>, scale: f64) -> String {
+    let percent = (scale * 100_f64).round() as u32;
+    let hint = if (scale - 1_f64).abs() < f64::EPSILON {
+        ", slowest"
+    } else if (scale - PROCESSING_SCALES[PROCESSING_SCALES.len() - 1]).abs() < f64::EPSILON {
+        ", fastest"
+    } else {
+        ""
+    };
+    match res {
+        Some(r) => format!("{} ({}%{})", scale_resolution(r, scale), percent, hint),
+        None => format!("{}%{}", percent, hint),
+    }
+}
+
+// runs `f` on the first item labeled `field`, wherever it is in the tree
+fn with_field(owner: TRef<Tree>, field: &str, f: impl FnOnce(&TreeItem)) {
+    fn find(item: TRef<TreeItem>, field: &str) -> Option<Ref<TreeItem>> {
+        if item.get_text(0).to_string() == field {
+            return Some(item.claim());
+        }
+        let mut child = item.get_children();
+        while let Some(c) = child {
+            let c = unsafe { c.assume_safe() };
+            if let Some(found) = find(c, field) {
+                return Some(found);
+            }
+            child = c.get_next();
+        }
+        None
+    }
+
+    let root = match owner.get_root() {
+        Some(r) => unsafe { r.assume_safe() },
+        None => return,
+    };
+    if let Some(item) = find(root, field) {
+        f(&*unsafe { item.assume_safe() });
+    }
+}
+
+fn set_field_text(owner: TRef<Tree>, field: &str, text: &str) {
+    with_field(owner, field, |item| item.set_text(1, text));
+}
+
+fn set_field_editable(owner: TRef<Tree>, field: &str, editable: bool) {
+    with_field(owner, field, |item| item.set_editable(1, editable));
+}
+
+fn read_preview_layers(owner: TRef<Tree>) -> PreviewLayers {
+    let checked = |field: &str| {
+        let mut checked = false;
+        with_field(owner, field, |item| checked = item.is_checked(1));
+        checked
+    };
+    PreviewLayers {
+        camera_image: checked(SHOW_CAMERA_IMAGE),
+        face_box: checked(SHOW_FACE_BOX),
+        landmarks: checked(SHOW_LANDMARKS),
+        landmark_indices: checked(SHOW_LANDMARK_INDICES),
+        head_pose: checked(SHOW_HEAD_POSE),
+        metrics: checked(SHOW_METRICS),
+    }
+}
+
+// fills the Image Adjustments rows from `cfg`
+fn show_preprocess(owner: TRef<Tree>, cfg: &PreprocessConfig) {
+    let set_checked = |field: &str, checked: bool| {
+        with_field(owner, field, |item| item.set_checked(1, checked));
+    };
+    let set_range = |field: &str, value: f64| {
+        with_field(owner, field, |item| item.set_range(1, value));
+    };
+    set_checked(MIRROR, cfg.flip_horizontal);
+    set_checked(FLIP_VERTICAL, cfg.flip_vertical);
+    set_checked(LOW_LIGHT, cfg.clahe);
+    set_checked(GRAYSCALE, cfg.grayscale);
+    set_range(ROTATION, f64::from(cfg.rotation.degrees()));
+    set_range(CROP_LEFT, cfg.crop.x * 100_f64);
+    set_range(CROP_TOP, cfg.crop.y * 100_f64);
+    set_range(CROP_WIDTH, cfg.crop.width * 100_f64);
+    set_range(CROP_HEIGHT, cfg.crop.height * 100_f64);
+    set_range(GAMMA, cfg.gamma);
+}
+
+// the reverse of `show_preprocess`
+fn read_preprocess(owner: TRef<Tree>) -> PreprocessConfig {
+    let checked = |field: &str| {
+        let mut checked = false;
+        with_field(owner, field, |item| checked = item.is_checked(1));
+        checked
+    };
+    let range = |field: &str, default: f64| {
+        let mut value = default;
+        with_field(owner, field, |item| value = item.get_range(1));
+        value
+    };
+    let mut cfg = PreprocessConfig::default();
+    cfg.flip_horizontal = checked(MIRROR);
+    cfg.flip_vertical = checked(FLIP_VERTICAL);
+    cfg.clahe = checked(LOW_LIGHT);
+    cfg.grayscale = checked(GRAYSCALE);
+    cfg.rotation = Rotation::from_degrees(range(ROTATION, 0_f64) as i64);
+    cfg.crop.x = range(CROP_LEFT, 0_f64) / 100_f64;
+    cfg.crop.y = range(CROP_TOP, 0_f64) / 100_f64;
+    cfg.crop.width = range(CROP_WIDTH, 100_f64) / 100_f64;
+    cfg.crop.height = range(CROP_HEIGHT, 100_f64) / 100_f64;
+    cfg.gamma = range(GAMMA, cfg.gamma);
+    cfg
+}
+
+// read back by `preprocess_from_dictionary` in the viewport holder
+fn preprocess_to_dictionary(cfg: &PreprocessConfig) -> Dictionary {
+    let dict = Dictionary::new();
+    dict.insert("flip_horizontal", cfg.flip_horizontal);
+    dict.insert("flip_vertical", cfg.flip_vertical);
+    dict.insert("rotation", i64::from(cfg.rotation.degrees()));
+    dict.insert("crop_x", cfg.crop.x);
+    dict.insert("crop_y", cfg.crop.y);
+    dict.insert("crop_width", cfg.crop.width);
+    dict.insert("crop_height", cfg.crop.height);
+    dict.insert("gamma", cfg.gamma);
+    dict.insert("clahe", cfg.clahe);
+    dict.insert("grayscale", cfg.grayscale);
+    dict.into_shared()
+}
@@ -0,0 +1,80 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{
+    error::output_error::OutputError::CannotWrite, output::PacketSink,
+    util::misc::FullyCalculatedPacket,
+};
+use serde_json::json;
+use std::{io::Write, time::Instant};
+
+/// One JSON object per face per frame, one per line. Coordinates are pixels of the camera frame,
+/// angles are radians.
+pub struct JsonLinesSink<W: Write> {
+    name: String,
+    out: W,
+    started: Instant,
+}
+
+impl<W: Write> JsonLinesSink<W> {
+    pub fn new(name: String, out: W, started: Instant) -> Self {
+        JsonLinesSink { name, out, started }
+    }
+}
+
+impl<W: Write> PacketSink for JsonLinesSink<W> {
+    fn send(&mut self, packet: &FullyCalculatedPacket) -> Result<(), Box<dyn std::error::Error>> {
+        // the box comes in bottom up
+        let (min, max) = (packet.face_location.min, packet.face_location.max);
+        let landmarks: Vec<[f64; 2]> = packet.landmarks.iter().map(|pt| [pt.x, pt.y]).collect();
+        let line = json!({
+            "t_ms": packet
+                .captured_at
+                .saturating_duration_since(self.started)
+                .as_millis() as u64,
+            "frame": packet.frame_number,
+            "face_id": packet.face_id,
+            "primary": packet.is_primary,
+            "confidence": {
+                "detection": packet.confidence.detection,
+                "landmarks": packet.confidence.landmarks,
+                "reprojection_error": packet.confidence.reprojection_error,
+            },
+            "box": {
+                "left": min.x.min(max.x),
+                "top": min.y.min(max.y),
+                "right": min.x.max(max.x),
+                "bottom": min.y.max(max.y),
+            },
+            "euler": {
+                "x": packet.euler.x,
+                "y": packet.euler.y,
+                "z": packet.euler.z,
+            },
+            "landmarks": landmarks,
+        });
+        writeln!(self.out, "{}", line)
+            .map_err(|why| CannotWrite(self.name.clone(), why.to_string()))?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.out
+            .flush()
+            .map_err(|why| CannotWrite(self.name.clone(), why.to_string()))?;
+        Ok(())
+    }
+}
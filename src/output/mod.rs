@@ -0,0 +1,192 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// getting packets out of the tracker without Godot, for the headless binary and the app
+
+pub mod json_lines;
+pub mod vmc;
+
+use crate::{
+    configuration::fields::{ConfigField, FieldAccess, SettingsSection},
+    error::output_error::OutputError::{CannotOpen, InvalidTarget},
+    output::{json_lines::JsonLinesSink, vmc::VmcSink},
+    util::misc::FullyCalculatedPacket,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    fmt::{Display, Formatter},
+    fs::OpenOptions,
+    io::{stdout, BufWriter},
+    net::ToSocketAddrs,
+    path::PathBuf,
+    str::FromStr,
+    time::Instant,
+};
+
+/// What VMC receivers listen on unless told otherwise.
+pub const VMC_DEFAULT_PORT: u16 = 39539;
+
+/// Somewhere packets go to. Gets every packet of every face, it is up to the sink to pick.
+pub trait PacketSink {
+    fn send(&mut self, packet: &FullyCalculatedPacket) -> Result<(), Box<dyn std::error::Error>>;
+    fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// Saved the way it is written on the command line, see `FromStr`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum OutputTarget {
+    /// JSON lines on standard output.
+    Stdout,
+    /// JSON lines into a file, a recording that can be looked at or replayed later.
+    File(PathBuf),
+    /// The head pose and a few blend shapes over the VMC protocol, to `host:port`. The host is
+    /// only looked up when the output is opened, so a name that doesn't resolve yet still loads.
+    Vmc(String),
+}
+
+impl OutputTarget {
+    /// Timestamps in the output count from `started`. Files are appended to, an earlier
+    /// recording is never overwritten.
+    pub fn open(
+        &self,
+        started: Instant,
+    ) -> Result<Box<dyn PacketSink>, Box<dyn std::error::Error>> {
+        match self {
+            OutputTarget::Stdout => Ok(Box::new(JsonLinesSink::new(
+                self.to_string(),
+                BufWriter::new(stdout()),
+                started,
+            ))),
+            OutputTarget::File(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|why| CannotOpen(self.to_string(), why.to_string()))?;
+                Ok(Box::new(JsonLinesSink::new(
+                    self.to_string(),
+                    BufWriter::new(file),
+                    started,
+                )))
+            }
+            OutputTarget::Vmc(host) => {
+                let addr = host
+                    .to_socket_addrs()
+                    .map_err(|why| CannotOpen(self.to_string(), why.to_string()))?
+                    .next()
+                    .ok_or_else(|| {
+                        CannotOpen(self.to_string(), "the host has no addresses".to_string())
+                    })?;
+                Ok(Box::new(VmcSink::new(addr, started)?))
+            }
+        }
+    }
+}
+
+/// `-` or `stdout`, `vmc://host[:port]`, and anything else is a file path.
+impl FromStr for OutputTarget {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(Box::new(InvalidTarget(s.to_string())));
+        }
+        if s == "-" || s.eq_ignore_ascii_case("stdout") {
+            return Ok(OutputTarget::Stdout);
+        }
+        if let Some(host) = s.strip_prefix("vmc://") {
+            let host = host.trim_end_matches('/');
+            // a bracketed IPv6 address has colons of its own
+            let has_port = match host.rfind(']') {
+                Some(bracket) => host[bracket..].contains(':'),
+                None => host.contains(':'),
+            };
+            let host = if has_port {
+                host.to_string()
+            } else {
+                format!("{}:{}", host, VMC_DEFAULT_PORT)
+            };
+            // only check it looks like host:port, resolving waits for `open`
+            let valid = match host.rsplitn(2, ':').collect::<Vec<_>>()[..] {
+                [port, name] => !name.is_empty() && port.parse::<u16>().is_ok(),
+                _ => false,
+            };
+            if !valid {
+                return Err(Box::new(InvalidTarget(s.to_string())));
+            }
+            return Ok(OutputTarget::Vmc(host));
+        }
+        Ok(OutputTarget::File(PathBuf::from(s)))
+    }
+}
+
+impl Display for OutputTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputTarget::Stdout => write!(f, "stdout"),
+            OutputTarget::File(path) => write!(f, "{}", path.display()),
+            OutputTarget::Vmc(host) => write!(f, "vmc://{}", host),
+        }
+    }
+}
+
+impl TryFrom<String> for OutputTarget {
+    type Error = Box<dyn std::error::Error>;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<OutputTarget> for String {
+    fn from(target: OutputTarget) -> Self {
+        target.to_string()
+    }
+}
+
+/// Where the app sends tracking results besides the avatar.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct OutputConfig {
+    pub targets: Vec<OutputTarget>,
+}
+
+impl SettingsSection for OutputConfig {
+    const NAME: &'static str = "output";
+    const TITLE: &'static str = "Outputs";
+    const FIELDS: &'static [ConfigField<Self>] = &[ConfigField {
+        key: "output.targets",
+        label: "Send tracking to",
+        hint: "One per line: vmc://HOST[:PORT] for VMC, anything else is a file to record to.",
+        access: FieldAccess::Lines {
+            get: |cfg| cfg.targets.iter().map(OutputTarget::to_string).collect(),
+            set: |cfg, lines| {
+                cfg.targets = lines
+                    .iter()
+                    .map(|line| line.trim())
+                    .filter(|line| !line.is_empty())
+                    .map(|line| {
+                        line.parse::<OutputTarget>()
+                            .map_err(|why| format!("has a bad entry: {}", why))
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(())
+            },
+        },
+    }];
+}
@@ -0,0 +1,178 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{
+    error::output_error::OutputError::{CannotOpen, CannotWrite},
+    output::PacketSink,
+    processing::aspect_ratio::{calc_ear, calc_mar},
+    util::misc::FullyCalculatedPacket,
+};
+use euclid::default::Vector2D;
+use nalgebra::UnitQuaternion;
+use std::{
+    net::{SocketAddr, UdpSocket},
+    time::Instant,
+};
+
+// eye aspect ratio of a wide open and a shut eye, anything in between is a partial blink
+const EAR_OPEN: f32 = 0.28;
+const EAR_CLOSED: f32 = 0.15;
+// mouth aspect ratio of a closed and a wide open mouth
+const MAR_CLOSED: f32 = 0.1;
+const MAR_OPEN: f32 = 0.6;
+
+/// Sends the primary face to a VMC receiver (VSeeFace, VMagicMirror, ...) over UDP: the head
+/// bone's rotation and the `Blink_L`, `Blink_R` and `A` blend shapes.
+pub struct VmcSink {
+    addr: SocketAddr,
+    socket: UdpSocket,
+    started: Instant,
+    // reused for every message so sending doesn't allocate
+    buffer: Vec<u8>,
+}
+
+impl VmcSink {
+    pub fn new(addr: SocketAddr, started: Instant) -> Result<Self, Box<dyn std::error::Error>> {
+        let bind_to = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind_to)
+            .and_then(|socket| socket.connect(addr).map(|_| socket))
+            .map_err(|why| CannotOpen(format!("vmc://{}", addr), why.to_string()))?;
+        Ok(VmcSink {
+            addr,
+            socket,
+            started,
+            buffer: Vec::with_capacity(128),
+        })
+    }
+
+    fn send_message(
+        &mut self,
+        address: &str,
+        args: &[OscArg],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        encode_message(&mut self.buffer, address, args);
+        self.socket
+            .send(&self.buffer)
+            .map_err(|why| CannotWrite(format!("vmc://{}", self.addr), why.to_string()))?;
+        Ok(())
+    }
+}
+
+impl PacketSink for VmcSink {
+    fn send(&mut self, packet: &FullyCalculatedPacket) -> Result<(), Box<dyn std::error::Error>> {
+        // an avatar only has the one head
+        if !packet.is_primary {
+            return Ok(());
+        }
+
+        let time = packet
+            .captured_at
+            .saturating_duration_since(self.started)
+            .as_secs_f32();
+        self.send_message("/VMC/Ext/OK", &[OscArg::Int(1)])?;
+        self.send_message("/VMC/Ext/T", &[OscArg::Float(time)])?;
+
+        // the pose is in camera space with y pointing down, Unity has it pointing up
+        let rotation =
+            UnitQuaternion::from_euler_angles(packet.euler.x, packet.euler.y, packet.euler.z);
+        let (qx, qy, qz, qw) = (-rotation.i, rotation.j, -rotation.k, rotation.w);
+        self.send_message(
+            "/VMC/Ext/Bone/Pos",
+            &[
+                OscArg::Str("Head"),
+                OscArg::Float(0_f32),
+                OscArg::Float(0_f32),
+                OscArg::Float(0_f32),
+                OscArg::Float(qx as f32),
+                OscArg::Float(qy as f32),
+                OscArg::Float(qz as f32),
+                OscArg::Float(qw as f32),
+            ],
+        )?;
+
+        // the ratios need the full 68 points
+        if packet.landmarks.len() >= 68 {
+            let landmarks: Vec<Vector2D<f32>> = packet
+                .landmarks
+                .iter()
+                .map(|pt| Vector2D::new(pt.x as f32, pt.y as f32))
+                .collect();
+            // the eye on the left of the image is the person's right one
+            let (image_left, image_right) = calc_ear(&landmarks);
+            let blink_l = 1_f32 - normalize(image_right, EAR_CLOSED, EAR_OPEN);
+            let blink_r = 1_f32 - normalize(image_left, EAR_CLOSED, EAR_OPEN);
+            let mouth = normalize(calc_mar(&landmarks), MAR_CLOSED, MAR_OPEN);
+            for (name, value) in &[("Blink_L", blink_l), ("Blink_R", blink_r), ("A", mouth)] {
+                self.send_message(
+                    "/VMC/Ext/Blend/Val",
+                    &[OscArg::Str(*name), OscArg::Float(*value)],
+                )?;
+            }
+            self.send_message("/VMC/Ext/Blend/Apply", &[])?;
+        }
+        Ok(())
+    }
+
+    // UDP has nothing to flush
+    fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+}
+
+// where `value` is between `low` and `high`, clamped to 0.0 ~ 1.0
+fn normalize(value: f32, low: f32, high: f32) -> f32 {
+    ((value - low) / (high - low)).max(0_f32).min(1_f32)
+}
+
+// the little bit of OSC that VMC uses, not worth a dependency
+enum OscArg<'a> {
+    Int(i32),
+    Float(f32),
+    Str(&'a str),
+}
+
+fn encode_message(out: &mut Vec<u8>, address: &str, args: &[OscArg]) {
+    out.clear();
+    push_osc_string(out, address);
+    let mut tags = String::with_capacity(args.len() + 1);
+    tags.push(',');
+    for arg in args {
+        tags.push(match arg {
+            OscArg::Int(_) => 'i',
+            OscArg::Float(_) => 'f',
+            OscArg::Str(_) => 's',
+        });
+    }
+    push_osc_string(out, &tags);
+    for arg in args {
+        match arg {
+            OscArg::Int(int) => out.extend_from_slice(&int.to_be_bytes()),
+            OscArg::Float(float) => out.extend_from_slice(&float.to_be_bytes()),
+            OscArg::Str(st) => push_osc_string(out, st),
+        }
+    }
+}
+
+// null terminated, then padded with more nulls to a multiple of 4 bytes
+fn push_osc_string(out: &mut Vec<u8>, st: &str) {
+    out.extend_from_slice(st.as_bytes());
+    let padding = 4 - st.len() % 4;
+    out.extend(std::iter::repeat(0_u8).take(padding));
+}
@@ -0,0 +1,71 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// eye and mouth aspect ratios off the 68 point dlib layout
+
+use euclid::{UnknownUnit, Vector2D};
+
+// FIXME: this is ***BAD***
+#[inline]
+pub fn calc_ear(landmarks: &[Vector2D<f32, UnknownUnit>]) -> (f32, f32) {
+    // eye left
+    let left = single_ear(
+        *landmarks.get(36).unwrap(),
+        *landmarks.get(37).unwrap(),
+        *landmarks.get(38).unwrap(),
+        *landmarks.get(39).unwrap(),
+        *landmarks.get(40).unwrap(),
+        *landmarks.get(41).unwrap(),
+    );
+    let right = single_ear(
+        *landmarks.get(42).unwrap(),
+        *landmarks.get(43).unwrap(),
+        *landmarks.get(44).unwrap(),
+        *landmarks.get(45).unwrap(),
+        *landmarks.get(46).unwrap(),
+        *landmarks.get(47).unwrap(),
+    );
+    (left, right)
+}
+
+#[inline]
+pub fn calc_mar(landmarks: &[Vector2D<f32, UnknownUnit>]) -> f32 {
+    single_ear(
+        *landmarks.get(48).unwrap(),
+        *landmarks.get(50).unwrap(),
+        *landmarks.get(52).unwrap(),
+        *landmarks.get(54).unwrap(),
+        *landmarks.get(56).unwrap(),
+        *landmarks.get(58).unwrap(),
+    )
+}
+
+#[inline]
+fn single_ear(
+    p1: Vector2D<f32, UnknownUnit>,
+    p2: Vector2D<f32, UnknownUnit>,
+    p3: Vector2D<f32, UnknownUnit>,
+    p4: Vector2D<f32, UnknownUnit>,
+    p5: Vector2D<f32, UnknownUnit>,
+    p6: Vector2D<f32, UnknownUnit>,
+) -> f32 {
+    (euclid_distance(p2, p6) + euclid_distance(p3, p5)) / (2_f32 * euclid_distance(p1, p4))
+}
+
+#[inline]
+fn euclid_distance(p1: Vector2D<f32, UnknownUnit>, p2: Vector2D<f32, UnknownUnit>) -> f32 {
+    ((p1.x - p2.x).powf(2_f32) + (p1.y - p2.y).powf(2_f32)).sqrt()
+}
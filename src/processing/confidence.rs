@@ -0,0 +1,152 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use dlib_face_recognition::Rectangle;
+use facial_processing::utils::misc::Point2D;
+
+// reprojection error (in pixels) at which the pose only counts half
+const HALF_CONFIDENCE_REPROJECTION_PX: f64 = 8.0;
+// dlib boxes cut off the forehead, so even a perfect fit never gets close to 1.0 IoU
+const GOOD_BOX_IOU: f64 = 0.6;
+
+/// How much a face packet can be trusted. Everything is 0.0 (garbage) ~ 1.0 (perfect)
+/// except `reprojection_error`, which is in pixels of the camera frame.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FaceConfidence {
+    pub detection: f64,
+    pub landmarks: f64,
+    /// `None` if PnP could not find a pose at all.
+    pub reprojection_error: Option<f64>,
+}
+
+impl FaceConfidence {
+    pub fn new(rect: &Rectangle, landmarks: &[Point2D], reprojection_error: Option<f64>) -> Self {
+        FaceConfidence {
+            detection: detection_confidence(rect, landmarks),
+            landmarks: landmark_quality(rect, landmarks),
+            reprojection_error,
+        }
+    }
+
+    pub fn pose(&self) -> f64 {
+        match self.reprojection_error {
+            Some(err) => HALF_CONFIDENCE_REPROJECTION_PX / (HALF_CONFIDENCE_REPROJECTION_PX + err),
+            None => 0_f64,
+        }
+    }
+
+    /// Everything rolled into one number, for consumers that don't care about the details.
+    pub fn overall(&self) -> f64 {
+        self.detection * self.landmarks * self.pose()
+    }
+}
+
+impl Default for FaceConfidence {
+    fn default() -> Self {
+        FaceConfidence {
+            detection: 0_f64,
+            landmarks: 0_f64,
+            reprojection_error: None,
+        }
+    }
+}
+
+/// The dlib detector doesn't give us its score, so use how well the face box agrees with the
+/// landmarks that were fit inside it instead.
+pub fn detection_confidence(rect: &Rectangle, landmarks: &[Point2D]) -> f64 {
+    if landmarks.is_empty() {
+        return 0_f64;
+    }
+    let (mut left, mut top) = (f64::MAX, f64::MAX);
+    let (mut right, mut bottom) = (f64::MIN, f64::MIN);
+    for pt in landmarks {
+        left = left.min(pt.x);
+        top = top.min(pt.y);
+        right = right.max(pt.x);
+        bottom = bottom.max(pt.y);
+    }
+
+    let inter_w = (right.min(rect.right as f64) - left.max(rect.left as f64)).max(0_f64);
+    let inter_h = (bottom.min(rect.bottom as f64) - top.max(rect.top as f64)).max(0_f64);
+    let intersection = inter_w * inter_h;
+    let rect_area = ((rect.right - rect.left) * (rect.bottom - rect.top)) as f64;
+    let landmark_area = (right - left) * (bottom - top);
+    let union = rect_area + landmark_area - intersection;
+    if union <= 0_f64 {
+        return 0_f64;
+    }
+    (intersection / union / GOOD_BOX_IOU).min(1_f64)
+}
+
+/// Rough estimate of whether the 68 points still look like a face: how many stay inside the
+/// (padded) face box, times how many of the basic "brows above eyes above nose above mouth"
+/// rules hold along the face's own axis, so head roll doesn't count against it.
+pub fn landmark_quality(rect: &Rectangle, landmarks: &[Point2D]) -> f64 {
+    if landmarks.len() != 68 {
+        return 0_f64;
+    }
+
+    let pad_x = (rect.right - rect.left) as f64 * 0.2;
+    let pad_y = (rect.bottom - rect.top) as f64 * 0.2;
+    let inside = landmarks
+        .iter()
+        .filter(|pt| {
+            pt.x >= rect.left as f64 - pad_x
+                && pt.x <= rect.right as f64 + pad_x
+                && pt.y >= rect.top as f64 - pad_y
+                && pt.y <= rect.bottom as f64 + pad_y
+        })
+        .count() as f64
+        / landmarks.len() as f64;
+
+    // down the face: nose bridge (27) to chin (8)
+    let origin = &landmarks[27];
+    let (dx, dy) = (landmarks[8].x - origin.x, landmarks[8].y - origin.y);
+    let length = (dx * dx + dy * dy).sqrt();
+    if length < 1_f64 {
+        return 0_f64;
+    }
+    let (dx, dy) = (dx / length, dy / length);
+    let down = |range: std::ops::Range<usize>| {
+        let count = range.len() as f64;
+        range
+            .map(|idx| (landmarks[idx].x - origin.x) * dx + (landmarks[idx].y - origin.y) * dy)
+            .sum::<f64>()
+            / count
+    };
+    // across the face, left to right in the image
+    let across = |range: std::ops::Range<usize>| {
+        let count = range.len() as f64;
+        range
+            .map(|idx| (landmarks[idx].x - origin.x) * dy - (landmarks[idx].y - origin.y) * dx)
+            .sum::<f64>()
+            / count
+    };
+
+    let rules = [
+        down(17..27) < down(36..48),     // brows above eyes
+        down(36..48) < down(30..31),     // eyes above nose tip
+        down(30..31) < down(51..52),     // nose tip above upper lip
+        down(51..52) <= down(57..58),    // upper lip above lower lip
+        down(57..58) < down(8..9),       // lower lip above chin
+        across(36..42) < across(42..48), // eyes the right way around
+        across(0..1) < across(16..17),   // jaw
+        across(48..49) < across(54..55), // mouth corners
+    ];
+    let rules_held = rules.iter().filter(|held| **held).count() as f64 / rules.len() as f64;
+
+    inside * rules_held
+}
@@ -0,0 +1,231 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::util::camera::device_utils::Resolution;
+use dlib_face_recognition::Rectangle;
+use facial_processing::utils::misc::Point2D;
+use serde::{Deserialize, Serialize};
+
+// below this IoU we fall back to comparing landmarks
+const MIN_IOU: f64 = 0.3;
+// mean landmark distance relative to the face box diagonal
+const MAX_LANDMARK_DISTANCE: f64 = 0.5;
+// how many frames a face can go missing before its ID is thrown away
+const MAX_MISSED_FRAMES: u32 = 15;
+
+/// Which face drives the avatar when there is more than one in frame.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PrimaryFacePolicy {
+    Largest,
+    ClosestToCenter,
+    /// Lock onto whatever face is primary right now. Turns into `Locked` on the next frame.
+    LockCurrent,
+    Locked(u32),
+}
+
+impl Default for PrimaryFacePolicy {
+    fn default() -> Self {
+        PrimaryFacePolicy::Largest
+    }
+}
+
+struct KnownFace {
+    id: u32,
+    rect: Rectangle,
+    landmarks: Vec<Point2D>,
+    missed: u32,
+}
+
+/// Gives every face a stable ID across frames and picks the primary face.
+pub struct FaceIdentifier {
+    next_id: u32,
+    known: Vec<KnownFace>,
+    policy: PrimaryFacePolicy,
+    last_primary: Option<u32>,
+}
+
+impl FaceIdentifier {
+    pub fn new(policy: PrimaryFacePolicy) -> Self {
+        FaceIdentifier {
+            next_id: 0,
+            known: Vec::new(),
+            policy,
+            last_primary: None,
+        }
+    }
+
+    pub fn policy(&self) -> PrimaryFacePolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: PrimaryFacePolicy) {
+        self.policy = policy;
+    }
+
+    /// Forget every known face. IDs keep counting up so old ones never get reused.
+    pub fn clear(&mut self) {
+        self.known.clear();
+        self.last_primary = None;
+    }
+
+    /// Match the faces of this frame against the ones we already know.
+    /// Returns one ID per face, in the same order as `rects`.
+    pub fn identify(&mut self, rects: &[Rectangle], landmarks: &[Vec<Point2D>]) -> Vec<u32> {
+        // every plausible (score, new face, known face) pair, best first
+        let mut candidates = Vec::new();
+        for (new_idx, (rect, points)) in rects.iter().zip(landmarks).enumerate() {
+            for (known_idx, known) in self.known.iter().enumerate() {
+                if let Some(score) = match_score(rect, points, known) {
+                    candidates.push((score, new_idx, known_idx));
+                }
+            }
+        }
+        candidates.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+
+        let mut ids: Vec<Option<u32>> = vec![None; rects.len()];
+        let mut known_taken = vec![false; self.known.len()];
+        for (_, new_idx, known_idx) in candidates {
+            if ids[new_idx].is_none() && !known_taken[known_idx] {
+                ids[new_idx] = Some(self.known[known_idx].id);
+                known_taken[known_idx] = true;
+            }
+        }
+
+        for (known, taken) in self.known.iter_mut().zip(&known_taken) {
+            if !taken {
+                known.missed += 1;
+            }
+        }
+        self.known.retain(|known| known.missed <= MAX_MISSED_FRAMES);
+
+        let mut assigned = Vec::with_capacity(rects.len());
+        for ((rect, points), id) in rects.iter().zip(landmarks).zip(ids) {
+            let id = match id {
+                Some(id) => id,
+                None => {
+                    let id = self.next_id;
+                    self.next_id = self.next_id.wrapping_add(1);
+                    id
+                }
+            };
+            match self.known.iter_mut().find(|known| known.id == id) {
+                Some(known) => {
+                    known.rect = *rect;
+                    known.landmarks = points.clone();
+                    known.missed = 0;
+                }
+                None => self.known.push(KnownFace {
+                    id,
+                    rect: *rect,
+                    landmarks: points.clone(),
+                    missed: 0,
+                }),
+            }
+            assigned.push(id);
+        }
+        assigned
+    }
+
+    /// Pick the face that should drive the avatar this frame, if any.
+    pub fn primary(&mut self, ids: &[u32], rects: &[Rectangle], frame: Resolution) -> Option<u32> {
+        let primary = match self.policy {
+            PrimaryFacePolicy::Largest | PrimaryFacePolicy::LockCurrent => ids
+                .iter()
+                .zip(rects)
+                .max_by_key(|(_, rect)| (rect.right - rect.left) * (rect.bottom - rect.top))
+                .map(|(id, _)| *id),
+            PrimaryFacePolicy::ClosestToCenter => {
+                let center_x = f64::from(frame.x) / 2_f64;
+                let center_y = f64::from(frame.y) / 2_f64;
+                ids.iter()
+                    .zip(rects)
+                    .map(|(id, rect)| {
+                        let x = (rect.left + rect.right) as f64 / 2_f64 - center_x;
+                        let y = (rect.top + rect.bottom) as f64 / 2_f64 - center_y;
+                        (*id, x * x + y * y)
+                    })
+                    .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+                    .map(|(id, _)| id)
+            }
+            PrimaryFacePolicy::Locked(locked) => {
+                if ids.contains(&locked) {
+                    Some(locked)
+                } else {
+                    None
+                }
+            }
+        };
+
+        if self.policy == PrimaryFacePolicy::LockCurrent {
+            // prefer the face the user was looking at when they pressed the button
+            let lock = self
+                .last_primary
+                .filter(|id| self.known.iter().any(|known| known.id == *id))
+                .or(primary);
+            if let Some(id) = lock {
+                self.policy = PrimaryFacePolicy::Locked(id);
+                self.last_primary = Some(id);
+                return if ids.contains(&id) { Some(id) } else { None };
+            }
+        }
+
+        if primary.is_some() {
+            self.last_primary = primary;
+        }
+        primary
+    }
+}
+
+// Higher is better. IoU matches always beat landmark distance matches.
+fn match_score(rect: &Rectangle, landmarks: &[Point2D], known: &KnownFace) -> Option<f64> {
+    let iou = intersection_over_union(rect, &known.rect);
+    if iou >= MIN_IOU {
+        return Some(1_f64 + iou);
+    }
+
+    if landmarks.is_empty() || landmarks.len() != known.landmarks.len() {
+        return None;
+    }
+    let width = (known.rect.right - known.rect.left) as f64;
+    let height = (known.rect.bottom - known.rect.top) as f64;
+    let diagonal = (width * width + height * height).sqrt().max(1_f64);
+    let mean_distance = landmarks
+        .iter()
+        .zip(&known.landmarks)
+        .map(|(a, b)| ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)).sqrt())
+        .sum::<f64>()
+        / landmarks.len() as f64;
+    let relative = mean_distance / diagonal;
+    if relative < MAX_LANDMARK_DISTANCE {
+        Some(1_f64 - relative)
+    } else {
+        None
+    }
+}
+
+fn intersection_over_union(a: &Rectangle, b: &Rectangle) -> f64 {
+    let left = a.left.max(b.left);
+    let top = a.top.max(b.top);
+    let right = a.right.min(b.right);
+    let bottom = a.bottom.min(b.bottom);
+    if right <= left || bottom <= top {
+        return 0_f64;
+    }
+    let intersection = ((right - left) * (bottom - top)) as f64;
+    let area_a = ((a.right - a.left) * (a.bottom - a.top)) as f64;
+    let area_b = ((b.right - b.left) * (b.bottom - b.top)) as f64;
+    intersection / (area_a + area_b - intersection)
+}
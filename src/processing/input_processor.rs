@@ -1,42 +1,94 @@
 use crate::{
-    error::thread_send_message_error::ThreadSendMessageError,
-    globalize_path, handle_boxerr,
-    processing::pnp::FacePnP,
+    error::{
+        invalid_device_error::InvalidDeviceError, processing_thread_error::ProcessingThreadError,
+        thread_send_message_error::ThreadSendMessageError,
+    },
+    handle_boxerr, log_error, log_info,
+    processing::{
+        face_identity::PrimaryFacePolicy,
+        landmark::landmark_model_from_backend,
+        pipeline::{
+            analyze_frames, sequence_frames, CapturedFrame, PreviewFrame, SequencerMessage,
+        },
+        pipeline_stats::{PipelineStats, PipelineStatsSnapshot, Stage},
+        preprocess::PreprocessConfig,
+        supervisor::ThreadExit,
+        tracker::{FaceTracker, TrackingMode},
+    },
     util::{
         camera::{
-            camera_device::{OpenCvCameraDevice, UVCameraDevice, V4LinuxDevice},
+            camera_controls::CameraControl,
+            camera_device::{OpenCvCameraDevice, UVCameraDevice, V4LinuxDevice, VideoFileDevice},
             device_utils::{DeviceConfig, DeviceContact, DeviceFormat, PossibleDevice, Resolution},
+            frame::FramePool,
             webcam::Webcam,
         },
-        misc::{BackendConfig, FullyCalculatedPacket, MessageType},
+        misc::{
+            Backend, BackendConfig, ControlReply, ControlRequest, ControlResponse, DecodeOptions,
+            DeviceState, FilterParams, FullyCalculatedPacket, MessageType, NeutralPose,
+        },
     },
 };
-use dlib_face_recognition::{
-    FaceDetector, FaceDetectorTrait, ImageMatrix, LandmarkPredictor, LandmarkPredictorTrait,
-};
-use facial_processing::utils::{
-    face::FaceLandmark,
-    misc::{BoundingBox, EulerAngles, Point2D},
-};
-use flume::{Receiver, Sender};
-use gdnative::godot_print;
-use image::{ImageBuffer, Rgb};
-
-use euclid::{Box2D, Point2D as EPoint2D};
+use flume::{Receiver, SendTimeoutError, Sender, TrySendError};
 use std::{
     cell::{Cell, RefCell},
     line,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     thread::{Builder, JoinHandle},
+    time::{Duration, Instant},
 };
 
+// a couple frames worth of faces, anything more is latency nobody wants
+const RESULT_QUEUE_LEN: usize = 8;
+const CONTROL_QUEUE_LEN: usize = 16;
+const RESPONSE_QUEUE_LEN: usize = 32;
+// frames waiting for each worker
+const FRAME_QUEUE_LEN: usize = 2;
+// packets older than this are not worth showing anymore
+const MAX_PACKET_AGE: Duration = Duration::from_millis(250);
+// how often a lossless send looks up from a full queue to see if the worker is still there
+const WORKER_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// one worker, the way the capture thread sees it
+struct FrameQueue {
+    frames: Sender<CapturedFrame>,
+    // the other end of `frames`, to throw out the oldest frame when the worker falls behind. it
+    // also keeps the channel from ever disconnecting, so whether the worker is there is `alive`.
+    overflow: Receiver<CapturedFrame>,
+    alive: Arc<AtomicBool>,
+}
+
+impl FrameQueue {
+    fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Acquire)
+    }
+}
+
+// cleared when the worker thread ends, whether it returned or panicked
+struct AliveGuard(Arc<AtomicBool>);
+
+impl Drop for AliveGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
 pub struct InputProcesser {
     device: RefCell<PossibleDevice>,
-    // bruh wtf
-    backend_cfg: Cell<BackendConfig>,
+    backend_cfg: RefCell<BackendConfig>,
     // face_detector: Arc<Mutex<Box<dyn DetectorTrait>>>,
-    thread: JoinHandle<u8>,
+    thread: JoinHandle<()>,
+    receiver_exit: Receiver<ThreadExit>,
     receiver_fromthread: Receiver<FullyCalculatedPacket>,
-    sender_tothread: Sender<MessageType>,
+    sender_tothread: Sender<ControlRequest>,
+    receiver_responses: Receiver<ControlResponse>,
+    next_request_id: Cell<u64>,
+    stats: Arc<PipelineStats>,
+    preview: Arc<Mutex<Option<PreviewFrame>>>,
 }
 
 impl InputProcesser {
@@ -44,25 +96,58 @@ impl InputProcesser {
         device: PossibleDevice,
         config: BackendConfig,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let backend_cfg = Cell::new(config);
-        let (sender_fromthread, receiver_fromthread) = flume::unbounded();
-        let (sender_tothread, receiver_tothread) = flume::unbounded();
-        let cfg2 = backend_cfg.get();
+        let cfg2 = config.clone();
+        let backend_cfg = RefCell::new(config);
+        // both ways are bounded so a stalled UI can't make the queue grow forever. The thread
+        // keeps its own receiver to throw out the oldest packet when the queue is full.
+        let (sender_fromthread, receiver_fromthread) = flume::bounded(RESULT_QUEUE_LEN);
+        let overflow_fromthread = receiver_fromthread.clone();
+        let (sender_tothread, receiver_tothread) = flume::bounded(CONTROL_QUEUE_LEN);
+        let (sender_responses, receiver_responses) = flume::bounded(RESPONSE_QUEUE_LEN);
+        let (sender_exit, receiver_exit) = flume::bounded(1);
         let dev2 = device.clone();
+        let stats = Arc::new(PipelineStats::default());
+        let stats2 = stats.clone();
+        let preview = Arc::new(Mutex::new(None));
+        let preview2 = preview.clone();
 
         // Create a seperate thread to run the process_input pipeline in to avoid choking the main UI thread.
         let thread = Builder::new()
             .name("input_processor".to_string())
             .stack_size(33_554_432) // 32 MiB
-            .spawn(move || process_input(cfg2, dev2, sender_fromthread, receiver_tothread))
-            .unwrap();
+            .spawn(move || {
+                let result = catch_unwind(AssertUnwindSafe(|| {
+                    process_input(
+                        cfg2,
+                        dev2,
+                        sender_fromthread,
+                        overflow_fromthread,
+                        receiver_tothread,
+                        sender_responses,
+                        stats2,
+                        preview2,
+                    )
+                }));
+                let exit = match result {
+                    Ok(Ok(())) => ThreadExit::Shutdown,
+                    Ok(Err(why)) => ThreadExit::Failed(why),
+                    Err(panic) => ThreadExit::Panicked(panic_message(panic.as_ref())),
+                };
+                // nobody might be listening anymore, that's fine
+                let _ = sender_exit.send(exit);
+            })?;
 
         Ok(InputProcesser {
             device: RefCell::new(device),
             backend_cfg,
             thread,
+            receiver_exit,
             receiver_fromthread,
             sender_tothread,
+            receiver_responses,
+            next_request_id: Cell::new(0),
+            stats,
+            preview,
         })
     }
 
@@ -70,204 +155,710 @@ impl InputProcesser {
         device_contact: DeviceContact,
         res: Resolution,
         fps: u32,
+        fmt: DeviceFormat,
         cfg: BackendConfig,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let device =
-            PossibleDevice::from_device_contact(device_contact, res, fps, DeviceFormat::MJpeg);
+        let device = PossibleDevice::from_device_contact(device_contact, res, fps, fmt);
         InputProcesser::new(device, cfg)
     }
 
+    // All of the request functions below return the id of the request, its answer shows up in
+    // `query_responses` with the same id.
+
     pub fn change_device(
         &self,
         new_device: PossibleDevice,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<u64, Box<dyn std::error::Error>> {
         self.device.replace(new_device.clone());
-        if self
-            .sender_tothread
-            .send(MessageType::SetDevice {
-                name: None,
-                device: new_device,
-            })
-            .is_err()
-        {
-            return Err(Box::new(ThreadSendMessageError::CannotSend));
-        }
-        Ok(())
+        self.request(MessageType::SetDevice {
+            name: None,
+            device: new_device,
+        })
     }
 
-    pub fn set_device_cfg(&self, dev_cfg: DeviceConfig) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn set_device_cfg(&self, dev_cfg: DeviceConfig) -> Result<u64, Box<dyn std::error::Error>> {
         let current_possible = self.device.borrow().clone().change_config(dev_cfg);
         self.device.replace(current_possible);
-        if self
-            .sender_tothread
-            .send(MessageType::ChangeDevice(dev_cfg))
-            .is_err()
-        {
-            return Err(Box::new(ThreadSendMessageError::CannotSend));
+        self.request(MessageType::ChangeDevice(dev_cfg))
+    }
+
+    pub fn set_scale_factor(&self, scale: f64) -> Result<u64, Box<dyn std::error::Error>> {
+        let new_cfg = self.backend_cfg.borrow().clone().with_scale_factor(scale);
+        self.backend_cfg.replace(new_cfg);
+        self.request(MessageType::SetScaleFactor(scale))
+    }
+
+    pub fn set_primary_face(
+        &self,
+        policy: PrimaryFacePolicy,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        self.request(MessageType::SetPrimaryFace(policy))
+    }
+
+    pub fn set_backend(&self, backend: Backend) -> Result<u64, Box<dyn std::error::Error>> {
+        let new_cfg = self
+            .backend_cfg
+            .borrow()
+            .clone()
+            .with_backend(backend.clone());
+        self.backend_cfg.replace(new_cfg);
+        self.request(MessageType::SetBackend(backend))
+    }
+
+    pub fn set_filter(&self, filter: FilterParams) -> Result<u64, Box<dyn std::error::Error>> {
+        let new_cfg = self.backend_cfg.borrow().clone().with_filter(filter);
+        self.backend_cfg.replace(new_cfg);
+        self.request(MessageType::SetFilter(filter))
+    }
+
+    pub fn set_decode(&self, decode: DecodeOptions) -> Result<u64, Box<dyn std::error::Error>> {
+        let new_cfg = self.backend_cfg.borrow().clone().with_decode(decode);
+        self.backend_cfg.replace(new_cfg);
+        self.request(MessageType::SetDecode(decode))
+    }
+
+    pub fn set_preprocess(
+        &self,
+        preprocess: PreprocessConfig,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let new_cfg = self
+            .backend_cfg
+            .borrow()
+            .clone()
+            .with_preprocess(preprocess);
+        self.backend_cfg.replace(new_cfg);
+        self.request(MessageType::SetPreprocess(preprocess))
+    }
+
+    pub fn set_preview(&self, preview: bool) -> Result<u64, Box<dyn std::error::Error>> {
+        let new_cfg = self.backend_cfg.borrow().clone().with_preview(preview);
+        self.backend_cfg.replace(new_cfg);
+        self.request(MessageType::SetPreview(preview))
+    }
+
+    pub fn pause(&self) -> Result<u64, Box<dyn std::error::Error>> {
+        self.request(MessageType::Pause)
+    }
+
+    pub fn resume(&self) -> Result<u64, Box<dyn std::error::Error>> {
+        self.request(MessageType::Resume)
+    }
+
+    pub fn step(&self) -> Result<u64, Box<dyn std::error::Error>> {
+        self.request(MessageType::Step)
+    }
+
+    pub fn calibrate(&self) -> Result<u64, Box<dyn std::error::Error>> {
+        self.request(MessageType::Calibrate)
+    }
+
+    pub fn set_calibration(
+        &self,
+        calibration: Option<NeutralPose>,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let new_cfg = self
+            .backend_cfg
+            .borrow()
+            .clone()
+            .with_calibration(calibration);
+        self.backend_cfg.replace(new_cfg);
+        self.request(MessageType::SetCalibration(calibration))
+    }
+
+    pub fn query_device(&self) -> Result<u64, Box<dyn std::error::Error>> {
+        self.request(MessageType::QueryDevice)
+    }
+
+    /// Ask the processing thread for its stats. Use `stats` to read them without the round trip.
+    pub fn request_stats(&self) -> Result<u64, Box<dyn std::error::Error>> {
+        self.request(MessageType::QueryStats)
+    }
+
+    pub fn set_control(
+        &self,
+        control: CameraControl,
+        value: i64,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        self.request(MessageType::SetControl(control, value))
+    }
+
+    pub fn query_controls(&self) -> Result<u64, Box<dyn std::error::Error>> {
+        self.request(MessageType::QueryControls)
+    }
+
+    pub fn shutdown(&self) -> Result<u64, Box<dyn std::error::Error>> {
+        self.request(MessageType::Shutdown)
+    }
+
+    pub fn request(&self, msg: MessageType) -> Result<u64, Box<dyn std::error::Error>> {
+        let id = self.next_request_id.get();
+        match self.sender_tothread.try_send(ControlRequest { id, msg }) {
+            Ok(_) => {
+                self.next_request_id.set(id.wrapping_add(1));
+                Ok(id)
+            }
+            Err(TrySendError::Full(_)) => Err(Box::new(ThreadSendMessageError::QueueFull)),
+            Err(TrySendError::Disconnected(_)) => Err(Box::new(ThreadSendMessageError::CannotSend)),
         }
-        Ok(())
     }
 
+    /// Every answer the processing thread sent since the last call.
+    pub fn query_responses(&self) -> Vec<ControlResponse> {
+        self.receiver_responses.drain().collect()
+    }
+
+    /// Every packet that is still fresh enough to show, oldest first. Lossless pipelines keep
+    /// the stale ones too.
     pub fn query_gotten_results(&self) -> Vec<FullyCalculatedPacket> {
+        if self.backend_cfg.borrow().lossless() {
+            return self.receiver_fromthread.drain().collect();
+        }
         let mut point_vec = Vec::new();
+        let mut stale = 0;
         for point in self.receiver_fromthread.drain() {
             // lmao imagine using a blocking function in a loop that waits until everything has been dropped, couldn't be me
+            if point.captured_at.elapsed() > MAX_PACKET_AGE {
+                stale += 1;
+                continue;
+            }
             point_vec.push(point);
         }
+        if stale > 0 {
+            self.stats.dropped_stale(stale);
+        }
         point_vec
     }
 
+    /// Only the packets of the newest frame, everything older gets dropped. This is what the UI
+    /// wants, there is no point in replaying frames that already happened.
+    pub fn query_latest_results(&self) -> Vec<FullyCalculatedPacket> {
+        let mut results = self.query_gotten_results();
+        let newest = match results.iter().map(|pkt| pkt.frame_number).max() {
+            Some(frame) => frame,
+            None => return results,
+        };
+        let before = results.len();
+        results.retain(|pkt| pkt.frame_number == newest);
+        let superseded = (before - results.len()) as u64;
+        if superseded > 0 {
+            self.stats.dropped_superseded(superseded);
+        }
+        results
+    }
+
+    /// Like `query_gotten_results`, but waits up to `timeout` for something to show up. For when
+    /// there is nothing else to do in the meantime, like in the headless tracker.
+    pub fn wait_results(&self, timeout: Duration) -> Vec<FullyCalculatedPacket> {
+        match self.receiver_fromthread.recv_timeout(timeout) {
+            Ok(first) => {
+                let mut results = vec![first];
+                results.extend(self.query_gotten_results());
+                results
+            }
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Whether every packet the pipeline is ever going to send has been taken.
+    pub fn results_finished(&self) -> bool {
+        self.receiver_fromthread.is_disconnected() && self.receiver_fromthread.is_empty()
+    }
+
+    /// The newest frame, if previews are on and there is one that hasn't been taken yet. It is
+    /// full resolution with `DecodeOptions::keep_full_frame`, otherwise the working resolution.
+    /// Either way it is what the camera saw, before any preprocessing.
+    pub fn take_preview(&self) -> Option<PreviewFrame> {
+        self.preview.lock().unwrap().take()
+    }
+
+    /// Read the stats right now, without going through the processing thread.
+    pub fn stats(&self) -> PipelineStatsSnapshot {
+        self.stats.snapshot()
+    }
+
     /// Get a reference to the input processer's backend cfg.
-    pub fn backend_cfg(&self) -> &Cell<BackendConfig> {
+    pub fn backend_cfg(&self) -> &RefCell<BackendConfig> {
         &self.backend_cfg
     }
 
+    /// The device this processer was last told to use.
+    pub fn device(&self) -> PossibleDevice {
+        self.device.borrow().clone()
+    }
+
     /// Get a reference to the input processer's thread.
-    pub fn thread(&self) -> &JoinHandle<u8> {
+    pub fn thread(&self) -> &JoinHandle<()> {
         &self.thread
     }
+
+    /// `Some` once the processing thread has stopped, with the reason why. Only returns it once.
+    pub fn poll_exit(&self) -> Option<ThreadExit> {
+        self.receiver_exit.try_recv().ok()
+    }
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        (*msg).to_string()
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+// if the UI isn't reading the answers there is nobody to tell anyway
+fn reply(
+    responses: &Sender<ControlResponse>,
+    id: u64,
+    result: Result<ControlReply, ProcessingThreadError>,
+) {
+    let _ = responses.try_send(ControlResponse { id, result });
+}
+
+// The face boxes found so far don't mean anything in the new picture.
+fn clear_faces(
+    tracker: &Mutex<FaceTracker>,
+    sequencer_sender: &Sender<SequencerMessage>,
+) -> Result<ControlReply, ProcessingThreadError> {
+    tracker.lock().unwrap().lost();
+    sequencer_sender
+        .send(SequencerMessage::ClearFaces)
+        .map(|_| ControlReply::Done)
+        .map_err(|_| ProcessingThreadError::Disconnected)
+}
+
+fn spawn_workers(
+    cfg: &BackendConfig,
+    tracker: &Arc<Mutex<FaceTracker>>,
+    sequencer_sender: &Sender<SequencerMessage>,
+    stats: &Arc<PipelineStats>,
+    pool: &FramePool,
+) -> Result<Vec<FrameQueue>, ProcessingThreadError> {
+    // one thread for capture, one for the sequencer, the rest analyse frames.
+    // optical flow needs to see the frames in order, so it only gets one worker.
+    let worker_count = match cfg.tracking() {
+        TrackingMode::Roi {
+            optical_flow: true, ..
+        } => 1,
+        _ => cfg.max_threads().saturating_sub(2),
+    };
+
+    let mut workers = Vec::with_capacity(worker_count);
+    for worker_idx in 0..worker_count {
+        let (frame_sender, frame_receiver) = flume::bounded(FRAME_QUEUE_LEN);
+        let frame_overflow = frame_receiver.clone();
+        let alive = Arc::new(AtomicBool::new(true));
+        let worker_alive = AliveGuard(alive.clone());
+        let backend = cfg.backend().clone();
+        let cnn_detector = cfg.cnn_detector();
+        let model_dir = cfg.model_dir().to_path_buf();
+        let decode = cfg.decode();
+        let worker_tracker = tracker.clone();
+        let results = sequencer_sender.clone();
+        let worker_stats = stats.clone();
+        let worker_pool = pool.clone();
+        let name = format!("input_worker_{}", worker_idx);
+        if let Err(why) = Builder::new()
+            .name(name.clone())
+            .stack_size(33_554_432) // 32 MiB
+            .spawn(move || {
+                let _alive = worker_alive;
+                analyze_frames(
+                    backend,
+                    cnn_detector,
+                    model_dir,
+                    decode,
+                    frame_receiver,
+                    worker_tracker,
+                    results,
+                    worker_stats,
+                    worker_pool,
+                )
+            })
+        {
+            return Err(ProcessingThreadError::CannotSpawn(name, why.to_string()));
+        }
+        workers.push(FrameQueue {
+            frames: frame_sender,
+            overflow: frame_overflow,
+            alive,
+        });
+    }
+    Ok(workers)
 }
 
+// the old workers finish the frame they are on and exit once their queues are gone. whatever was
+// still queued for them never gets analysed, so the sequencer has to stop waiting for it.
+fn retire_workers(
+    workers: Vec<FrameQueue>,
+    sequencer_sender: &Sender<SequencerMessage>,
+    stats: &PipelineStats,
+) -> Result<(), ProcessingThreadError> {
+    for worker in workers {
+        for old in worker.overflow.try_iter() {
+            stats.dropped_capture();
+            sequencer_sender
+                .send(SequencerMessage::Dropped(old.seq))
+                .map_err(|_| ProcessingThreadError::Disconnected)?;
+        }
+    }
+    Ok(())
+}
+
+// Capture stage. Owns the camera and answers the control requests, everything else happens on
+// the worker and sequencer threads it spawns (see `pipeline`).
 fn process_input(
-    _cfg: BackendConfig,
+    mut cfg: BackendConfig,
     device: PossibleDevice,
     sender: Sender<FullyCalculatedPacket>,
-    message: Receiver<MessageType>,
-) -> u8 {
-    let init_res = device.res();
-    let init_fps = device.fps();
-    let face_detector = FaceDetector::new();
-    let mut device = match get_dyn_webcam(Some("".to_string()), device) {
-        Ok(webcam) => webcam,
-        Err(_) => return 255,
-    };
-    let ld_detector = LandmarkPredictor::new(globalize_path!(
-        "res://models/facial-processing-rs-models/shape_predictor_68_face_landmarks.dat"
-    ))
-    .unwrap();
-    let pnp_solver = FacePnP::new();
-    let mut prev_euler = EulerAngles {
-        x: 0_f64,
-        y: 0_f64,
-        z: 0_f64,
-    };
+    overflow: Receiver<FullyCalculatedPacket>,
+    message: Receiver<ControlRequest>,
+    responses: Sender<ControlResponse>,
+    stats: Arc<PipelineStats>,
+    preview: Arc<Mutex<Option<PreviewFrame>>>,
+) -> Result<(), ProcessingThreadError> {
+    // what the camera was last opened or changed to
+    let mut running = device.clone();
+    let mut device = get_dyn_webcam(Some("".to_string()), device, cfg.lossless())
+        .map_err(|why| ProcessingThreadError::CannotOpenCamera(why.to_string()))?;
+    log_info!(
+        Processing,
+        "Tracking {} at {} {} fps with {} threads",
+        device.name(),
+        running.res(),
+        running.fps(),
+        cfg.max_threads()
+    );
+    let tracker = Arc::new(Mutex::new(FaceTracker::new(cfg.tracking())));
+    // enough for a raw and a decoded buffer for every frame the workers can be sitting on
+    let pool = FramePool::new(cfg.max_threads() * (FRAME_QUEUE_LEN + 2) * 2);
 
-    match device.open_stream() {
-        Ok(_) => {}
-        Err(why) => {
-            godot_print!("died {}, {}", line!(), why.to_string());
-        }
-    };
+    let (sequencer_sender, sequencer_receiver) = flume::unbounded();
+    let mut workers = spawn_workers(&cfg, &tracker, &sequencer_sender, &stats, &pool)?;
+
+    let primary_face = cfg.primary_face();
+    let calibration = cfg.calibration();
+    let lossless = cfg.lossless();
+    let sequencer_tracker = tracker.clone();
+    let sequencer_responses = responses.clone();
+    let sequencer_stats = stats.clone();
+    let sequencer_pool = pool.clone();
+    if let Err(why) = Builder::new()
+        .name("input_sequencer".to_string())
+        .spawn(move || {
+            sequence_frames(
+                primary_face,
+                calibration,
+                lossless,
+                sequencer_receiver,
+                sequencer_tracker,
+                sender,
+                overflow,
+                sequencer_responses,
+                sequencer_stats,
+                sequencer_pool,
+                preview,
+            )
+        })
+    {
+        return Err(ProcessingThreadError::CannotSpawn(
+            "input_sequencer".to_string(),
+            why.to_string(),
+        ));
+    }
+
+    let mut frame_number: u64 = 0;
+    let mut seq: u64 = 0;
+    let mut paused = false;
+    let mut step_request: Option<u64> = None;
+
+    if let Err(why) = device.open_stream() {
+        log_error!(
+            Camera,
+            "Could not open the stream of {}: {}",
+            device.name(),
+            why
+        );
+    }
 
     // pipeline
     loop {
-        if let Ok(msg_recv) = message.try_recv() {
-            match msg_recv {
-                MessageType::Die(code) => {
-                    return code;
+        // handle everything that piled up since the last frame
+        while let Ok(ControlRequest { id, msg }) = message.try_recv() {
+            let result = match msg {
+                MessageType::Shutdown => {
+                    reply(&responses, id, Ok(ControlReply::Done));
+                    return Ok(());
                 }
                 MessageType::SetDevice {
                     name,
                     device: new_dev,
                 } => {
-                    device = match get_dyn_webcam(name, new_dev) {
-                        Ok(webcam) => webcam,
-                        Err(why) => {
-                            godot_print!("died {}, {}", line!(), why.to_string());
-                            return 255;
+                    let opened = if new_dev.to_device_contact() == running.to_device_contact() {
+                        // another format on the same camera. it won't take one while our own
+                        // stream still has it, so let go first and go back if it doesn't work out
+                        drop(device);
+                        match open_webcam(name, new_dev.clone(), cfg.lossless()) {
+                            Ok(webcam) => {
+                                device = webcam;
+                                Ok(())
+                            }
+                            Err(why) => {
+                                device = open_webcam(None, running.clone(), cfg.lossless())
+                                    .map_err(|why| {
+                                        ProcessingThreadError::CannotOpenCamera(why.to_string())
+                                    })?;
+                                Err(why)
+                            }
                         }
+                    } else {
+                        // keep going with the old camera if the new one doesn't work out
+                        open_webcam(name, new_dev.clone(), cfg.lossless())
+                            .map(|webcam| device = webcam)
                     };
-
-                    match device.open_stream() {
-                        Ok(_) => {}
-                        Err(why) => {
-                            godot_print!("died {}, {}", line!(), why.to_string());
+                    match opened {
+                        Ok(_) => {
+                            running = new_dev;
+                            clear_faces(&tracker, &sequencer_sender)
                         }
+                        Err(why) => Err(ProcessingThreadError::CannotOpenCamera(why.to_string())),
                     }
                 }
                 MessageType::ChangeDevice(new_cfg) => {
-                    let new_res = new_cfg.res;
-                    let new_fps = new_cfg.fps;
-                    if new_res != init_res {
-                        handle_boxerr!(device.set_resolution(new_res), 253);
+                    let mut result = Ok(ControlReply::Done);
+                    // only what the camera took counts as running
+                    let mut now = DeviceConfig::from(running.clone());
+                    if new_cfg.res != now.res {
+                        match device.set_resolution(new_cfg.res) {
+                            Ok(_) => now.res = new_cfg.res,
+                            Err(why) => {
+                                result = Err(ProcessingThreadError::CannotConfigureCamera(
+                                    why.to_string(),
+                                ))
+                            }
+                        }
                     }
-                    if new_fps != init_fps {
-                        handle_boxerr!(device.set_framerate(new_fps), 253);
+                    if new_cfg.fps != now.fps {
+                        match device.set_framerate(new_cfg.fps) {
+                            Ok(_) => now.fps = new_cfg.fps,
+                            Err(why) => {
+                                result = Err(ProcessingThreadError::CannotConfigureCamera(
+                                    why.to_string(),
+                                ))
+                            }
+                        }
                     }
+                    running = running.change_config(now);
+                    // even a failed change may have left the camera somewhere else
+                    clear_faces(&tracker, &sequencer_sender).and(result)
                 }
-            }
+                MessageType::SetScaleFactor(scale) => {
+                    cfg = cfg.with_scale_factor(scale);
+                    // the old face boxes are in the old working resolution
+                    clear_faces(&tracker, &sequencer_sender)
+                }
+                MessageType::SetPrimaryFace(policy) => sequencer_sender
+                    .send(SequencerMessage::SetPrimaryFace(policy))
+                    .map(|_| ControlReply::Done)
+                    .map_err(|_| ProcessingThreadError::Disconnected),
+                MessageType::Pause => {
+                    paused = true;
+                    Ok(ControlReply::Done)
+                }
+                MessageType::Resume => {
+                    paused = false;
+                    Ok(ControlReply::Done)
+                }
+                MessageType::Step => {
+                    if paused {
+                        // answered once the frame is on its way
+                        step_request = Some(id);
+                        continue;
+                    }
+                    Err(ProcessingThreadError::NotPaused)
+                }
+                MessageType::SetBackend(backend) => {
+                    // load it once here so a broken model gets reported instead of killing the workers
+                    match landmark_model_from_backend(&backend, cfg.model_dir()) {
+                        Ok(_) => {
+                            cfg = cfg.with_backend(backend);
+                            tracker.lock().unwrap().lost();
+                            let new_workers =
+                                spawn_workers(&cfg, &tracker, &sequencer_sender, &stats, &pool)?;
+                            retire_workers(
+                                std::mem::replace(&mut workers, new_workers),
+                                &sequencer_sender,
+                                &stats,
+                            )
+                            .map(|_| ControlReply::Done)
+                        }
+                        Err(why) => Err(ProcessingThreadError::CannotLoadModel(why.to_string())),
+                    }
+                }
+                MessageType::SetFilter(filter) => {
+                    cfg = cfg.with_filter(filter);
+                    tracker.lock().unwrap().set_mode(filter.tracking);
+                    Ok(ControlReply::Done)
+                }
+                MessageType::SetDecode(decode) => {
+                    cfg = cfg.with_decode(decode);
+                    let new_workers =
+                        spawn_workers(&cfg, &tracker, &sequencer_sender, &stats, &pool)?;
+                    retire_workers(
+                        std::mem::replace(&mut workers, new_workers),
+                        &sequencer_sender,
+                        &stats,
+                    )
+                    .map(|_| ControlReply::Done)
+                }
+                MessageType::SetPreprocess(preprocess) => {
+                    cfg = cfg.with_preprocess(preprocess);
+                    // the face boxes were found in a differently cropped or turned image
+                    clear_faces(&tracker, &sequencer_sender)
+                }
+                MessageType::SetPreview(preview) => {
+                    cfg = cfg.with_preview(preview);
+                    Ok(ControlReply::Done)
+                }
+                MessageType::Calibrate => {
+                    // answered by the sequencer
+                    if sequencer_sender
+                        .send(SequencerMessage::Calibrate(id))
+                        .is_err()
+                    {
+                        return Err(ProcessingThreadError::Disconnected);
+                    }
+                    continue;
+                }
+                MessageType::SetCalibration(calibration) => {
+                    cfg = cfg.with_calibration(calibration);
+                    sequencer_sender
+                        .send(SequencerMessage::SetCalibration(calibration))
+                        .map(|_| ControlReply::Done)
+                        .map_err(|_| ProcessingThreadError::Disconnected)
+                }
+                MessageType::QueryDevice => Ok(ControlReply::Device(DeviceState {
+                    name: device.name(),
+                    res: device.get_resolution().unwrap_or_else(|_| running.res()),
+                    fps: device.get_framerate().unwrap_or_else(|_| running.fps()),
+                    paused,
+                    backend: cfg.backend().clone(),
+                    scale_factor: cfg.scale_factor(),
+                })),
+                MessageType::QueryStats => Ok(ControlReply::Stats(stats.snapshot())),
+                MessageType::SetControl(control, value) => device
+                    .set_control(control, value)
+                    .map(|_| ControlReply::Done)
+                    .map_err(|why| ProcessingThreadError::CannotConfigureCamera(why.to_string())),
+                MessageType::QueryControls => device
+                    .get_controls()
+                    .map(ControlReply::Controls)
+                    .map_err(|why| ProcessingThreadError::CannotConfigureCamera(why.to_string())),
+            };
+            reply(&responses, id, result);
         }
 
-        // get frame
-        let mut frame_data = match device.get_frame() {
-            Ok(f) => f,
-            Err(why) => {
-                godot_print!("died {}, {}", line!(), why.to_string());
-                return 255;
-            }
-        };
-        let res = device.get_resolution().unwrap();
-        frame_data.resize((res.x * res.y * 3) as usize, 0_u8);
+        // the sequencer goes away once nobody reads the packets anymore
+        if sequencer_sender.is_disconnected() {
+            return Err(ProcessingThreadError::Disconnected);
+        }
 
-        let framebuf = match ImageBuffer::from_raw(res.x, res.y, frame_data) {
-            Some(v) => {
-                let img_buf: ImageBuffer<Rgb<u8>, Vec<u8>> = v;
-                (ImageMatrix::from_image(&img_buf), img_buf)
+        // get frame, the workers decode it
+        let capture_start = Instant::now();
+        let frame_data = device.get_raw_frame(&pool).map_err(|why| {
+            match why.downcast_ref::<InvalidDeviceError>() {
+                Some(InvalidDeviceError::EndOfStream) => ProcessingThreadError::EndOfStream,
+                _ => ProcessingThreadError::CannotCapture(why.to_string()),
             }
-            None => {
-                godot_print!("no frame");
-                continue;
-            }
-        };
+        })?;
+        stats.record_stage(Stage::Capture, capture_start.elapsed());
+        frame_number += 1;
+        stats.frame_captured();
 
-        for rect in face_detector.face_locations(&framebuf.0).iter() {
-            let landmarks = ld_detector.face_landmarks(&framebuf.0, rect);
-
-            let mut pt_vec = vec![];
-            let mut point_vec = vec![];
-            for lm_point in landmarks.iter() {
-                pt_vec.push(Point2D {
-                    x: lm_point.x() as f64,
-                    y: lm_point.y() as f64,
-                });
-                point_vec.push(*lm_point)
+        // keep reading the camera while paused so we don't get a pile of old frames on resume
+        if paused {
+            match step_request.take() {
+                Some(id) => reply(&responses, id, Ok(ControlReply::Done)),
+                None => continue,
             }
+        }
 
-            let facelandmark = FaceLandmark::from_dlib(BoundingBox::from(*rect), point_vec);
-
-            let pnp = match pnp_solver.calculate(&framebuf.1, facelandmark) {
-                Some(pnp) => {
-                    prev_euler = pnp;
-                    godot_print!("euler: {}", pnp);
-                    pnp
+        let mut frame = CapturedFrame {
+            seq,
+            frame_number,
+            work_res: cfg.working_res(frame_data.res()),
+            preprocess: cfg.preprocess(),
+            preview: cfg.preview(),
+            frame: frame_data,
+        };
+        // hand frames out round robin so the sequencer knows exactly which seq comes next.
+        // if that worker is still busy throw out its oldest frame instead of waiting on it.
+        let worker = &workers[(seq % workers.len() as u64) as usize];
+        // it couldn't load its models or it panicked, either way its frames are never coming
+        if !worker.is_alive() {
+            return Err(ProcessingThreadError::WorkerDied);
+        }
+        seq += 1;
+        if cfg.lossless() {
+            // `overflow` keeps the channel open even if the worker is gone, so keep checking
+            loop {
+                match worker.frames.send_timeout(frame, WORKER_CHECK_INTERVAL) {
+                    Ok(_) => break,
+                    Err(SendTimeoutError::Timeout(returned)) => {
+                        if !worker.is_alive() {
+                            return Err(ProcessingThreadError::WorkerDied);
+                        }
+                        frame = returned;
+                    }
+                    Err(SendTimeoutError::Disconnected(_)) => {
+                        return Err(ProcessingThreadError::WorkerDied)
+                    }
+                }
+            }
+            continue;
+        }
+        loop {
+            match worker.frames.try_send(frame) {
+                Ok(_) => break,
+                Err(TrySendError::Full(returned)) => {
+                    if let Ok(old) = worker.overflow.try_recv() {
+                        stats.dropped_capture();
+                        if sequencer_sender
+                            .send(SequencerMessage::Dropped(old.seq))
+                            .is_err()
+                        {
+                            return Err(ProcessingThreadError::Disconnected);
+                        }
+                    }
+                    frame = returned;
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    return Err(ProcessingThreadError::WorkerDied)
                 }
-                None => prev_euler,
-            };
-
-            let facebox_2d = Box2D::new(
-                EPoint2D::new(rect.left as i32, rect.bottom as i32),
-                EPoint2D::new(rect.right as i32, rect.top as i32),
-            );
-
-            if sender
-                .send(FullyCalculatedPacket {
-                    face_location: facebox_2d,
-                    landmarks: pt_vec,
-                    euler: pnp,
-                })
-                .is_err()
-            {
-                godot_print!("died {}", line!());
-                return 254;
             }
         }
     }
 }
 
+// `get_dyn_webcam`, streaming already
+fn open_webcam<'a>(
+    name: Option<String>,
+    device: PossibleDevice,
+    lossless: bool,
+) -> Result<Box<dyn Webcam<'a> + 'a>, Box<dyn std::error::Error>> {
+    let webcam = get_dyn_webcam(name, device, lossless)?;
+    webcam.open_stream()?;
+    Ok(webcam)
+}
+
+// `lossless` plays video files as fast as they can be read, see `BackendConfig::with_lossless`
 fn get_dyn_webcam<'a>(
     name: Option<String>,
     device: PossibleDevice,
+    lossless: bool,
 ) -> Result<Box<dyn Webcam<'a> + 'a>, Box<dyn std::error::Error>> {
+    let fmt = device.fmt();
     let device_held: Box<dyn Webcam<'a>> = match device {
         PossibleDevice::UniversalVideoCamera {
             vendor_id,
@@ -287,6 +878,7 @@ fn get_dyn_webcam<'a>(
                     return Err(why);
                 }
             };
+            handle_boxerr!(uvcam.set_format(fmt));
             handle_boxerr!(uvcam.set_framerate(fps));
             handle_boxerr!(uvcam.set_resolution(res));
             Box::new(uvcam)
@@ -303,6 +895,8 @@ fn get_dyn_webcam<'a>(
                     return Err(why);
                 }
             };
+            // the format goes to the driver together with the resolution
+            handle_boxerr!(v4lcam.set_format(fmt));
             handle_boxerr!(v4lcam.set_resolution(res));
             handle_boxerr!(v4lcam.set_framerate(fps));
             Box::new(v4lcam)
@@ -326,6 +920,14 @@ fn get_dyn_webcam<'a>(
             handle_boxerr!(ocvcam.set_framerate(fps));
             Box::new(ocvcam)
         }
+        PossibleDevice::VideoFile { path, .. } => {
+            let video = VideoFileDevice::new(path)?;
+            if lossless {
+                Box::new(video.unpaced())
+            } else {
+                Box::new(video)
+            }
+        }
     };
 
     Ok(device_held)
@@ -0,0 +1,347 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{
+    error::processing_error::ProcessingError::{
+        CnnModelNotFound, InvalidModelOutput, LandmarkPredictorNotFound, OnnxModelNotFound,
+    },
+    ret_boxerr,
+    util::misc::{resolve_model_path, Backend},
+};
+use dlib_face_recognition::{
+    FaceDetector, FaceDetectorCnn, FaceDetectorTrait, ImageMatrix, LandmarkPredictor,
+    LandmarkPredictorTrait, Point, Rectangle,
+};
+use facial_processing::utils::misc::Point2D;
+use image::{
+    imageops::{crop_imm, resize, FilterType},
+    ImageBuffer, Rgb,
+};
+use onnxruntime::{
+    environment::Environment, ndarray::Array4, session::Session, tensor::OrtOwnedTensor,
+    GraphOptimizationLevel, LoggingLevel,
+};
+use serde::{Deserialize, Serialize};
+use std::{error::Error, path::Path};
+
+/// The model directory when nobody says otherwise, relative to the working directory.
+pub const DEFAULT_MODEL_DIR: &str = "models";
+pub const DLIB_68_MODEL_FILE: &str =
+    "facial-processing-rs-models/shape_predictor_68_face_landmarks.dat";
+pub const DLIB_CNN_MODEL_FILE: &str = "facial-processing-rs-models/mmod_human_face_detector.dat";
+
+// One ONNX environment for the whole program, same deal as the UVC context in lib.rs.
+lazy_static! {
+    static ref ONNX_ENVIRONMENT: Environment = Environment::builder()
+        .with_name("open2dholo")
+        .with_log_level(LoggingLevel::Warning)
+        .build()
+        .unwrap();
+}
+
+// WFLW 98 point index -> iBUG 300-W 68 point index.
+// jaw (17), brows (5 + 5), nose (9), eyes (6 + 6), mouth (20)
+const WFLW_98_TO_68: [usize; 68] = [
+    0, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 22, 24, 26, 28, 30, 32, // jaw
+    33, 34, 35, 36, 37, // left brow
+    42, 43, 44, 45, 46, // right brow
+    51, 52, 53, 54, 55, 56, 57, 58, 59, // nose
+    60, 61, 63, 64, 65, 67, // left eye
+    68, 69, 71, 72, 73, 75, // right eye
+    76, 77, 78, 79, 80, 81, 82, 83, 84, 85, 86, 87, 88, 89, 90, 91, 92, 93, 94, 95, // mouth
+];
+
+/// A face landmark model. Every implementation has to return points in the iBUG 68 point layout
+/// (the one dlib uses), in the coordinate space of the full frame.
+pub trait LandmarkModel {
+    fn name(&self) -> &'static str;
+    fn predict(
+        &mut self,
+        image: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        matrix: &ImageMatrix,
+        face: &Rectangle,
+    ) -> Result<Vec<Point2D>, Box<dyn Error>>;
+}
+
+/// How the raw output tensor of an ONNX landmark model is laid out.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum LandmarkLayout {
+    /// 68 (x, y) pairs, already in the dlib order.
+    Ibug68,
+    /// 68 (x, y, z) triples, e.g. 3DDFA-style exports. The depth is thrown away.
+    Ibug68Xyz,
+    /// 98 (x, y) pairs from models trained on WFLW, such as most PFLD exports.
+    Wflw98,
+}
+
+impl LandmarkLayout {
+    pub fn point_count(self) -> usize {
+        match self {
+            LandmarkLayout::Ibug68 | LandmarkLayout::Ibug68Xyz => 68,
+            LandmarkLayout::Wflw98 => 98,
+        }
+    }
+
+    pub fn stride(self) -> usize {
+        match self {
+            LandmarkLayout::Ibug68 | LandmarkLayout::Wflw98 => 2,
+            LandmarkLayout::Ibug68Xyz => 3,
+        }
+    }
+
+    pub fn output_len(self) -> usize {
+        self.point_count() * self.stride()
+    }
+
+    /// Turn a flat output tensor into 68 points, still in whatever space the model outputs.
+    pub fn normalize(self, raw: &[f32]) -> Result<Vec<Point2D>, Box<dyn Error>> {
+        if raw.len() != self.output_len() {
+            ret_boxerr!(InvalidModelOutput {
+                expected: self.output_len(),
+                got: raw.len(),
+            })
+        }
+        let points: Vec<Point2D> = raw
+            .chunks_exact(self.stride())
+            .map(|pt| Point2D {
+                x: f64::from(pt[0]),
+                y: f64::from(pt[1]),
+            })
+            .collect();
+
+        match self {
+            LandmarkLayout::Ibug68 | LandmarkLayout::Ibug68Xyz => Ok(points),
+            LandmarkLayout::Wflw98 => Ok(WFLW_98_TO_68
+                .iter()
+                .map(|idx| points[*idx].clone())
+                .collect()),
+        }
+    }
+}
+
+/// What space the coordinates coming out of the ONNX model are in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum LandmarkScale {
+    /// 0.0 ~ 1.0 relative to the face crop.
+    Normalized,
+    /// Pixels of the model input (0 ~ `input_size`).
+    InputPixels,
+}
+
+pub struct DlibLandmarkModel {
+    predictor: LandmarkPredictor,
+}
+
+impl DlibLandmarkModel {
+    pub fn new(path: String) -> Result<Self, Box<dyn Error>> {
+        match LandmarkPredictor::new(path.clone()) {
+            Ok(predictor) => Ok(DlibLandmarkModel { predictor }),
+            Err(_why) => ret_boxerr!(LandmarkPredictorNotFound(path)),
+        }
+    }
+}
+
+impl LandmarkModel for DlibLandmarkModel {
+    fn name(&self) -> &'static str {
+        "dlib"
+    }
+
+    fn predict(
+        &mut self,
+        _image: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        matrix: &ImageMatrix,
+        face: &Rectangle,
+    ) -> Result<Vec<Point2D>, Box<dyn Error>> {
+        Ok(self
+            .predictor
+            .face_landmarks(matrix, face)
+            .iter()
+            .map(|pt| Point2D {
+                x: pt.x() as f64,
+                y: pt.y() as f64,
+            })
+            .collect())
+    }
+}
+
+pub struct OnnxLandmarkModel {
+    session: Session<'static>,
+    input_size: u32,
+    padding: f64,
+    layout: LandmarkLayout,
+    scale: LandmarkScale,
+}
+
+impl OnnxLandmarkModel {
+    pub fn new(
+        path: String,
+        input_size: u32,
+        layout: LandmarkLayout,
+        scale: LandmarkScale,
+    ) -> Result<Self, Box<dyn Error>> {
+        if !std::path::Path::new(&path).exists() {
+            ret_boxerr!(OnnxModelNotFound(path))
+        }
+        let session = ONNX_ENVIRONMENT
+            .new_session_builder()?
+            .with_optimization_level(GraphOptimizationLevel::Basic)?
+            .with_number_threads(1)?
+            .with_model_from_file(path)?;
+
+        // trust the model over the config if it has a fixed input size
+        let input_size = session
+            .inputs
+            .get(0)
+            .and_then(|input| input.dimensions.get(3).copied().flatten())
+            .unwrap_or(input_size);
+
+        Ok(OnnxLandmarkModel {
+            session,
+            input_size,
+            padding: 0.1,
+            layout,
+            scale,
+        })
+    }
+}
+
+impl LandmarkModel for OnnxLandmarkModel {
+    fn name(&self) -> &'static str {
+        "onnx"
+    }
+
+    fn predict(
+        &mut self,
+        image: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        _matrix: &ImageMatrix,
+        face: &Rectangle,
+    ) -> Result<Vec<Point2D>, Box<dyn Error>> {
+        let (crop_x, crop_y, crop_side) = square_crop(image, face, self.padding);
+        let face_img = crop_imm(image, crop_x, crop_y, crop_side, crop_side).to_image();
+        let input_img = resize(
+            &face_img,
+            self.input_size,
+            self.input_size,
+            FilterType::Triangle,
+        );
+
+        // NCHW, 0.0 ~ 1.0
+        let side = self.input_size as usize;
+        let input = Array4::from_shape_fn((1, 3, side, side), |(_, c, y, x)| {
+            f32::from(input_img.get_pixel(x as u32, y as u32)[c]) / 255_f32
+        });
+
+        let outputs: Vec<OrtOwnedTensor<f32, _>> = self.session.run(vec![input])?;
+        // PFLD style models export the auxiliary pose branch too, pick the one that fits
+        let raw: Vec<f32> = match outputs
+            .iter()
+            .find(|out| out.len() == self.layout.output_len())
+        {
+            Some(out) => out.iter().copied().collect(),
+            None => ret_boxerr!(InvalidModelOutput {
+                expected: self.layout.output_len(),
+                got: outputs.get(0).map_or(0, |out| out.len()),
+            }),
+        };
+
+        let unit = match self.scale {
+            LandmarkScale::Normalized => 1_f64,
+            LandmarkScale::InputPixels => f64::from(self.input_size),
+        };
+        let crop_scale = f64::from(crop_side) / unit;
+
+        Ok(self
+            .layout
+            .normalize(&raw)?
+            .into_iter()
+            .map(|pt| Point2D {
+                x: f64::from(crop_x) + pt.x * crop_scale,
+                y: f64::from(crop_y) + pt.y * crop_scale,
+            })
+            .collect())
+    }
+}
+
+/// Relative model paths are looked up in `model_dir`.
+pub fn landmark_model_from_backend(
+    backend: &Backend,
+    model_dir: &Path,
+) -> Result<Box<dyn LandmarkModel>, Box<dyn Error>> {
+    match backend {
+        Backend::Dlib => Ok(Box::new(DlibLandmarkModel::new(resolve_model_path(
+            model_dir,
+            DLIB_68_MODEL_FILE,
+        ))?)),
+        Backend::Onnx {
+            model_path,
+            input_size,
+            layout,
+            scale,
+        } => Ok(Box::new(OnnxLandmarkModel::new(
+            resolve_model_path(model_dir, model_path),
+            *input_size,
+            *layout,
+            *scale,
+        )?)),
+    }
+}
+
+/// dlib's HOG face detector, or its CNN one if `use_cnn`. The CNN model is looked up in
+/// `model_dir`.
+pub fn face_detector(
+    use_cnn: bool,
+    model_dir: &Path,
+) -> Result<Box<dyn FaceDetectorTrait>, Box<dyn Error>> {
+    if !use_cnn {
+        return Ok(Box::new(FaceDetector::new()));
+    }
+    let path = resolve_model_path(model_dir, DLIB_CNN_MODEL_FILE);
+    match FaceDetectorCnn::new(path.clone()) {
+        Ok(detector) => Ok(Box::new(detector)),
+        Err(_why) => ret_boxerr!(CnnModelNotFound(path)),
+    }
+}
+
+/// Convert the points back into dlib points so they can go through `FaceLandmark::from_dlib`.
+pub fn to_dlib_points(points: &[Point2D]) -> Vec<Point> {
+    points
+        .iter()
+        .map(|pt| Point::new(pt.x.round() as i64, pt.y.round() as i64))
+        .collect()
+}
+
+// Square box around the face, padded and clamped to the image. Returns (x, y, side).
+fn square_crop(
+    image: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    face: &Rectangle,
+    padding: f64,
+) -> (u32, u32, u32) {
+    let width = (face.right - face.left) as f64;
+    let height = (face.bottom - face.top) as f64;
+    let center_x = face.left as f64 + width / 2_f64;
+    let center_y = face.top as f64 + height / 2_f64;
+    let side = (width.max(height) * (1_f64 + padding))
+        .min(f64::from(image.width().min(image.height())))
+        .max(1_f64);
+
+    let x = (center_x - side / 2_f64)
+        .max(0_f64)
+        .min(f64::from(image.width()) - side);
+    let y = (center_y - side / 2_f64)
+        .max(0_f64)
+        .min(f64::from(image.height()) - side);
+    (x as u32, y as u32, side as u32)
+}
@@ -14,5 +14,15 @@
 //     You should have received a copy of the GNU General Public License
 //     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod aspect_ratio;
+pub mod confidence;
+pub mod face_identity;
 pub mod input_processor;
+pub mod landmark;
+pub mod pipeline;
+pub mod pipeline_stats;
 pub mod pnp;
+pub mod preprocess;
+pub mod smoothing;
+pub mod supervisor;
+pub mod tracker;
@@ -0,0 +1,579 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The stages after capture. The capture thread hands frames out round robin to the analysis
+// workers (detection, landmarks, pose), and the sequencer puts the results back into capture
+// order and does everything that depends on the previous frame (face IDs, tracking).
+
+use crate::{
+    log_debug, log_error, log_warn,
+    processing::{
+        confidence::FaceConfidence,
+        face_identity::{FaceIdentifier, PrimaryFacePolicy},
+        landmark::{face_detector, landmark_model_from_backend, to_dlib_points},
+        pipeline_stats::{PipelineStats, Stage},
+        pnp::FacePnP,
+        preprocess::{preprocess, CoordMap, PreprocessConfig},
+        tracker::FaceTracker,
+    },
+    util::{
+        camera::{
+            device_utils::Resolution,
+            frame::{Frame, FramePool, PixelFormat},
+        },
+        misc::{
+            Backend, ControlReply, ControlResponse, DecodeOptions, FullyCalculatedPacket,
+            NeutralPose,
+        },
+    },
+};
+use dlib_face_recognition::{FaceDetector, FaceDetectorTrait, ImageMatrix, Rectangle};
+use euclid::{Box2D, Point2D as EPoint2D};
+use facial_processing::utils::{
+    face::FaceLandmark,
+    misc::{BoundingBox, EulerAngles, Point2D},
+};
+use flume::{Receiver, SendTimeoutError, Sender, TrySendError};
+use image::{
+    imageops::{resize, FilterType},
+    ImageBuffer, Rgb,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    line,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// how often a lossless send that is waiting on the reader checks whether there still is one
+const READER_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A raw frame on its way from the capture thread to a worker.
+pub struct CapturedFrame {
+    /// Position in the order frames were handed to the workers. Has no gaps, unlike `frame_number`.
+    pub seq: u64,
+    pub frame_number: u64,
+    pub work_res: Resolution,
+    pub preprocess: PreprocessConfig,
+    /// Send a copy of this frame to the UI.
+    pub preview: bool,
+    pub frame: Frame,
+}
+
+pub struct AnalyzedFace {
+    rect: Rectangle,
+    landmarks: Vec<Point2D>,
+    pose: Option<(EulerAngles, f64)>,
+}
+
+pub struct AnalyzedFrame {
+    seq: u64,
+    frame_number: u64,
+    captured_at: Instant,
+    res: Resolution,
+    work_res: Resolution,
+    /// After preprocessing, `faces` are in its coordinates.
+    image: ImageBuffer<Rgb<u8>, Vec<u8>>,
+    map: CoordMap,
+    faces: Vec<AnalyzedFace>,
+    preview: Option<Frame>,
+}
+
+/// An RGB frame for the UI, with the number of the packets that were found in it.
+pub struct PreviewFrame {
+    pub frame_number: u64,
+    pub frame: Frame,
+    /// What the camera delivered, the packets are in its coordinates. Not necessarily the size
+    /// of `frame`, and it can change under a running pipeline when the device does.
+    pub source_res: Resolution,
+}
+
+pub enum SequencerMessage {
+    Analyzed(AnalyzedFrame),
+    /// The frame with this `seq` is never coming, don't wait for it.
+    Dropped(u64),
+    /// Face boxes from before this point are in a different working resolution.
+    ClearFaces,
+    SetPrimaryFace(PrimaryFacePolicy),
+    /// Use the next primary face pose as neutral, then answer the request with this id.
+    Calibrate(u64),
+    SetCalibration(Option<NeutralPose>),
+}
+
+/// Worker thread body. Runs until the capture thread hangs up or the sequencer is gone.
+pub fn analyze_frames(
+    backend: Backend,
+    cnn_detector: bool,
+    model_dir: PathBuf,
+    decode: DecodeOptions,
+    frames: Receiver<CapturedFrame>,
+    tracker: Arc<Mutex<FaceTracker>>,
+    results: Sender<SequencerMessage>,
+    stats: Arc<PipelineStats>,
+    pool: FramePool,
+) {
+    let face_detector: Box<dyn FaceDetectorTrait> = match face_detector(cnn_detector, &model_dir) {
+        Ok(detector) => detector,
+        Err(why) => {
+            // still better than not tracking at all
+            log_warn!(Model, "Using the default face detector: {}", why);
+            Box::new(FaceDetector::new())
+        }
+    };
+    let mut landmark_model = match landmark_model_from_backend(&backend, &model_dir) {
+        Ok(model) => model,
+        Err(why) => {
+            log_error!(Model, "Worker could not load the landmark model: {}", why);
+            return;
+        }
+    };
+    let pnp_solver = FacePnP::new();
+
+    for frame in frames.iter() {
+        let seq = frame.seq;
+        let frame_number = frame.frame_number;
+        let captured_at = frame.frame.timestamp();
+        let res = frame.frame.res();
+        let work_res = frame.work_res;
+        let preprocess_cfg = frame.preprocess;
+        let wants_preview = frame.preview;
+
+        let decoded = stats.time(Stage::Decode, || {
+            if decode.keep_full_frame {
+                frame.frame.into_rgb24(&pool)
+            } else if decode.grayscale {
+                // dlib still wants three channels, spreading the luma out is cheap next to the decode
+                frame
+                    .frame
+                    .into_gray8_scaled(&pool, work_res)
+                    .and_then(|gray| gray.into_rgb24(&pool))
+            } else {
+                frame.frame.into_rgb24_scaled(&pool, work_res)
+            }
+        });
+
+        let working = decoded.and_then(|decoded| {
+            if decode.keep_full_frame {
+                let image = stats.time(Stage::Resize, || working_image(&decoded, work_res, &pool));
+                image.map(|image| (image, Some(decoded)))
+            } else {
+                let mut img_buf = decoded.into_image()?;
+                // compare against what was decoded, cameras don't always send what they were asked for
+                if img_buf.dimensions() != (work_res.x, work_res.y) {
+                    let resized = stats.time(Stage::Resize, || {
+                        resize(&img_buf, work_res.x, work_res.y, FilterType::Triangle)
+                    });
+                    pool.recycle(std::mem::replace(&mut img_buf, resized).into_raw());
+                }
+                Some((img_buf, None))
+            }
+        });
+        let (image, full) = match working {
+            Some(v) => v,
+            None => {
+                log_debug!(
+                    Camera,
+                    "Dropped frame {}, it could not be decoded",
+                    frame_number
+                );
+                if results.send(SequencerMessage::Dropped(seq)).is_err() {
+                    return;
+                }
+                continue;
+            }
+        };
+        // the full frame is the better preview if there is one, otherwise copy the working image
+        // before preprocessing changes it. packets are in camera coordinates, so is this.
+        let preview = match full {
+            Some(full) if wants_preview => Some(full),
+            _ if wants_preview => {
+                let mut buffer = pool.buffer(image.as_raw().len());
+                buffer.extend_from_slice(image.as_raw());
+                Some(Frame::new(
+                    PixelFormat::Rgb24,
+                    work_res,
+                    captured_at,
+                    buffer,
+                ))
+            }
+            _ => None,
+        };
+        let (image, map) = stats.time(Stage::Preprocess, || {
+            preprocess(image, &preprocess_cfg, &pool)
+        });
+        let matrix = stats.time(Stage::ImageMatrix, || ImageMatrix::from_image(&image));
+
+        let detection_start = Instant::now();
+        // only go through the (slow) full frame detector when the tracker asks for it
+        let mut face_rects: Vec<Rectangle> = {
+            let mut tracker = tracker.lock().unwrap();
+            if tracker.needs_detection(seq) {
+                Vec::new()
+            } else {
+                tracker.predict_rois(&image)
+            }
+        };
+        if face_rects.is_empty() {
+            tracker.lock().unwrap().detected();
+            face_rects = face_detector
+                .face_locations(&matrix)
+                .iter()
+                .copied()
+                .collect();
+        }
+        stats.record_stage(Stage::Detection, detection_start.elapsed());
+
+        let mut landmarks_took = Duration::default();
+        let mut pnp_took = Duration::default();
+        let mut faces = Vec::with_capacity(face_rects.len());
+        for rect in face_rects {
+            let landmarks_start = Instant::now();
+            let predicted = landmark_model.predict(&image, &matrix, &rect);
+            landmarks_took += landmarks_start.elapsed();
+            let landmarks = match predicted {
+                Ok(points) => points,
+                Err(why) => {
+                    log_warn!(Model, "{} landmarks failed: {}", landmark_model.name(), why);
+                    continue;
+                }
+            };
+            let facelandmark =
+                FaceLandmark::from_dlib(BoundingBox::from(rect), to_dlib_points(&landmarks));
+            let pnp_start = Instant::now();
+            let pose = pnp_solver.calculate_with_error(&image, facelandmark);
+            pnp_took += pnp_start.elapsed();
+            faces.push(AnalyzedFace {
+                rect,
+                landmarks,
+                pose,
+            });
+        }
+
+        stats.record_stage(Stage::Landmarks, landmarks_took);
+        stats.record_stage(Stage::Pnp, pnp_took);
+
+        let analyzed = AnalyzedFrame {
+            seq,
+            frame_number,
+            captured_at,
+            res,
+            work_res,
+            image,
+            map,
+            faces,
+            preview,
+        };
+        if results.send(SequencerMessage::Analyzed(analyzed)).is_err() {
+            return;
+        }
+    }
+}
+
+// The detection input for a frame that has to stay around at full size, without giving it up.
+fn working_image(
+    frame: &Frame,
+    work_res: Resolution,
+    pool: &FramePool,
+) -> Option<ImageBuffer<Rgb<u8>, Vec<u8>>> {
+    let Resolution { x, y } = frame.res();
+    let full: ImageBuffer<Rgb<u8>, &[u8]> = ImageBuffer::from_raw(x, y, frame.data())?;
+    if (x, y) == (work_res.x, work_res.y) {
+        let mut copy = pool.buffer(frame.data().len());
+        copy.extend_from_slice(frame.data());
+        return ImageBuffer::from_raw(x, y, copy.into_vec());
+    }
+    Some(resize(&full, work_res.x, work_res.y, FilterType::Triangle))
+}
+
+/// Sequencer thread body. Returns once every worker is gone or nobody listens for packets anymore.
+pub fn sequence_frames(
+    primary_face: PrimaryFacePolicy,
+    neutral: Option<NeutralPose>,
+    lossless: bool,
+    incoming: Receiver<SequencerMessage>,
+    tracker: Arc<Mutex<FaceTracker>>,
+    sender: Sender<FullyCalculatedPacket>,
+    overflow: Receiver<FullyCalculatedPacket>,
+    responses: Sender<ControlResponse>,
+    stats: Arc<PipelineStats>,
+    pool: FramePool,
+    preview: Arc<Mutex<Option<PreviewFrame>>>,
+) {
+    let mut identifier = FaceIdentifier::new(primary_face);
+    let mut prev_eulers: HashMap<u32, EulerAngles> = HashMap::new();
+    let mut calibration = Calibration {
+        neutral,
+        pending: Vec::new(),
+    };
+    // frames that finished early, waiting for the ones before them
+    let mut pending: BTreeMap<u64, Option<AnalyzedFrame>> = BTreeMap::new();
+    let mut next_seq: u64 = 0;
+
+    for msg in incoming.iter() {
+        match msg {
+            SequencerMessage::Analyzed(frame) => {
+                pending.insert(frame.seq, Some(frame));
+            }
+            SequencerMessage::Dropped(seq) => {
+                pending.insert(seq, None);
+            }
+            SequencerMessage::ClearFaces => {
+                identifier.clear();
+                prev_eulers.clear();
+                continue;
+            }
+            SequencerMessage::SetPrimaryFace(policy) => {
+                identifier.set_policy(policy);
+                continue;
+            }
+            SequencerMessage::Calibrate(id) => {
+                calibration.pending.push(id);
+                continue;
+            }
+            SequencerMessage::SetCalibration(neutral) => {
+                calibration.neutral = neutral;
+                continue;
+            }
+        }
+
+        while let Some(entry) = pending.remove(&next_seq) {
+            next_seq += 1;
+            if let Some(frame) = entry {
+                let send_start = Instant::now();
+                let captured_at = frame.captured_at;
+                let sent = emit_frame(
+                    frame,
+                    &mut identifier,
+                    &mut prev_eulers,
+                    &mut calibration,
+                    &responses,
+                    &tracker,
+                    &sender,
+                    &overflow,
+                    lossless,
+                    &stats,
+                    &pool,
+                    &preview,
+                );
+                if !sent {
+                    log_debug!(
+                        Processing,
+                        "Nobody is taking packets anymore, sequencer done"
+                    );
+                    return;
+                }
+                stats.record_stage(Stage::Send, send_start.elapsed());
+                stats.frame_finished(captured_at);
+            }
+        }
+    }
+}
+
+struct Calibration {
+    neutral: Option<NeutralPose>,
+    // calibrate requests waiting for a primary face
+    pending: Vec<u64>,
+}
+
+// Everything that needs the frames in order. Returns false once nobody is listening anymore.
+#[allow(clippy::too_many_arguments)]
+fn emit_frame(
+    frame: AnalyzedFrame,
+    identifier: &mut FaceIdentifier,
+    prev_eulers: &mut HashMap<u32, EulerAngles>,
+    calibration: &mut Calibration,
+    responses: &Sender<ControlResponse>,
+    tracker: &Mutex<FaceTracker>,
+    sender: &Sender<FullyCalculatedPacket>,
+    overflow: &Receiver<FullyCalculatedPacket>,
+    lossless: bool,
+    stats: &PipelineStats,
+    pool: &FramePool,
+    preview: &Mutex<Option<PreviewFrame>>,
+) -> bool {
+    // detection and landmarks ran on the preprocessed working resolution image, map everything
+    // back to the camera resolution right before it is sent
+    let scale_x = f64::from(frame.res.x) / f64::from(frame.work_res.x);
+    let scale_y = f64::from(frame.res.y) / f64::from(frame.work_res.y);
+
+    let found_rects: Vec<Rectangle> = frame.faces.iter().map(|face| face.rect).collect();
+    let found_faces: Vec<Vec<Point2D>> = frame
+        .faces
+        .iter()
+        .map(|face| face.landmarks.clone())
+        .collect();
+
+    let face_ids = identifier.identify(&found_rects, &found_faces);
+    let image_res = Resolution::new(frame.image.width(), frame.image.height());
+    let primary_id = identifier.primary(&face_ids, &found_rects, image_res);
+    // forget the angles of faces that are gone so the map doesn't grow forever
+    prev_eulers.retain(|id, _| face_ids.contains(id));
+
+    for (face, face_id) in frame.faces.iter().zip(&face_ids) {
+        let (pnp, reprojection_error) = match face.pose {
+            Some((pnp, error)) => {
+                prev_eulers.insert(*face_id, pnp);
+                // the error is in working resolution pixels
+                (pnp, Some(error * (scale_x + scale_y) / 2_f64))
+            }
+            None => (
+                prev_eulers.get(face_id).copied().unwrap_or(EulerAngles {
+                    x: 0_f64,
+                    y: 0_f64,
+                    z: 0_f64,
+                }),
+                None,
+            ),
+        };
+        let confidence = FaceConfidence::new(&face.rect, &face.landmarks, reprojection_error);
+
+        let is_primary = primary_id == Some(*face_id);
+        if is_primary && face.pose.is_some() && !calibration.pending.is_empty() {
+            let neutral = NeutralPose {
+                x: pnp.x,
+                y: pnp.y,
+                z: pnp.z,
+            };
+            calibration.neutral = Some(neutral);
+            for id in calibration.pending.drain(..) {
+                let _ = responses.try_send(ControlResponse {
+                    id,
+                    result: Ok(ControlReply::Calibrated(neutral)),
+                });
+            }
+        }
+        let pnp = match calibration.neutral {
+            Some(neutral) => EulerAngles {
+                x: pnp.x - neutral.x,
+                y: pnp.y - neutral.y,
+                z: pnp.z - neutral.z,
+            },
+            None => pnp,
+        };
+
+        let rect = frame.map.rect_to_source(&face.rect);
+        let facebox_2d = Box2D::new(
+            EPoint2D::new(
+                (rect.left as f64 * scale_x) as i32,
+                (rect.bottom as f64 * scale_y) as i32,
+            ),
+            EPoint2D::new(
+                (rect.right as f64 * scale_x) as i32,
+                (rect.top as f64 * scale_y) as i32,
+            ),
+        );
+
+        let landmarks = face
+            .landmarks
+            .iter()
+            .map(|pt| {
+                let (x, y) = frame.map.to_source(pt.x, pt.y);
+                Point2D {
+                    x: x * scale_x,
+                    y: y * scale_y,
+                }
+            })
+            .collect();
+
+        let packet = FullyCalculatedPacket {
+            frame_number: frame.frame_number,
+            captured_at: frame.captured_at,
+            face_id: *face_id,
+            is_primary,
+            face_location: facebox_2d,
+            landmarks,
+            euler: pnp,
+            confidence,
+        };
+        let sent = if lossless {
+            send_waiting(sender, packet, stats)
+        } else {
+            send_latest(sender, overflow, packet, stats)
+        };
+        if !sent {
+            return false;
+        }
+    }
+
+    tracker
+        .lock()
+        .unwrap()
+        .update(frame.seq, &frame.image, found_faces);
+    pool.recycle(frame.image.into_raw());
+    if let Some(full) = frame.preview {
+        // whatever the UI didn't pick up yet is old news now
+        *preview.lock().unwrap() = Some(PreviewFrame {
+            frame_number: frame.frame_number,
+            frame: full,
+            source_res: frame.res,
+        });
+    }
+    true
+}
+
+// Send a packet, throwing out the oldest queued one if the UI is behind.
+// Returns false once nobody is listening anymore.
+fn send_latest(
+    sender: &Sender<FullyCalculatedPacket>,
+    overflow: &Receiver<FullyCalculatedPacket>,
+    mut packet: FullyCalculatedPacket,
+    stats: &PipelineStats,
+) -> bool {
+    // we hold a receiver ourselves, so the channel never disconnects on its own
+    if sender.receiver_count() <= 1 {
+        return false;
+    }
+    loop {
+        match sender.try_send(packet) {
+            Ok(_) => {
+                stats.packet_sent();
+                return true;
+            }
+            Err(TrySendError::Full(returned)) => {
+                if overflow.try_recv().is_ok() {
+                    stats.dropped_queue_full();
+                }
+                packet = returned;
+            }
+            Err(TrySendError::Disconnected(_)) => return false,
+        }
+    }
+}
+
+// Send a packet, waiting for the reader to make room however long it takes.
+// Returns false once nobody is listening anymore.
+fn send_waiting(
+    sender: &Sender<FullyCalculatedPacket>,
+    mut packet: FullyCalculatedPacket,
+    stats: &PipelineStats,
+) -> bool {
+    loop {
+        // our own receiver keeps the channel open, so check every now and then if anyone else
+        // is still reading
+        if sender.receiver_count() <= 1 {
+            return false;
+        }
+        match sender.send_timeout(packet, READER_CHECK_INTERVAL) {
+            Ok(_) => {
+                stats.packet_sent();
+                return true;
+            }
+            Err(SendTimeoutError::Timeout(returned)) => packet = returned,
+            Err(SendTimeoutError::Disconnected(_)) => return false,
+        }
+    }
+}
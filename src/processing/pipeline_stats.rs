@@ -0,0 +1,275 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{
+    collections::VecDeque,
+    fmt::{Display, Formatter},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+const STAGE_COUNT: usize = 9;
+// how many samples the rolling averages and the histogram look at
+const WINDOW_LEN: usize = 120;
+// upper bounds of the latency histogram buckets in milliseconds, the last bucket catches the rest
+pub const LATENCY_BUCKETS_MS: [f64; 8] = [5.0, 10.0, 20.0, 33.0, 50.0, 100.0, 200.0, 500.0];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stage {
+    Capture,
+    Decode,
+    Resize,
+    Preprocess,
+    ImageMatrix,
+    Detection,
+    Landmarks,
+    Pnp,
+    Send,
+}
+
+impl Stage {
+    pub const ALL: [Stage; STAGE_COUNT] = [
+        Stage::Capture,
+        Stage::Decode,
+        Stage::Resize,
+        Stage::Preprocess,
+        Stage::ImageMatrix,
+        Stage::Detection,
+        Stage::Landmarks,
+        Stage::Pnp,
+        Stage::Send,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Capture => "capture",
+            Stage::Decode => "decode",
+            Stage::Resize => "resize",
+            Stage::Preprocess => "preprocess",
+            Stage::ImageMatrix => "image_matrix",
+            Stage::Detection => "detection",
+            Stage::Landmarks => "landmarks",
+            Stage::Pnp => "pnp",
+            Stage::Send => "send",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Debug, Default)]
+struct Timings {
+    stages: [VecDeque<Duration>; STAGE_COUNT],
+    latencies: VecDeque<Duration>,
+    finished_at: VecDeque<Instant>,
+}
+
+fn push_rolling<T>(window: &mut VecDeque<T>, value: T) {
+    if window.len() >= WINDOW_LEN {
+        window.pop_front();
+    }
+    window.push_back(value);
+}
+
+/// Counters and timings shared between the processing threads and the UI.
+/// The counters only ever go up, the timings cover the last couple of frames.
+#[derive(Debug, Default)]
+pub struct PipelineStats {
+    frames_captured: AtomicU64,
+    packets_sent: AtomicU64,
+    dropped_capture: AtomicU64,
+    dropped_queue_full: AtomicU64,
+    dropped_stale: AtomicU64,
+    dropped_superseded: AtomicU64,
+    timings: Mutex<Timings>,
+}
+
+impl PipelineStats {
+    pub fn frame_captured(&self) {
+        self.frames_captured.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn packet_sent(&self) {
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The workers were all busy and the frame never got analysed.
+    pub fn dropped_capture(&self) {
+        self.dropped_capture.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The UI didn't pick up packets fast enough and the oldest one got thrown out.
+    pub fn dropped_queue_full(&self) {
+        self.dropped_queue_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The packet was older than the UI is willing to show.
+    pub fn dropped_stale(&self, count: u64) {
+        self.dropped_stale.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// A newer frame arrived in the same batch, so this one was never shown.
+    pub fn dropped_superseded(&self, count: u64) {
+        self.dropped_superseded.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// How long a stage took for one frame.
+    pub fn record_stage(&self, stage: Stage, took: Duration) {
+        if let Ok(mut timings) = self.timings.lock() {
+            push_rolling(&mut timings.stages[stage.index()], took);
+        }
+    }
+
+    /// Run `f` and record how long it took as `stage`.
+    pub fn time<T>(&self, stage: Stage, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let ret = f();
+        self.record_stage(stage, start.elapsed());
+        ret
+    }
+
+    /// A frame made it all the way through the pipeline.
+    pub fn frame_finished(&self, captured_at: Instant) {
+        let now = Instant::now();
+        if let Ok(mut timings) = self.timings.lock() {
+            push_rolling(&mut timings.latencies, now.duration_since(captured_at));
+            push_rolling(&mut timings.finished_at, now);
+        }
+    }
+
+    pub fn snapshot(&self) -> PipelineStatsSnapshot {
+        let mut snapshot = PipelineStatsSnapshot {
+            frames_captured: self.frames_captured.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            dropped_capture: self.dropped_capture.load(Ordering::Relaxed),
+            dropped_queue_full: self.dropped_queue_full.load(Ordering::Relaxed),
+            dropped_stale: self.dropped_stale.load(Ordering::Relaxed),
+            dropped_superseded: self.dropped_superseded.load(Ordering::Relaxed),
+            ..PipelineStatsSnapshot::default()
+        };
+
+        let timings = match self.timings.lock() {
+            Ok(timings) => timings,
+            Err(_) => return snapshot,
+        };
+        snapshot.stages = Stage::ALL
+            .iter()
+            .map(|stage| StageSummary::from_samples(*stage, &timings.stages[stage.index()]))
+            .collect();
+        snapshot.latency = StageSummary::from_samples(Stage::Send, &timings.latencies);
+        snapshot.latency_histogram = vec![0; LATENCY_BUCKETS_MS.len() + 1];
+        for latency in &timings.latencies {
+            let ms = as_ms(*latency);
+            let bucket = LATENCY_BUCKETS_MS
+                .iter()
+                .position(|bound| ms <= *bound)
+                .unwrap_or(LATENCY_BUCKETS_MS.len());
+            snapshot.latency_histogram[bucket] += 1;
+        }
+        if let (Some(first), Some(last)) = (timings.finished_at.front(), timings.finished_at.back())
+        {
+            let span = last.duration_since(*first).as_secs_f64();
+            if span > 0_f64 {
+                snapshot.fps = (timings.finished_at.len() - 1) as f64 / span;
+            }
+        }
+        snapshot
+    }
+}
+
+fn as_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000_f64
+}
+
+/// Timings of one stage over the rolling window, in milliseconds.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct StageSummary {
+    pub stage: Stage,
+    pub last_ms: f64,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+}
+
+impl StageSummary {
+    fn from_samples(stage: Stage, samples: &VecDeque<Duration>) -> Self {
+        let count = samples.len().max(1) as f64;
+        StageSummary {
+            stage,
+            last_ms: samples.back().copied().map(as_ms).unwrap_or_default(),
+            mean_ms: samples.iter().copied().map(as_ms).sum::<f64>() / count,
+            max_ms: samples.iter().copied().map(as_ms).fold(0_f64, f64::max),
+        }
+    }
+}
+
+impl Default for StageSummary {
+    fn default() -> Self {
+        StageSummary {
+            stage: Stage::Send,
+            last_ms: 0_f64,
+            mean_ms: 0_f64,
+            max_ms: 0_f64,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PipelineStatsSnapshot {
+    pub frames_captured: u64,
+    pub packets_sent: u64,
+    pub dropped_capture: u64,
+    pub dropped_queue_full: u64,
+    pub dropped_stale: u64,
+    pub dropped_superseded: u64,
+    /// Frames coming out the end of the pipeline per second.
+    pub fps: f64,
+    pub stages: Vec<StageSummary>,
+    /// Capture to send, the `stage` field means nothing here.
+    pub latency: StageSummary,
+    /// One count per bucket in `LATENCY_BUCKETS_MS`, plus one for everything slower.
+    pub latency_histogram: Vec<u64>,
+}
+
+impl PipelineStatsSnapshot {
+    pub fn total_dropped(&self) -> u64 {
+        self.dropped_capture
+            + self.dropped_queue_full
+            + self.dropped_stale
+            + self.dropped_superseded
+    }
+}
+
+impl Display for PipelineStatsSnapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.1} fps, latency {:.1}ms (max {:.1}ms), dropped {}",
+            self.fps,
+            self.latency.mean_ms,
+            self.latency.max_ms,
+            self.total_dropped()
+        )?;
+        for stage in &self.stages {
+            write!(f, ", {} {:.1}ms", stage.stage.name(), stage.mean_ms)?;
+        }
+        Ok(())
+    }
+}
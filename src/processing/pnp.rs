@@ -35,6 +35,17 @@ impl FacePnP {
         image: &ImageBuffer<Rgb<u8>, Vec<u8>>,
         face_landmarks: FaceLandmark,
     ) -> Option<EulerAngles> {
+        self.calculate_with_error(image, face_landmarks)
+            .map(|(euler, _)| euler)
+    }
+
+    /// Same as `calculate`, but also returns the mean reprojection error of the 6 points in pixels.
+    /// When LambdaTwist comes up with more than one pose, the one that fits best wins.
+    pub fn calculate_with_error(
+        &self,
+        image: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        face_landmarks: FaceLandmark,
+    ) -> Option<(EulerAngles, f64)> {
         let img_x = f64::from(image.width());
         let img_y = f64::from(image.height());
 
@@ -51,13 +62,42 @@ impl FacePnP {
                 FeatureWorldMatch(*nrm_img_pt, WorldPoint::from_point(*world_face_pt))
             });
 
-        // TODO: check out second rotation
+        let mut best: Option<(EulerAngles, f64)> = None;
         for pose in Estimator::estimate(&self.lambda, face_points_with_nrm_img_points) {
             let isometry: &Isometry<f64, U3, Rotation<f64, U3>> = pose.as_ref();
-            let (x, y, z) = isometry.rotation.euler_angles();
-            return Some(EulerAngles { x, y, z });
+            // poses with points behind the camera can't be what we are looking at
+            let error = match self.reprojection_error(isometry, &facial_landmarks_6pt, img_x, img_y)
+            {
+                Some(error) if error.is_finite() => error,
+                _ => continue,
+            };
+            if best.map_or(true, |(_, best_error)| error < best_error) {
+                let (x, y, z) = isometry.rotation.euler_angles();
+                best = Some((EulerAngles { x, y, z }, error));
+            }
         }
-        None
+        best
+    }
+
+    fn reprojection_error(
+        &self,
+        isometry: &Isometry<f64, U3, Rotation<f64, U3>>,
+        image_points: &[NormalizedKeyPoint],
+        img_x: f64,
+        img_y: f64,
+    ) -> Option<f64> {
+        let mut total = 0_f64;
+        for (world_pt, image_pt) in self.face_points.iter().zip(image_points) {
+            let camera_pt = isometry.transform_point(world_pt);
+            if camera_pt.z <= f64::EPSILON {
+                // behind or on the camera, this pose is nonsense
+                return None;
+            }
+            let dx = (camera_pt.x / camera_pt.z - image_pt.0.x) * img_x;
+            let dy = (camera_pt.y / camera_pt.z - image_pt.0.y) * img_y;
+            total += (dx * dx + dy * dy).sqrt();
+        }
+        Some(total / self.face_points.len() as f64)
     }
 }
 
@@ -66,3 +106,53 @@ impl Default for FacePnP {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cv::nalgebra::{Translation3, Vector3};
+
+    // the model points as seen by a camera looking straight at them from `distance` away
+    fn pose_at(distance: f64) -> Isometry<f64, U3, Rotation<f64, U3>> {
+        Isometry::from_parts(
+            Translation3::from(Vector3::new(0.0, 0.0, distance)),
+            Rotation::identity(),
+        )
+    }
+
+    #[test]
+    fn exact_projection_has_no_error() {
+        let pnp = FacePnP::new();
+        let pose = pose_at(1000.0);
+        let image_points: Vec<NormalizedKeyPoint> = pnp
+            .face_points
+            .iter()
+            .map(|pt| {
+                let camera_pt = pose.transform_point(pt);
+                NormalizedKeyPoint(Point2::new(
+                    camera_pt.x / camera_pt.z,
+                    camera_pt.y / camera_pt.z,
+                ))
+            })
+            .collect();
+        let error = pnp
+            .reprojection_error(&pose, &image_points, 640.0, 480.0)
+            .unwrap();
+        assert!(error < 1e-9);
+    }
+
+    #[test]
+    fn points_behind_the_camera_fail() {
+        let pnp = FacePnP::new();
+        let image_points = vec![NormalizedKeyPoint(Point2::new(0.0, 0.0)); 6];
+        assert_eq!(
+            pnp.reprojection_error(&pose_at(-1000.0), &image_points, 640.0, 480.0),
+            None
+        );
+        // the nose tip sits right on the camera
+        assert_eq!(
+            pnp.reprojection_error(&pose_at(0.0), &image_points, 640.0, 480.0),
+            None
+        );
+    }
+}
@@ -0,0 +1,380 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// What happens to a frame between decoding and detection. Runs on the working resolution image,
+// in the order crop, flip, rotate, tone. Everything found in the result gets mapped back with the
+// `CoordMap` that comes out with it.
+
+use crate::util::camera::{device_utils::Resolution, frame::FramePool};
+use dlib_face_recognition::Rectangle;
+use image::{
+    imageops::{flip_horizontal, flip_vertical, rotate180, rotate270, rotate90},
+    GenericImageView, ImageBuffer, Rgb,
+};
+use serde::{Deserialize, Serialize};
+
+// CLAHE tiles per side and how far above an even histogram a bin may go
+const CLAHE_TILES: u32 = 8;
+const CLAHE_CLIP_LIMIT: f64 = 2.0;
+
+/// Clockwise, like the camera would have to be turned to look upright.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Rotation {
+    None,
+    Cw90,
+    Cw180,
+    Cw270,
+}
+
+impl Rotation {
+    pub fn degrees(&self) -> u32 {
+        match self {
+            Rotation::None => 0,
+            Rotation::Cw90 => 90,
+            Rotation::Cw180 => 180,
+            Rotation::Cw270 => 270,
+        }
+    }
+
+    /// Rounds to the closest quarter turn.
+    pub fn from_degrees(degrees: i64) -> Self {
+        match ((degrees as f64 / 90_f64).round() as i64).rem_euclid(4) {
+            1 => Rotation::Cw90,
+            2 => Rotation::Cw180,
+            3 => Rotation::Cw270,
+            _ => Rotation::None,
+        }
+    }
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Rotation::None
+    }
+}
+
+/// The part of the frame to keep, in fractions of the frame so it still fits when the resolution
+/// changes.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CropRect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl CropRect {
+    pub fn full() -> Self {
+        CropRect {
+            x: 0_f64,
+            y: 0_f64,
+            width: 1_f64,
+            height: 1_f64,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.to_pixels(Resolution::new(10_000, 10_000)) == (0, 0, 10_000, 10_000)
+    }
+
+    /// How much of the frame's height is kept.
+    pub fn height_fraction(&self) -> f64 {
+        self.height.max(0_f64).min(1_f64)
+    }
+
+    // x, y, width, height. always at least a pixel, and never outside the frame.
+    fn to_pixels(&self, res: Resolution) -> (u32, u32, u32, u32) {
+        let fraction = |value: f64| value.max(0_f64).min(1_f64);
+        let x = (fraction(self.x) * f64::from(res.x)).round() as u32;
+        let y = (fraction(self.y) * f64::from(res.y)).round() as u32;
+        let x = x.min(res.x.saturating_sub(1));
+        let y = y.min(res.y.saturating_sub(1));
+        let width = (fraction(self.width) * f64::from(res.x)).round() as u32;
+        let height = (fraction(self.height) * f64::from(res.y)).round() as u32;
+        (
+            x,
+            y,
+            width.max(1).min(res.x - x),
+            height.max(1).min(res.y - y),
+        )
+    }
+}
+
+impl Default for CropRect {
+    fn default() -> Self {
+        CropRect::full()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PreprocessConfig {
+    /// Mirror left and right.
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+    pub rotation: Rotation,
+    pub crop: CropRect,
+    /// 1.0 leaves the image alone, anything above brightens the shadows.
+    pub gamma: f64,
+    /// Contrast limited adaptive histogram equalization, for faces in dark rooms.
+    pub clahe: bool,
+    pub grayscale: bool,
+}
+
+impl PreprocessConfig {
+    /// Whether this does nothing at all, so the stage can be skipped.
+    pub fn is_identity(&self) -> bool {
+        !self.flip_horizontal
+            && !self.flip_vertical
+            && self.rotation == Rotation::None
+            && self.crop.is_full()
+            && (self.gamma - 1_f64).abs() < f64::EPSILON
+            && !self.clahe
+            && !self.grayscale
+    }
+}
+
+impl Default for PreprocessConfig {
+    fn default() -> Self {
+        PreprocessConfig {
+            flip_horizontal: false,
+            flip_vertical: false,
+            rotation: Rotation::None,
+            crop: CropRect::full(),
+            gamma: 1_f64,
+            clahe: false,
+            grayscale: false,
+        }
+    }
+}
+
+/// Takes points in a preprocessed image back to the image it was made from.
+#[derive(Copy, Clone, Debug)]
+pub struct CoordMap {
+    crop_x: f64,
+    crop_y: f64,
+    // size after cropping, before rotating
+    width: f64,
+    height: f64,
+    flip_horizontal: bool,
+    flip_vertical: bool,
+    rotation: Rotation,
+}
+
+impl CoordMap {
+    pub fn identity(res: Resolution) -> Self {
+        CoordMap {
+            crop_x: 0_f64,
+            crop_y: 0_f64,
+            width: f64::from(res.x),
+            height: f64::from(res.y),
+            flip_horizontal: false,
+            flip_vertical: false,
+            rotation: Rotation::None,
+        }
+    }
+
+    pub fn to_source(&self, x: f64, y: f64) -> (f64, f64) {
+        let (w, h) = (self.width, self.height);
+        // undo the rotation, the other steps all happened in the unrotated frame
+        let (mut x, mut y) = match self.rotation {
+            Rotation::None => (x, y),
+            Rotation::Cw90 => (y, h - x),
+            Rotation::Cw180 => (w - x, h - y),
+            Rotation::Cw270 => (w - y, x),
+        };
+        if self.flip_horizontal {
+            x = w - x;
+        }
+        if self.flip_vertical {
+            y = h - y;
+        }
+        (x + self.crop_x, y + self.crop_y)
+    }
+
+    pub fn rect_to_source(&self, rect: &Rectangle) -> Rectangle {
+        let (x0, y0) = self.to_source(rect.left as f64, rect.top as f64);
+        let (x1, y1) = self.to_source(rect.right as f64, rect.bottom as f64);
+        Rectangle {
+            left: x0.min(x1).round() as i64,
+            top: y0.min(y1).round() as i64,
+            right: x0.max(x1).round() as i64,
+            bottom: y0.max(y1).round() as i64,
+        }
+    }
+}
+
+/// Run `image` through everything `cfg` asks for. Buffers that aren't needed anymore go back to
+/// `pool`.
+pub fn preprocess(
+    image: ImageBuffer<Rgb<u8>, Vec<u8>>,
+    cfg: &PreprocessConfig,
+    pool: &FramePool,
+) -> (ImageBuffer<Rgb<u8>, Vec<u8>>, CoordMap) {
+    let mut map = CoordMap::identity(Resolution::new(image.width(), image.height()));
+    if cfg.is_identity() {
+        return (image, map);
+    }
+
+    let mut image = image;
+    let replace = |image: &mut ImageBuffer<Rgb<u8>, Vec<u8>>, new| {
+        pool.recycle(std::mem::replace(image, new).into_raw());
+    };
+
+    if !cfg.crop.is_full() {
+        let (x, y, width, height) = cfg
+            .crop
+            .to_pixels(Resolution::new(image.width(), image.height()));
+        let cropped = image.view(x, y, width, height).to_image();
+        replace(&mut image, cropped);
+        map.crop_x = f64::from(x);
+        map.crop_y = f64::from(y);
+        map.width = f64::from(width);
+        map.height = f64::from(height);
+    }
+    if cfg.flip_horizontal {
+        let flipped = flip_horizontal(&image);
+        replace(&mut image, flipped);
+        map.flip_horizontal = true;
+    }
+    if cfg.flip_vertical {
+        let flipped = flip_vertical(&image);
+        replace(&mut image, flipped);
+        map.flip_vertical = true;
+    }
+    let rotated = match cfg.rotation {
+        Rotation::None => None,
+        Rotation::Cw90 => Some(rotate90(&image)),
+        Rotation::Cw180 => Some(rotate180(&image)),
+        Rotation::Cw270 => Some(rotate270(&image)),
+    };
+    if let Some(rotated) = rotated {
+        replace(&mut image, rotated);
+        map.rotation = cfg.rotation;
+    }
+
+    adjust_tone(&mut image, cfg);
+    (image, map)
+}
+
+// gamma, CLAHE and grayscale all only touch the brightness, so they share one pass over the image
+fn adjust_tone(image: &mut ImageBuffer<Rgb<u8>, Vec<u8>>, cfg: &PreprocessConfig) {
+    let gamma = (cfg.gamma - 1_f64).abs() >= f64::EPSILON && cfg.gamma > 0_f64;
+    if !gamma && !cfg.clahe && !cfg.grayscale {
+        return;
+    }
+
+    let mut luma: Vec<u8> = image
+        .pixels()
+        .map(|px| {
+            ((77 * u32::from(px[0]) + 150 * u32::from(px[1]) + 29 * u32::from(px[2])) >> 8) as u8
+        })
+        .collect();
+    let original = luma.clone();
+
+    if gamma {
+        let mut lut = [0_u8; 256];
+        for (value, entry) in lut.iter_mut().enumerate() {
+            *entry = (255_f64 * (value as f64 / 255_f64).powf(1_f64 / cfg.gamma)).round() as u8;
+        }
+        for value in luma.iter_mut() {
+            *value = lut[*value as usize];
+        }
+    }
+    if cfg.clahe {
+        clahe(&mut luma, image.width(), image.height());
+    }
+
+    for ((px, old), new) in image.pixels_mut().zip(original).zip(luma) {
+        if cfg.grayscale || old == 0 {
+            *px = Rgb([new, new, new]);
+        } else {
+            // keep the colour, only move the brightness
+            let ratio = f64::from(new) / f64::from(old);
+            for channel in px.0.iter_mut() {
+                *channel = (f64::from(*channel) * ratio).round().min(255_f64) as u8;
+            }
+        }
+    }
+}
+
+// Equalize every tile's histogram on its own, with the bins clipped so noise in flat areas
+// doesn't get blown up, then blend between the four closest tiles so the seams don't show.
+fn clahe(luma: &mut [u8], width: u32, height: u32) {
+    let tiles_x = CLAHE_TILES.min(width).max(1);
+    let tiles_y = CLAHE_TILES.min(height).max(1);
+    let tile_w = (width + tiles_x - 1) / tiles_x;
+    let tile_h = (height + tiles_y - 1) / tiles_y;
+
+    let mut luts = vec![[0_u8; 256]; (tiles_x * tiles_y) as usize];
+    for ty in 0..tiles_y {
+        for tx in 0..tiles_x {
+            let mut histogram = [0_u32; 256];
+            let mut count = 0_u32;
+            for y in (ty * tile_h)..((ty + 1) * tile_h).min(height) {
+                for x in (tx * tile_w)..((tx + 1) * tile_w).min(width) {
+                    histogram[luma[(y * width + x) as usize] as usize] += 1;
+                    count += 1;
+                }
+            }
+            if count == 0 {
+                continue;
+            }
+
+            let limit = ((CLAHE_CLIP_LIMIT * f64::from(count) / 256_f64) as u32).max(1);
+            let mut excess = 0;
+            for bin in histogram.iter_mut() {
+                if *bin > limit {
+                    excess += *bin - limit;
+                    *bin = limit;
+                }
+            }
+            let spread = excess / 256;
+            let leftover = (excess % 256) as usize;
+            for (idx, bin) in histogram.iter_mut().enumerate() {
+                *bin += spread + u32::from(idx < leftover);
+            }
+
+            let lut = &mut luts[(ty * tiles_x + tx) as usize];
+            let mut cdf = 0_u32;
+            for (value, bin) in histogram.iter().enumerate() {
+                cdf += bin;
+                lut[value] = (u64::from(cdf) * 255 / u64::from(count)) as u8;
+            }
+        }
+    }
+
+    // which two tiles a pixel sits between, and how far towards the second one
+    let neighbours = |pos: u32, tile: u32, tiles: u32| {
+        let centered = (f64::from(pos) + 0.5) / f64::from(tile) - 0.5;
+        let first = centered.floor().max(0_f64).min(f64::from(tiles - 1)) as u32;
+        let second = (first + 1).min(tiles - 1);
+        let weight = (centered - f64::from(first)).max(0_f64).min(1_f64);
+        (first, second, weight)
+    };
+    for y in 0..height {
+        let (ty0, ty1, wy) = neighbours(y, tile_h, tiles_y);
+        for x in 0..width {
+            let (tx0, tx1, wx) = neighbours(x, tile_w, tiles_x);
+            let idx = (y * width + x) as usize;
+            let value = luma[idx] as usize;
+            let lookup = |tx: u32, ty: u32| f64::from(luts[(ty * tiles_x + tx) as usize][value]);
+            let top = lookup(tx0, ty0) * (1_f64 - wx) + lookup(tx1, ty0) * wx;
+            let bottom = lookup(tx0, ty1) * (1_f64 - wx) + lookup(tx1, ty1) * wx;
+            luma[idx] = (top * (1_f64 - wy) + bottom * wy).round() as u8;
+        }
+    }
+}
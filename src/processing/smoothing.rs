@@ -0,0 +1,155 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Evens out the jitter dlib's landmarks have from one frame to the next, at the cost of some lag.
+
+use crate::{
+    configuration::fields::{Accessor, ConfigField, FieldAccess, SettingsSection},
+    util::misc::FullyCalculatedPacket,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Past this everything lags so far behind it looks broken.
+pub const MAX_SMOOTHING_STRENGTH: f64 = 0.95;
+
+/// How long a face can go unseen before it starts over from scratch. Most UI ticks have no new
+/// results at all, so "not in this batch" can't mean gone.
+const FACE_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SmoothingConfig {
+    pub enabled: bool,
+    /// 0.0 (follow every frame) ~ `MAX_SMOOTHING_STRENGTH`, how much of the last frame is kept.
+    pub strength: f64,
+}
+
+impl Default for SmoothingConfig {
+    fn default() -> Self {
+        SmoothingConfig {
+            enabled: false,
+            strength: 0.5,
+        }
+    }
+}
+
+impl SettingsSection for SmoothingConfig {
+    const NAME: &'static str = "smoothing";
+    const TITLE: &'static str = "Smoothing";
+    const FIELDS: &'static [ConfigField<Self>] = &[
+        ConfigField {
+            key: "smoothing.enabled",
+            label: "Smooth tracking",
+            hint: "Takes the jitter out of the landmarks and head rotation.",
+            access: FieldAccess::Toggle(Accessor {
+                get: |cfg| cfg.enabled,
+                set: |cfg, on| cfg.enabled = on,
+            }),
+        },
+        ConfigField {
+            key: "smoothing.strength",
+            label: "Smoothing strength",
+            hint: "Higher is steadier, but lags further behind.",
+            access: FieldAccess::Number {
+                min: 0.0,
+                max: MAX_SMOOTHING_STRENGTH,
+                step: 0.05,
+                value: Accessor {
+                    get: |cfg| cfg.strength,
+                    set: |cfg, strength| cfg.strength = strength,
+                },
+            },
+        },
+    ];
+}
+
+struct SmoothedFace {
+    landmarks: Vec<(f64, f64)>,
+    euler: (f64, f64, f64),
+    last_seen: Instant,
+}
+
+/// Exponential smoothing of the landmarks and head rotation, per face.
+pub struct Smoother {
+    config: SmoothingConfig,
+    faces: HashMap<u32, SmoothedFace>,
+}
+
+impl Smoother {
+    pub fn new(config: SmoothingConfig) -> Self {
+        Smoother {
+            config,
+            faces: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> SmoothingConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: SmoothingConfig) {
+        self.config = config;
+        self.faces.clear();
+    }
+
+    /// Smooths the faces of one frame in place. Faces not seen for `FACE_TIMEOUT` are forgotten,
+    /// so someone walking back in doesn't slide over from where they left.
+    pub fn apply(&mut self, packets: &mut [FullyCalculatedPacket]) {
+        if !self.config.enabled || packets.is_empty() {
+            return;
+        }
+        let keep = self.config.strength.max(0_f64).min(MAX_SMOOTHING_STRENGTH);
+        let now = Instant::now();
+        self.faces
+            .retain(|_, face| now.duration_since(face.last_seen) < FACE_TIMEOUT);
+
+        for pkt in packets.iter_mut() {
+            let face = match self.faces.get_mut(&pkt.face_id) {
+                // a different landmark count means another model, nothing to blend with
+                Some(face) if face.landmarks.len() == pkt.landmarks.len() => face,
+                _ => {
+                    self.faces.insert(
+                        pkt.face_id,
+                        SmoothedFace {
+                            landmarks: pkt.landmarks.iter().map(|pt| (pt.x, pt.y)).collect(),
+                            euler: (pkt.euler.x, pkt.euler.y, pkt.euler.z),
+                            last_seen: now,
+                        },
+                    );
+                    continue;
+                }
+            };
+            for (point, last) in pkt.landmarks.iter_mut().zip(face.landmarks.iter_mut()) {
+                point.x = blend(last.0, point.x, keep);
+                point.y = blend(last.1, point.y, keep);
+                *last = (point.x, point.y);
+            }
+            pkt.euler.x = blend(face.euler.0, pkt.euler.x, keep);
+            pkt.euler.y = blend(face.euler.1, pkt.euler.y, keep);
+            pkt.euler.z = blend(face.euler.2, pkt.euler.z, keep);
+            face.euler = (pkt.euler.x, pkt.euler.y, pkt.euler.z);
+            face.last_seen = now;
+        }
+    }
+}
+
+fn blend(last: f64, current: f64, keep: f64) -> f64 {
+    last * keep + current * (1_f64 - keep)
+}
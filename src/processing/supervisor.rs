@@ -0,0 +1,164 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::error::processing_thread_error::ProcessingThreadError;
+use std::{
+    fmt::{Display, Formatter},
+    time::{Duration, Instant},
+};
+
+/// Why the processing thread stopped.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ThreadExit {
+    /// Someone asked it to.
+    Shutdown,
+    Failed(ProcessingThreadError),
+    Panicked(String),
+}
+
+impl ThreadExit {
+    /// Whether starting the thread again on the same device has a chance of working.
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            ThreadExit::Shutdown => false,
+            ThreadExit::Failed(why) => matches!(
+                why,
+                ProcessingThreadError::CannotOpenCamera(_)
+                    | ProcessingThreadError::CannotCapture(_)
+                    | ProcessingThreadError::WorkerDied
+            ),
+            ThreadExit::Panicked(_) => true,
+        }
+    }
+}
+
+impl Display for ThreadExit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ThreadExit::Shutdown => write!(f, "The processing thread was shut down."),
+            ThreadExit::Failed(why) => write!(f, "{}", why),
+            ThreadExit::Panicked(why) => write!(f, "The processing thread crashed: {}", why),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RestartPolicy {
+    pub enabled: bool,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Give up after this many restarts in a row that never got a frame through. `None` never gives up.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            enabled: true,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: Some(10),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SupervisorAction {
+    /// Leave it dead.
+    GiveUp,
+    /// Start it again once `ready_to_restart` says so.
+    Restart { after: Duration },
+}
+
+/// Decides if and when a dead processing thread gets restarted. Doubles the wait after every
+/// restart that didn't manage to get a frame through.
+pub struct Supervisor {
+    policy: RestartPolicy,
+    attempts: u32,
+    restart_at: Option<Instant>,
+}
+
+impl Supervisor {
+    pub fn new(policy: RestartPolicy) -> Self {
+        Supervisor {
+            policy,
+            attempts: 0,
+            restart_at: None,
+        }
+    }
+
+    pub fn policy(&self) -> RestartPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: RestartPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn exited(&mut self, exit: &ThreadExit) -> SupervisorAction {
+        self.restart_at = None;
+        if !self.policy.enabled || !exit.is_recoverable() {
+            return SupervisorAction::GiveUp;
+        }
+        if let Some(max) = self.policy.max_attempts {
+            if self.attempts >= max {
+                return SupervisorAction::GiveUp;
+            }
+        }
+
+        let after = self
+            .policy
+            .initial_backoff
+            .checked_mul(2_u32.saturating_pow(self.attempts))
+            .unwrap_or(self.policy.max_backoff)
+            .min(self.policy.max_backoff);
+        self.attempts += 1;
+        self.restart_at = Some(Instant::now() + after);
+        SupervisorAction::Restart { after }
+    }
+
+    /// Whether a restart is scheduled and due. Only says yes once per scheduled restart.
+    pub fn ready_to_restart(&mut self) -> bool {
+        match self.restart_at {
+            Some(at) if Instant::now() >= at => {
+                self.restart_at = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn restart_pending(&self) -> bool {
+        self.restart_at.is_some()
+    }
+
+    /// Frames are coming through again, start counting from zero next time.
+    pub fn healthy(&mut self) {
+        self.attempts = 0;
+    }
+
+    /// Forget about any scheduled restart, e.g. because the user picked another camera.
+    pub fn cancel(&mut self) {
+        self.restart_at = None;
+        self.attempts = 0;
+    }
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Supervisor::new(RestartPolicy::default())
+    }
+}
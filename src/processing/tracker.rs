@@ -0,0 +1,278 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use dlib_face_recognition::Rectangle;
+use facial_processing::utils::misc::Point2D;
+use image::{imageops::grayscale, ImageBuffer, Rgb};
+use opencv::{
+    core::{Mat, MatTrait, Point2f, Size, TermCriteria, TermCriteria_Type, Vector},
+    video::calc_optical_flow_pyr_lk,
+};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum TrackingMode {
+    /// Run the face detector on every single frame. Slow, but never loses a face.
+    DetectEveryFrame,
+    /// Only run the face detector every `redetect_interval` frames or when the face is lost,
+    /// otherwise guess the face box from the landmarks of the last frame.
+    Roi {
+        redetect_interval: u32,
+        padding: f64,
+        optical_flow: bool,
+    },
+}
+
+impl Default for TrackingMode {
+    fn default() -> Self {
+        TrackingMode::Roi {
+            redetect_interval: 30,
+            padding: 0.15,
+            optical_flow: false,
+        }
+    }
+}
+
+struct TrackedFace {
+    landmarks: Vec<Point2D>,
+}
+
+pub struct FaceTracker {
+    mode: TrackingMode,
+    frames_since_detect: u32,
+    tracked: Vec<TrackedFace>,
+    // the seq of the frame `tracked` is from
+    tracked_seq: Option<u64>,
+    prev_gray: Option<Mat>,
+}
+
+impl FaceTracker {
+    pub fn new(mode: TrackingMode) -> Self {
+        FaceTracker {
+            mode,
+            frames_since_detect: 0,
+            tracked: Vec::new(),
+            tracked_seq: None,
+            prev_gray: None,
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: TrackingMode) {
+        self.mode = mode;
+        self.lost();
+    }
+
+    /// Whether frame `seq` has to go through the full frame face detector. With more than one
+    /// worker the faces we have can be from a few frames back, too far to guess from.
+    pub fn needs_detection(&self, seq: u64) -> bool {
+        match self.mode {
+            TrackingMode::DetectEveryFrame => true,
+            TrackingMode::Roi {
+                redetect_interval, ..
+            } => {
+                self.tracked.is_empty()
+                    || self.frames_since_detect >= redetect_interval
+                    || self.tracked_seq.map_or(true, |tracked| tracked + 1 != seq)
+            }
+        }
+    }
+
+    pub fn detected(&mut self) {
+        self.frames_since_detect = 0;
+    }
+
+    /// Predict where the faces are in this frame from the previous landmarks.
+    pub fn predict_rois(&mut self, image: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Vec<Rectangle> {
+        self.frames_since_detect += 1;
+        let (padding, optical_flow) = match self.mode {
+            TrackingMode::DetectEveryFrame => return Vec::new(),
+            TrackingMode::Roi {
+                padding,
+                optical_flow,
+                ..
+            } => (padding, optical_flow),
+        };
+
+        if optical_flow {
+            match self.flow_landmarks(image) {
+                Ok(true) => {}
+                Ok(false) | Err(_) => {
+                    // not enough points survived, go back to the detector
+                    self.lost();
+                    return Vec::new();
+                }
+            }
+        }
+
+        self.tracked
+            .iter()
+            .map(|face| landmark_bounds(&face.landmarks, padding, image.width(), image.height()))
+            .collect()
+    }
+
+    /// Store the landmarks found in frame `seq`. An empty list means we lost every face.
+    pub fn update(
+        &mut self,
+        seq: u64,
+        image: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        faces: Vec<Vec<Point2D>>,
+    ) {
+        let mut tracked = Vec::with_capacity(faces.len());
+        for landmarks in faces {
+            if plausible_landmarks(&landmarks, image.width(), image.height()) {
+                tracked.push(TrackedFace { landmarks });
+            }
+        }
+        self.tracked = tracked;
+        self.tracked_seq = Some(seq);
+        if let TrackingMode::Roi {
+            optical_flow: true, ..
+        } = self.mode
+        {
+            self.prev_gray = to_gray_mat(image).ok();
+        }
+    }
+
+    /// Forget everything, the next frame gets a full detection.
+    pub fn lost(&mut self) {
+        self.tracked.clear();
+        self.tracked_seq = None;
+        self.prev_gray = None;
+        self.frames_since_detect = 0;
+    }
+
+    // Move the stored landmarks along the optical flow between the last frame and this one.
+    // Returns false if the track should be dropped.
+    fn flow_landmarks(
+        &mut self,
+        image: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    ) -> Result<bool, Box<dyn Error>> {
+        let next_gray = to_gray_mat(image)?;
+        let prev_gray = match self.prev_gray.take() {
+            Some(prev) => prev,
+            None => {
+                self.prev_gray = Some(next_gray);
+                return Ok(true);
+            }
+        };
+
+        for face in &mut self.tracked {
+            let prev_pts: Vector<Point2f> = face
+                .landmarks
+                .iter()
+                .map(|pt| Point2f::new(pt.x as f32, pt.y as f32))
+                .collect();
+            let mut next_pts: Vector<Point2f> = Vector::new();
+            let mut status: Vector<u8> = Vector::new();
+            let mut err: Vector<f32> = Vector::new();
+
+            calc_optical_flow_pyr_lk(
+                &prev_gray,
+                &next_gray,
+                &prev_pts,
+                &mut next_pts,
+                &mut status,
+                &mut err,
+                Size::new(21, 21),
+                3,
+                TermCriteria::new(
+                    TermCriteria_Type::COUNT as i32 + TermCriteria_Type::EPS as i32,
+                    30,
+                    0.01,
+                )?,
+                0,
+                1e-4,
+            )?;
+
+            // use the median shift of the points that were found so a few bad ones don't drag the box
+            let mut shift_x = Vec::new();
+            let mut shift_y = Vec::new();
+            for (idx, found) in status.iter().enumerate() {
+                if found == 1 {
+                    let prev = prev_pts.get(idx)?;
+                    let next = next_pts.get(idx)?;
+                    shift_x.push(next.x - prev.x);
+                    shift_y.push(next.y - prev.y);
+                }
+            }
+            if shift_x.len() < face.landmarks.len() / 2 {
+                return Ok(false);
+            }
+            let dx = f64::from(median(&mut shift_x));
+            let dy = f64::from(median(&mut shift_y));
+            for pt in &mut face.landmarks {
+                pt.x += dx;
+                pt.y += dy;
+            }
+        }
+
+        self.prev_gray = Some(next_gray);
+        Ok(true)
+    }
+}
+
+// Bounding box of the landmarks, grown by `padding` on every side and clamped to the frame.
+fn landmark_bounds(landmarks: &[Point2D], padding: f64, width: u32, height: u32) -> Rectangle {
+    let (mut left, mut top) = (f64::MAX, f64::MAX);
+    let (mut right, mut bottom) = (f64::MIN, f64::MIN);
+    for pt in landmarks {
+        left = left.min(pt.x);
+        top = top.min(pt.y);
+        right = right.max(pt.x);
+        bottom = bottom.max(pt.y);
+    }
+    let pad_x = (right - left) * padding;
+    let pad_y = (bottom - top) * padding;
+    Rectangle {
+        left: (left - pad_x).max(0_f64) as i64,
+        top: (top - pad_y).max(0_f64) as i64,
+        right: (right + pad_x).min(f64::from(width) - 1_f64) as i64,
+        bottom: (bottom + pad_y).min(f64::from(height) - 1_f64) as i64,
+    }
+}
+
+// Landmarks that collapsed into a dot or wandered off the frame mean the predictor lost the face.
+fn plausible_landmarks(landmarks: &[Point2D], width: u32, height: u32) -> bool {
+    if landmarks.is_empty() {
+        return false;
+    }
+    let bounds = landmark_bounds(landmarks, 0_f64, width, height);
+    let inside = landmarks
+        .iter()
+        .filter(|pt| {
+            pt.x >= 0_f64 && pt.y >= 0_f64 && pt.x < f64::from(width) && pt.y < f64::from(height)
+        })
+        .count();
+    (bounds.right - bounds.left) > 8
+        && (bounds.bottom - bounds.top) > 8
+        && inside * 4 >= landmarks.len() * 3
+}
+
+fn to_gray_mat(image: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Result<Mat, Box<dyn Error>> {
+    let gray = grayscale(image);
+    let flat = Mat::from_slice(gray.as_raw())?;
+    let shaped = flat.reshape(1, gray.height() as i32)?;
+    // from_slice borrows the buffer, copy it so the Mat can outlive `gray`
+    let mut owned = Mat::default();
+    shaped.copy_to(&mut owned)?;
+    Ok(owned)
+}
+
+fn median(values: &mut [f32]) -> f32 {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    values[values.len() / 2]
+}
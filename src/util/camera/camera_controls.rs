@@ -0,0 +1,109 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use serde::{Deserialize, Serialize};
+
+/// The image controls we let the user touch. Everything a camera exposes beyond these is left alone.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CameraControl {
+    AutoExposure,
+    Exposure,
+    Gain,
+    Brightness,
+    Contrast,
+    AutoWhiteBalance,
+    WhiteBalance,
+    AutoFocus,
+    Focus,
+    PowerLineFrequency,
+}
+
+impl CameraControl {
+    pub const ALL: [CameraControl; 10] = [
+        CameraControl::AutoExposure,
+        CameraControl::Exposure,
+        CameraControl::Gain,
+        CameraControl::Brightness,
+        CameraControl::Contrast,
+        CameraControl::AutoWhiteBalance,
+        CameraControl::WhiteBalance,
+        CameraControl::AutoFocus,
+        CameraControl::Focus,
+        CameraControl::PowerLineFrequency,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CameraControl::AutoExposure => "Auto Exposure",
+            CameraControl::Exposure => "Exposure",
+            CameraControl::Gain => "Gain",
+            CameraControl::Brightness => "Brightness",
+            CameraControl::Contrast => "Contrast",
+            CameraControl::AutoWhiteBalance => "Auto White Balance",
+            CameraControl::WhiteBalance => "White Balance",
+            CameraControl::AutoFocus => "Auto Focus",
+            CameraControl::Focus => "Focus",
+            CameraControl::PowerLineFrequency => "Power Line Frequency",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        CameraControl::ALL
+            .iter()
+            .copied()
+            .find(|control| control.name() == name)
+    }
+
+    /// The manual control that only does something while this one is off.
+    pub fn manual_counterpart(&self) -> Option<CameraControl> {
+        match self {
+            CameraControl::AutoExposure => Some(CameraControl::Exposure),
+            CameraControl::AutoWhiteBalance => Some(CameraControl::WhiteBalance),
+            CameraControl::AutoFocus => Some(CameraControl::Focus),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ControlKind {
+    /// 0 is off, anything else is on.
+    Toggle,
+    Range,
+    /// The value is the index into the labels.
+    Menu(Vec<String>),
+}
+
+/// A control the camera supports, and what it is set to right now.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ControlDescription {
+    pub control: CameraControl,
+    pub kind: ControlKind,
+    pub min: i64,
+    pub max: i64,
+    pub step: i64,
+    pub default: i64,
+    pub value: i64,
+}
+
+impl ControlDescription {
+    pub fn clamp(&self, value: i64) -> i64 {
+        value.max(self.min).min(self.max)
+    }
+}
+
+/// What `PowerLineFrequency` means, the same on every backend.
+pub const POWER_LINE_LABELS: [&str; 3] = ["Disabled", "50 Hz", "60 Hz"];
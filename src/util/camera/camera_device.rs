@@ -17,13 +17,16 @@
 use crate::{
     error::invalid_device_error::InvalidDeviceError::{
         CannotFindDevice, CannotGetDeviceInfo, CannotGetFrame, CannotGetProperty, CannotOpenStream,
-        CannotSetProperty,
+        CannotSetProperty, EndOfStream,
     },
-    ret_boxerr,
+    log_debug, ret_boxerr,
     util::camera::{
+        camera_controls::{CameraControl, ControlDescription, ControlKind, POWER_LINE_LABELS},
         device_utils::{
-            get_os_webcam_index, DeviceContact, DeviceFormat, PathIndex, PossibleDevice, Resolution,
+            get_os_webcam_index, DeviceContact, DeviceDesc, DeviceFormat, PathIndex,
+            PossibleDevice, Resolution,
         },
+        frame::{Frame, FramePool, PixelFormat},
         webcam::{QueryCamera, Webcam, WebcamType},
     },
 };
@@ -33,8 +36,10 @@ use opencv::{
     core::{Mat, MatTrait, MatTraitManual, Vec3b},
     videoio::{
         VideoCapture, VideoCaptureAPIs::CAP_ANY, VideoCaptureProperties, VideoCaptureTrait,
-        VideoWriter, CAP_MSMF, CAP_PROP_FOURCC, CAP_PROP_FPS, CAP_PROP_FRAME_HEIGHT,
-        CAP_PROP_FRAME_WIDTH, CAP_V4L2,
+        VideoWriter, CAP_MSMF, CAP_PROP_AUTOFOCUS, CAP_PROP_AUTO_EXPOSURE, CAP_PROP_AUTO_WB,
+        CAP_PROP_BRIGHTNESS, CAP_PROP_CONTRAST, CAP_PROP_EXPOSURE, CAP_PROP_FOCUS, CAP_PROP_FOURCC,
+        CAP_PROP_FPS, CAP_PROP_FRAME_HEIGHT, CAP_PROP_FRAME_WIDTH, CAP_PROP_GAIN,
+        CAP_PROP_WB_TEMPERATURE, CAP_V4L2,
     },
 };
 use ouroboros::self_referencing;
@@ -43,9 +48,9 @@ use std::{
     convert::TryInto,
     error::Error,
     mem::MaybeUninit,
-    ops::Deref,
-    slice::from_raw_parts,
     sync::{atomic::AtomicUsize, Arc},
+    thread::sleep,
+    time::{Duration, Instant},
 };
 use usb_enumeration::enumerate;
 use uvc::{
@@ -54,6 +59,7 @@ use uvc::{
 };
 use v4l::{
     buffer::Type,
+    control::Control,
     format::Format,
     framesize::FrameSizeEnum,
     io::{mmap::Stream, traits::CaptureStream},
@@ -61,6 +67,39 @@ use v4l::{
     FourCC,
 };
 
+// frames the UVC stream callback keeps around for reuse
+const UVC_POOLED_FRAMES: usize = 4;
+
+// V4L2 control ids, see videodev2.h
+const V4L2_CID_BRIGHTNESS: u32 = 0x0098_0900;
+const V4L2_CID_CONTRAST: u32 = 0x0098_0901;
+const V4L2_CID_AUTO_WHITE_BALANCE: u32 = 0x0098_090c;
+const V4L2_CID_GAIN: u32 = 0x0098_0913;
+const V4L2_CID_POWER_LINE_FREQUENCY: u32 = 0x0098_0918;
+const V4L2_CID_WHITE_BALANCE_TEMPERATURE: u32 = 0x0098_091a;
+const V4L2_CID_EXPOSURE_AUTO: u32 = 0x009a_0901;
+const V4L2_CID_EXPOSURE_ABSOLUTE: u32 = 0x009a_0902;
+const V4L2_CID_FOCUS_ABSOLUTE: u32 = 0x009a_090a;
+const V4L2_CID_FOCUS_AUTO: u32 = 0x009a_090c;
+// exposure auto is a menu, webcams only ever do manual and aperture priority
+const V4L2_EXPOSURE_MANUAL: i64 = 1;
+const V4L2_EXPOSURE_APERTURE_PRIORITY: i64 = 3;
+
+fn v4l2_control_id(control: CameraControl) -> u32 {
+    match control {
+        CameraControl::AutoExposure => V4L2_CID_EXPOSURE_AUTO,
+        CameraControl::Exposure => V4L2_CID_EXPOSURE_ABSOLUTE,
+        CameraControl::Gain => V4L2_CID_GAIN,
+        CameraControl::Brightness => V4L2_CID_BRIGHTNESS,
+        CameraControl::Contrast => V4L2_CID_CONTRAST,
+        CameraControl::AutoWhiteBalance => V4L2_CID_AUTO_WHITE_BALANCE,
+        CameraControl::WhiteBalance => V4L2_CID_WHITE_BALANCE_TEMPERATURE,
+        CameraControl::AutoFocus => V4L2_CID_FOCUS_AUTO,
+        CameraControl::Focus => V4L2_CID_FOCUS_ABSOLUTE,
+        CameraControl::PowerLineFrequency => V4L2_CID_POWER_LINE_FREQUENCY,
+    }
+}
+
 // TODO: Split me out into a different crate!
 // Let the people have a cross-platform native Webcam library!
 
@@ -73,6 +112,8 @@ pub struct V4LinuxDevice<'a> {
     // why do i have to wrap this in 2 refcells please option give an option for a mutable reference PLEASE
     pub inner: RefCell<v4l::Device>,
     opened: Cell<bool>,
+    // resolution and bytes per row of the open stream, so frames don't each ask the driver
+    stream_format: Cell<Option<(Resolution, usize)>>,
 }
 
 impl<'a> V4LinuxDevice<'a> {
@@ -96,6 +137,7 @@ impl<'a> V4LinuxDevice<'a> {
             device_stream: RefCell::new(None),
             inner: RefCell::new(device),
             opened: Cell::new(false),
+            stream_format: Cell::new(None),
         })
     }
     pub fn new_path(path: String) -> Result<Self, Box<dyn std::error::Error>> {
@@ -118,6 +160,7 @@ impl<'a> V4LinuxDevice<'a> {
             device_stream: RefCell::new(None),
             inner: RefCell::new(device),
             opened: Cell::new(false),
+            stream_format: Cell::new(None),
         })
     }
     pub fn new_location(location: PathIndex) -> Result<Self, Box<dyn std::error::Error>> {
@@ -126,6 +169,19 @@ impl<'a> V4LinuxDevice<'a> {
             PathIndex::Index(i) => V4LinuxDevice::new(i.to_owned()),
         }
     }
+
+    fn stream_format(&self) -> Result<(Resolution, usize), Box<dyn std::error::Error>> {
+        if let Some(format) = self.stream_format.get() {
+            return Ok(format);
+        }
+        let format = self.inner.borrow().format()?;
+        let format = (
+            Resolution::new(format.width, format.height),
+            format.stride as usize,
+        );
+        self.stream_format.set(Some(format));
+        Ok(format)
+    }
 }
 
 impl<'a> Webcam<'a> for V4LinuxDevice<'a> {
@@ -138,15 +194,10 @@ impl<'a> Webcam<'a> for V4LinuxDevice<'a> {
     }
 
     fn set_resolution(&self, res: Resolution) -> Result<(), Box<dyn std::error::Error>> {
-        let mut v4l2_format = FourCC::new(b"MJPG");
-        match self.device_format.get() {
-            DeviceFormat::Yuyv => {}
-            DeviceFormat::MJpeg => {
-                v4l2_format = FourCC::new(b"MJPG");
-            }
-        }
-        let fmt = Format::new(res.x, res.y, v4l2_format);
+        let fmt = Format::new(res.x, res.y, self.device_format.get().fourcc());
         self.inner.borrow_mut().set_format(&fmt)?;
+        // the driver may have picked something close instead, ask again on the next frame
+        self.stream_format.set(None);
         Ok(())
     }
 
@@ -170,8 +221,9 @@ impl<'a> Webcam<'a> for V4LinuxDevice<'a> {
         let device = &*self.inner.borrow();
         match device.params() {
             Ok(param) => {
-                dbg!(
-                    "num: {}, denom: {}",
+                log_debug!(
+                    Camera,
+                    "frame interval {}/{}",
                     param.interval.numerator,
                     param.interval.denominator
                 );
@@ -189,11 +241,14 @@ impl<'a> Webcam<'a> for V4LinuxDevice<'a> {
 
     fn open_stream(&self) -> Result<(), Box<dyn std::error::Error>> {
         if !self.opened.get() {
-            return match Stream::with_buffers(&*self.inner.borrow_mut(), Type::VideoCapture, 60) {
+            // the device can't stay borrowed, `stream_format` needs it
+            let stream = Stream::with_buffers(&*self.inner.borrow_mut(), Type::VideoCapture, 60);
+            return match stream {
                 Ok(stream) => {
                     *self.device_stream.borrow_mut() = Some(RefCell::new(stream));
                     self.opened.set(true);
-                    Ok(())
+                    self.stream_format.set(None);
+                    self.stream_format().map(|_| ())
                 }
                 Err(why) => Err(Box::new(CannotOpenStream(why.to_string()))),
             };
@@ -202,12 +257,31 @@ impl<'a> Webcam<'a> for V4LinuxDevice<'a> {
     }
 
     fn get_frame(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        decode_single_frame(self)
+    }
+
+    fn get_raw_frame(&self, pool: &FramePool) -> Result<Frame, Box<dyn std::error::Error>> {
         match self.device_stream.try_borrow_mut() {
             Ok(m) => match &*m {
                 Some(stream) => {
                     let a = &mut *stream.borrow_mut();
+                    let fmt = self.device_format.get();
+                    let (res, stride) = self.stream_format()?;
                     match a.next() {
-                        Ok(fr) => Ok(convert_mjpeg_rgb24(fr.0)),
+                        Ok((data, meta)) => {
+                            let timestamp = Instant::now();
+                            // the mmap buffer goes back to the driver, so this is the one copy
+                            let used = (meta.bytesused as usize).min(data.len());
+                            let mut buffer = pool.buffer(used);
+                            buffer.extend_from_slice(&data[..used]);
+                            Ok(Frame::with_stride(
+                                fmt.into(),
+                                res,
+                                stride,
+                                timestamp,
+                                buffer,
+                            ))
+                        }
                         Err(why) => {
                             ret_boxerr!(why)
                         }
@@ -224,17 +298,115 @@ impl<'a> Webcam<'a> for V4LinuxDevice<'a> {
             }
         }
     }
+
+    fn get_format(&self) -> DeviceFormat {
+        self.device_format.get()
+    }
+
+    // only remembered here, `set_resolution` is what tells the driver. touching the format while
+    // someone else is streaming from the camera fails, and the device list does this a lot.
+    fn set_format(&self, fmt: DeviceFormat) -> Result<(), Box<dyn std::error::Error>> {
+        self.device_format.set(fmt);
+        Ok(())
+    }
+
+    fn get_controls(&self) -> Result<Vec<ControlDescription>, Box<dyn std::error::Error>> {
+        let device = self.inner.borrow();
+        let descriptions = match device.query_controls() {
+            Ok(d) => d,
+            Err(why) => ret_boxerr!(CannotGetProperty(why.to_string())),
+        };
+
+        let mut controls = Vec::new();
+        for control in CameraControl::ALL.iter().copied() {
+            let id = v4l2_control_id(control);
+            let desc = match descriptions.iter().find(|desc| desc.id == id) {
+                Some(d) => d,
+                None => continue,
+            };
+            // inactive controls (e.g. exposure while auto exposure is on) can refuse to be read
+            let value = match device.control(id) {
+                Ok(Control::Value(v)) => i64::from(v),
+                _ => i64::from(desc.default),
+            };
+            let default = i64::from(desc.default);
+            controls.push(match control {
+                CameraControl::AutoExposure => ControlDescription {
+                    control,
+                    kind: ControlKind::Toggle,
+                    min: 0,
+                    max: 1,
+                    step: 1,
+                    default: i64::from(default != V4L2_EXPOSURE_MANUAL),
+                    value: i64::from(value != V4L2_EXPOSURE_MANUAL),
+                },
+                CameraControl::AutoWhiteBalance | CameraControl::AutoFocus => ControlDescription {
+                    control,
+                    kind: ControlKind::Toggle,
+                    min: 0,
+                    max: 1,
+                    step: 1,
+                    default,
+                    value,
+                },
+                CameraControl::PowerLineFrequency => ControlDescription {
+                    control,
+                    kind: ControlKind::Menu(
+                        POWER_LINE_LABELS.iter().map(|l| l.to_string()).collect(),
+                    ),
+                    min: 0,
+                    max: POWER_LINE_LABELS.len() as i64 - 1,
+                    step: 1,
+                    default,
+                    value,
+                },
+                _ => ControlDescription {
+                    control,
+                    kind: ControlKind::Range,
+                    min: i64::from(desc.minimum),
+                    max: i64::from(desc.maximum),
+                    step: i64::from(desc.step).max(1),
+                    default,
+                    value,
+                },
+            });
+        }
+        Ok(controls)
+    }
+
+    fn set_control(
+        &self,
+        control: CameraControl,
+        value: i64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let raw = match control {
+            CameraControl::AutoExposure => {
+                if value != 0 {
+                    V4L2_EXPOSURE_APERTURE_PRIORITY
+                } else {
+                    V4L2_EXPOSURE_MANUAL
+                }
+            }
+            _ => value,
+        };
+        match self
+            .inner
+            .borrow_mut()
+            .set_control(v4l2_control_id(control), Control::Value(raw as i32))
+        {
+            Ok(_) => Ok(()),
+            Err(why) => ret_boxerr!(CannotSetProperty(format!(
+                "{}: {}",
+                control.name(),
+                why.to_string()
+            ))),
+        }
+    }
 }
 
 impl<'a> QueryCamera<'a> for V4LinuxDevice<'a> {
     fn get_supported_resolutions(&self) -> Result<Vec<Resolution>, Box<dyn std::error::Error>> {
-        let mut v4l2_format = FourCC::new(b"MJPG");
-        match self.device_format.get() {
-            DeviceFormat::Yuyv => {}
-            DeviceFormat::MJpeg => {
-                v4l2_format = FourCC::new(b"MJPG");
-            }
-        }
+        let v4l2_format = self.device_format.get().fourcc();
         return match self.inner.borrow().enum_framesizes(v4l2_format) {
             Ok(formats) => {
                 let mut ret: Vec<Resolution> = Vec::new();
@@ -263,13 +435,7 @@ impl<'a> QueryCamera<'a> for V4LinuxDevice<'a> {
         &self,
         res: Resolution,
     ) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
-        let mut v4l2_format = FourCC::new(b"MJPG");
-        match self.device_format.get() {
-            DeviceFormat::Yuyv => {}
-            DeviceFormat::MJpeg => {
-                v4l2_format = FourCC::new(b"MJPG");
-            }
-        }
+        let v4l2_format = self.device_format.get().fourcc();
         return match self
             .inner
             .borrow()
@@ -302,6 +468,13 @@ impl<'a> QueryCamera<'a> for V4LinuxDevice<'a> {
             location: (self.device_path).clone(),
         }
     }
+
+    fn get_identity(&self) -> DeviceDesc {
+        match self.inner.borrow().query_caps() {
+            Ok(caps) => DeviceDesc::from_v4l2(caps.bus, caps.card),
+            Err(_why) => DeviceDesc::from_default().with_name(self.name()),
+        }
+    }
 }
 
 // If you are getting linter errors about how `'this` isn't defined/a valid lifetime,
@@ -314,8 +487,8 @@ pub struct UVCameraDevice<'a> {
     device_format: Box<Cell<DeviceFormat>>,
     device_resolution: Box<Cell<Option<Resolution>>>,
     device_framerate: Box<Cell<Option<u32>>>,
-    device_receiver: Box<Receiver<Vec<u8>>>,
-    device_sender: Box<Sender<Vec<u8>>>,
+    device_receiver: Box<Receiver<Frame>>,
+    device_sender: Box<Sender<Frame>>,
     opened: Box<Cell<bool>>,
     str: &'a str, // Im too lazy to use PhantomData, so here is a lifetime box &str.
     ctx: Box<Context<'static>>,
@@ -425,9 +598,7 @@ impl<'a> UVCameraDevice<'a> {
             description.product_id,
             devices_list
                 .get(0)
-                .unwrap()
-                .description
-                .clone()
+                .and_then(|device| device.description.clone())
                 .unwrap_or_else(|| String::from(""))
         );
 
@@ -518,6 +689,20 @@ impl<'a> Webcam<'a> for UVCameraDevice<'a> {
         **self.with_device_type(|dev_type| dev_type)
     }
 
+    fn get_format(&self) -> DeviceFormat {
+        DeviceFormat::from_uvc(self.with_device_format(|f| f.get()).uvc_format())
+    }
+
+    fn set_format(&self, fmt: DeviceFormat) -> Result<(), Box<dyn std::error::Error>> {
+        if fmt == DeviceFormat::Nv12 {
+            ret_boxerr!(CannotSetProperty(
+                "NV12 is not supported over libuvc".to_string()
+            ))
+        }
+        self.with_device_format(|f| f.set(fmt));
+        Ok(())
+    }
+
     fn open_stream(&self) -> Result<(), Box<dyn std::error::Error>> {
         // check if we already did this
         let opened: bool = self.with_opened(|o| o.get());
@@ -529,14 +714,10 @@ impl<'a> Webcam<'a> for UVCameraDevice<'a> {
         self.with(|fields| {
             let resolution: Resolution = self.with_device_resolution(|res| res).get().unwrap();
             let fps: u32 = self.with_device_framerate(|f| f).get().unwrap();
+            let format = self.with_device_format(|f| f).get().uvc_format();
             let devh = fields.device_handle;
             let stream_handle = devh
-                .get_stream_handle_with_format_size_and_fps(
-                    FrameFormat::MJPEG,
-                    resolution.x,
-                    resolution.y,
-                    fps,
-                )
+                .get_stream_handle_with_format_size_and_fps(format, resolution.x, resolution.y, fps)
                 .unwrap();
             let mut streamhandle_init = MaybeUninit::<StreamHandle>::uninit();
             *fields.stream_handle.borrow_mut() = unsafe {
@@ -548,7 +729,13 @@ impl<'a> Webcam<'a> for UVCameraDevice<'a> {
         // this is cursedstr: Box::new("a"); and forever will be cursed with lifetime errors
         self.with(|fields| {
             let cnt = Arc::new(AtomicUsize::new(0));
-            let sender: Sender<Vec<u8>> = *(self.with_device_sender(|send| send)).clone();
+            let sender: Sender<Frame> = *(self.with_device_sender(|send| send)).clone();
+            let fmt = PixelFormat::from(self.get_format());
+            let res = self
+                .with_device_resolution(|res| res.get())
+                .unwrap_or_else(|| Resolution::new(0, 0));
+            // lives as long as the stream, frames still out there when it stops are just freed
+            let pool = FramePool::new(UVC_POOLED_FRAMES);
             let streamh_ref = unsafe {
                 let raw_ptr =
                     (*fields.stream_handle.borrow_mut()).as_ptr() as *mut MaybeUninit<StreamHandle>;
@@ -561,8 +748,15 @@ impl<'a> Webcam<'a> for UVCameraDevice<'a> {
             let _act_stream = &mut streamh_init
                 .start_stream(
                     move |frame, _count| {
-                        let vec_frame: Vec<u8> = frame.to_rgb().unwrap().to_bytes().to_vec();
-                        if sender.send(vec_frame).is_err() {
+                        // decoded on the worker threads, see `get_raw_frame`
+                        let timestamp = Instant::now();
+                        let bytes = frame.to_bytes();
+                        let mut buffer = pool.buffer(bytes.len());
+                        buffer.extend_from_slice(bytes);
+                        if sender
+                            .send(Frame::new(fmt, res, timestamp, buffer))
+                            .is_err()
+                        {
                             // do nothing
                         }
                     },
@@ -577,13 +771,18 @@ impl<'a> Webcam<'a> for UVCameraDevice<'a> {
     }
 
     fn get_frame(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let frame: Result<Vec<u8>, TryRecvError> =
+        decode_single_frame(self)
+    }
+
+    // the frames come out of the stream callback's own pool, not `_pool`
+    fn get_raw_frame(&self, _pool: &FramePool) -> Result<Frame, Box<dyn std::error::Error>> {
+        let frame: Result<Frame, TryRecvError> =
             self.with_device_receiver(|recv| match recv.try_recv() {
                 Ok(v) => Ok(v),
                 Err(why) => Err(why),
             });
         match frame {
-            Ok(v) => Ok(convert_mjpeg_rgb24(v)),
+            Ok(v) => Ok(v),
             Err(why) => {
                 ret_boxerr!(why)
             }
@@ -666,6 +865,14 @@ impl<'a> QueryCamera<'a> for UVCameraDevice<'a> {
             },
         }
     }
+
+    fn get_identity(&self) -> DeviceDesc {
+        let desc: uvc::Result<DeviceDescription> = self.with_device(|dev| dev.description());
+        match desc {
+            Ok(description) => DeviceDesc::from_description(description).with_name(self.name()),
+            Err(_why) => DeviceDesc::from_default().with_name(self.name()),
+        }
+    }
 }
 
 pub struct OpenCvCameraDevice {
@@ -673,6 +880,7 @@ pub struct OpenCvCameraDevice {
     res: Cell<Resolution>,
     fps: Cell<u32>,
     index: Cell<u32>,
+    format: Cell<DeviceFormat>,
     video_capture: RefCell<VideoCapture>,
 }
 
@@ -690,7 +898,7 @@ impl OpenCvCameraDevice {
                 Err(why) => ret_boxerr!(why),
             };
 
-            if let Err(why) = set_properties(&mut v_cap, res, fps) {
+            if let Err(why) = set_properties(&mut v_cap, res, fps, DeviceFormat::MJpeg) {
                 return Err(why);
             }
 
@@ -702,6 +910,7 @@ impl OpenCvCameraDevice {
             res: Cell::new(res),
             fps: Cell::new(fps),
             index: Cell::new(idx),
+            format: Cell::new(DeviceFormat::MJpeg),
             video_capture,
         })
     }
@@ -713,6 +922,7 @@ impl OpenCvCameraDevice {
         let name = RefCell::new(n);
         let res = Cell::new(possible_device.res());
         let fps = Cell::new(possible_device.fps());
+        let format = Cell::new(possible_device.fmt());
 
         let idx = match get_os_webcam_index(possible_device) {
             Ok(i) => i,
@@ -726,7 +936,7 @@ impl OpenCvCameraDevice {
                 Err(why) => ret_boxerr!(why),
             };
 
-            if let Err(why) = set_properties(&mut v_cap, res.get(), fps.get()) {
+            if let Err(why) = set_properties(&mut v_cap, res.get(), fps.get(), format.get()) {
                 return Err(why);
             }
 
@@ -738,6 +948,7 @@ impl OpenCvCameraDevice {
             res,
             fps,
             index: Cell::new(idx),
+            format,
             video_capture,
         })
     }
@@ -776,6 +987,10 @@ impl OpenCvCameraDevice {
             DeviceContact::OpenComVision { index } => {
                 OpenCvCameraDevice::new("OpenCVCamera".to_string(), index, framerate, resolution)
             }
+            DeviceContact::VideoFile { path } => ret_boxerr!(CannotFindDevice(format!(
+                "{} is a video file, open it with VideoFileDevice",
+                path
+            ))),
         }
     }
 
@@ -795,7 +1010,6 @@ impl OpenCvCameraDevice {
 
     fn fps(&self) -> u32 {
         let fps = self.video_capture.borrow().get(CAP_PROP_FPS).unwrap();
-        dbg!("{}", fps);
         fps as u32
     }
 
@@ -845,97 +1059,12 @@ impl OpenCvCameraDevice {
         Ok(())
     }
 
-    fn get_next_frame(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let vc = &mut *self.video_capture.borrow_mut();
-        {
-            let mut frame = Mat::default();
-            match vc.read(&mut frame) {
-                Ok(_) => {}
-                Err(why) => {
-                    ret_boxerr!(why);
-                }
-            };
-
-            if frame.size().unwrap().width > 0 {
-                if frame.is_continuous().unwrap() {
-                    // use a memcpy - we about to get f u n k y
-                    let mut ret_vec: Vec<u8> = Vec::new();
-                    ret_vec.reserve(
-                        (frame.rows() * frame.cols() * frame.channels().unwrap_or(3)) as usize,
-                    );
-                    // make a scope so the vec outlives the pointer 100%
-                    // {
-                    //     let vec_ptr = ret_vec.as_mut_ptr().cast(); // this looks, feels, and is probably a sin.
-                    //     let mat_ptr = frame.as_raw_Mat();
-                    //     unsafe {
-                    //         mat_ptr.copy_to_nonoverlapping(
-                    //             vec_ptr,
-                    //             (size_of::<u8>() as i32
-                    //                 * frame.rows()
-                    //                 * frame.cols()
-                    //                 * frame.channels().unwrap_or(3))
-                    //                 as usize,
-                    //         );
-                    //     }
-                    // }
-                    for row in 0..(frame.rows() - 1) {
-                        let mat_rw = match frame.row(row) {
-                            Ok(m) => m,
-                            Err(why) => {
-                                dbg!("{}", why.to_string());
-                                ret_boxerr!(why);
-                            }
-                        };
-                        let slice = match mat_rw.data_typed::<Vec3b>() {
-                            Ok(sl) => sl,
-                            Err(why) => {
-                                dbg!("{}", why.to_string());
-                                ret_boxerr!(why);
-                            }
-                        };
-                        for px in slice {
-                            ret_vec.push(px.0[2]);
-                            ret_vec.push(px.0[1]);
-                            ret_vec.push(px.0[0]);
-                        }
-                    }
-                    return Ok(ret_vec);
-                }
-                // non continuous mat
-                // TODO: Fix
-                let mut ret_vec: Vec<u8> = Vec::new();
-                ret_vec.reserve(
-                    (frame.rows() * frame.cols() * frame.channels().unwrap_or(3)) as usize,
-                );
-
-                for row in 0..(frame.rows() - 1) {
-                    let mat_rw = match frame.row(row) {
-                        Ok(m) => m,
-                        Err(why) => {
-                            dbg!("{}", why.to_string());
-                            ret_boxerr!(why);
-                        }
-                    };
-                    let slice = match mat_rw.data_typed::<Vec3b>() {
-                        Ok(sl) => sl,
-                        Err(why) => {
-                            dbg!("{}", why.to_string());
-                            ret_boxerr!(why);
-                        }
-                    };
-                    for px in slice {
-                        ret_vec.push(px.0[2]);
-                        ret_vec.push(px.0[1]);
-                        ret_vec.push(px.0[0]);
-                    }
-                }
-                dbg!("aaa");
-                return Ok(ret_vec);
-            }
+    // reads the next frame into `out` as RGB
+    fn get_next_frame(&self, out: &mut Vec<u8>) -> Result<Resolution, Box<dyn std::error::Error>> {
+        match read_rgb24(&mut *self.video_capture.borrow_mut(), out)? {
+            Some(res) => Ok(res),
+            None => ret_boxerr!(CannotGetFrame("Unsatisfied Conditions".to_string())),
         }
-        ret_boxerr!(CannotGetFrame("Unsatisfied Conditions".to_string()))
-
-        // TODO: convert Mat to array/slice/nalgebra matrix
     }
 
     // hide the body
@@ -975,7 +1104,282 @@ impl<'a> Webcam<'a> for OpenCvCameraDevice {
     }
 
     fn get_frame(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        self.get_next_frame()
+        let mut data = Vec::new();
+        self.get_next_frame(&mut data)?;
+        Ok(data)
+    }
+
+    fn get_raw_frame(&self, pool: &FramePool) -> Result<Frame, Box<dyn std::error::Error>> {
+        let mut buffer = pool.buffer((self.res().x * self.res().y * 3) as usize);
+        let res = self.get_next_frame(&mut buffer)?;
+        Ok(Frame::new(PixelFormat::Rgb24, res, Instant::now(), buffer))
+    }
+
+    fn get_format(&self) -> DeviceFormat {
+        self.format.get()
+    }
+
+    fn set_format(&self, fmt: DeviceFormat) -> Result<(), Box<dyn std::error::Error>> {
+        set_property_fourcc(&mut *self.video_capture.borrow_mut(), fmt)?;
+        self.format.set(fmt);
+        Ok(())
+    }
+
+    // OpenCV can't tell us the ranges, so these are what the usual backends use
+    fn get_controls(&self) -> Result<Vec<ControlDescription>, Box<dyn std::error::Error>> {
+        let vc = self.video_capture.borrow();
+        let mut controls = Vec::new();
+        for control in CameraControl::ALL.iter().copied() {
+            let (prop, kind, min, max) = match opencv_control(control) {
+                Some(c) => c,
+                None => continue,
+            };
+            let value = match vc.get(prop) {
+                // -1 is how most backends say they don't have it
+                Ok(v) if v >= 0_f64 || control == CameraControl::Exposure => v,
+                _ => continue,
+            };
+            let value = match kind {
+                ControlKind::Toggle => i64::from(value > OPENCV_AUTO_THRESHOLD),
+                _ => value.round() as i64,
+            };
+            controls.push(ControlDescription {
+                control,
+                kind,
+                min,
+                max,
+                step: 1,
+                default: value,
+                value,
+            });
+        }
+        Ok(controls)
+    }
+
+    fn set_control(
+        &self,
+        control: CameraControl,
+        value: i64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (prop, kind, _, _) = match opencv_control(control) {
+            Some(c) => c,
+            None => ret_boxerr!(CannotSetProperty(format!(
+                "{} is not supported by this camera",
+                control.name()
+            ))),
+        };
+        let raw = match (control, kind) {
+            (CameraControl::AutoExposure, _) => {
+                if value != 0 {
+                    OPENCV_AUTO_EXPOSURE_ON
+                } else {
+                    OPENCV_AUTO_EXPOSURE_OFF
+                }
+            }
+            (_, ControlKind::Toggle) => f64::from(value != 0),
+            _ => value as f64,
+        };
+        match self.video_capture.borrow_mut().set(prop, raw) {
+            Ok(true) => Ok(()),
+            Ok(false) => ret_boxerr!(CannotSetProperty(control.name().to_string())),
+            Err(why) => ret_boxerr!(why),
+        }
+    }
+}
+
+// Reads the next frame into `out` as RGB, OpenCV decodes everything to BGR itself. `None` if
+// there was nothing to read.
+fn read_rgb24(
+    vc: &mut VideoCapture,
+    out: &mut Vec<u8>,
+) -> Result<Option<Resolution>, Box<dyn std::error::Error>> {
+    let mut frame = Mat::default();
+    match vc.read(&mut frame) {
+        Ok(_) => {}
+        Err(why) => {
+            ret_boxerr!(why);
+        }
+    };
+
+    if frame.size().map_or(true, |size| size.width <= 0) {
+        return Ok(None);
+    }
+    out.reserve((frame.rows() * frame.cols() * frame.channels().unwrap_or(3)) as usize);
+    for row in 0..frame.rows() {
+        let mat_rw = match frame.row(row) {
+            Ok(m) => m,
+            Err(why) => {
+                ret_boxerr!(why);
+            }
+        };
+        let slice = match mat_rw.data_typed::<Vec3b>() {
+            Ok(sl) => sl,
+            Err(why) => {
+                ret_boxerr!(why);
+            }
+        };
+        for px in slice {
+            out.extend_from_slice(&[px.0[2], px.0[1], px.0[0]]);
+        }
+    }
+    Ok(Some(Resolution::new(
+        frame.cols() as u32,
+        frame.rows() as u32,
+    )))
+}
+
+/// Plays a video file as if it was a camera, so recordings can go through the same pipeline.
+/// Frames come out at the file's frame rate unless `unpaced`, and once it runs out every read is
+/// `EndOfStream`.
+pub struct VideoFileDevice {
+    path: String,
+    res: Resolution,
+    fps: u32,
+    paced: bool,
+    // when the next frame is due, `None` until the first one was read
+    next_frame_at: Cell<Option<Instant>>,
+    video_capture: RefCell<VideoCapture>,
+}
+
+impl VideoFileDevice {
+    pub fn new(path: String) -> Result<Self, Box<dyn std::error::Error>> {
+        let video_capture = match VideoCapture::from_file(&path, CAP_ANY as i32) {
+            Ok(vc) => vc,
+            Err(why) => ret_boxerr!(why),
+        };
+        if !video_capture.is_opened().unwrap_or(false) {
+            ret_boxerr!(CannotOpenStream(format!(
+                "{} is not a video OpenCV can read",
+                path
+            )));
+        }
+        let res = Resolution::new(
+            video_capture.get(CAP_PROP_FRAME_WIDTH).unwrap_or(0_f64) as u32,
+            video_capture.get(CAP_PROP_FRAME_HEIGHT).unwrap_or(0_f64) as u32,
+        );
+        // some containers don't say, 30 is as good a guess as any
+        let fps = match video_capture.get(CAP_PROP_FPS) {
+            Ok(fps) if fps >= 1_f64 => fps.round() as u32,
+            _ => 30,
+        };
+
+        Ok(VideoFileDevice {
+            path,
+            res,
+            fps,
+            paced: true,
+            next_frame_at: Cell::new(None),
+            video_capture: RefCell::new(video_capture),
+        })
+    }
+
+    /// Hand out frames as fast as they are read.
+    pub fn unpaced(mut self) -> Self {
+        self.paced = false;
+        self
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // hold the frame back until it is its turn, reading a file is a lot faster than a camera
+    fn wait_for_frame(&self) {
+        if !self.paced {
+            return;
+        }
+        let interval = Duration::from_secs_f64(1_f64 / f64::from(self.fps));
+        let now = Instant::now();
+        let due = self.next_frame_at.get().unwrap_or(now);
+        if due > now {
+            sleep(due - now);
+        }
+        // if we fell behind, don't try to catch up by rushing the next frames
+        self.next_frame_at.set(Some(due.max(now) + interval));
+    }
+}
+
+impl<'a> Webcam<'a> for VideoFileDevice {
+    fn name(&self) -> String {
+        self.path.clone()
+    }
+
+    fn set_resolution(&self, res: Resolution) -> Result<(), Box<dyn std::error::Error>> {
+        if res == self.res {
+            return Ok(());
+        }
+        ret_boxerr!(CannotSetProperty(format!(
+            "{} is {}, a video can't change resolution",
+            self.path, self.res
+        )))
+    }
+
+    fn set_framerate(&self, fps: u32) -> Result<(), Box<dyn std::error::Error>> {
+        if fps == self.fps {
+            return Ok(());
+        }
+        ret_boxerr!(CannotSetProperty(format!(
+            "{} runs at {} fps, a video can't change frame rate",
+            self.path, self.fps
+        )))
+    }
+
+    fn get_resolution(&self) -> Result<Resolution, Box<dyn Error>> {
+        Ok(self.res)
+    }
+
+    fn get_framerate(&self) -> Result<u32, Box<dyn Error>> {
+        Ok(self.fps)
+    }
+
+    fn get_camera_type(&self) -> WebcamType {
+        WebcamType::OpenCVCapture
+    }
+
+    // opened in `new` already, and opening it again would start it over
+    fn open_stream(&self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn get_frame(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut data = Vec::new();
+        self.wait_for_frame();
+        match read_rgb24(&mut *self.video_capture.borrow_mut(), &mut data)? {
+            Some(_) => Ok(data),
+            None => ret_boxerr!(EndOfStream),
+        }
+    }
+
+    fn get_raw_frame(&self, pool: &FramePool) -> Result<Frame, Box<dyn std::error::Error>> {
+        let mut buffer = pool.buffer((self.res.x * self.res.y * 3) as usize);
+        self.wait_for_frame();
+        match read_rgb24(&mut *self.video_capture.borrow_mut(), &mut buffer)? {
+            Some(res) => Ok(Frame::new(PixelFormat::Rgb24, res, Instant::now(), buffer)),
+            None => ret_boxerr!(EndOfStream),
+        }
+    }
+}
+
+// the V4L2 backend wants 0.75 for auto and 0.25 for manual, everything else takes them too
+const OPENCV_AUTO_EXPOSURE_ON: f64 = 0.75;
+const OPENCV_AUTO_EXPOSURE_OFF: f64 = 0.25;
+const OPENCV_AUTO_THRESHOLD: f64 = 0.5;
+
+fn opencv_control(control: CameraControl) -> Option<(i32, ControlKind, i64, i64)> {
+    match control {
+        CameraControl::AutoExposure => Some((CAP_PROP_AUTO_EXPOSURE, ControlKind::Toggle, 0, 1)),
+        // DirectShow and MSMF take exposure as log2 seconds
+        CameraControl::Exposure => Some((CAP_PROP_EXPOSURE, ControlKind::Range, -13, 0)),
+        CameraControl::Gain => Some((CAP_PROP_GAIN, ControlKind::Range, 0, 255)),
+        CameraControl::Brightness => Some((CAP_PROP_BRIGHTNESS, ControlKind::Range, 0, 255)),
+        CameraControl::Contrast => Some((CAP_PROP_CONTRAST, ControlKind::Range, 0, 255)),
+        CameraControl::AutoWhiteBalance => Some((CAP_PROP_AUTO_WB, ControlKind::Toggle, 0, 1)),
+        CameraControl::WhiteBalance => {
+            Some((CAP_PROP_WB_TEMPERATURE, ControlKind::Range, 2800, 6500))
+        }
+        CameraControl::AutoFocus => Some((CAP_PROP_AUTOFOCUS, ControlKind::Toggle, 0, 1)),
+        CameraControl::Focus => Some((CAP_PROP_FOCUS, ControlKind::Range, 0, 255)),
+        CameraControl::PowerLineFrequency => None,
     }
 }
 
@@ -983,8 +1387,9 @@ fn set_properties(
     vc: &mut VideoCapture,
     res: Resolution,
     fps: u32,
+    fmt: DeviceFormat,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    set_property_fourcc(vc)?;
+    set_property_fourcc(vc, fmt)?;
     set_property_res(vc, res)?;
     set_property_fps(vc, fps)?;
     Ok(())
@@ -1037,10 +1442,18 @@ fn set_property_fps(vc: &mut VideoCapture, fps: u32) -> Result<(), Box<dyn std::
     Ok(())
 }
 
-fn set_property_fourcc(vc: &mut VideoCapture) -> Result<(), Box<dyn std::error::Error>> {
+// OpenCV still hands us decoded frames, this only changes what goes over the wire
+fn set_property_fourcc(
+    vc: &mut VideoCapture,
+    fmt: DeviceFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let code = fmt.fourcc().repr;
     match vc.set(
         CAP_PROP_FOURCC as i32,
-        f64::from(VideoWriter::fourcc('M' as i8, 'J' as i8, 'P' as i8, 'G' as i8).unwrap()),
+        f64::from(
+            VideoWriter::fourcc(code[0] as i8, code[1] as i8, code[2] as i8, code[3] as i8)
+                .unwrap(),
+        ),
     ) {
         Ok(r) => {
             if !r {
@@ -1062,12 +1475,57 @@ fn get_api_pref_int() -> u32 {
     }
 }
 
-#[inline]
-fn convert_mjpeg_rgb24<S: Deref<Target = [u8]>>(data: S) -> Vec<u8> {
-    let mut decompressor = Decompress::new_mem(data.as_ref()).unwrap().rgb().unwrap();
-    let decomp = decompressor.read_scanlines::<[u8; 3]>().unwrap();
-    unsafe {
-        &*(from_raw_parts(decomp.as_ptr(), data.len() * 3) as *const [[u8; 3]] as *const [u8])
+// Decode into `out`, which should be empty. `None` if the data isn't a JPEG we can read.
+pub(crate) fn convert_mjpeg_rgb24(data: &[u8], out: &mut Vec<u8>) -> Option<Resolution> {
+    decode_mjpeg(data, None, false, out)
+}
+
+// With a `target` libjpeg scales the DCT down to the smallest of 1/2, 1/4 or 1/8 that is still at
+// least that big, which skips most of the IDCT and colour conversion instead of throwing the
+// pixels away afterwards. `grayscale` only decodes the Y channel.
+pub(crate) fn decode_mjpeg(
+    data: &[u8],
+    target: Option<Resolution>,
+    grayscale: bool,
+    out: &mut Vec<u8>,
+) -> Option<Resolution> {
+    let mut decompressor = Decompress::new_mem(data).ok()?;
+    if let Some(target) = target {
+        let full = Resolution::new(decompressor.width() as u32, decompressor.height() as u32);
+        decompressor.scale(dct_scale(full, target));
+    }
+    let (mut started, channels) = if grayscale {
+        (decompressor.grayscale().ok()?, 1)
+    } else {
+        (decompressor.rgb().ok()?, 3)
+    };
+    let res = Resolution::new(started.width() as u32, started.height() as u32);
+    out.resize((res.x * res.y * channels) as usize, 0_u8);
+    started.read_scanlines_flat_into(out)?;
+    started.finish_decompress();
+    Some(res)
+}
+
+// in eighths, that is all libjpeg can do
+fn dct_scale(full: Resolution, target: Resolution) -> u8 {
+    let scaled = |dim: u32, eighths: u32| (dim * eighths + 7) / 8;
+    [1_u8, 2, 4]
+        .iter()
+        .copied()
+        .find(|eighths| {
+            scaled(full.x, u32::from(*eighths)) >= target.x
+                && scaled(full.y, u32::from(*eighths)) >= target.y
+        })
+        .unwrap_or(8)
+}
+
+// `get_frame` for the backends that hand out raw frames, for the odd one off frame
+fn decode_single_frame<'a>(webcam: &dyn Webcam<'a>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let pool = FramePool::new(0);
+    match webcam.get_raw_frame(&pool)?.into_rgb24(&pool) {
+        Some(frame) => Ok(frame.into_data()),
+        None => Err(Box::new(CannotGetFrame(
+            "Could not decode frame".to_string(),
+        ))),
     }
-    .to_vec()
 }
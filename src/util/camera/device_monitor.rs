@@ -0,0 +1,284 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{
+    log_warn,
+    util::camera::device_utils::{rescan_cache_device, CachedDeviceList},
+};
+use flume::{Receiver, Sender};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{Builder, JoinHandle},
+    time::Duration,
+};
+
+// how often to check if we should stop, and how often to rescan where there is nothing to watch
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const RESCAN_INTERVAL: Duration = Duration::from_secs(2);
+// udev creates the node before it sets the permissions, give it a moment before opening it
+const SETTLE_TIME: Duration = Duration::from_millis(300);
+
+#[derive(Clone)]
+pub enum DeviceEvent {
+    Added {
+        name: String,
+        device: CachedDeviceList,
+    },
+    Removed {
+        name: String,
+        device: CachedDeviceList,
+    },
+}
+
+impl DeviceEvent {
+    pub fn name(&self) -> &str {
+        match self {
+            DeviceEvent::Added { name, .. } | DeviceEvent::Removed { name, .. } => name,
+        }
+    }
+}
+
+/// Watches for cameras coming and going on a background thread. On Linux this listens on `/dev`
+/// for `video*` nodes, everywhere else it just enumerates the devices every couple of seconds.
+pub struct DeviceMonitor {
+    events: Receiver<DeviceEvent>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DeviceMonitor {
+    /// `known` is the device list the caller already has, only changes to it get reported.
+    pub fn new(
+        known: HashMap<String, CachedDeviceList>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let (sender, events) = flume::unbounded();
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let thread = Builder::new()
+            .name("device_monitor".to_string())
+            .spawn(move || watch_devices(known, sender, thread_running))?;
+        Ok(DeviceMonitor {
+            events,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    /// Everything that changed since the last call, oldest first.
+    pub fn query_events(&self) -> Vec<DeviceEvent> {
+        self.events.try_iter().collect()
+    }
+}
+
+impl Drop for DeviceMonitor {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn watch_devices(
+    mut known: HashMap<String, CachedDeviceList>,
+    sender: Sender<DeviceEvent>,
+    running: Arc<AtomicBool>,
+) {
+    let mut watcher = DevWatcher::new();
+    let mut since_rescan = Duration::default();
+    while running.load(Ordering::Relaxed) {
+        std::thread::sleep(POLL_INTERVAL);
+        since_rescan += POLL_INTERVAL;
+
+        let rescan = match &mut watcher {
+            Some(watcher) => watcher.changed(),
+            None => since_rescan >= RESCAN_INTERVAL,
+        };
+        if !rescan {
+            continue;
+        }
+        since_rescan = Duration::default();
+        if watcher.is_some() {
+            std::thread::sleep(SETTLE_TIME);
+        }
+
+        // one of the known ones is likely streaming, leave those alone
+        let current = match rescan_cache_device(&known) {
+            Some(list) => list,
+            None => continue,
+        };
+        for event in diff_devices(&known, &current) {
+            if sender.send(event).is_err() {
+                // nobody is listening anymore
+                return;
+            }
+        }
+        known = current;
+    }
+}
+
+fn diff_devices(
+    old: &HashMap<String, CachedDeviceList>,
+    new: &HashMap<String, CachedDeviceList>,
+) -> Vec<DeviceEvent> {
+    let mut events = Vec::new();
+    for (name, device) in old {
+        if !new.contains_key(name) {
+            events.push(DeviceEvent::Removed {
+                name: name.clone(),
+                device: device.clone(),
+            });
+        }
+    }
+    for (name, device) in new {
+        if !old.contains_key(name) {
+            events.push(DeviceEvent::Added {
+                name: name.clone(),
+                device: device.clone(),
+            });
+        }
+    }
+    events
+}
+
+#[cfg(target_os = "linux")]
+struct DevWatcher {
+    inotify: inotify::Inotify,
+    buffer: [u8; 4096],
+}
+
+#[cfg(target_os = "linux")]
+impl DevWatcher {
+    // `None` means fall back to rescanning on a timer
+    fn new() -> Option<Self> {
+        use inotify::{Inotify, WatchMask};
+
+        let mut inotify = match Inotify::init() {
+            Ok(i) => i,
+            Err(why) => {
+                log_warn!(Camera, "Can't watch /dev, rescanning on a timer: {}", why);
+                return None;
+            }
+        };
+        if let Err(why) = inotify.add_watch(
+            "/dev",
+            WatchMask::CREATE | WatchMask::DELETE | WatchMask::ATTRIB,
+        ) {
+            log_warn!(Camera, "Can't watch /dev, rescanning on a timer: {}", why);
+            return None;
+        }
+        Some(DevWatcher {
+            inotify,
+            buffer: [0; 4096],
+        })
+    }
+
+    // whether a video node showed up or went away since the last call. never blocks.
+    fn changed(&mut self) -> bool {
+        let mut changed = false;
+        while let Ok(events) = self.inotify.read_events(&mut self.buffer) {
+            let mut any = false;
+            for event in events {
+                any = true;
+                if let Some(name) = event.name.and_then(|n| n.to_str()) {
+                    if name.starts_with("video") {
+                        changed = true;
+                    }
+                }
+            }
+            if !any {
+                break;
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct DevWatcher;
+
+#[cfg(not(target_os = "linux"))]
+impl DevWatcher {
+    fn new() -> Option<Self> {
+        None
+    }
+
+    fn changed(&mut self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn devices(names: &[&str]) -> HashMap<String, CachedDeviceList> {
+        names
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| (name.to_string(), CachedDeviceList::named(name, idx as u32)))
+            .collect()
+    }
+
+    // (added, removed), sorted since the maps have no order
+    fn changes(events: &[DeviceEvent]) -> (Vec<&str>, Vec<&str>) {
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+        for event in events {
+            match event {
+                DeviceEvent::Added { name, .. } => added.push(name.as_str()),
+                DeviceEvent::Removed { name, .. } => removed.push(name.as_str()),
+            }
+        }
+        added.sort_unstable();
+        removed.sort_unstable();
+        (added, removed)
+    }
+
+    #[test]
+    fn same_list_is_no_change() {
+        let list = devices(&["Laptop Camera", "USB Camera"]);
+        assert!(diff_devices(&list, &list).is_empty());
+    }
+
+    #[test]
+    fn reports_plugged_in_and_out() {
+        let old = devices(&["Laptop Camera", "USB Camera"]);
+        let new = devices(&["Laptop Camera", "Capture Card", "Phone"]);
+        let events = diff_devices(&old, &new);
+        assert_eq!(
+            changes(&events),
+            (vec!["Capture Card", "Phone"], vec!["USB Camera"])
+        );
+    }
+
+    #[test]
+    fn removed_events_keep_the_old_entry() {
+        let old = devices(&["USB Camera"]);
+        let events = diff_devices(&old, &HashMap::new());
+        match &events[..] {
+            [DeviceEvent::Removed { name, device }] => {
+                assert_eq!(name, "USB Camera");
+                assert_eq!(device.get_name(), "USB Camera");
+            }
+            _ => panic!("expected one removal"),
+        }
+    }
+}
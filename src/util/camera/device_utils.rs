@@ -16,7 +16,7 @@
 
 use crate::{
     error::{
-        conversion_error::ConversionError::{ConversionFromError, MatchFailedError},
+        conversion_error::ConversionError::{self, MatchFailedError},
         invalid_device_error::InvalidDeviceError::CannotFindDevice,
     },
     ret_boxerr,
@@ -25,21 +25,26 @@ use crate::{
         webcam::QueryCamera,
     },
 };
-use gdnative::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::{
     cmp::Ordering, collections::HashMap, convert::TryFrom, error::Error, fmt::Display,
-    fmt::Formatter, os::raw::c_int,
+    fmt::Formatter, os::raw::c_int, str::FromStr,
 };
 use usb_enumeration::UsbDevice;
 use uvc::{DeviceHandle, FrameFormat};
 use v4l::{framesize::FrameSizeEnum, prelude::*, FourCC};
 
-#[derive(Clone, Deserialize, Serialize)]
+/// Which physical camera this is, independent of where the OS happens to put it this time.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
 pub struct DeviceDesc {
     pub(crate) vid: Option<c_int>,
     pub(crate) pid: Option<c_int>,
     pub(crate) ser: Option<String>,
+    // V4L2 bus info, e.g. "usb-0000:00:14.0-2". stays put as long as the camera stays in its port.
+    #[serde(default)]
+    pub(crate) bus: Option<String>,
+    #[serde(default)]
+    pub(crate) name: Option<String>,
 }
 
 impl DeviceDesc {
@@ -49,6 +54,8 @@ impl DeviceDesc {
             vid: Some(c_int::from(device_desc.vendor_id)),
             pid: Some(c_int::from(device_desc.product_id)),
             ser: device_desc.serial_number,
+            bus: None,
+            name: None,
         })
     }
     pub fn from_description(device: uvc::DeviceDescription) -> Self {
@@ -56,6 +63,8 @@ impl DeviceDesc {
             vid: Some(c_int::from(device.vendor_id)),
             pid: Some(c_int::from(device.product_id)),
             ser: device.serial_number,
+            bus: None,
+            name: None,
         }
     }
     pub fn from_default() -> Self {
@@ -63,8 +72,46 @@ impl DeviceDesc {
             vid: None,
             pid: None,
             ser: None,
+            bus: None,
+            name: None,
         }
     }
+    pub fn from_v4l2(bus: String, name: String) -> Self {
+        DeviceDesc {
+            bus: Some(bus),
+            name: Some(name),
+            ..DeviceDesc::from_default()
+        }
+    }
+    pub fn with_name(mut self, name: String) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    /// Nothing saved, e.g. on first launch.
+    pub fn is_empty(&self) -> bool {
+        *self == DeviceDesc::from_default()
+    }
+
+    /// Whether this is the same physical camera as `other`.
+    /// Serial numbers win, then the USB ids, then the V4L2 bus and name.
+    pub fn matches(&self, other: &DeviceDesc) -> bool {
+        if self.is_empty() || other.is_empty() {
+            return false;
+        }
+        if let (Some(ser), Some(other_ser)) = (&self.ser, &other.ser) {
+            if !ser.is_empty() && !other_ser.is_empty() {
+                return ser == other_ser && self.vid == other.vid && self.pid == other.pid;
+            }
+        }
+        if self.vid.is_some() && other.vid.is_some() {
+            return self.vid == other.vid && self.pid == other.pid;
+        }
+        if self.bus.is_some() && other.bus.is_some() {
+            return self.bus == other.bus && self.name == other.name;
+        }
+        self.name.is_some() && self.name == other.name
+    }
 }
 
 #[derive(Clone)]
@@ -132,7 +179,7 @@ impl PartialEq for DeviceHolder {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Deserialize, Serialize)]
 pub struct Resolution {
     pub x: u32,
     pub y: u32,
@@ -142,25 +189,6 @@ impl Resolution {
     pub fn new(x: u32, y: u32) -> Self {
         Resolution { x, y }
     }
-
-    pub fn from_variant(var: &Variant) -> Result<Self, Box<dyn std::error::Error>> {
-        if let Some(v) = var.try_to_vector2() {
-            return if v.x > 0.0 && v.y > 0.0 {
-                let x = v.x as u32;
-                let y = v.y as u32;
-                Ok(Resolution { x, y })
-            } else {
-                Err(Box::new(ConversionFromError {
-                    from: "Variant".to_string(),
-                    to: "u32".to_string(),
-                }))
-            };
-        }
-        Err(Box::new(ConversionFromError {
-            from: "Variant".to_string(),
-            to: "Vector2".to_string(),
-        }))
-    }
 }
 
 impl TryFrom<v4l::framesize::FrameSize> for Resolution {
@@ -213,25 +241,59 @@ impl Ord for Resolution {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub enum DeviceFormat {
     Yuyv,
     MJpeg,
+    Nv12,
 }
 
 impl DeviceFormat {
-    pub fn from_variant(var: &Variant) -> Result<Self, Box<dyn std::error::Error>> {
-        if let Some(st) = var.try_to_string() {
-            return match &st.to_lowercase()[..] {
-                "yuyv" => Ok(DeviceFormat::Yuyv),
-                "mjpg" | "mjpeg" => Ok(DeviceFormat::MJpeg),
-                _ => Err(Box::new(MatchFailedError(st))),
-            };
+    pub const ALL: [DeviceFormat; 3] =
+        [DeviceFormat::MJpeg, DeviceFormat::Yuyv, DeviceFormat::Nv12];
+
+    pub fn fourcc(&self) -> FourCC {
+        match self {
+            DeviceFormat::Yuyv => FourCC::new(b"YUYV"),
+            DeviceFormat::MJpeg => FourCC::new(b"MJPG"),
+            DeviceFormat::Nv12 => FourCC::new(b"NV12"),
+        }
+    }
+
+    pub fn from_fourcc(fourcc: FourCC) -> Self {
+        match &fourcc.repr {
+            b"YUYV" => DeviceFormat::Yuyv,
+            b"NV12" => DeviceFormat::Nv12,
+            _ => DeviceFormat::MJpeg,
+        }
+    }
+
+    // the libuvc bindings don't know about NV12, those cameras get YUYV out of UVC instead
+    pub fn uvc_format(&self) -> FrameFormat {
+        match self {
+            DeviceFormat::Yuyv | DeviceFormat::Nv12 => FrameFormat::YUYV,
+            DeviceFormat::MJpeg => FrameFormat::MJPEG,
+        }
+    }
+
+    pub fn from_uvc(fmt: FrameFormat) -> Self {
+        match fmt {
+            FrameFormat::YUYV => DeviceFormat::Yuyv,
+            _ => DeviceFormat::MJpeg,
+        }
+    }
+}
+
+impl FromStr for DeviceFormat {
+    type Err = ConversionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "yuyv" => Ok(DeviceFormat::Yuyv),
+            "mjpg" | "mjpeg" => Ok(DeviceFormat::MJpeg),
+            "nv12" => Ok(DeviceFormat::Nv12),
+            _ => Err(MatchFailedError(s.to_string())),
         }
-        Err(Box::new(ConversionFromError {
-            from: "Variant".to_string(),
-            to: "String".to_string(),
-        }))
     }
 }
 
@@ -244,6 +306,9 @@ impl Display for DeviceFormat {
             DeviceFormat::MJpeg => {
                 write!(f, "MJPG")
             }
+            DeviceFormat::Nv12 => {
+                write!(f, "NV12")
+            }
         }
     }
 }
@@ -275,6 +340,12 @@ pub enum PossibleDevice {
         fps: u32,
         fmt: FrameFormat,
     },
+    /// Not a camera at all. `res` and `fps` are what the file has, they can't be changed.
+    VideoFile {
+        path: String,
+        res: Resolution,
+        fps: u32,
+    },
 }
 
 impl<'a> PossibleDevice {
@@ -290,10 +361,7 @@ impl<'a> PossibleDevice {
                 product_id,
                 serial,
             } => {
-                let dev_format = match fmt {
-                    DeviceFormat::Yuyv => FrameFormat::YUYV,
-                    DeviceFormat::MJpeg => FrameFormat::MJPEG,
-                };
+                let dev_format = fmt.uvc_format();
 
                 PossibleDevice::UniversalVideoCamera {
                     vendor_id: *vendor_id,
@@ -305,10 +373,7 @@ impl<'a> PossibleDevice {
                 }
             }
             DeviceContact::Video4Linux2 { location } => {
-                let dev_format = match fmt {
-                    DeviceFormat::Yuyv => FourCC::new(b"MJPG"),
-                    DeviceFormat::MJpeg => FourCC::new(b"YUYV"),
-                };
+                let dev_format = fmt.fourcc();
                 let lc: PathIndex = match location {
                     PathIndex::Path(p) => PathIndex::Path(p.clone()),
                     PathIndex::Index(i) => PathIndex::Index(*i),
@@ -324,7 +389,12 @@ impl<'a> PossibleDevice {
                 index: *index,
                 res,
                 fps,
-                fmt: FrameFormat::MJPEG,
+                fmt: fmt.uvc_format(),
+            },
+            DeviceContact::VideoFile { path } => PossibleDevice::VideoFile {
+                path: path.clone(),
+                res,
+                fps,
             },
         }
     }
@@ -341,10 +411,7 @@ impl<'a> PossibleDevice {
                 product_id,
                 serial,
             } => {
-                let dev_format = match fmt {
-                    DeviceFormat::Yuyv => FrameFormat::YUYV,
-                    DeviceFormat::MJpeg => FrameFormat::MJPEG,
-                };
+                let dev_format = fmt.uvc_format();
 
                 PossibleDevice::UniversalVideoCamera {
                     vendor_id,
@@ -356,10 +423,7 @@ impl<'a> PossibleDevice {
                 }
             }
             DeviceContact::Video4Linux2 { location } => {
-                let dev_format = match fmt {
-                    DeviceFormat::Yuyv => FourCC::new(b"MJPG"),
-                    DeviceFormat::MJpeg => FourCC::new(b"YUYV"),
-                };
+                let dev_format = fmt.fourcc();
                 let lc: PathIndex = match location {
                     PathIndex::Path(p) => PathIndex::Path(p),
                     PathIndex::Index(i) => PathIndex::Index(i),
@@ -375,8 +439,9 @@ impl<'a> PossibleDevice {
                 index,
                 res,
                 fps,
-                fmt: FrameFormat::MJPEG,
+                fmt: fmt.uvc_format(),
             },
+            DeviceContact::VideoFile { path } => PossibleDevice::VideoFile { path, res, fps },
         }
     }
 
@@ -408,6 +473,9 @@ impl<'a> PossibleDevice {
                 fps: _fps,
                 fmt: _fmt,
             } => DeviceContact::OpenComVision { index: *index },
+            PossibleDevice::VideoFile { path, .. } => {
+                DeviceContact::VideoFile { path: path.clone() }
+            }
         }
     }
 
@@ -433,6 +501,7 @@ impl<'a> PossibleDevice {
                 fps: _fps,
                 fmt: _fmt,
             } => *res,
+            PossibleDevice::VideoFile { res, .. } => *res,
         }
     }
 
@@ -458,11 +527,18 @@ impl<'a> PossibleDevice {
                 fps,
                 fmt: _fmt,
             } => *fps,
+            PossibleDevice::VideoFile { fps, .. } => *fps,
         }
     }
 
     pub fn fmt(&self) -> DeviceFormat {
-        DeviceFormat::MJpeg
+        match self {
+            PossibleDevice::UniversalVideoCamera { fmt, .. } => DeviceFormat::from_uvc(*fmt),
+            PossibleDevice::Video4Linux2 { fmt, .. } => DeviceFormat::from_fourcc(*fmt),
+            PossibleDevice::OpenComVision { fmt, .. } => DeviceFormat::from_uvc(*fmt),
+            // whatever it is, OpenCV decodes it for us
+            PossibleDevice::VideoFile { .. } => DeviceFormat::MJpeg,
+        }
     }
 
     pub fn change_config(self, dev_cfg: DeviceConfig) -> Self {
@@ -504,11 +580,12 @@ impl<'a> PossibleDevice {
                 fps: dev_cfg.fps,
                 fmt,
             },
+            file @ PossibleDevice::VideoFile { .. } => file,
         }
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum PathIndex {
     Path(String),
     Index(usize),
@@ -527,7 +604,7 @@ impl Display for PathIndex {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum DeviceContact {
     UniversalVideoCamera {
         vendor_id: Option<u16>,
@@ -540,6 +617,9 @@ pub enum DeviceContact {
     OpenComVision {
         index: u32,
     },
+    VideoFile {
+        path: String,
+    },
 }
 
 impl DeviceContact {
@@ -569,6 +649,7 @@ impl DeviceContact {
                 fps: _fps,
                 fmt: _fmt,
             } => DeviceContact::OpenComVision { index },
+            PossibleDevice::VideoFile { path, .. } => DeviceContact::VideoFile { path },
         }
     }
 }
@@ -583,8 +664,9 @@ impl From<PossibleDevice> for DeviceContact {
 pub struct CachedDeviceList {
     device_name: String,
     device_location: DeviceContact,
-    device_format_mjpg: Box<HashMap<Resolution, Vec<u32>>>,
-    device_format_yuyv: Box<HashMap<Resolution, Vec<u32>>>,
+    device_identity: DeviceDesc,
+    // format => resolution => framerates
+    device_formats: HashMap<DeviceFormat, HashMap<Resolution, Vec<u32>>>,
 }
 
 impl CachedDeviceList {
@@ -592,30 +674,59 @@ impl CachedDeviceList {
     pub fn from_webcam(camera: &dyn QueryCamera) -> Result<Self, Box<dyn std::error::Error>> {
         let device_name = camera.name();
         let device_location = camera.get_location();
-        let mut resolutions = match camera.get_supported_resolutions() {
-            Ok(res) => res,
-            Err(why) => {
-                return Err(why);
-            }
-        };
+        let device_identity = camera.get_identity();
+        let mut device_formats = HashMap::new();
+        let mut last_error = None;
 
-        resolutions.sort();
+        for fmt in DeviceFormat::ALL.iter().copied() {
+            if camera.set_format(fmt).is_err() {
+                continue;
+            }
+            let mut resolutions = match camera.get_supported_resolutions() {
+                Ok(res) => res,
+                Err(why) => {
+                    last_error = Some(why);
+                    continue;
+                }
+            };
+            resolutions.sort();
 
-        let mut fmt_res_mjpg: HashMap<Resolution, Vec<u32>> = HashMap::new();
+            let mut fmt_res: HashMap<Resolution, Vec<u32>> = HashMap::new();
+            for res in resolutions {
+                if let Ok(framerates) = camera.get_supported_framerate(res) {
+                    fmt_res.insert(res, framerates.clone());
+                }
+            }
+            if !fmt_res.is_empty() {
+                device_formats.insert(fmt, fmt_res);
+            }
+        }
+        let _ = camera.set_format(DeviceFormat::MJpeg);
 
-        for res in resolutions {
-            if let Ok(framerates) = camera.get_supported_framerate(res) {
-                fmt_res_mjpg.insert(res, framerates.clone());
+        if device_formats.is_empty() {
+            if let Some(why) = last_error {
+                return Err(why);
             }
         }
         Ok(Self {
             device_name,
             device_location,
-            device_format_yuyv: Box::new(fmt_res_mjpg.clone()),
-            device_format_mjpg: Box::new(fmt_res_mjpg),
+            device_identity,
+            device_formats,
         })
     }
 
+    // a camera that supports nothing, for tests that only care about which cameras there are
+    #[cfg(test)]
+    pub(crate) fn named(name: &str, index: u32) -> Self {
+        CachedDeviceList {
+            device_name: name.to_string(),
+            device_location: DeviceContact::OpenComVision { index },
+            device_identity: DeviceDesc::from_default().with_name(name.to_string()),
+            device_formats: HashMap::new(),
+        }
+    }
+
     pub fn set_custom_cached_idx(&mut self, idx: u32) {
         self.device_location = DeviceContact::OpenComVision { index: idx };
     }
@@ -628,12 +739,21 @@ impl CachedDeviceList {
         self.device_location.clone()
     }
 
-    pub fn get_supported_yuyv(&self) -> Box<HashMap<Resolution, Vec<u32>>> {
-        self.device_format_yuyv.clone()
+    pub fn get_identity(&self) -> DeviceDesc {
+        self.device_identity.clone()
     }
 
-    pub fn get_supported_mjpg(&self) -> Box<HashMap<Resolution, Vec<u32>>> {
-        self.device_format_mjpg.clone()
+    /// Formats this camera can do, MJPEG first since that's what most cameras do best.
+    pub fn get_formats(&self) -> Vec<DeviceFormat> {
+        DeviceFormat::ALL
+            .iter()
+            .copied()
+            .filter(|fmt| self.device_formats.contains_key(fmt))
+            .collect()
+    }
+
+    pub fn get_supported(&self, fmt: DeviceFormat) -> HashMap<Resolution, Vec<u32>> {
+        self.device_formats.get(&fmt).cloned().unwrap_or_default()
     }
 }
 
@@ -660,6 +780,15 @@ impl From<PossibleDevice> for DeviceConfig {
     }
 }
 pub fn enumerate_cache_device() -> Option<HashMap<String, CachedDeviceList>> {
+    rescan_cache_device(&HashMap::new())
+}
+
+/// `enumerate_cache_device`, but cameras that are in `known` already aren't opened again where
+/// that would get in the way. Through libuvc that means claiming the camera and switching it
+/// through every format, which breaks the stream of whoever is using it right now.
+pub fn rescan_cache_device(
+    known: &HashMap<String, CachedDeviceList>,
+) -> Option<HashMap<String, CachedDeviceList>> {
     let mut known_devices: HashMap<String, CachedDeviceList> = HashMap::new();
     // get device list from v4l2
     match std::env::consts::OS {
@@ -683,11 +812,21 @@ pub fn enumerate_cache_device() -> Option<HashMap<String, CachedDeviceList>> {
             match crate::UVC.devices() {
                 Ok(list) => {
                     for (idx, uvc_device) in list.enumerate() {
-                        if let Ok(mut camera_device) = {
-                            let b: Box<dyn QueryCamera> =
-                                Box::new(UVCameraDevice::from_device(uvc_device).unwrap());
-                            CachedDeviceList::from_webcam(b.as_ref())
-                        } {
+                        let seen = uvc_device.description().ok().and_then(|desc| {
+                            let identity = DeviceDesc::from_description(desc);
+                            known
+                                .values()
+                                .find(|cached| cached.get_identity().matches(&identity))
+                                .cloned()
+                        });
+                        let cached = match seen {
+                            Some(cached) => Ok(cached),
+                            None => UVCameraDevice::from_device(uvc_device).and_then(|camera| {
+                                let b: Box<dyn QueryCamera> = Box::new(camera);
+                                CachedDeviceList::from_webcam(b.as_ref())
+                            }),
+                        };
+                        if let Ok(mut camera_device) = cached {
                             let dev_name = camera_device.get_name();
                             camera_device.set_custom_cached_idx(idx as u32);
                             // weed out the repeating
@@ -766,5 +905,65 @@ pub fn get_os_webcam_index(device: PossibleDevice) -> Result<u32, Box<dyn std::e
             fps: _fps,
             fmt: _fmt,
         } => Ok(index),
+        PossibleDevice::VideoFile { path, .. } => Err(Box::new(CannotFindDevice(format!(
+            "{} is a video file, not a camera",
+            path
+        )))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usb(vid: c_int, pid: c_int, ser: Option<&str>) -> DeviceDesc {
+        DeviceDesc {
+            vid: Some(vid),
+            pid: Some(pid),
+            ser: ser.map(str::to_string),
+            ..DeviceDesc::from_default()
+        }
+    }
+
+    fn v4l2(bus: &str, name: &str) -> DeviceDesc {
+        DeviceDesc::from_v4l2(bus.to_string(), name.to_string())
+    }
+
+    #[test]
+    fn nothing_saved_matches_nothing() {
+        let empty = DeviceDesc::from_default();
+        assert!(!empty.matches(&empty));
+        assert!(!empty.matches(&usb(1, 2, None)));
+        assert!(!usb(1, 2, None).matches(&empty));
+    }
+
+    #[test]
+    fn serial_numbers_tell_identical_cameras_apart() {
+        assert!(usb(1, 2, Some("A")).matches(&usb(1, 2, Some("A"))));
+        assert!(!usb(1, 2, Some("A")).matches(&usb(1, 2, Some("B"))));
+        // the same serial on another model is another camera
+        assert!(!usb(1, 2, Some("A")).matches(&usb(1, 3, Some("A"))));
+    }
+
+    #[test]
+    fn usb_ids_without_serials() {
+        assert!(usb(1, 2, None).matches(&usb(1, 2, Some("A"))));
+        assert!(usb(1, 2, Some("")).matches(&usb(1, 2, Some("A"))));
+        assert!(!usb(1, 2, None).matches(&usb(1, 3, None)));
+    }
+
+    #[test]
+    fn v4l2_goes_by_port_and_name() {
+        let cam = v4l2("usb-0000:00:14.0-2", "HD Webcam");
+        assert!(cam.matches(&v4l2("usb-0000:00:14.0-2", "HD Webcam")));
+        assert!(!cam.matches(&v4l2("usb-0000:00:14.0-3", "HD Webcam")));
+        assert!(!cam.matches(&v4l2("usb-0000:00:14.0-2", "Other Webcam")));
+    }
+
+    #[test]
+    fn name_is_the_last_resort() {
+        let named = |name: &str| DeviceDesc::from_default().with_name(name.to_string());
+        assert!(named("HD Webcam").matches(&named("HD Webcam")));
+        assert!(!named("HD Webcam").matches(&named("Other Webcam")));
     }
 }
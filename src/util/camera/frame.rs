@@ -0,0 +1,311 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::util::camera::{
+    camera_device::{convert_mjpeg_rgb24, decode_mjpeg},
+    device_utils::{DeviceFormat, Resolution},
+    pixel_format::{nv12_to_gray8, nv12_to_rgb24, rgb24_to_gray8, yuyv_to_gray8, yuyv_to_rgb24},
+};
+use image::{ImageBuffer, Rgb};
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex, Weak},
+    time::Instant,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    MJpeg,
+    Yuyv,
+    Nv12,
+    Rgb24,
+    Gray8,
+}
+
+impl PixelFormat {
+    /// Bytes per row. Compressed formats don't have rows, they get 0.
+    pub fn stride(&self, width: u32) -> usize {
+        let width = width as usize;
+        match self {
+            PixelFormat::MJpeg => 0,
+            PixelFormat::Yuyv => width * 2,
+            // the Y plane, the UV plane has the same stride at half the height
+            PixelFormat::Nv12 | PixelFormat::Gray8 => width,
+            PixelFormat::Rgb24 => width * 3,
+        }
+    }
+}
+
+impl From<DeviceFormat> for PixelFormat {
+    fn from(fmt: DeviceFormat) -> Self {
+        match fmt {
+            DeviceFormat::MJpeg => PixelFormat::MJpeg,
+            DeviceFormat::Yuyv => PixelFormat::Yuyv,
+            DeviceFormat::Nv12 => PixelFormat::Nv12,
+        }
+    }
+}
+
+struct PoolInner {
+    free: Mutex<Vec<Vec<u8>>>,
+    max_free: usize,
+}
+
+/// Hands out frame buffers and takes them back once the frame is dropped, so the capture path
+/// allocates while it warms up and then stops. Cloning gives another handle to the same pool.
+#[derive(Clone)]
+pub struct FramePool {
+    inner: Arc<PoolInner>,
+}
+
+impl FramePool {
+    /// Keeps at most `max_free` unused buffers around, anything past that is freed.
+    pub fn new(max_free: usize) -> Self {
+        FramePool {
+            inner: Arc::new(PoolInner {
+                free: Mutex::new(Vec::with_capacity(max_free)),
+                max_free,
+            }),
+        }
+    }
+
+    /// An empty buffer that can hold at least `capacity` bytes.
+    pub fn buffer(&self, capacity: usize) -> PooledBuffer {
+        let reused = {
+            let mut free = self.inner.free.lock().unwrap();
+            // prefer one that is already big enough, otherwise grow the biggest one
+            match free.iter().position(|buf| buf.capacity() >= capacity) {
+                Some(idx) => Some(free.swap_remove(idx)),
+                None => free.pop(),
+            }
+        };
+        let mut data = reused.unwrap_or_default();
+        data.clear();
+        data.reserve(capacity);
+        self.adopt(data)
+    }
+
+    /// Put a buffer someone else allocated under the pool, it comes back here once dropped.
+    pub fn adopt(&self, data: Vec<u8>) -> PooledBuffer {
+        PooledBuffer {
+            data,
+            pool: Arc::downgrade(&self.inner),
+        }
+    }
+
+    /// Give back a buffer that was taken out with `PooledBuffer::into_vec`.
+    pub fn recycle(&self, data: Vec<u8>) {
+        recycle(&self.inner, data);
+    }
+}
+
+fn recycle(pool: &PoolInner, data: Vec<u8>) {
+    if data.capacity() == 0 {
+        return;
+    }
+    let mut free = pool.free.lock().unwrap();
+    if free.len() < pool.max_free {
+        free.push(data);
+    }
+}
+
+/// A `Vec<u8>` that goes back to its pool when dropped. If the pool is gone it is just freed.
+pub struct PooledBuffer {
+    data: Vec<u8>,
+    pool: Weak<PoolInner>,
+}
+
+impl PooledBuffer {
+    /// Take the bytes out of the pool's hands, for APIs that insist on owning a `Vec`.
+    pub fn into_vec(mut self) -> Vec<u8> {
+        std::mem::take(&mut self.data)
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.data
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.data
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.upgrade() {
+            recycle(&pool, std::mem::take(&mut self.data));
+        }
+    }
+}
+
+/// A frame off the camera, or what it got converted into.
+pub struct Frame {
+    format: PixelFormat,
+    res: Resolution,
+    stride: usize,
+    timestamp: Instant,
+    buffer: PooledBuffer,
+}
+
+impl Frame {
+    /// With rows packed tightly, see `with_stride` for frames that aren't.
+    pub fn new(
+        format: PixelFormat,
+        res: Resolution,
+        timestamp: Instant,
+        buffer: PooledBuffer,
+    ) -> Self {
+        Frame::with_stride(format, res, format.stride(res.x), timestamp, buffer)
+    }
+
+    /// For buffers with padding after every row, like drivers hand out. Anything shorter than a
+    /// packed row is taken as packed.
+    pub fn with_stride(
+        format: PixelFormat,
+        res: Resolution,
+        stride: usize,
+        timestamp: Instant,
+        buffer: PooledBuffer,
+    ) -> Self {
+        Frame {
+            format,
+            res,
+            stride: stride.max(format.stride(res.x)),
+            timestamp,
+            buffer,
+        }
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// For MJPEG this is what the camera was asked for, the decoded frame has the real one.
+    pub fn res(&self) -> Resolution {
+        self.res
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// When the frame came off the camera. Conversions keep it.
+    pub fn timestamp(&self) -> Instant {
+        self.timestamp
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// The bytes on their own, they don't go back to the pool after this.
+    pub fn into_data(self) -> Vec<u8> {
+        self.buffer.into_vec()
+    }
+
+    /// Decode or convert into packed RGB. The old buffer goes back to the pool, the new one
+    /// comes out of `pool`. `None` if the frame is broken.
+    pub fn into_rgb24(self, pool: &FramePool) -> Option<Frame> {
+        if self.format == PixelFormat::Rgb24 {
+            return Some(self);
+        }
+        let mut out = pool.buffer(self.res.x as usize * self.res.y as usize * 3);
+        let res = match self.format {
+            PixelFormat::Rgb24 => unreachable!(),
+            PixelFormat::MJpeg => convert_mjpeg_rgb24(&self.buffer, &mut out)?,
+            PixelFormat::Yuyv => {
+                yuyv_to_rgb24(&self.buffer, self.res, self.stride, &mut out);
+                self.res
+            }
+            PixelFormat::Nv12 => {
+                nv12_to_rgb24(&self.buffer, self.res, self.stride, &mut out);
+                self.res
+            }
+            PixelFormat::Gray8 => {
+                for luma in self.buffer.iter() {
+                    out.extend_from_slice(&[*luma; 3]);
+                }
+                self.res
+            }
+        };
+        Some(Frame::new(PixelFormat::Rgb24, res, self.timestamp, out))
+    }
+
+    /// Like `into_rgb24`, but MJPEG gets decoded straight to about `target` instead of full size.
+    /// It never comes out smaller than `target`, so there may be a resize left to do. The other
+    /// formats have nothing to gain and come out at full size.
+    pub fn into_rgb24_scaled(self, pool: &FramePool, target: Resolution) -> Option<Frame> {
+        if self.format != PixelFormat::MJpeg {
+            return self.into_rgb24(pool);
+        }
+        let mut out = pool.buffer(target.x as usize * target.y as usize * 3);
+        let res = decode_mjpeg(&self.buffer, Some(target), false, &mut out)?;
+        Some(Frame::new(PixelFormat::Rgb24, res, self.timestamp, out))
+    }
+
+    /// `into_rgb24_scaled` for luma only, MJPEG skips the chroma entirely.
+    pub fn into_gray8_scaled(self, pool: &FramePool, target: Resolution) -> Option<Frame> {
+        if self.format != PixelFormat::MJpeg {
+            return self.into_gray8(pool);
+        }
+        let mut out = pool.buffer(target.x as usize * target.y as usize);
+        let res = decode_mjpeg(&self.buffer, Some(target), true, &mut out)?;
+        Some(Frame::new(PixelFormat::Gray8, res, self.timestamp, out))
+    }
+
+    /// One byte of luma per pixel. Cheap for the YUV formats, they already carry it.
+    pub fn into_gray8(self, pool: &FramePool) -> Option<Frame> {
+        if self.format == PixelFormat::Gray8 {
+            return Some(self);
+        }
+        let mut out = pool.buffer(self.res.x as usize * self.res.y as usize);
+        match self.format {
+            PixelFormat::Gray8 => unreachable!(),
+            PixelFormat::Yuyv => yuyv_to_gray8(&self.buffer, self.res, self.stride, &mut out),
+            PixelFormat::Nv12 => nv12_to_gray8(&self.buffer, self.res, self.stride, &mut out),
+            PixelFormat::Rgb24 => rgb24_to_gray8(&self.buffer, &mut out),
+            PixelFormat::MJpeg => {
+                let res = decode_mjpeg(&self.buffer, None, true, &mut out)?;
+                return Some(Frame::new(PixelFormat::Gray8, res, self.timestamp, out));
+            }
+        }
+        Some(Frame::new(
+            PixelFormat::Gray8,
+            self.res,
+            self.timestamp,
+            out,
+        ))
+    }
+
+    /// Hand an RGB frame's buffer over to `image` without copying it. Give it back with
+    /// `FramePool::recycle(image.into_raw())` once done.
+    pub fn into_image(self) -> Option<ImageBuffer<Rgb<u8>, Vec<u8>>> {
+        if self.format != PixelFormat::Rgb24 {
+            return None;
+        }
+        let Resolution { x, y } = self.res;
+        let mut data = self.buffer.into_vec();
+        // cameras like to hand out short frames every now and then
+        data.resize(x as usize * y as usize * 3, 0_u8);
+        ImageBuffer::from_raw(x, y, data)
+    }
+}
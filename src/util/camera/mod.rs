@@ -14,6 +14,10 @@
 //     You should have received a copy of the GNU General Public License
 //     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod camera_controls;
 pub mod camera_device;
+pub mod device_monitor;
 pub mod device_utils;
+pub mod frame;
+pub mod pixel_format;
 pub mod webcam;
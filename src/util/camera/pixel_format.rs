@@ -0,0 +1,164 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Conversions for the uncompressed formats webcams hand out. Everything here is BT.601 limited
+// range, which is what every UVC camera we've seen uses, and integer only so it stays cheap
+// enough to run on the capture path. They all append to `out` so pooled buffers can be reused.
+// `stride` is the bytes per row of `data` (per row of the Y plane for NV12), drivers like to pad
+// rows out to some alignment. `out` is always packed.
+
+use crate::util::camera::device_utils::Resolution;
+
+#[inline]
+fn clamp_u8(value: i32) -> u8 {
+    value.max(0).min(255) as u8
+}
+
+#[inline]
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let c = 298 * (i32::from(y) - 16);
+    let d = i32::from(u) - 128;
+    let e = i32::from(v) - 128;
+    [
+        clamp_u8((c + 409 * e + 128) >> 8),
+        clamp_u8((c - 100 * d - 208 * e + 128) >> 8),
+        clamp_u8((c + 516 * d + 128) >> 8),
+    ]
+}
+
+// the used part of every row, a short last row counts, a missing one ends it
+fn rows(data: &[u8], stride: usize, used: usize, height: usize) -> impl Iterator<Item = &[u8]> {
+    let stride = stride.max(used).max(1);
+    data.chunks(stride)
+        .take(height)
+        .map(move |row| &row[..used.min(row.len())])
+}
+
+/// Packed 4:2:2, `Y0 U Y1 V` for every two pixels.
+pub fn yuyv_to_rgb24(data: &[u8], res: Resolution, stride: usize, out: &mut Vec<u8>) {
+    out.reserve((res.x * res.y * 3) as usize);
+    for row in rows(data, stride, res.x as usize * 2, res.y as usize) {
+        for chunk in row.chunks_exact(4) {
+            out.extend_from_slice(&yuv_to_rgb(chunk[0], chunk[1], chunk[3]));
+            out.extend_from_slice(&yuv_to_rgb(chunk[2], chunk[1], chunk[3]));
+        }
+    }
+}
+
+/// Planar 4:2:0, a full size Y plane followed by interleaved `U V` at half resolution, both with
+/// the same stride. With an odd width the last `U V` pair only covers one pixel.
+pub fn nv12_to_rgb24(data: &[u8], res: Resolution, stride: usize, out: &mut Vec<u8>) {
+    let width = res.x as usize;
+    let height = res.y as usize;
+    let chroma_width = (width + 1) & !1;
+    let stride = stride.max(width);
+    let (luma, chroma) = data.split_at((stride * height).min(data.len()));
+    out.reserve(width * height * 3);
+    for row in 0..height {
+        let luma_row = match luma.get(row * stride..row * stride + width) {
+            Some(r) => r,
+            None => break,
+        };
+        let chroma_start = (row / 2) * stride;
+        let chroma_row = chroma
+            .get(chroma_start..chroma.len().min(chroma_start + chroma_width))
+            .filter(|uv| uv.len() >= width);
+        for (col, y) in luma_row.iter().enumerate() {
+            let (u, v) = match chroma_row {
+                // a short last row can be missing the V of an odd last column
+                Some(uv) => (uv[col & !1], uv.get(col | 1).copied().unwrap_or(128)),
+                // truncated frame, grey is better than garbage
+                None => (128, 128),
+            };
+            out.extend_from_slice(&yuv_to_rgb(*y, u, v));
+        }
+    }
+}
+
+pub fn yuyv_to_gray8(data: &[u8], res: Resolution, stride: usize, out: &mut Vec<u8>) {
+    for row in rows(data, stride, res.x as usize * 2, res.y as usize) {
+        out.extend(row.iter().step_by(2));
+    }
+}
+
+pub fn nv12_to_gray8(data: &[u8], res: Resolution, stride: usize, out: &mut Vec<u8>) {
+    for row in rows(data, stride, res.x as usize, res.y as usize) {
+        out.extend_from_slice(row);
+    }
+}
+
+pub fn rgb24_to_gray8(data: &[u8], out: &mut Vec<u8>) {
+    out.extend(data.chunks_exact(3).map(|px| {
+        ((77 * u32::from(px[0]) + 150 * u32::from(px[1]) + 29 * u32::from(px[2])) >> 8) as u8
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GREY: [u8; 3] = [127, 127, 127];
+
+    #[test]
+    fn nv12_odd_width() {
+        // 3x2, the chroma row is 4 bytes long with the last pair covering one pixel
+        let mut data = vec![125; 6];
+        data.extend_from_slice(&[128; 4]);
+        let mut out = Vec::new();
+        nv12_to_rgb24(&data, Resolution::new(3, 2), 3, &mut out);
+        assert_eq!(out, GREY.repeat(6));
+    }
+
+    #[test]
+    fn nv12_odd_width_without_chroma_padding() {
+        // a driver that only sends `width` bytes of chroma per row mustn't crash us
+        let mut data = vec![125; 6];
+        data.extend_from_slice(&[128; 3]);
+        let mut out = Vec::new();
+        nv12_to_rgb24(&data, Resolution::new(3, 2), 3, &mut out);
+        assert_eq!(out, GREY.repeat(6));
+    }
+
+    #[test]
+    fn nv12_skips_stride_padding() {
+        let packed = [125, 125, 125, 125, 128, 128];
+        let padded = [125, 125, 0, 0, 125, 125, 0, 0, 128, 128, 0, 0];
+        let mut from_packed = Vec::new();
+        let mut from_padded = Vec::new();
+        nv12_to_rgb24(&packed, Resolution::new(2, 2), 2, &mut from_packed);
+        nv12_to_rgb24(&padded, Resolution::new(2, 2), 4, &mut from_padded);
+        assert_eq!(from_packed, GREY.repeat(4));
+        assert_eq!(from_padded, from_packed);
+    }
+
+    #[test]
+    fn nv12_truncated_frame_is_grey() {
+        let mut out = Vec::new();
+        nv12_to_rgb24(&[125; 4], Resolution::new(2, 2), 2, &mut out);
+        assert_eq!(out, GREY.repeat(4));
+    }
+
+    #[test]
+    fn yuyv_skips_stride_padding() {
+        let padded = [125, 128, 125, 128, 0, 0, 125, 128, 125, 128, 0, 0];
+        let mut rgb = Vec::new();
+        let mut gray = Vec::new();
+        yuyv_to_rgb24(&padded, Resolution::new(2, 2), 6, &mut rgb);
+        yuyv_to_gray8(&padded, Resolution::new(2, 2), 6, &mut gray);
+        assert_eq!(rgb, GREY.repeat(4));
+        assert_eq!(gray, vec![125; 4]);
+    }
+}
@@ -14,7 +14,15 @@
 //     You should have received a copy of the GNU General Public License
 //     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::util::camera::device_utils::{DeviceContact, PossibleDevice, Resolution};
+use crate::{
+    error::invalid_device_error::InvalidDeviceError::CannotSetProperty,
+    util::camera::{
+        camera_controls::{CameraControl, ControlDescription},
+        device_utils::{DeviceContact, DeviceDesc, DeviceFormat, PossibleDevice, Resolution},
+        frame::{Frame, FramePool, PixelFormat},
+    },
+};
+use std::time::Instant;
 
 pub trait Webcam<'a> {
     fn name(&self) -> String;
@@ -25,6 +33,44 @@ pub trait Webcam<'a> {
     fn get_camera_type(&self) -> WebcamType;
     fn open_stream(&self) -> Result<(), Box<dyn std::error::Error>>;
     fn get_frame(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>>;
+    /// Get the next frame without decoding it, so the decode can happen on another thread.
+    /// Cameras that only ever give us decoded frames can leave this alone.
+    fn get_raw_frame(&self, pool: &FramePool) -> Result<Frame, Box<dyn std::error::Error>> {
+        let data = self.get_frame()?;
+        Ok(Frame::new(
+            PixelFormat::Rgb24,
+            self.get_resolution()?,
+            Instant::now(),
+            pool.adopt(data),
+        ))
+    }
+    /// What the camera sends frames as. Changing it only takes effect on the next `open_stream`.
+    fn get_format(&self) -> DeviceFormat {
+        DeviceFormat::MJpeg
+    }
+    fn set_format(&self, fmt: DeviceFormat) -> Result<(), Box<dyn std::error::Error>> {
+        if fmt == DeviceFormat::MJpeg {
+            return Ok(());
+        }
+        Err(Box::new(CannotSetProperty(format!(
+            "{} is not supported by this camera",
+            fmt
+        ))))
+    }
+    /// The image controls this camera has, with their current values. Empty if we can't touch any.
+    fn get_controls(&self) -> Result<Vec<ControlDescription>, Box<dyn std::error::Error>> {
+        Ok(vec![])
+    }
+    fn set_control(
+        &self,
+        control: CameraControl,
+        _value: i64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Err(Box::new(CannotSetProperty(format!(
+            "{} is not supported by this camera",
+            control.name()
+        ))))
+    }
     // fn as_any(&self) -> &dyn Any;
 }
 
@@ -35,6 +81,10 @@ pub trait QueryCamera<'a>: Webcam<'a> {
         res: Resolution,
     ) -> Result<Vec<u32>, Box<dyn std::error::Error>>;
     fn get_location(&self) -> DeviceContact;
+    /// Something that still finds this camera after a reboot or after replugging it.
+    fn get_identity(&self) -> DeviceDesc {
+        DeviceDesc::from_default().with_name(self.name())
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -49,7 +99,9 @@ impl WebcamType {
         match pd {
             PossibleDevice::UniversalVideoCamera { .. } => WebcamType::UsbVideo,
             PossibleDevice::Video4Linux2 { .. } => WebcamType::V4linux2,
-            PossibleDevice::OpenComVision { .. } => WebcamType::OpenCVCapture,
+            PossibleDevice::OpenComVision { .. } | PossibleDevice::VideoFile { .. } => {
+                WebcamType::OpenCVCapture
+            }
         }
     }
 }
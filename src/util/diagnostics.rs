@@ -0,0 +1,128 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{
+    configuration::{overrides::Overrides, user_config::UserConfig},
+    util::{
+        camera::device_utils::{enumerate_cache_device, Resolution},
+        log::{log_dir, log_file_path},
+    },
+};
+use std::{
+    fs::{read_to_string, File},
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+// only the end of each log, nobody reads a week of frame drops in a bug report
+const LOG_TAIL_BYTES: u64 = 262_144;
+// the current log and the one before it, in case it was a crash
+const LOGS_INCLUDED: usize = 2;
+
+/// Everything worth attaching to a bug report as one block of text: version, platform, the
+/// settings file and launch options, what the cameras say they can do and the end of the newest
+/// logs.
+pub fn collect_diagnostics() -> String {
+    let mut out = format!(
+        "Open2DHolo {} on {} {}\n",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    );
+
+    let settings_path = UserConfig::settings_path();
+    section(&mut out, &format!("Settings ({})", settings_path.display()));
+    match read_to_string(&settings_path) {
+        Ok(settings) => out.push_str(&settings),
+        Err(why) => out.push_str(&format!("Could not read them: {}\n", why)),
+    }
+
+    let overrides = Overrides::current();
+    if !overrides.is_empty() {
+        section(&mut out, "Launch options");
+        out.push_str(&format!("{}\n", overrides));
+    }
+
+    section(&mut out, "Cameras");
+    out.push_str(&device_dump());
+
+    match log_dir() {
+        Some(dir) => {
+            for age in 0..LOGS_INCLUDED {
+                let path = log_file_path(&dir, age);
+                if path.exists() {
+                    section(&mut out, &format!("Log ({})", path.display()));
+                    out.push_str(&tail(&path));
+                }
+            }
+        }
+        None => section(&mut out, "No log directory on this platform"),
+    }
+    out
+}
+
+/// Every camera we can see, with every format, resolution and frame rate it claims to support.
+pub fn device_dump() -> String {
+    let mut devices: Vec<_> = enumerate_cache_device()
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    if devices.is_empty() {
+        return "None found.\n".to_string();
+    }
+    devices.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut out = String::new();
+    for (name, device) in devices {
+        out.push_str(&format!("{}: {:?}\n", name, device.get_identity()));
+        for fmt in device.get_formats() {
+            let mut supported: Vec<(Resolution, Vec<u32>)> =
+                device.get_supported(fmt).into_iter().collect();
+            supported.sort_by(|(a, _), (b, _)| b.cmp(a));
+            for (res, mut fps) in supported {
+                fps.sort_unstable();
+                let fps: Vec<String> = fps.iter().map(ToString::to_string).collect();
+                out.push_str(&format!("    {} {} @ {}\n", fmt, res, fps.join(", ")));
+            }
+        }
+    }
+    out
+}
+
+fn section(out: &mut String, title: &str) {
+    out.push_str(&format!("\n===== {} =====\n", title));
+}
+
+// the last `LOG_TAIL_BYTES` of a file, starting on a whole line
+fn tail(path: &Path) -> String {
+    let read = || -> Result<String, std::io::Error> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let start = len.saturating_sub(LOG_TAIL_BYTES);
+        file.seek(SeekFrom::Start(start))?;
+        let mut bytes = Vec::with_capacity((len - start) as usize);
+        file.read_to_end(&mut bytes)?;
+        let text = String::from_utf8_lossy(&bytes).to_string();
+        if start == 0 {
+            return Ok(text);
+        }
+        Ok(match text.find('\n') {
+            Some(newline) => text[newline + 1..].to_string(),
+            None => text,
+        })
+    };
+    read().unwrap_or_else(|why| format!("Could not read it: {}\n", why))
+}
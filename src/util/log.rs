@@ -0,0 +1,289 @@
+//     Open2DHolo - Open 2D Holo, a program to procedurally animate your face onto an 3D Model.
+//     Copyright (C) 2020-2021 l1npengtul
+//
+//     This program is free software: you can redistribute it and/or modify
+//     it under the terms of the GNU General Public License as published by
+//     the Free Software Foundation, either version 3 of the License, or
+//     (at your option) any later version.
+//
+//     This program is distributed in the hope that it will be useful,
+//     but WITHOUT ANY WARRANTY; without even the implied warranty of
+//     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//     GNU General Public License for more details.
+//
+//     You should have received a copy of the GNU General Public License
+//     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{
+    collections::HashMap,
+    fmt::{Arguments, Display, Formatter},
+    fs::{create_dir_all, remove_file, rename, File, OpenOptions},
+    io::{LineWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Once the current log gets this big it is moved aside and a new one started.
+pub const MAX_LOG_SIZE: u64 = 1_048_576;
+/// The current log plus this many old ones are kept around.
+pub const KEPT_LOG_FILES: usize = 4;
+const LOG_NAME: &str = "open2dholo";
+
+/// Most important first, so `level <= max` means it gets logged.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        };
+        // padded so the messages line up
+        write!(f, "{:<5}", name)
+    }
+}
+
+/// Which part of the program a message is about.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Target {
+    Camera,
+    Processing,
+    Model,
+    Ui,
+    Output,
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Target::Camera => "camera",
+            Target::Processing => "processing",
+            Target::Model => "model",
+            Target::Ui => "ui",
+            Target::Output => "output",
+        };
+        write!(f, "{:<10}", name)
+    }
+}
+
+pub struct Record<'a> {
+    pub level: Level,
+    pub target: Target,
+    pub time: SystemTime,
+    /// The name of the thread it was logged from, if it has one.
+    pub thread: Option<&'a str>,
+    pub message: &'a str,
+}
+
+impl<'a> Record<'a> {
+    /// The whole record on one line, the way it goes into the log file.
+    pub fn line(&self) -> String {
+        format!(
+            "{} {} {} [{}] {}",
+            format_timestamp(self.time),
+            self.level,
+            self.target,
+            self.thread.unwrap_or("?"),
+            self.message
+        )
+    }
+}
+
+/// Somewhere log records go. Every logger gets every record that passes the level filter.
+pub trait Logger: Send + Sync {
+    fn log(&self, record: &Record);
+}
+
+pub struct StderrLogger;
+
+impl Logger for StderrLogger {
+    fn log(&self, record: &Record) {
+        eprintln!("{}", record.line());
+    }
+}
+
+struct LogState {
+    loggers: Vec<Box<dyn Logger>>,
+    max_level: Level,
+    target_levels: HashMap<Target, Level>,
+}
+
+lazy_static! {
+    static ref STATE: RwLock<LogState> = RwLock::new(LogState {
+        loggers: vec![Box::new(StderrLogger)],
+        max_level: Level::Info,
+        target_levels: HashMap::new(),
+    });
+}
+
+/// Replace every logger for the whole program, from every thread on.
+pub fn set_loggers(loggers: Vec<Box<dyn Logger>>) {
+    STATE.write().unwrap().loggers = loggers;
+}
+
+pub fn add_logger(logger: Box<dyn Logger>) {
+    STATE.write().unwrap().loggers.push(logger);
+}
+
+/// The least important level that still gets logged, for targets without their own.
+pub fn set_max_level(level: Level) {
+    STATE.write().unwrap().max_level = level;
+}
+
+/// Give a single target its own level, `None` puts it back on the global one.
+pub fn set_target_level(target: Target, level: Option<Level>) {
+    let mut state = STATE.write().unwrap();
+    match level {
+        Some(level) => state.target_levels.insert(target, level),
+        None => state.target_levels.remove(&target),
+    };
+}
+
+pub fn enabled(level: Level, target: Target) -> bool {
+    let state = STATE.read().unwrap();
+    level <= *state.target_levels.get(&target).unwrap_or(&state.max_level)
+}
+
+/// What the `log_*!` macros end up calling.
+pub fn log(level: Level, target: Target, args: Arguments) {
+    if !enabled(level, target) {
+        return;
+    }
+    let message = args.to_string();
+    let thread = std::thread::current();
+    let record = Record {
+        level,
+        target,
+        time: SystemTime::now(),
+        thread: thread.name(),
+        message: &message,
+    };
+    for logger in &STATE.read().unwrap().loggers {
+        logger.log(&record);
+    }
+}
+
+/// Where the log files go, in the user's data directory.
+pub fn log_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("open2dholo").join("logs"))
+}
+
+/// `open2dholo.log` for the current one, `open2dholo.1.log` for the one before and so on.
+pub fn log_file_path(dir: &Path, age: usize) -> PathBuf {
+    if age == 0 {
+        dir.join(format!("{}.log", LOG_NAME))
+    } else {
+        dir.join(format!("{}.{}.log", LOG_NAME, age))
+    }
+}
+
+struct LogFile {
+    writer: LineWriter<File>,
+    written: u64,
+}
+
+/// Writes into `log_dir`, starting a new file every run and whenever the current one gets past
+/// `MAX_LOG_SIZE`. Only the newest `KEPT_LOG_FILES + 1` files stay around.
+pub struct RotatingFileLogger {
+    dir: PathBuf,
+    file: Mutex<Option<LogFile>>,
+}
+
+impl RotatingFileLogger {
+    pub fn new(dir: PathBuf) -> Result<Self, std::io::Error> {
+        create_dir_all(&dir)?;
+        let logger = RotatingFileLogger {
+            dir,
+            file: Mutex::new(None),
+        };
+        *logger.file.lock().unwrap() = Some(logger.rotate()?);
+        Ok(logger)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // shift every old file up by one, dropping the oldest, then start a fresh one
+    fn rotate(&self) -> Result<LogFile, std::io::Error> {
+        let oldest = log_file_path(&self.dir, KEPT_LOG_FILES);
+        if oldest.exists() {
+            remove_file(&oldest)?;
+        }
+        for age in (0..KEPT_LOG_FILES).rev() {
+            let from = log_file_path(&self.dir, age);
+            if from.exists() {
+                rename(&from, log_file_path(&self.dir, age + 1))?;
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(log_file_path(&self.dir, 0))?;
+        Ok(LogFile {
+            writer: LineWriter::new(file),
+            written: 0,
+        })
+    }
+}
+
+impl Logger for RotatingFileLogger {
+    fn log(&self, record: &Record) {
+        let mut file = self.file.lock().unwrap();
+        if file
+            .as_ref()
+            .map_or(true, |file| file.written >= MAX_LOG_SIZE)
+        {
+            // a log that can't be written has nowhere to complain to, it just stops
+            *file = self.rotate().ok();
+        }
+        if let Some(file) = file.as_mut() {
+            let line = record.line();
+            if writeln!(file.writer, "{}", line).is_ok() {
+                file.written += line.len() as u64 + 1;
+            }
+        }
+    }
+}
+
+// UTC, down to the millisecond. Not worth a date crate for this one line.
+fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let secs_of_day = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+// days since 1970-01-01 to year, month, day. See Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
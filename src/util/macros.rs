@@ -118,6 +118,48 @@ macro_rules! wtf {
     }};
 }
 
+// `log_at!(Warn, Camera, "...", ...)`, the level and target being `util::log::Level` and
+// `util::log::Target` variants. The `log_error!` etc. below are shorter.
+#[macro_export]
+macro_rules! log_at {
+    ($level:ident, $target:ident, $($args:tt)*) => {{
+        let level = $crate::util::log::Level::$level;
+        let target = $crate::util::log::Target::$target;
+        // don't bother formatting what nobody is going to see
+        if $crate::util::log::enabled(level, target) {
+            $crate::util::log::log(level, target, format_args!($($args)*));
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($target:ident, $($args:tt)*) => {
+        $crate::log_at!(Error, $target, $($args)*)
+    };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($target:ident, $($args:tt)*) => {
+        $crate::log_at!(Warn, $target, $($args)*)
+    };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($target:ident, $($args:tt)*) => {
+        $crate::log_at!(Info, $target, $($args)*)
+    };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($target:ident, $($args:tt)*) => {
+        $crate::log_at!(Debug, $target, $($args)*)
+    };
+}
+
 #[macro_export]
 macro_rules! globalize_path {
     ($path:expr) => {{
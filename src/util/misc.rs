@@ -14,95 +14,384 @@
 //     You should have received a copy of the GNU General Public License
 //     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::util::camera::device_utils::{DeviceConfig, PossibleDevice, Resolution};
+use crate::{
+    error::processing_thread_error::ProcessingThreadError,
+    log_warn,
+    processing::{
+        confidence::FaceConfidence,
+        face_identity::PrimaryFacePolicy,
+        landmark::{LandmarkLayout, LandmarkScale, DEFAULT_MODEL_DIR, DLIB_68_MODEL_FILE},
+        pipeline_stats::PipelineStatsSnapshot,
+        preprocess::PreprocessConfig,
+        tracker::TrackingMode,
+    },
+    util::camera::{
+        camera_controls::{CameraControl, ControlDescription},
+        device_utils::{DeviceConfig, PossibleDevice, Resolution},
+    },
+};
 use euclid::{Box2D, UnknownUnit};
 use facial_processing::utils::misc::{BackendProviders, EulerAngles, Point2D};
-use gdnative::core_types::{ToVariant, Variant, Vector2, Vector2Array, Vector3};
 use serde::{Deserialize, Serialize};
-use std::{fs::File, io::Read};
-
-// TODO: Change to acutal data format
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+/// Requests for the processing thread. Every request gets exactly one `ControlResponse` back.
 #[derive(Clone)]
 pub enum MessageType {
-    Die(u8),
+    Shutdown,
     SetDevice {
         name: Option<String>,
         device: PossibleDevice,
     },
     ChangeDevice(DeviceConfig),
+    SetScaleFactor(f64),
+    SetPrimaryFace(PrimaryFacePolicy),
+    /// Keep reading the camera, but stop processing frames.
+    Pause,
+    Resume,
+    /// Let exactly one frame through while paused.
+    Step,
+    SetBackend(Backend),
+    SetFilter(FilterParams),
+    SetDecode(DecodeOptions),
+    SetPreprocess(PreprocessConfig),
+    /// Whether the workers should hand out a copy of the frames for the UI to show.
+    SetPreview(bool),
+    /// Take the current head pose of the primary face as looking straight ahead.
+    /// Answered with `ControlReply::Calibrated` once the next primary face has been seen.
+    Calibrate,
+    /// Put back a neutral pose from an earlier `Calibrate`, or forget it with `None`.
+    SetCalibration(Option<NeutralPose>),
+    QueryDevice,
+    QueryStats,
+    SetControl(CameraControl, i64),
+    QueryControls,
+}
+
+pub struct ControlRequest {
+    pub id: u64,
+    pub msg: MessageType,
+}
+
+pub struct ControlResponse {
+    /// The `id` of the `ControlRequest` this answers.
+    pub id: u64,
+    pub result: Result<ControlReply, ProcessingThreadError>,
+}
+
+pub enum ControlReply {
+    Done,
+    /// The neutral pose a `Calibrate` ended up with.
+    Calibrated(NeutralPose),
+    Device(DeviceState),
+    Stats(PipelineStatsSnapshot),
+    Controls(Vec<ControlDescription>),
+}
+
+#[derive(Clone, Debug)]
+pub struct DeviceState {
+    pub name: String,
+    pub res: Resolution,
+    pub fps: u32,
+    pub paused: bool,
+    pub backend: Backend,
+    pub scale_factor: f64,
+}
+
+/// Everything that decides which frames are good enough to use.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FilterParams {
+    pub tracking: TrackingMode,
+    pub min_confidence: f64,
+}
+
+/// The head rotation that counts as looking straight ahead, in radians like `EulerAngles`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct NeutralPose {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+/// How the workers turn camera frames into detection input.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct DecodeOptions {
+    /// Only decode brightness. Cheaper for MJPEG, and dlib throws the colour away anyway.
+    pub grayscale: bool,
+    /// Keep every frame at full resolution for the preview. MJPEG has to be decoded at full
+    /// size for it, so this gives up most of what decoding at the working resolution saves.
+    pub keep_full_frame: bool,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub enum Backend {
     Dlib,
+    // dlib still does the face detection, the model only replaces the landmark predictor.
+    Onnx {
+        model_path: String,
+        input_size: u32,
+        layout: LandmarkLayout,
+        scale: LandmarkScale,
+    },
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct BackendConfig {
     backend: Backend,
     input_src_original: Resolution,
     input_src_scaled: Resolution,
+    tracking: TrackingMode,
+    primary_face: PrimaryFacePolicy,
+    min_confidence: f64,
+    max_threads: usize,
+    decode: DecodeOptions,
+    preprocess: PreprocessConfig,
+    preview: bool,
+    lossless: bool,
+    cnn_detector: bool,
+    model_dir: PathBuf,
+    calibration: Option<NeutralPose>,
     // DeviceConfig TODO: wait for TVM
 }
 impl BackendConfig {
     pub fn new(res: Resolution, backend: Backend) -> Self {
-        // let mut scaled_res = Resolution::new(640, 480);
-        // let mut scale_x = 1_f64;
-        // let mut scale_y = 1_f64;
-        // if res.x < 640 {
-
-        // }
+        // anything above 480p is wasted on the detector, only ever scale down
+        let scale = (f64::from(DEFAULT_WORKING_HEIGHT) / f64::from(res.y)).min(1_f64);
         BackendConfig {
             backend,
             input_src_original: res,
-            input_src_scaled: res, // TODO: find min scale factor
+            input_src_scaled: scale_resolution(res, scale),
+            tracking: TrackingMode::default(),
+            primary_face: PrimaryFacePolicy::default(),
+            min_confidence: DEFAULT_MIN_CONFIDENCE,
+            max_threads: DEFAULT_MAX_THREADS,
+            decode: DecodeOptions::default(),
+            preprocess: PreprocessConfig::default(),
+            preview: false,
+            lossless: false,
+            cnn_detector: false,
+            model_dir: PathBuf::from(DEFAULT_MODEL_DIR),
+            calibration: None,
         }
     }
 
-    pub fn backend_as_facial(&self) -> BackendProviders {
+    /// Set the fraction of the input resolution the frames get processed at. 1.0 means no scaling.
+    pub fn with_scale_factor(mut self, scale: f64) -> Self {
+        self.input_src_scaled = scale_resolution(self.input_src_original, scale.min(1_f64));
+        self
+    }
+
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub fn with_filter(self, filter: FilterParams) -> Self {
+        self.with_tracking(filter.tracking)
+            .with_min_confidence(filter.min_confidence)
+    }
+
+    pub fn with_tracking(mut self, tracking: TrackingMode) -> Self {
+        self.tracking = tracking;
+        self
+    }
+
+    pub fn with_primary_face(mut self, primary_face: PrimaryFacePolicy) -> Self {
+        self.primary_face = primary_face;
+        self
+    }
+
+    /// Packets with an overall confidence below this don't get to move the avatar.
+    pub fn with_min_confidence(mut self, min_confidence: f64) -> Self {
+        self.min_confidence = min_confidence.max(0_f64).min(1_f64);
+        self
+    }
+
+    /// How many threads the processing pipeline may use in total, capture and the sequencer
+    /// included. Anything below `MIN_THREADS` gets `MIN_THREADS`, the pipeline can't run on less.
+    pub fn with_max_threads(mut self, max_threads: usize) -> Self {
+        if max_threads < MIN_THREADS {
+            log_warn!(
+                Processing,
+                "Tracking needs at least {} threads, not {}, using {}",
+                MIN_THREADS,
+                max_threads,
+                MIN_THREADS
+            );
+        }
+        self.max_threads = max_threads.max(MIN_THREADS);
+        self
+    }
+
+    pub fn with_decode(mut self, decode: DecodeOptions) -> Self {
+        self.decode = decode;
+        self
+    }
+
+    /// Flip, rotate, crop and light adjustments done to every frame before detection.
+    pub fn with_preprocess(mut self, preprocess: PreprocessConfig) -> Self {
+        self.preprocess = preprocess;
+        self
+    }
+
+    /// Keep a working resolution copy of every frame around for the camera preview.
+    pub fn with_preview(mut self, preview: bool) -> Self {
+        self.preview = preview;
+        self
+    }
+
+    /// Track every frame and deliver every packet, for recordings and batch runs. Capture waits
+    /// for the workers and the sequencer waits for the reader instead of dropping anything, and
+    /// video files are read as fast as they can be tracked instead of at their frame rate. Only
+    /// for readers that keep up on their own, nothing gets old enough to be thrown out either.
+    pub fn with_lossless(mut self, lossless: bool) -> Self {
+        self.lossless = lossless;
+        self
+    }
+
+    /// Find faces with dlib's CNN detector instead of the HOG one. Catches faces turned further
+    /// away, but takes many times as long per frame without a GPU.
+    pub fn with_cnn_detector(mut self, cnn_detector: bool) -> Self {
+        self.cnn_detector = cnn_detector;
+        self
+    }
+
+    /// Where the bundled models are, and where relative model paths start from. Relative to the
+    /// working directory by default, Godot points it into the project.
+    pub fn with_model_dir(mut self, model_dir: PathBuf) -> Self {
+        self.model_dir = model_dir;
+        self
+    }
+
+    /// Start out with a neutral pose from an earlier calibration.
+    pub fn with_calibration(mut self, calibration: Option<NeutralPose>) -> Self {
+        self.calibration = calibration;
+        self
+    }
+
+    pub fn backend_as_facial(&self) -> Option<BackendProviders> {
         match self.backend {
-            Backend::Dlib => {
-                BackendProviders::DLib {
-                    face_alignment_path: globalize_path!("res://models/facial-processing-rs-models/shape_predictor_68_face_landmarks.dat")
-                }
-            }
+            Backend::Dlib => Some(BackendProviders::DLib {
+                face_alignment_path: resolve_model_path(&self.model_dir, DLIB_68_MODEL_FILE),
+            }),
+            Backend::Onnx { .. } => None,
         }
     }
 
+    pub fn backend(&self) -> &Backend {
+        &self.backend
+    }
+
+    pub fn tracking(&self) -> TrackingMode {
+        self.tracking
+    }
+
+    pub fn primary_face(&self) -> PrimaryFacePolicy {
+        self.primary_face
+    }
+
+    pub fn min_confidence(&self) -> f64 {
+        self.min_confidence
+    }
+
+    pub fn filter(&self) -> FilterParams {
+        FilterParams {
+            tracking: self.tracking,
+            min_confidence: self.min_confidence,
+        }
+    }
+
+    pub fn max_threads(&self) -> usize {
+        self.max_threads
+    }
+
+    pub fn decode(&self) -> DecodeOptions {
+        self.decode
+    }
+
+    pub fn preprocess(&self) -> PreprocessConfig {
+        self.preprocess
+    }
+
+    pub fn preview(&self) -> bool {
+        self.preview
+    }
+
+    pub fn lossless(&self) -> bool {
+        self.lossless
+    }
+
+    pub fn cnn_detector(&self) -> bool {
+        self.cnn_detector
+    }
+
+    pub fn calibration(&self) -> Option<NeutralPose> {
+        self.calibration
+    }
+
+    pub fn model_dir(&self) -> &Path {
+        &self.model_dir
+    }
+
     pub fn res(&self) -> Resolution {
         self.input_src_original
     }
+
+    pub fn scaled_res(&self) -> Resolution {
+        self.input_src_scaled
+    }
+
+    pub fn scale_factor(&self) -> f64 {
+        f64::from(self.input_src_scaled.y) / f64::from(self.input_src_original.y)
+    }
+
+    /// The resolution a frame of `frame_res` gets processed at. Uses the scale factor rather than
+    /// `scaled_res` directly since the camera resolution can change under us. The crop happens
+    /// after this, so a cropped frame is decoded that much bigger (up to the camera resolution)
+    /// for what is left of it to still get the working height.
+    pub fn working_res(&self, frame_res: Resolution) -> Resolution {
+        let kept = self.preprocess.crop.height_fraction().max(f64::EPSILON);
+        scale_resolution(frame_res, (self.scale_factor() / kept).min(1_f64))
+    }
+}
+
+pub const DEFAULT_WORKING_HEIGHT: u32 = 480;
+pub const DEFAULT_MIN_CONFIDENCE: f64 = 0.2;
+pub const DEFAULT_MAX_THREADS: usize = 8;
+/// Capture, the sequencer and one worker.
+pub const MIN_THREADS: usize = 3;
+
+pub fn scale_resolution(res: Resolution, scale: f64) -> Resolution {
+    Resolution::new(
+        ((f64::from(res.x) * scale).round() as u32).max(1),
+        ((f64::from(res.y) * scale).round() as u32).max(1),
+    )
+}
+
+/// `path` if it is absolute, otherwise `path` inside `model_dir`.
+pub fn resolve_model_path(model_dir: &Path, path: &str) -> String {
+    model_dir.join(path).to_string_lossy().to_string()
 }
 
 #[derive(Clone)]
 pub struct FullyCalculatedPacket {
+    /// Counts up by one for every frame the camera gives us. All faces of a frame share it.
+    pub frame_number: u64,
+    /// When the frame was pulled off the camera.
+    pub captured_at: Instant,
+    /// Stays the same for as long as we keep seeing the same face.
+    pub face_id: u32,
+    /// Whether this is the face that should drive the avatar.
+    pub is_primary: bool,
     pub face_location: Box2D<i32, UnknownUnit>,
     pub landmarks: Vec<Point2D>,
     pub euler: EulerAngles,
-}
-
-impl FullyCalculatedPacket {
-    pub fn to_variants(&self) -> (Variant, Variant, Variant) {
-        let facebox_as_rect2d = self.face_location.to_rect().to_f32().to_variant();
-        let landmarks_as_vector2array = {
-            let mut vector2_array = Vector2Array::new();
-            for point in &self.landmarks {
-                vector2_array.push(Vector2::new(point.x as f32, point.y as f32));
-            }
-            vector2_array.to_variant()
-        };
-        let angles_as_vector3 = Vector3::new(
-            self.euler.x as f32,
-            self.euler.y as f32,
-            self.euler.z as f32,
-        )
-        .to_variant();
-        (
-            facebox_as_rect2d,
-            landmarks_as_vector2array,
-            angles_as_vector3,
-        )
-    }
+    pub confidence: FaceConfidence,
 }
 
 // TODO: Add serde serialize/deserialize to RON or equivalent
@@ -444,21 +733,11 @@ impl ModelReference {
         &self.model_path
     }
 
-    /// the model reference's model path, global path not res://
-    pub fn globalized_model_path(&self) -> String {
-        globalize_path!(self.model_path())
-    }
-
     /// the model reference's tscn path.
     pub fn tscn_path(&self) -> &String {
         &self.tscn_path
     }
 
-    /// the model reference's Scene path, global path not res://
-    pub fn globalized_tscn_path(&self) -> String {
-        globalize_path!(self.tscn_path())
-    }
-
     /// Get a reference to the model reference's creator meta.
     pub fn creator_meta(&self) -> &Option<CreatorMetadata> {
         &self.creator_meta
@@ -15,6 +15,8 @@
 //     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 pub mod camera;
+pub mod diagnostics;
+pub mod log;
 #[macro_use]
 pub mod macros;
 pub mod misc;